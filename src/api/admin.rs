@@ -0,0 +1,1715 @@
+//staff api under /admin, every handler states the permission it needs through Authorized<require::X>
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::{
+        balance::{
+            get_account_state, get_account_tier, get_balance, list_status_changes, set_account_status,
+            set_account_tier, AccountState, AccountStatus, StatusChange,
+        },
+        fees::{create_fee_rule, delete_fee_rule, list_fee_rules, FeeRule, FeeRuleFields},
+        kyc::{get_kyc_submission, list_kyc_submissions, review_kyc, KycLevel, KycSubmission},
+        limits::{create_limit_rule, delete_limit_rule, list_limit_rules, LimitRule, LimitRuleFields},
+        monitoring::{decide_alert, get_alert, list_alerts, Alert, AlertFilter, AlertStatus},
+        screening::{decide_screening, get_screening_result, list_screening_results, ScreeningFilter, ScreeningResult},
+        transactions::{close_account, list_all_transactions, TransactionDetails},
+        users::{get_user_by_id, search_users, set_role, UserInfo},
+    },
+    utilities::{
+        envelope::{db_failure, failure, success},
+        errors::TransactionError,
+        monitoring::RuleSet,
+        rbac::{require, Authorized, Role},
+        screening::SanctionsList,
+    },
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AdminUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub role: Role,
+    pub phone: Option<String>,
+    pub handle: Option<String>,
+    pub kyc_level: KycLevel,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<UserInfo> for AdminUser {
+    fn from(v: UserInfo) -> Self {
+        AdminUser {
+            user_id: v.user_id,
+            username: v.username,
+            email: v.email,
+            role: v.role,
+            phone: v.phone,
+            handle: v.handle,
+            kyc_level: v.kyc_level,
+            email_verified_at: v.email_verified_at,
+            created_at: v.created_at,
+            updated_at: v.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AccountView {
+    pub user: AdminUser,
+    #[schema(value_type = String, example = "25.50")]
+    pub balance: rust_decimal::Decimal,
+    pub state: AccountState,
+    //picks the fee rules of the account
+    #[schema(example = "standard")]
+    pub tier: String,
+    //oldest first
+    pub status_history: Vec<StatusChange>,
+    pub transactions: Vec<TransactionDetails>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct UserSearch {
+    //matched against username and e-mail, case insensitive
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleChangeReq {
+    pub role: Role,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct StatusChangeReq {
+    //kept on the account, shown to staff
+    #[schema(example = "Chargeback investigation #1234")]
+    pub reason: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct StatusUpdateReq {
+    //active, frozen or debit_blocked, closing has its own endpoint
+    pub status: AccountStatus,
+    #[schema(example = "Customer reported a lost card")]
+    pub reason: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CloseAccountReq {
+    #[schema(example = "Customer request")]
+    pub reason: String,
+    //account receiving a remaining balance, defaults to accounts.closure_sweep_account
+    pub sweep_to: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TierChangeReq {
+    #[schema(example = "premium")]
+    pub tier: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AccountTier {
+    pub user_id: Uuid,
+    #[schema(example = "premium")]
+    pub tier: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ClosedAccount {
+    pub state: AccountState,
+    //the transfer that moved the remaining balance, none when it was zero
+    pub sweep_transaction_id: Option<Uuid>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "admin",
+    params(UserSearch),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Matching users, newest first", body = Envelope<Vec<AdminUser>>),
+        (status = 403, description = "Missing permission read_users, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn find_users(
+    data: web::Data<AppState>,
+    query: web::Query<UserSearch>,
+    _auth: Authorized<require::ReadUsers>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match search_users(&data.db, query.q.as_deref().unwrap_or(""), limit, offset).await {
+        Ok(v) => success(
+            StatusCode::OK,
+            "Users",
+            v.into_iter().map(AdminUser::from).collect::<Vec<_>>(),
+        ),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The user with its role", body = Envelope<AdminUser>),
+        (status = 403, description = "Missing permission read_users, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such user, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_any_user(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    _auth: Authorized<require::ReadUsers>,
+) -> impl Responder {
+    match get_user_by_id(&data.db, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "User details", AdminUser::from(v)),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/role",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id")),
+    request_body = RoleChangeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Role changed, effective for staff tokens right away", body = Envelope<AdminUser>),
+        (status = 403, description = "Missing permission manage_roles, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such user, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Admins cannot change their own role, code own_role", body = ErrorEnvelope)
+    )
+)]
+pub async fn change_role(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<RoleChangeReq>,
+    auth: Authorized<require::ManageRoles>,
+) -> impl Responder {
+    let pool = &data.db;
+    let user_id = path.into_inner();
+    //keeps the last admin from locking everyone out
+    if user_id == auth.caller.user_id {
+        return failure(
+            StatusCode::CONFLICT,
+            "own_role",
+            "Cannot change your own role",
+        );
+    }
+    if let Err(e) = set_role(pool, user_id, content.role).await {
+        return db_failure(e);
+    }
+    info!(user_id = %user_id, role = %content.role, by = %auth.caller.user_id, "Role changed");
+    match get_user_by_id(pool, user_id).await {
+        Ok(v) => success(StatusCode::OK, "Role changed", AdminUser::from(v)),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/accounts/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Owner, balance, status and every transaction of the account", body = Envelope<AccountView>),
+        (status = 403, description = "Missing permission read_accounts, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    _auth: Authorized<require::ReadAccounts>,
+) -> impl Responder {
+    let pool = &data.db;
+    let user_id = path.into_inner();
+    let user = match get_user_by_id(pool, user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let balance = match get_balance(pool, user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let state = match get_account_state(pool, user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let tier = match get_account_tier(pool, user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let status_history = match list_status_changes(pool, user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match list_all_transactions(pool, user_id).await {
+        Ok(transactions) => success(
+            StatusCode::OK,
+            "Account details",
+            AccountView {
+                user: AdminUser::from(user),
+                balance: balance.balance,
+                state,
+                tier,
+                status_history,
+                transactions,
+            },
+        ),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/accounts/{id}/freeze",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    request_body = StatusChangeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Account frozen, it can neither send nor receive money", body = Envelope<AccountState>),
+        (status = 400, description = "Empty reason, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission freeze_accounts, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The account is closed, code account_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn freeze_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<StatusChangeReq>,
+    auth: Authorized<require::FreezeAccounts>,
+) -> impl Responder {
+    change_status(&data, path.into_inner(), AccountStatus::Frozen, &content.reason, auth.caller.user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/accounts/{id}/unfreeze",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    request_body = StatusChangeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Account active again", body = Envelope<AccountState>),
+        (status = 400, description = "Empty reason, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission freeze_accounts, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The account is closed, code account_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn unfreeze_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<StatusChangeReq>,
+    auth: Authorized<require::FreezeAccounts>,
+) -> impl Responder {
+    change_status(&data, path.into_inner(), AccountStatus::Active, &content.reason, auth.caller.user_id).await
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/accounts/{id}/status",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    request_body = StatusUpdateReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Status changed and added to the status history", body = Envelope<AccountState>),
+        (status = 400, description = "Empty reason or status closed, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission freeze_accounts, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The account is closed, code account_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn update_account_status(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<StatusUpdateReq>,
+    auth: Authorized<require::FreezeAccounts>,
+) -> impl Responder {
+    change_status(&data, path.into_inner(), content.status, &content.reason, auth.caller.user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/accounts/{id}/close",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    request_body = CloseAccountReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Account closed, a remaining balance was swept", body = Envelope<ClosedAccount>),
+        (status = 400, description = "Empty reason or sweep_to is the account itself", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission close_accounts, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account or sweep account, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Already closed (code account_closed), or a balance is left and there is no sweep account (code balance_not_zero)", body = ErrorEnvelope),
+        (status = 422, description = "The sweep account cannot accept funds, code receiver_unavailable", body = ErrorEnvelope)
+    )
+)]
+pub async fn close(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<CloseAccountReq>,
+    auth: Authorized<require::CloseAccounts>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let reason = content.reason.trim();
+    if reason.is_empty() {
+        return reason_required();
+    }
+    let sweep_to = content
+        .sweep_to
+        .or_else(|| data.settings.accounts.closure_sweep_account());
+    match close_account(&data.db, user_id, sweep_to, reason, auth.caller.user_id).await {
+        Ok((state, sweep_transaction_id)) => {
+            info!(user_id = %user_id, by = %auth.caller.user_id, "Account closed");
+            success(
+                StatusCode::OK,
+                "Account closed",
+                ClosedAccount {
+                    state,
+                    sweep_transaction_id,
+                },
+            )
+        }
+        Err(e) => status_failure(e),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/accounts/{id}/tier",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "user_id of the account owner")),
+    request_body = TierChangeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Tier changed, the next transaction is priced with its fee rules", body = Envelope<AccountTier>),
+        (status = 400, description = "Invalid tier, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission manage_fees, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such account, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn change_tier(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<TierChangeReq>,
+    auth: Authorized<require::ManageFees>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    match set_account_tier(&data.db, user_id, &content.tier).await {
+        Ok(tier) => {
+            info!(user_id = %user_id, tier, by = %auth.caller.user_id, "Account tier changed");
+            success(StatusCode::OK, "Account tier changed", AccountTier { user_id, tier })
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/fee_rules",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Every fee rule by transaction type, the rules for every tier last", body = Envelope<Vec<FeeRule>>),
+        (status = 403, description = "Missing permission manage_fees, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_fee_rules(
+    data: web::Data<AppState>,
+    _auth: Authorized<require::ManageFees>,
+) -> impl Responder {
+    match list_fee_rules(&data.db).await {
+        Ok(v) => success(StatusCode::OK, "Fee rules", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/fee_rules",
+    tag = "admin",
+    request_body = FeeRuleFields,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Fee rule created, it applies to the next transaction", body = Envelope<FeeRule>),
+        (status = 400, description = "Invalid type, tier, amounts or bands, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission manage_fees, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such revenue account, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The transaction type already has a rule for this tier, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn add_fee_rule(
+    data: web::Data<AppState>,
+    content: web::Json<FeeRuleFields>,
+    auth: Authorized<require::ManageFees>,
+) -> impl Responder {
+    match create_fee_rule(&data.db, content.into_inner()).await {
+        Ok(v) => {
+            info!(rule_id = %v.rule_id, by = %auth.caller.user_id, "Fee rule added");
+            success(StatusCode::CREATED, "Fee rule created", v)
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/fee_rules/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "rule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "Fee rule deleted, booked fees stay as they are"),
+        (status = 403, description = "Missing permission manage_fees, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such fee rule, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn remove_fee_rule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    auth: Authorized<require::ManageFees>,
+) -> impl Responder {
+    let rule_id = path.into_inner();
+    match delete_fee_rule(&data.db, rule_id).await {
+        Ok(()) => {
+            info!(rule_id = %rule_id, by = %auth.caller.user_id, "Fee rule deleted");
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/limit_rules",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Every limit rule by transaction type, the rules for every tier last", body = Envelope<Vec<LimitRule>>),
+        (status = 403, description = "Missing permission manage_limits, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_limit_rules(
+    data: web::Data<AppState>,
+    _auth: Authorized<require::ManageLimits>,
+) -> impl Responder {
+    match list_limit_rules(&data.db).await {
+        Ok(v) => success(StatusCode::OK, "Limit rules", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/limit_rules",
+    tag = "admin",
+    request_body = LimitRuleFields,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Limit rule created, it applies to the next transaction", body = Envelope<LimitRule>),
+        (status = 400, description = "Invalid type, tier or limits, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission manage_limits, code forbidden", body = ErrorEnvelope),
+        (status = 409, description = "The transaction type already has a rule for this tier, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn add_limit_rule(
+    data: web::Data<AppState>,
+    content: web::Json<LimitRuleFields>,
+    auth: Authorized<require::ManageLimits>,
+) -> impl Responder {
+    match create_limit_rule(&data.db, content.into_inner()).await {
+        Ok(v) => {
+            info!(rule_id = %v.rule_id, by = %auth.caller.user_id, "Limit rule added");
+            success(StatusCode::CREATED, "Limit rule created", v)
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/limit_rules/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "rule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "Limit rule deleted"),
+        (status = 403, description = "Missing permission manage_limits, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such limit rule, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn remove_limit_rule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    auth: Authorized<require::ManageLimits>,
+) -> impl Responder {
+    let rule_id = path.into_inner();
+    match delete_limit_rule(&data.db, rule_id).await {
+        Ok(()) => {
+            info!(rule_id = %rule_id, by = %auth.caller.user_id, "Limit rule deleted");
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ReviewDecisionReq {
+    //kept on the alert
+    #[schema(example = "Customer confirmed the payment by phone")]
+    pub note: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/reviews",
+    tag = "admin",
+    params(AlertFilter),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Monitoring alerts with the status, oldest first", body = Envelope<Vec<Alert>>),
+        (status = 400, description = "Unknown status, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_reviews(
+    data: web::Data<AppState>,
+    query: web::Query<AlertFilter>,
+    _auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    let status = match query.status.as_deref().unwrap_or("open") {
+        "open" => AlertStatus::Open,
+        "approved" => AlertStatus::Approved,
+        "rejected" => AlertStatus::Rejected,
+        "blocked" => AlertStatus::Blocked,
+        _ => {
+            return failure(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "Status must be open, approved, rejected or blocked",
+            )
+        }
+    };
+    match list_alerts(&data.db, status).await {
+        Ok(v) => success(StatusCode::OK, "Monitoring alerts", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/reviews/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "alert_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The monitoring alert", body = Envelope<Alert>),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such alert, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_review(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    _auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    match get_alert(&data.db, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "Monitoring alert", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reviews/{id}/approve",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "alert_id")),
+    request_body = ReviewDecisionReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Held transaction completed", body = Envelope<Alert>),
+        (status = 403, description = "Missing permission review_transactions or the transaction was sent by the caller, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such alert, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The review was already decided or nothing is held, code review_closed", body = ErrorEnvelope),
+        (status = 422, description = "The receiver cannot accept funds any more, code receiver_unavailable", body = ErrorEnvelope)
+    )
+)]
+pub async fn approve_review(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<ReviewDecisionReq>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    decide(&data, path.into_inner(), true, content.into_inner(), auth.caller.user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reviews/{id}/reject",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "alert_id")),
+    request_body = ReviewDecisionReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Held transaction failed, the amount was returned to the sender", body = Envelope<Alert>),
+        (status = 403, description = "Missing permission review_transactions or the transaction was sent by the caller, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such alert, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The review was already decided or nothing is held, code review_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn reject_review(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<ReviewDecisionReq>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    decide(&data, path.into_inner(), false, content.into_inner(), auth.caller.user_id).await
+}
+
+async fn decide(data: &AppState, alert_id: Uuid, approve: bool, content: ReviewDecisionReq, decided_by: Uuid) -> HttpResponse {
+    match decide_alert(&data.db, alert_id, approve, decided_by, content.note.as_deref()).await {
+        Ok(v) => {
+            let message = match approve {
+                true => "Transaction approved",
+                false => "Transaction rejected",
+            };
+            success(StatusCode::OK, message, v)
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/monitoring/rules",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The monitoring rules in use and where they were read from", body = Envelope<RuleSet>),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_monitoring_rules(data: web::Data<AppState>, _auth: Authorized<require::ReviewTransactions>) -> impl Responder {
+    success(StatusCode::OK, "Monitoring rules", &*data.compliance.rules.current())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/monitoring/reload",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Rules file read again, its rules are in use", body = Envelope<RuleSet>),
+        (status = 400, description = "The rules file cannot be read or has errors, the previous rules stay in use, code invalid_rules", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn reload_monitoring_rules(
+    data: web::Data<AppState>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    match data.compliance.rules.reload(&data.settings.monitoring) {
+        Ok(v) => {
+            info!(rules = v.rules.len(), by = %auth.caller.user_id, "Monitoring rules reloaded");
+            success(StatusCode::OK, "Monitoring rules reloaded", &*v)
+        }
+        Err(e) => failure(StatusCode::BAD_REQUEST, "invalid_rules", &e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/screening/results",
+    tag = "admin",
+    params(ScreeningFilter),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Screening results, newest first, every filter is optional", body = Envelope<Vec<ScreeningResult>>),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_screening_results(
+    data: web::Data<AppState>,
+    query: web::Query<ScreeningFilter>,
+    _auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match list_screening_results(&data.db, &query, limit, offset).await {
+        Ok(v) => success(StatusCode::OK, "Screening results", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/screening/results/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "result_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The screening result", body = Envelope<ScreeningResult>),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such screening result, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_screening(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    _auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    match get_screening_result(&data.db, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "Screening result", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/screening/results/{id}/clear",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "result_id")),
+    request_body = ReviewDecisionReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "False positive, the account frozen at registration is active again", body = Envelope<ScreeningResult>),
+        (status = 403, description = "Missing permission review_transactions or the result is about the caller, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such screening result, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The result was already decided or is not under review, code review_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn clear_screening(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<ReviewDecisionReq>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    decide_hit(&data, path.into_inner(), true, content.into_inner(), auth.caller.user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/screening/results/{id}/confirm",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "result_id")),
+    request_body = ReviewDecisionReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Confirmed match, the account stays frozen", body = Envelope<ScreeningResult>),
+        (status = 403, description = "Missing permission review_transactions or the result is about the caller, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such screening result, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The result was already decided or is not under review, code review_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn confirm_screening(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<ReviewDecisionReq>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    decide_hit(&data, path.into_inner(), false, content.into_inner(), auth.caller.user_id).await
+}
+
+async fn decide_hit(data: &AppState, result_id: Uuid, clear: bool, content: ReviewDecisionReq, decided_by: Uuid) -> HttpResponse {
+    match decide_screening(&data.db, result_id, clear, decided_by, content.note.as_deref()).await {
+        Ok(v) => {
+            let message = match clear {
+                true => "Screening hit cleared",
+                false => "Screening hit confirmed",
+            };
+            success(StatusCode::OK, message, v)
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/screening/list",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Size, source and scores of the sanctions list in use", body = Envelope<SanctionsList>),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_sanctions_list(data: web::Data<AppState>, _auth: Authorized<require::ReviewTransactions>) -> impl Responder {
+    success(StatusCode::OK, "Sanctions list", &*data.compliance.sanctions.current())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/screening/reload",
+    tag = "admin",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "List file read again, it is in use", body = Envelope<SanctionsList>),
+        (status = 400, description = "The list file cannot be read or has errors, the previous list stays in use, code invalid_list", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission review_transactions, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn reload_sanctions_list(
+    data: web::Data<AppState>,
+    auth: Authorized<require::ReviewTransactions>,
+) -> impl Responder {
+    match data.compliance.sanctions.reload(&data.settings.screening) {
+        Ok(v) => {
+            info!(entries = v.size, by = %auth.caller.user_id, "Sanctions list reloaded");
+            success(StatusCode::OK, "Sanctions list reloaded", &*v)
+        }
+        Err(e) => failure(StatusCode::BAD_REQUEST, "invalid_list", &e),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct KycQuery {
+    //pending, approved or rejected, pending when missing
+    pub status: Option<String>,
+}
+
+//rejecting needs a reason, it is sent to the user
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct KycRejectReq {
+    #[schema(example = "The document is expired")]
+    pub reason: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/kyc",
+    tag = "admin",
+    params(KycQuery),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "KYC submissions with the status, oldest first", body = Envelope<Vec<KycSubmission>>),
+        (status = 400, description = "Unknown status", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission review_kyc, code forbidden", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_kyc_submissions(
+    data: web::Data<AppState>,
+    query: web::Query<KycQuery>,
+    _auth: Authorized<require::ReviewKyc>,
+) -> impl Responder {
+    let status = query.status.as_deref().unwrap_or("pending");
+    if !["pending", "approved", "rejected"].contains(&status) {
+        return failure(StatusCode::BAD_REQUEST, "invalid_request", "Status must be pending, approved or rejected");
+    }
+    match list_kyc_submissions(&data.db, status).await {
+        Ok(v) => success(StatusCode::OK, "KYC submissions", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/kyc/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "submission_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The KYC submission", body = Envelope<KycSubmission>),
+        (status = 403, description = "Missing permission review_kyc, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such submission, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_kyc(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    _auth: Authorized<require::ReviewKyc>,
+) -> impl Responder {
+    match get_kyc_submission(&data.db, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "KYC submission", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/kyc/{id}/approve",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "submission_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Submission approved, the user is verified at its level", body = Envelope<KycSubmission>),
+        (status = 403, description = "Missing permission review_kyc or the submission is the caller's own, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such submission, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The submission was already reviewed, code review_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn approve_kyc(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    auth: Authorized<require::ReviewKyc>,
+) -> impl Responder {
+    match review_kyc(&data.db, path.into_inner(), true, auth.caller.user_id, None).await {
+        Ok(v) => success(StatusCode::OK, "KYC submission approved", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/kyc/{id}/reject",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "submission_id")),
+    request_body = KycRejectReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Submission rejected, the user keeps the current level", body = Envelope<KycSubmission>),
+        (status = 400, description = "Missing reason", body = ErrorEnvelope),
+        (status = 403, description = "Missing permission review_kyc or the submission is the caller's own, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such submission, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "The submission was already reviewed, code review_closed", body = ErrorEnvelope)
+    )
+)]
+pub async fn reject_kyc(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<KycRejectReq>,
+    auth: Authorized<require::ReviewKyc>,
+) -> impl Responder {
+    match review_kyc(&data.db, path.into_inner(), false, auth.caller.user_id, Some(&content.reason)).await {
+        Ok(v) => success(StatusCode::OK, "KYC submission rejected", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+async fn change_status(
+    data: &AppState,
+    user_id: Uuid,
+    status: AccountStatus,
+    reason: &str,
+    changed_by: Uuid,
+) -> actix_web::HttpResponse {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return reason_required();
+    }
+    if status == AccountStatus::Closed {
+        return failure(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "Accounts are closed through /admin/accounts/{id}/close",
+        );
+    }
+    match set_account_status(&data.db, user_id, status, reason, changed_by).await {
+        Ok(v) => {
+            info!(user_id = %user_id, status = status.as_str(), by = %changed_by, "Account status changed");
+            success(StatusCode::OK, "Account status changed", v)
+        }
+        Err(e) => status_failure(e),
+    }
+}
+
+fn reason_required() -> actix_web::HttpResponse {
+    failure(
+        StatusCode::BAD_REQUEST,
+        "invalid_request",
+        "A reason is required",
+    )
+}
+
+//a closed account is a conflict here, for payments it is a refusal
+fn status_failure(e: sqlx::Error) -> actix_web::HttpResponse {
+    match TransactionError::from_sqlx(&e) {
+        Some(TransactionError::AccountClosed) => failure(
+            StatusCode::CONFLICT,
+            TransactionError::AccountClosed.reason(),
+            &TransactionError::AccountClosed.to_string(),
+        ),
+        _ => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{Method, StatusCode},
+        test,
+    };
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::{
+        models::{
+            balance::{get_balance, set_account_tier, AccountStatus},
+            limits::{create_limit_rule, LimitRuleFields},
+        },
+        config::settings::ScreeningSettings,
+        utilities::{
+            monitoring::RuleSet,
+            rbac::Role,
+            screening::SanctionsList,
+            test_harness::TestDb,
+        },
+    };
+
+    #[actix_web::test]
+    async fn test_admin_scope() {
+        let db = TestDb::new().await;
+        let alice = db.user().username("alice_admin_test").balance(dec!(30)).create().await;
+        let bob = db.user().balance(dec!(5)).create().await;
+        let support = db.user().role(Role::Support).create().await;
+        let auditor = db.user().role(Role::Auditor).create().await;
+        let admin = db.user().role(Role::Admin).create().await;
+        let app = db.app().await;
+
+        //customers see neither the admin scope nor other users
+        for uri in [
+            "/admin/users?q=alice".to_string(),
+            format!("/admin/accounts/{}", bob.user_id),
+            format!("/v1/users/{}", bob.user_id),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(alice.bearer())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        //support searches, % is not a wildcard
+        let req = test::TestRequest::get()
+            .uri("/admin/users?q=ALICE_ADMIN")
+            .insert_header(support.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(resp_body["data"][0]["role"], "customer");
+        assert!(resp_body["data"][0].get("password").is_none());
+
+        let req = test::TestRequest::get()
+            .uri("/admin/users?q=%25")
+            .insert_header(support.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp_body["data"].as_array().unwrap().is_empty());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/users/{}", alice.user_id))
+            .insert_header(auditor.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        //auditors read but cannot freeze or move money
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/accounts/{}/freeze", alice.user_id))
+            .insert_header(auditor.bearer())
+            .set_json(json!({"reason": "test"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(auditor.bearer())
+            .set_json(json!({"amount": "1.00", "transaction_type": "deposit"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/accounts/{}/freeze", alice.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"reason": " "}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/accounts/{}/freeze", alice.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"reason": "chargeback"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["status"], "frozen");
+        assert_eq!(resp_body["data"]["updated_by"], admin.user_id.to_string());
+
+        //a frozen account neither sends nor receives
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(alice.bearer())
+            .set_json(json!({"receiver": bob.user_id, "amount": "1.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "account_frozen");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(bob.bearer())
+            .set_json(json!({"receiver": alice.user_id, "amount": "1.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "receiver_unavailable");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/accounts/{}", alice.user_id))
+            .insert_header(support.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["balance"], "30.00");
+        assert_eq!(resp_body["data"]["state"]["reason"], "chargeback");
+        assert_eq!(resp_body["data"]["transactions"].as_array().unwrap().len(), 1);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/accounts/{}/unfreeze", alice.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"reason": "resolved"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(alice.bearer())
+            .set_json(json!({"receiver": bob.user_id, "amount": "1.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        //roles are managed by admins, never on themselves
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", admin.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"role": "customer"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", bob.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"role": "support"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["role"], "support");
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", bob.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"role": "root"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_account_status_lifecycle() {
+        let db = TestDb::new().await;
+        let alice = db.user().balance(dec!(20)).create().await;
+        let bob = db.user().balance(dec!(5)).create().await;
+        let treasury = db.user().create().await;
+        let admin = db.user().role(Role::Admin).create().await;
+        let app = db.app().await;
+        let status_uri = format!("/admin/accounts/{}/status", alice.user_id);
+        let close_uri = format!("/admin/accounts/{}/close", alice.user_id);
+
+        let req = test::TestRequest::put()
+            .uri(&status_uri)
+            .insert_header(admin.bearer())
+            .set_json(json!({"status": "debit_blocked", "reason": "suspicious logins"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        //money still comes in, nothing goes out
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(alice.bearer())
+            .set_json(json!({"amount": "1.00", "transaction_type": "withdrawl"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "account_debit_blocked");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(bob.bearer())
+            .set_json(json!({"receiver": alice.user_id, "amount": "5.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::put()
+            .uri(&status_uri)
+            .insert_header(admin.bearer())
+            .set_json(json!({"status": "closed", "reason": "done"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        //25.00 left and no sweep account configured
+        let req = test::TestRequest::post()
+            .uri(&close_uri)
+            .insert_header(admin.bearer())
+            .set_json(json!({"reason": "customer request"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "balance_not_zero");
+
+        let req = test::TestRequest::post()
+            .uri(&close_uri)
+            .insert_header(admin.bearer())
+            .set_json(json!({"reason": "customer request", "sweep_to": treasury.user_id}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["state"]["status"], "closed");
+        let sweep_id = resp_body["data"]["sweep_transaction_id"].clone();
+        assert!(sweep_id.is_string());
+        assert_eq!(
+            get_balance(&db.pool, treasury.user_id).await.unwrap().balance,
+            dec!(25)
+        );
+        assert_eq!(
+            get_balance(&db.pool, alice.user_id).await.unwrap().balance,
+            dec!(0)
+        );
+
+        //closed is final
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(alice.bearer())
+            .set_json(json!({"amount": "1.00", "transaction_type": "deposit"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "account_closed");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(bob.bearer())
+            .set_json(json!({"receiver": alice.user_id, "amount": "1.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        for (uri, body) in [
+            (status_uri.clone(), json!({"status": "active", "reason": "oops"})),
+            (close_uri.clone(), json!({"reason": "again"})),
+        ] {
+            let req = test::TestRequest::default()
+                .method(if uri == status_uri { Method::PUT } else { Method::POST })
+                .uri(&uri)
+                .insert_header(admin.bearer())
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT, "{}", uri);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["code"], "account_closed");
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/accounts/{}", alice.user_id))
+            .insert_header(admin.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let history = resp_body["data"]["status_history"].as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["to_status"], "debit_blocked");
+        assert_eq!(history[1]["from_status"], "debit_blocked");
+        assert_eq!(history[1]["to_status"], "closed");
+        assert_eq!(history[1]["sweep_transaction_id"], sweep_id);
+    }
+
+    #[actix_web::test]
+    async fn test_review_queue() {
+        let db = TestDb::new().await;
+        let alice = db.user().balance(dec!(20000)).create().await;
+        let bob = db.user().create().await;
+        let analyst = db.user().role(Role::Support).create().await;
+        let sam = db.user().balance(dec!(2000)).role(Role::Support).create().await;
+        let app = db.app().await;
+        //the rules and the limit rule below apply to this tier only
+        for u in [&alice, &bob, &sam] {
+            set_account_tier(&db.pool, u.user_id, "monitoring_test").await.unwrap();
+        }
+        let rules = r#"
+            [[rules]]
+            name = "new_payee"
+            kind = "new_payee_large_amount"
+            action = "review"
+            tiers = ["monitoring_test"]
+            amount = "1000"
+
+            [[rules]]
+            name = "round"
+            kind = "round_amount"
+            action = "block"
+            tiers = ["monitoring_test"]
+            transaction_types = ["withdrawl"]
+            multiple = "1000"
+            min_amount = "5000"
+        "#;
+        db.compliance.rules.install(RuleSet::parse(rules, "test").unwrap());
+
+        let mut statuses = vec![];
+        for body in [
+            json!({"receiver": bob.user_id, "amount": "1500.00"}),
+            json!({"receiver": bob.user_id, "amount": "2000.00"}),
+            json!({"amount": "5000.00", "transaction_type": "withdrawl"}),
+            json!({"amount": "5.00", "transaction_type": "withdrawl"}),
+        ] {
+            let uri = if body.get("receiver").is_some() { "/v1/transfers" } else { "/v1/transactions" };
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(alice.bearer())
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            statuses.push(resp.status());
+            let resp_body: Value = test::read_body_json(resp).await;
+            if resp_body["code"] != Value::Null {
+                assert_eq!(resp_body["code"], "transaction_blocked");
+            }
+        }
+        assert_eq!(
+            statuses,
+            vec![StatusCode::ACCEPTED, StatusCode::ACCEPTED, StatusCode::FORBIDDEN, StatusCode::CREATED]
+        );
+        //held transfers count toward the limits, they complete once approved
+        let fields = LimitRuleFields {
+            transaction_type: String::from("transfer"),
+            tier: Some(String::from("monitoring_test")),
+            per_transaction: None,
+            daily: Some(dec!(4000)),
+            monthly: None,
+            hourly_count: None,
+        };
+        create_limit_rule(&db.pool, fields).await.unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(alice.bearer())
+            .set_json(json!({"receiver": bob.user_id, "amount": "600.00"}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["code"], "daily_limit_exceeded");
+        //held money is set aside, not yet with the receiver
+        assert_eq!(get_balance(&db.pool, alice.user_id).await.unwrap().balance, dec!(16495));
+        assert_eq!(get_balance(&db.pool, bob.user_id).await.unwrap().balance, dec!(0));
+
+        let req = test::TestRequest::get()
+            .uri("/admin/reviews")
+            .insert_header(alice.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get()
+            .uri("/admin/reviews?status=blocked")
+            .insert_header(analyst.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let blocked: Vec<&Value> = resp_body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|a| a["user_id"] == json!(alice.user_id))
+            .collect();
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0]["rules"], json!(["round"]));
+        assert_eq!(blocked[0]["transaction_id"], Value::Null);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/reviews")
+            .insert_header(analyst.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let open: Vec<String> = resp_body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|a| a["user_id"] == json!(alice.user_id))
+            .map(|a| a["alert_id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(open.len(), 2);
+
+        for (alert_id, decision, status) in [
+            (&open[0], "approve", StatusCode::OK),
+            (&open[1], "reject", StatusCode::OK),
+            (&open[1], "approve", StatusCode::CONFLICT),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/admin/reviews/{}/{}", alert_id, decision))
+                .insert_header(analyst.bearer())
+                .set_json(json!({"note": "checked with the customer"}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{} {}", decision, alert_id);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/admin/reviews/{}", open[0]))
+            .insert_header(analyst.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["status"], "approved");
+        assert_eq!(resp_body["data"]["decided_by"], json!(analyst.user_id));
+        let held = resp_body["data"]["transaction_id"].as_str().unwrap();
+        let status: String = sqlx::query_scalar("SELECT status FROM transactions where transaction_id::text = $1")
+            .bind(held)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "completed");
+        assert_eq!(get_balance(&db.pool, alice.user_id).await.unwrap().balance, dec!(18495));
+        assert_eq!(get_balance(&db.pool, bob.user_id).await.unwrap().balance, dec!(1500));
+        let kinds: Vec<String> = sqlx::query_scalar("SELECT kind FROM notifications where user_id = $1 ORDER BY id")
+            .bind(alice.user_id)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(kinds, vec!["transaction_approved", "transaction_rejected"]);
+
+        //an analyst cannot decide on a transfer of their own
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sam.bearer())
+            .set_json(json!({"receiver": bob.user_id, "amount": "1500.00"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+        let alert_id: uuid::Uuid = sqlx::query_scalar("SELECT alert_id FROM monitoring_alerts where user_id = $1 and status = 'open'")
+            .bind(sam.user_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        for decision in ["approve", "reject"] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/admin/reviews/{}/{}", alert_id, decision))
+                .insert_header(sam.bearer())
+                .set_json(json!({}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["code"], "forbidden");
+        }
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/reviews/{}/reject", alert_id))
+            .insert_header(analyst.bearer())
+            .set_json(json!({}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(get_balance(&db.pool, sam.user_id).await.unwrap().balance, dec!(2000));
+    }
+
+    #[actix_web::test]
+    async fn test_sanctions_screening() {
+        let db = TestDb::new().await;
+        let alice = db.user().balance(dec!(100)).create().await;
+        let bob = db.user().create().await;
+        //registered before the list is loaded, its name is close to an entry
+        let carol = db.user().username("quintavax_zorgan").create().await;
+        let dave = db.user().role(Role::Support).create().await;
+        let analyst = db.user().role(Role::Support).create().await;
+        let app = db.app().await;
+        let list = b"9001,\"QUINTAVEX, Zorgon\",\"individual\",\"SDGT\",-0- ,-0- \n";
+        db.compliance.sanctions.install(SanctionsList::parse(list, "test", &ScreeningSettings::default()).unwrap());
+
+        let mut statuses = vec![];
+        for (uri, body) in [
+            ("/v1/users", json!({"username": "Zorgon Quintavex", "email": "zq@test.com", "password": "secret"})),
+            ("/v1/users", json!({"username": "quintavax.zorgan", "email": "qz@test.com", "password": "secret"})),
+            ("/v1/transfers", json!({"receiver": carol.user_id, "amount": "10.00"})),
+            ("/v1/transfers", json!({"receiver": bob.user_id, "amount": "10.00"})),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(alice.bearer())
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            statuses.push(resp.status());
+            let resp_body: Value = test::read_body_json(resp).await;
+            if resp_body["code"] != Value::Null {
+                assert_eq!(resp_body["code"], "screening_blocked");
+            }
+        }
+        assert_eq!(
+            statuses,
+            vec![StatusCode::FORBIDDEN, StatusCode::CREATED, StatusCode::ACCEPTED, StatusCode::CREATED]
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/admin/screening/results?status=open")
+            .insert_header(analyst.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let open: Vec<&Value> = resp_body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|r| r["screened_name"] == "quintavax.zorgan")
+            .collect();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0]["action"], "review");
+        assert_eq!(open[0]["entry_id"], "9001");
+        let new_user: uuid::Uuid = serde_json::from_value(open[0]["user_id"].clone()).unwrap();
+        let result_id = open[0]["result_id"].as_str().unwrap().to_string();
+        //frozen until the analyst decides
+        assert_eq!(get_balance(&db.pool, new_user).await.unwrap().status, AccountStatus::Frozen);
+
+        for (decision, status) in [("clear", StatusCode::OK), ("confirm", StatusCode::CONFLICT)] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/admin/screening/results/{}/{}", result_id, decision))
+                .insert_header(analyst.bearer())
+                .set_json(json!({"note": "different date of birth"}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", decision);
+        }
+        assert_eq!(get_balance(&db.pool, new_user).await.unwrap().status, AccountStatus::Active);
+
+        //the refused registration and both transfers are kept for audit
+        let actions: Vec<String> = sqlx::query_scalar(
+            "SELECT action FROM screening_results where screened_name = 'Zorgon Quintavex' or (context = 'transfer' and user_id = ANY($1)) ORDER BY id",
+        )
+        .bind(vec![carol.user_id, bob.user_id])
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert_eq!(actions, vec!["block", "review", "allow"]);
+        let req = test::TestRequest::get()
+            .uri("/admin/reviews")
+            .insert_header(analyst.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let held = resp_body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["receiver_id"] == json!(carol.user_id))
+            .unwrap()
+            .clone();
+        assert_eq!(held["rules"], json!(["sanctions_screening"]));
+
+        //a new name is screened on both routes, blocked names are refused and close ones freeze the account
+        let req = test::TestRequest::patch()
+            .uri(&format!("/v1/users/{}", dave.user_id))
+            .insert_header(dave.bearer())
+            .set_json(json!({"username": "Zorgon Quintavex"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "screening_blocked");
+        let req = test::TestRequest::post()
+            .uri("/user/update_user")
+            .insert_header(dave.bearer())
+            .set_json(json!({"username": "Zorgon Quintavex"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let username: String = sqlx::query_scalar("SELECT username FROM users where user_id = $1")
+            .bind(dave.user_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(username, dave.username);
+        assert_eq!(get_balance(&db.pool, dave.user_id).await.unwrap().status, AccountStatus::Active);
+
+        let req = test::TestRequest::post()
+            .uri("/user/update_user")
+            .insert_header(dave.bearer())
+            .set_json(json!({"username": "quintavax-zorgan"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(get_balance(&db.pool, dave.user_id).await.unwrap().status, AccountStatus::Frozen);
+        sqlx::query("UPDATE account_balance SET status = 'active' where user_id = $1")
+            .bind(dave.user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::patch()
+            .uri(&format!("/v1/users/{}", dave.user_id))
+            .insert_header(dave.bearer())
+            .set_json(json!({"username": "zorgan quintavax"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(get_balance(&db.pool, dave.user_id).await.unwrap().status, AccountStatus::Frozen);
+        let results: Vec<(String, String, Option<String>)> = sqlx::query_as(
+            "SELECT context, action, status FROM screening_results where user_id = $1 ORDER BY id",
+        )
+        .bind(dave.user_id)
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        let open = (String::from("rename"), String::from("review"), Some(String::from("open")));
+        assert_eq!(
+            results,
+            vec![
+                (String::from("rename"), String::from("block"), None),
+                (String::from("rename"), String::from("block"), None),
+                open.clone(),
+                open,
+            ]
+        );
+
+        //an analyst cannot clear a hit on their own name
+        let open: Vec<String> = sqlx::query_scalar("SELECT result_id::text FROM screening_results where user_id = $1 and status = 'open' ORDER BY id")
+            .bind(dave.user_id)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/screening/results/{}/clear", open[1]))
+            .insert_header(dave.bearer())
+            .set_json(json!({}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "forbidden");
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/screening/results/{}/clear", open[1]))
+            .insert_header(analyst.bearer())
+            .set_json(json!({}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(get_balance(&db.pool, dave.user_id).await.unwrap().status, AccountStatus::Active);
+    }
+}
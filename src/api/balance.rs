@@ -5,8 +5,9 @@ use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
+    api::openapi::StatusResponse,
     models::{
-        balance::get_balance,
+        balance::{get_balance, BalanceDetails},
         transactions::add_transaction,
         users::get_user_by_id,
     },
@@ -23,17 +24,17 @@ pub async fn add_balance(
     content: web::Json<AddBalanceReq>,
     req: HttpRequest,
 ) -> impl Responder {
-    println!("Hello from the add balance api");
-    let uid = req.extensions().get::<Uuid>().unwrap().clone();
-    let pool = data.db.lock().unwrap();
+    let uid = *req.extensions().get::<Uuid>().unwrap();
+    let pool = &data.db;
 
-    match get_balance(&pool, uid).await {
+    match get_balance(pool, uid).await {
         Ok(v) => {
             let new_bal: Decimal = v.balance + content.amount;
-            match get_user_by_id(&pool, uid).await {
+            match get_user_by_id(pool, uid).await {
                 Ok(v) => {
                     match add_transaction(
-                        &pool,
+                        pool,
+                        &data.compliance,
                         v.user_id,
                         None,
                         new_bal,
@@ -51,16 +52,25 @@ pub async fn add_balance(
                 Err(e) => api_error(e),
             }
         }
-        Err(e) => return api_error(e),
+        Err(e) => api_error(e),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/balance/fetch_balance",
+    tag = "balance",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Balance of the authenticated user", body = BalanceDetails),
+        (status = 400, description = "Invalid request", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT", body = StatusResponse)
+    )
+)]
 pub async fn fetch_balance(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
-    let pool = data.db.lock().unwrap();
-    println!("{:?}", req.extensions());
-    let uid = req.extensions().get::<Uuid>().unwrap().clone();
-    println!("uid = {}", uid);
-    match get_balance(&pool, uid).await {
+    let pool = &data.db;
+    let uid = *req.extensions().get::<Uuid>().unwrap();
+    match get_balance(pool, uid).await {
         Ok(v) => HttpResponse::Ok().json(v),
         Err(e) => api_error(e),
     }
@@ -68,55 +78,51 @@ pub async fn fetch_balance(data: web::Data<AppState>, req: HttpRequest) -> impl
 
 #[cfg(test)]
 mod test {
-    use std::sync::Mutex;
-
     use actix_web::{test, web, App};
-    use rust_decimal::Decimal;
-    use serde_json::{json, Value};
+    use rust_decimal_macros::dec;
 
-    use crate::{api::users::get_token, config::db::get_db, models::balance::BalanceDetails, utilities::utils::JwtMiddleware, AppState};
+    use crate::{
+        api::users::get_token,
+        models::balance::{AccountStatus, BalanceDetails},
+        utilities::{
+            test_harness::{login, TestDb},
+            utils::JwtMiddleware,
+        },
+    };
 
     use super::fetch_balance;
 
-    #[test]
+    #[actix_web::test]
     async fn test_fetch_balance() {
-        println!("Hello from the test fetch balance");
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(25.50)).create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
+                .app_data(db.app_data())
                 .route("/user/get_token", web::get().to(get_token))
                 .route("/balance/fetch_balance", web::get().to(fetch_balance)),
         )
         .await;
 
-        let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
-        });
+        let token = login(&app, &user.email, &user.password).await;
 
         let req = test::TestRequest::get()
-            .uri("/user/get_token")
-            .set_json(req_body)
+            .uri("/balance/fetch_balance")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
             .to_request();
-
         let resp = test::call_service(&app, req).await;
-
-        let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-        let req = test::TestRequest::get().uri("/balance/fetch_balance").insert_header(("Authorization",format!("Bearer {}",token))).to_request();
-        let resp =test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let resp_body: BalanceDetails = test::read_body_json(resp).await;
+        assert_eq!(
+            resp_body,
+            BalanceDetails {
+                user_id: user.user_id,
+                balance: dec!(25.50),
+                status: AccountStatus::Active,
+            }
+        );
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    api::openapi::{ReadyResponse, StatusResponse},
+    config::db::{applied_migration, latest_migration},
+    AppState,
+};
+
+//a readiness probe must answer quickly even when the pool is exhausted
+const READY_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+//liveness, the process is up and serving
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses((status = 200, description = "Alive", body = StatusResponse))
+)]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "status": "Success",
+        "message": "Alive"
+    }))
+}
+
+//readiness, the database is reachable, the schema is current and every background worker is beating
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready to serve traffic", body = ReadyResponse),
+        (status = 503, description = "A check failed", body = ReadyResponse)
+    )
+)]
+pub async fn readyz(data: web::Data<AppState>) -> impl Responder {
+    let mut checks = Map::new();
+    let mut ready = true;
+
+    match tokio::time::timeout(READY_DB_TIMEOUT, applied_migration(&data.db)).await {
+        Ok(Ok(applied)) => {
+            checks.insert(String::from("database"), Value::from("ok"));
+            let latest = latest_migration();
+            if applied == Some(latest) {
+                checks.insert(String::from("migrations"), Value::from("ok"));
+            } else {
+                ready = false;
+                checks.insert(
+                    String::from("migrations"),
+                    Value::from(format!(
+                        "schema at version {}, expected {}",
+                        applied.unwrap_or_default(),
+                        latest
+                    )),
+                );
+            }
+        }
+        Ok(Err(e)) => {
+            ready = false;
+            tracing::warn!(error = %e, "Readiness database check failed");
+            checks.insert(String::from("database"), Value::from("unreachable"));
+        }
+        Err(_) => {
+            ready = false;
+            checks.insert(String::from("database"), Value::from("timeout"));
+        }
+    }
+
+    let stalled = data.workers.stalled();
+    if data.workers.is_shutting_down() {
+        ready = false;
+        checks.insert(String::from("workers"), Value::from("shutting down"));
+    } else if stalled.is_empty() {
+        checks.insert(String::from("workers"), Value::from("ok"));
+    } else {
+        ready = false;
+        checks.insert(
+            String::from("workers"),
+            Value::from(format!("stalled: {}", stalled.join(", "))),
+        );
+    }
+
+    if ready {
+        HttpResponse::Ok().json(json!({
+            "status": "Success",
+            "message": "Ready",
+            "checks": checks
+        }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({
+            "status": "Error",
+            "message": "Not ready",
+            "checks": checks
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{test, web, App};
+    use serde_json::{json, Value};
+
+    use crate::utilities::{test_harness::TestDb, utils::JwtMiddleware};
+
+    use super::{healthz, readyz};
+
+    #[actix_web::test]
+    async fn test_health_and_readiness() {
+        let db = TestDb::new().await;
+        let data = db.app_data();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(JwtMiddleware)
+                .app_data(data.clone())
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(
+            resp_body["checks"],
+            json!({"database": "ok", "migrations": "ok", "workers": "ok"})
+        );
+
+        //a worker that stops beating and a schema that is behind both fail the probe
+        data.workers
+            .spawn("stuck", Duration::from_millis(1), |mut handle| async move {
+                handle.sleep(Duration::from_secs(60)).await;
+            });
+        sqlx::query("DELETE FROM schema_migrations WHERE version = (SELECT MAX(version) FROM schema_migrations)")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["checks"]["database"], "ok");
+        assert_ne!(resp_body["checks"]["migrations"], "ok");
+        assert_eq!(resp_body["checks"]["workers"], "stalled: stuck");
+
+        data.workers.shutdown(Duration::from_secs(1)).await;
+    }
+}
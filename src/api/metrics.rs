@@ -0,0 +1,16 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::{utilities::metrics::METRICS, AppState};
+
+//prometheus scrape endpoint, text exposition format 0.0.4
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain"))
+)]
+pub async fn metrics(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(METRICS.render(&data.db))
+}
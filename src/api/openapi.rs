@@ -0,0 +1,465 @@
+//OpenAPI 3 document of the service, served at /openapi.json with Swagger UI at /docs/
+//every routed handler carries a #[utoipa::path] and is listed here, test_spec_matches_routes keeps main.rs and the spec in sync
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+use uuid::Uuid;
+
+use crate::{
+    api::{admin, balance, health, metrics, transactions, users, v1},
+    models::{
+        api_keys::{ApiKey, NewApiKey, Scope},
+        balance::{AccountState, AccountStatus, BalanceDetails, StatusChange},
+        beneficiaries::Beneficiary,
+        fees::{FeeBand, FeeQuote, FeeRule, FeeRuleFields},
+        kyc::{Capabilities, KycLevel, KycSubmission, KycSubmissionFields},
+        limits::{AmountHeadroom, CountHeadroom, LimitHeadroom, LimitRule, LimitRuleFields},
+        monitoring::{Alert, AlertStatus},
+        screening::ScreeningResult,
+        notifications::Notification,
+        oauth::{Consent, NewOAuthClient, OAuthClient},
+        payees::PayeeDetails,
+        payouts::{BatchStatus, PayoutBatch, PayoutItem, PayoutMode, PayoutRow, RowError},
+        schedules::{Frequency, ScheduleRun, ScheduleStatus, ScheduledPayment},
+        transactions::TransactionDetails,
+        two_factor::{Enrollment, TwoFactorStatus},
+    },
+    utilities::{
+        monitoring::{Action, Condition, MonitoringRule, RuleSet},
+        rbac::Role,
+        screening::SanctionsList,
+        utils::LEGACY_SCOPES,
+    },
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "payments_dodo",
+        description = "Payments backend API. Protected endpoints expect `Authorization: Bearer <jwt>` from `/user/get_token`, server-to-server integrations may send `Authorization: Bearer sk_...` with an API key from `/v1/api_keys` on the routes its scopes cover, and third-party apps send OAuth2 access tokens from `/v1/oauth/token` on the same routes. Some GET endpoints take a JSON request body, as they always have."
+    ),
+    paths(
+        v1::users::create_user,
+        v1::users::resend_verification,
+        v1::users::confirm_verification,
+        v1::users::request_password_reset,
+        v1::users::confirm_password_reset,
+        v1::users::create_session,
+        v1::two_factor::get_two_factor_status,
+        v1::two_factor::enroll_two_factor,
+        v1::two_factor::confirm_two_factor,
+        v1::two_factor::disable_two_factor_auth,
+        v1::two_factor::create_recovery_codes,
+        v1::two_factor::elevate_session,
+        v1::api_keys::list_user_api_keys,
+        v1::api_keys::create_user_api_key,
+        v1::api_keys::rotate_user_api_key,
+        v1::api_keys::revoke_user_api_key,
+        v1::oauth::list_oauth_clients,
+        v1::oauth::create_oauth_client,
+        v1::oauth::revoke_oauth_client,
+        v1::oauth::authorize_client,
+        v1::oauth::list_oauth_consents,
+        v1::oauth::revoke_oauth_consent,
+        v1::oauth::issue_oauth_token,
+        v1::oauth::introspect_oauth_token,
+        v1::users::get_user_profile,
+        v1::users::update_user_profile,
+        v1::balance::get_account_balance,
+        v1::balance::get_account_limits,
+        v1::transactions::list_user_transactions,
+        v1::transactions::get_user_transaction,
+        v1::transactions::quote_transaction,
+        v1::transactions::create_transaction,
+        v1::transactions::create_transfer,
+        v1::transactions::resolve_payee,
+        v1::beneficiaries::list_user_beneficiaries,
+        v1::beneficiaries::create_user_beneficiary,
+        v1::beneficiaries::get_user_beneficiary,
+        v1::beneficiaries::update_user_beneficiary,
+        v1::beneficiaries::delete_user_beneficiary,
+        v1::schedules::list_user_schedules,
+        v1::schedules::create_user_schedule,
+        v1::schedules::get_user_schedule,
+        v1::schedules::pause_schedule,
+        v1::schedules::resume_schedule,
+        v1::schedules::cancel_schedule,
+        v1::payouts::create_payout,
+        v1::payouts::list_payouts,
+        v1::payouts::get_payout,
+        v1::payouts::get_payout_items,
+        v1::payouts::download_payout_results,
+        v1::kyc::get_kyc_status,
+        v1::kyc::create_kyc_submission,
+        v1::notifications::list_user_notifications,
+        admin::find_users,
+        admin::get_any_user,
+        admin::change_role,
+        admin::get_account,
+        admin::freeze_account,
+        admin::unfreeze_account,
+        admin::update_account_status,
+        admin::close,
+        admin::change_tier,
+        admin::get_fee_rules,
+        admin::add_fee_rule,
+        admin::remove_fee_rule,
+        admin::get_limit_rules,
+        admin::add_limit_rule,
+        admin::remove_limit_rule,
+        admin::get_reviews,
+        admin::get_review,
+        admin::approve_review,
+        admin::reject_review,
+        admin::get_monitoring_rules,
+        admin::reload_monitoring_rules,
+        admin::get_screening_results,
+        admin::get_screening,
+        admin::clear_screening,
+        admin::confirm_screening,
+        admin::get_sanctions_list,
+        admin::reload_sanctions_list,
+        admin::get_kyc_submissions,
+        admin::get_kyc,
+        admin::approve_kyc,
+        admin::reject_kyc,
+        users::user_register,
+        users::get_token,
+        users::get_user_details,
+        users::user_update,
+        balance::fetch_balance,
+        transactions::transaction,
+        transactions::fetch_transaction,
+        transactions::resolve_payee,
+        transactions::list_transactions,
+        metrics::metrics,
+        health::healthz,
+        health::readyz,
+    ),
+    components(schemas(
+        StatusResponse,
+        TokenResponse,
+        UserDetailsResponse,
+        ReadyResponse,
+        ErrorEnvelope,
+        v1::users::UserProfile,
+        v1::users::ProfileUpdateReq,
+        v1::users::CreatedUser,
+        v1::users::Session,
+        v1::users::TokenReq,
+        v1::users::PasswordResetReq,
+        v1::users::PasswordResetConfirmReq,
+        v1::two_factor::OtpReq,
+        v1::two_factor::PasswordReq,
+        v1::two_factor::RecoveryCodes,
+        TwoFactorStatus,
+        Enrollment,
+        v1::api_keys::ApiKeyReq,
+        ApiKey,
+        NewApiKey,
+        Scope,
+        v1::oauth::ClientReq,
+        v1::oauth::AuthorizeReq,
+        v1::oauth::Authorization,
+        v1::oauth::OAuthTokenReq,
+        v1::oauth::IntrospectReq,
+        v1::oauth::OAuthToken,
+        v1::oauth::OAuthError,
+        v1::oauth::Introspection,
+        OAuthClient,
+        NewOAuthClient,
+        Consent,
+        v1::transactions::TransferReq,
+        v1::transactions::ResolvePayeeReq,
+        v1::transactions::QuoteReq,
+        FeeQuote,
+        FeeRule,
+        FeeRuleFields,
+        FeeBand,
+        LimitRule,
+        LimitRuleFields,
+        LimitHeadroom,
+        AmountHeadroom,
+        CountHeadroom,
+        v1::beneficiaries::BeneficiaryReq,
+        v1::beneficiaries::BeneficiaryUpdateReq,
+        Beneficiary,
+        v1::schedules::ScheduleReq,
+        v1::schedules::ScheduleDetails,
+        ScheduledPayment,
+        ScheduleRun,
+        Frequency,
+        ScheduleStatus,
+        v1::payouts::PayoutReq,
+        PayoutRow,
+        PayoutMode,
+        PayoutBatch,
+        BatchStatus,
+        PayoutItem,
+        RowError,
+        RowErrorsEnvelope,
+        v1::kyc::KycStatus,
+        KycLevel,
+        Capabilities,
+        KycSubmission,
+        KycSubmissionFields,
+        Notification,
+        PayeeDetails,
+        ResolvePayeeResponse,
+        admin::AdminUser,
+        admin::AccountView,
+        admin::RoleChangeReq,
+        admin::StatusChangeReq,
+        admin::StatusUpdateReq,
+        admin::CloseAccountReq,
+        admin::ClosedAccount,
+        admin::TierChangeReq,
+        admin::AccountTier,
+        admin::ReviewDecisionReq,
+        Alert,
+        AlertStatus,
+        RuleSet,
+        MonitoringRule,
+        Condition,
+        Action,
+        ScreeningResult,
+        SanctionsList,
+        admin::KycRejectReq,
+        StatusChange,
+        Role,
+        AccountStatus,
+        AccountState,
+        users::UserRegisterReq,
+        users::GetTokenReq,
+        users::UserDetailsReq,
+        users::UserUpdateReq,
+        transactions::TransactionDataReq,
+        transactions::FetchTransactionReq,
+        BalanceDetails,
+        TransactionDetails,
+    )),
+    modifiers(&BearerAuth, &LegacyDeprecation),
+    tags(
+        (name = "v1", description = "Resource style API, responses use the {status, message, data} envelope and errors carry a code"),
+        (name = "admin", description = "Staff API, needs a support, auditor or admin role with the permission named on each operation"),
+        (name = "users", description = "Deprecated, use /v1/users and /v1/sessions"),
+        (name = "balance", description = "Deprecated, use /v1/balance"),
+        (name = "transactions", description = "Deprecated, use /v1/transactions and /v1/transfers"),
+        (name = "operations", description = "Health and metrics"),
+    )
+)]
+pub struct ApiDoc;
+
+//referenced as security(("bearer_auth" = [])) by the protected handlers
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+//operations under the legacy scopes are flagged deprecated, matching the Deprecation response header
+struct LegacyDeprecation;
+
+impl Modify for LegacyDeprecation {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !LEGACY_SCOPES.iter().any(|s| path.starts_with(&format!("{}/", s))) {
+                continue;
+            }
+            for op in [&mut item.get, &mut item.post].into_iter().flatten() {
+                op.deprecated = Some(utoipa::openapi::Deprecated::True);
+            }
+        }
+    }
+}
+
+//the schemas below only describe the json! bodies built in the handlers
+
+//{"status": "Success" | "Error", "message": ...}
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct StatusResponse {
+    #[schema(example = "Success")]
+    pub status: String,
+    pub message: String,
+}
+
+//envelope of every /v1 success response
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct Envelope<T> {
+    #[schema(example = "Success")]
+    pub status: String,
+    pub message: String,
+    pub data: T,
+}
+
+//envelope of every /v1 error response, code is stable and machine readable
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    #[schema(example = "Error")]
+    pub status: String,
+    pub message: String,
+    #[schema(example = "not_found")]
+    pub code: String,
+}
+
+//error envelope of a rejected upload, one entry per invalid row
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct RowErrorsEnvelope {
+    #[schema(example = "Error")]
+    pub status: String,
+    pub message: String,
+    #[schema(example = "invalid_rows")]
+    pub code: String,
+    pub errors: Vec<RowError>,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    #[schema(example = "Success")]
+    pub status: String,
+    pub message: String,
+    pub token: String,
+    pub user_id: Uuid,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct ResolvePayeeResponse {
+    #[schema(example = "Success")]
+    pub status: String,
+    pub message: String,
+    pub payee: PayeeDetails,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct UserDetailsResponse {
+    pub id: i32,
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, ToSchema)]
+pub struct ReadyResponse {
+    #[schema(example = "Success")]
+    pub status: String,
+    pub message: String,
+    //check name to "ok" or the reason it failed
+    #[schema(example = json!({"database": "ok", "migrations": "ok", "workers": "ok"}))]
+    pub checks: std::collections::HashMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+
+    //(method, path) of every .route() in main.rs, with the prefixes of the enclosing web::scope() calls
+    fn routes_in_main() -> BTreeSet<(String, String)> {
+        let src = include_str!("../main.rs");
+        let literal = |at: usize| -> String {
+            let rest = &src[at..];
+            rest[..rest.find('"').unwrap()].to_string()
+        };
+
+        let mut routes = BTreeSet::new();
+        let mut scopes: Vec<(String, usize)> = vec![];
+        let mut depth = 0;
+        for (i, c) in src.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    while scopes.last().is_some_and(|(_, d)| *d > depth) {
+                        scopes.pop();
+                    }
+                }
+                _ => {}
+            }
+            let rest = &src[i..];
+            if rest.starts_with("web::scope(\"") {
+                scopes.push((literal(i + "web::scope(\"".len()), depth));
+            } else if rest.starts_with(".route(\"") {
+                let path = literal(i + ".route(\"".len());
+                let method_at = i + rest.find("web::").unwrap() + "web::".len();
+                let method = src[method_at..method_at + src[method_at..].find('(').unwrap()].to_string();
+                let prefix: String = scopes.iter().map(|(s, _)| s.as_str()).collect();
+                routes.insert((method, format!("{}{}", prefix, path)));
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_spec_matches_routes() {
+        let spec = ApiDoc::openapi();
+        let mut documented = BTreeSet::new();
+        for (path, item) in spec.paths.paths.iter() {
+            for (method, op) in [
+                ("get", &item.get),
+                ("post", &item.post),
+                ("put", &item.put),
+                ("patch", &item.patch),
+                ("delete", &item.delete),
+            ] {
+                if op.is_some() {
+                    documented.insert((method.to_string(), path.clone()));
+                }
+            }
+        }
+
+        let routed = routes_in_main();
+        assert!(routed.len() > 5, "route parsing broke: {:?}", routed);
+        let missing: Vec<_> = routed.difference(&documented).collect();
+        let stale: Vec<_> = documented.difference(&routed).collect();
+        assert!(
+            missing.is_empty() && stale.is_empty(),
+            "OpenAPI spec drifted from main.rs\n  routed but undocumented: {:?}\n  documented but not routed: {:?}",
+            missing,
+            stale
+        );
+    }
+
+    #[test]
+    fn test_protected_operations_require_bearer_auth() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let public = crate::utilities::utils::PUBLIC_PATHS;
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, op) in item.as_object().unwrap() {
+                let secured = op.get("security").is_some();
+                assert_eq!(
+                    secured,
+                    !public.contains(&path.as_str()),
+                    "{} {} security does not match the JWT middleware",
+                    method,
+                    path
+                );
+            }
+        }
+    }
+}
@@ -2,87 +2,238 @@ use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::{
+    api::{
+        openapi::{ErrorEnvelope, ResolvePayeeResponse, StatusResponse},
+        v1::{transactions::ResolvePayeeReq, two_factor::step_up},
+    },
     models::{
-        transactions::{add_transaction, get_transaction, list_all_transactions},
+        beneficiaries::resolve_target,
+        payees::find_payee,
+        transactions::{
+            add_transaction_with, get_transaction, list_transactions_filtered, TransactionDetails,
+            TransactionFilter, TransactionOptions,
+        },
         users::get_user_by_id,
     },
-    utilities::errors::api_error,
+    utilities::{
+        errors::{api_error, TransactionError},
+        rbac::{require, Authorized, Caller, Permission},
+    },
     AppState,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct TransactionDataReq {
+    //required for transfers, must be empty for deposits and withdrawls
     pub receiver: Option<Uuid>,
+    //instead of receiver: the receiver's e-mail, phone number or @handle
+    #[serde(default)]
+    #[schema(example = "@alice")]
+    pub payee: Option<String>,
+    //instead of receiver: a saved beneficiary, its limits apply
+    #[serde(default)]
+    pub beneficiary_id: Option<Uuid>,
+    #[schema(value_type = String, example = "10.50")]
     pub amount: Decimal,
+    #[schema(example = "transfer")]
     pub transaction_type: String,
+    //up to 140 characters, a beneficiary's default memo is used when empty
+    #[serde(default)]
+    #[schema(example = "Dinner on Friday")]
+    pub memo: Option<String>,
+    //up to 64 characters, e.g. an order id
+    #[serde(default)]
+    #[schema(example = "order-1042")]
+    pub reference: Option<String>,
+    //a JSON object of at most 4 KiB
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, example = json!({"order_id": "1042"}))]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl TransactionDataReq {
+    //the memo, reference and metadata of the request win over what the receiver brings
+    pub fn apply_details(&mut self, options: &mut TransactionOptions) {
+        options.memo = self.memo.take().or(options.memo.take());
+        options.reference = self.reference.take();
+        options.metadata = self.metadata.take();
+    }
+}
+
+//the one-time password of a transfer above auth.step_up_amount
+pub const OTP_HEADER: &str = "X-OTP";
+
+pub fn otp_header(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(OTP_HEADER).and_then(|h| h.to_str().ok())
 }
 
+#[utoipa::path(
+    post,
+    path = "/transaction/operations",
+    tag = "transactions",
+    request_body = TransactionDataReq,
+    params(("X-OTP" = Option<String>, Header, description = "One-time password, required for transfers above auth.step_up_amount unless the token is elevated")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Transaction booked", body = StatusResponse),
+        (status = 400, description = "Rejected, e.g. insufficient balance, invalid amount or a transfer above the step-up amount without a valid one-time password", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT", body = StatusResponse),
+        (status = 403, description = "The role may not transact, code forbidden", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong one-time passwords, code otp_locked", body = ErrorEnvelope)
+    )
+)]
 pub async fn transaction(
+    req: HttpRequest,
     data: web::Data<AppState>,
     content: web::Json<TransactionDataReq>,
-    req: HttpRequest,
+    auth: Authorized<require::Transact>,
 ) -> impl Responder {
-    println!("Hello from the transaction");
-
-    let pool = data.db.lock().unwrap();
-    let id = req.extensions().get::<Uuid>().unwrap().clone();
-    match get_user_by_id(&pool, id).await {
+    let pool = &data.db;
+    let id = auth.caller.user_id;
+    let mut content = content.into_inner();
+    if let Err(resp) = step_up(
+        &data,
+        &auth.caller,
+        &content.transaction_type,
+        content.amount,
+        otp_header(&req),
+        api_error,
+    )
+    .await
+    {
+        return resp;
+    }
+    let (receiver, mut options) = match resolve_target(
+        pool,
+        id,
+        content.receiver,
+        content.payee.as_deref(),
+        content.beneficiary_id,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return api_error(e),
+    };
+    content.apply_details(&mut options);
+    match get_user_by_id(pool, id).await {
         Ok(v) => {
-            match add_transaction(
-                &pool,
-                v.user_id.clone(),
-                content.receiver.clone(),
+            match add_transaction_with(
+                pool,
+                &data.compliance,
+                v.user_id,
+                receiver,
                 content.amount,
-                content.transaction_type.clone(),
+                content.transaction_type,
+                options,
             )
             .await
             {
                 Ok(_) => {
-                    println!("Transaction added successfully");
-                    return HttpResponse::Ok().json(json!({
+                    HttpResponse::Ok().json(json!({
                         "status": "Success",
                         "message":"Transaction added successfully"
-                    }));
+                    }))
                 }
-                Err(e) => return api_error(e),
+                Err(e) => api_error(e),
             }
         }
         Err(e) => api_error(e),
     }
 }
 
-pub async fn list_transactions(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
-    println!("Hello from the list transactions");
-    let pool = data.db.lock().unwrap();
+#[utoipa::path(
+    get,
+    path = "/transaction/list_trans",
+    tag = "transactions",
+    params(TransactionFilter),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Every transaction the user sent or received that matches the filters", body = [TransactionDetails]),
+        (status = 400, description = "Invalid filter", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT", body = StatusResponse)
+    )
+)]
+pub async fn list_transactions(
+    data: web::Data<AppState>,
+    filter: web::Query<TransactionFilter>,
+    req: HttpRequest,
+) -> impl Responder {
+    let pool = &data.db;
 
-    let uid = req.extensions().get::<Uuid>().unwrap().clone();
+    let uid = *req.extensions().get::<Uuid>().unwrap();
 
-    match list_all_transactions(&pool, uid).await {
+    match list_transactions_filtered(pool, uid, &filter).await {
         Ok(v) => HttpResponse::Ok().json(v),
         Err(e) => api_error(e),
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[utoipa::path(
+    post,
+    path = "/transaction/resolve_payee",
+    tag = "transactions",
+    request_body = ResolvePayeeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Masked details of the payee, to be confirmed before paying", body = ResolvePayeeResponse),
+        (status = 400, description = "Not an e-mail, phone number or @handle", body = StatusResponse),
+        (status = 404, description = "No account that can receive money matches", body = StatusResponse)
+    )
+)]
+pub async fn resolve_payee(
+    data: web::Data<AppState>,
+    content: web::Json<ResolvePayeeReq>,
+) -> impl Responder {
+    match find_payee(&data.db, &content.payee).await {
+        Ok((_, payee)) => HttpResponse::Ok().json(json!({
+            "status": "Success",
+            "message": "Payee found",
+            "payee": payee
+        })),
+        //the same answer for unknown and for blocked accounts
+        Err(e) if TransactionError::from_sqlx(&e) == Some(TransactionError::PayeeNotFound) => {
+            HttpResponse::NotFound().json(json!({
+                "status": "Error",
+                "message": TransactionError::PayeeNotFound.to_string()
+            }))
+        }
+        Err(e) => api_error(e),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct FetchTransactionReq {
     pub transaction_id: Uuid,
 }
 
+#[utoipa::path(
+    get,
+    path = "/transaction/fetch_transaction",
+    tag = "transactions",
+    request_body = FetchTransactionReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The transaction", body = TransactionDetails),
+        (status = 400, description = "Transaction not found", body = StatusResponse),
+        (status = 401, description = "Missing JWT, or someone else's transaction without the read_accounts permission", body = StatusResponse)
+    )
+)]
 pub async fn fetch_transaction(
     data: web::Data<AppState>,
     content: web::Json<FetchTransactionReq>,
-    req: HttpRequest,
+    caller: Caller,
 ) -> impl Responder {
-    println!("Hello from the fetch_transaction");
-
-    let pool = data.db.lock().unwrap();
-    let id = req.extensions().get::<Uuid>().unwrap().clone();
-    match get_transaction(&pool, content.transaction_id).await {
+    let pool = &data.db;
+    match get_transaction(pool, content.transaction_id).await {
         Ok(v) => {
-            if v.sender != id {
+            if !caller.may_access(v.sender, Permission::ReadAccounts)
+                && !caller.may_access(v.receiver, Permission::ReadAccounts)
+            {
                 return HttpResponse::Unauthorized().json(json!({
                     "status": "Error",
                     "message" : "Unauthorized to access the trasaction"
@@ -96,311 +247,215 @@ pub async fn fetch_transaction(
 
 #[cfg(test)]
 mod tests {
-
-    use std::sync::Mutex;
-
     use actix_web::{test, web, App};
-    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
     use serde_json::{json, Value};
 
-    use crate::{api::users::{get_token, user_register}, config::db::get_db, models::transactions::TransactionDetails, utilities::utils::JwtMiddleware, AppState};
+    use crate::{
+        models::{balance::get_balance, transactions::TransactionDetails},
+        utilities::{test_harness::TestDb, utils::JwtMiddleware},
+    };
 
     use super::{fetch_transaction, list_transactions, transaction};
 
-    #[test]
+    #[actix_web::test]
     async fn test_transaction_deposit() {
-        println!("Hello from the test transaction depost");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/get_token", web::get().to(get_token))
+                .app_data(db.app_data())
                 .route("/transaction/operations", web::post().to(transaction)),
         )
         .await;
 
         let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
+            "amount": dec!(100.0),
+            "transaction_type": "deposit"
         });
 
-        let req = test::TestRequest::get()
-            .uri("/user/get_token")
+        let req = test::TestRequest::post()
+            .insert_header(user.bearer())
+            .uri("/transaction/operations")
             .set_json(req_body)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
         let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        let req_body = json!({
-            "amount": Decimal::new(1000,1),
-            "transaction_type": "deposit"
-        });
-
-        let req = test::TestRequest::post().insert_header(("Authorization",format!("Bearer {}",token))).uri("/transaction/operations").set_json(req_body).to_request();
-
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
-
-        let resp_body :Value = test::read_body_json(resp).await;
-        assert_eq!(json!({
-            "status": "Success",
-            "message":"Transaction added successfully"
-        }) , resp_body);
+        assert_eq!(
+            json!({
+                "status": "Success",
+                "message":"Transaction added successfully"
+            }),
+            resp_body
+        );
+        assert_eq!(
+            get_balance(&db.pool, user.user_id).await.unwrap().balance,
+            dec!(100.0)
+        );
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_transaction_withdrawl() {
-        println!("Hello from the test transaction withdrawl");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(50)).create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/get_token", web::get().to(get_token))
+                .app_data(db.app_data())
                 .route("/transaction/operations", web::post().to(transaction)),
         )
         .await;
 
         let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
+            "amount": dec!(10.0),
+            "transaction_type": "withdrawl"
         });
 
-        let req = test::TestRequest::get()
-            .uri("/user/get_token")
+        let req = test::TestRequest::post()
+            .insert_header(user.bearer())
+            .uri("/transaction/operations")
             .set_json(req_body)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
         let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        let req_body = json!({
-            "amount": Decimal::new(100,1),
-            "transaction_type": "withdrawl"
-        });
-
-        let req = test::TestRequest::post().insert_header(("Authorization",format!("Bearer {}",token))).uri("/transaction/operations").set_json(req_body).to_request();
-
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
-
-        let resp_body :Value = test::read_body_json(resp).await;
-        assert_eq!(json!({
-            "status": "Success",
-            "message":"Transaction added successfully"
-        }) , resp_body);
+        assert_eq!(
+            json!({
+                "status": "Success",
+                "message":"Transaction added successfully"
+            }),
+            resp_body
+        );
+        assert_eq!(
+            get_balance(&db.pool, user.user_id).await.unwrap().balance,
+            dec!(40)
+        );
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_transaction_transfer() {
-        println!("Hello from the test transaction withdrawl");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let sender = db.user().balance(dec!(50)).create().await;
+        let receiver = db.user().create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/register_user",web::post().to(user_register))
-                .route("/user/get_token", web::get().to(get_token))
+                .app_data(db.app_data())
                 .route("/transaction/operations", web::post().to(transaction)),
         )
         .await;
 
-        //sender details
-        let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
-        });
-
-        let req = test::TestRequest::get()
-            .uri("/user/get_token")
-            .set_json(req_body)
-            .to_request();
-
-        let resp = test::call_service(&app, req).await;
-
-        let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        //receiver details
         let req_body = json!({
-            "username":"test_receiver",
-            "email":"test1@test.com",
-            "password":"test"
+            "receiver": receiver.user_id,
+            "amount": dec!(10.0),
+            "transaction_type": "transfer"
         });
 
         let req = test::TestRequest::post()
-            .uri("/user/register_user")
-            .set_json(&req_body)
+            .insert_header(sender.bearer())
+            .uri("/transaction/operations")
+            .set_json(req_body)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
-        let resp_body:Value = test::read_body_json(resp).await;
-
-        let recv_id = resp_body.get("user_id").unwrap();
-
-
-
-
-        let req_body = json!({
-            "receiver":recv_id,
-            "amount": Decimal::new(100,1),
-            "transaction_type": "transfer"
-        });
-
-        let req = test::TestRequest::post().insert_header(("Authorization",format!("Bearer {}",token))).uri("/transaction/operations").set_json(req_body).to_request();
-
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
-
-        let resp_body :Value = test::read_body_json(resp).await;
-        assert_eq!(json!({
-            "status": "Success",
-            "message":"Transaction added successfully"
-        }) , resp_body);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(
+            json!({
+                "status": "Success",
+                "message":"Transaction added successfully"
+            }),
+            resp_body
+        );
+        assert_eq!(
+            get_balance(&db.pool, sender.user_id).await.unwrap().balance,
+            dec!(40)
+        );
+        assert_eq!(
+            get_balance(&db.pool, receiver.user_id).await.unwrap().balance,
+            dec!(10)
+        );
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_list_transaction() {
-        println!("Hello from the test list transaction");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(20)).create().await;
+        db.transaction(&user).withdrawl(dec!(5)).create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/get_token", web::get().to(get_token))
+                .app_data(db.app_data())
                 .route("/transaction/list_trans", web::get().to(list_transactions)),
         )
         .await;
 
-        //sender details
-        let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
-        });
-
         let req = test::TestRequest::get()
-            .uri("/user/get_token")
-            .set_json(req_body)
+            .insert_header(user.bearer())
+            .uri("/transaction/list_trans")
             .to_request();
 
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
-        let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        let req = test::TestRequest::get()
-        .insert_header(("Authorization",format!("Bearer {}",token))).uri("/transaction/list_trans").to_request();
-
-        let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
-
+        let resp_body: Vec<TransactionDetails> = test::read_body_json(resp).await;
+        assert_eq!(resp_body.len(), 2);
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_fetch_transaction() {
-        println!("Hello from the test fetch transactions");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
+        let other = db.user().create().await;
+        let transaction_id = db.transaction(&user).deposit(dec!(15)).create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/get_token", web::get().to(get_token))
-                .route("/transaction/list_trans",web::get().to(list_transactions))
-                .route("/transaction/fetch_transaction", web::get().to(fetch_transaction)),
+                .app_data(db.app_data())
+                .route(
+                    "/transaction/fetch_transaction",
+                    web::get().to(fetch_transaction),
+                ),
         )
         .await;
 
         let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
+            "transaction_id": transaction_id
         });
 
         let req = test::TestRequest::get()
-            .uri("/user/get_token")
-            .set_json(req_body)
+            .uri("/transaction/fetch_transaction")
+            .insert_header(user.bearer())
+            .set_json(&req_body)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
-        let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        let req = test::TestRequest::get().uri("/transaction/list_trans").insert_header(("Authorization",format!("Bearer {}",token))).to_request();
-
-        let resp = test::call_service(&app, req).await;
-
-        let resp_body :Vec<TransactionDetails>  = test::read_body_json(resp).await;
-        let data = &resp_body[0];
-        let req_body = json!({
-            "transaction_id":data.transaction_id
-        });
+        let resp_body: TransactionDetails = test::read_body_json(resp).await;
+        assert_eq!(resp_body.transaction_id, transaction_id);
+        assert_eq!(resp_body.status, "completed");
 
-        let req = test::TestRequest::get().uri("/transaction/fetch_transaction").insert_header(("Authorization",format!("Bearer {}",token))).set_json(req_body).to_request();
+        //someone else's transaction is not visible
+        let req = test::TestRequest::get()
+            .uri("/transaction/fetch_transaction")
+            .insert_header(other.bearer())
+            .set_json(&req_body)
+            .to_request();
 
         let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(),actix_web::http::StatusCode::OK);
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
     }
 }
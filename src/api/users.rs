@@ -1,124 +1,211 @@
 use crate::{
-    models::users::{get_user, get_user_by_id, register_user, update_user},
-    utilities::{auth::encode_jwt, errors::api_error},
+    api::openapi::{ErrorEnvelope, StatusResponse, TokenResponse, UserDetailsResponse},
+    models::{
+        login_attempts::{clear_login_failures, login_locked_for, record_login_failure},
+        two_factor::check_login,
+        users::{get_user, get_user_by_id, rehash_password, register_user, update_user, UserInfo},
+    },
+    utilities::{
+        auth::{encode_jwt, needs_rehash, verify_password},
+        errors::{api_error, TransactionError},
+        rate_limit::login_locked,
+        rbac::{Caller, Permission},
+    },
     AppState,
 };
 use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tracing::error;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct UserRegisterReq {
     pub username: String,
     pub email: String,
     pub password: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/user/register_user",
+    tag = "users",
+    request_body = UserRegisterReq,
+    responses(
+        (status = 200, description = "User registered", body = StatusResponse),
+        (status = 400, description = "Invalid request, e.g. e-mail already registered", body = StatusResponse)
+    )
+)]
 pub async fn user_register(
     data: web::Data<AppState>,
     content: web::Json<UserRegisterReq>,
 ) -> impl Responder {
-    println!("Hello from the user registration api");
-    let pool = data.db.lock().unwrap();
+    let pool = &data.db;
     match register_user(
-        &pool,
+        pool,
+        &data.compliance.sanctions,
         content.username.clone(),
         content.email.clone(),
         content.password.clone(),
     )
     .await
     {
-        Ok(v) => HttpResponse::Ok().json(json!(
+        Ok(_) => HttpResponse::Ok().json(json!(
             {"status": "Success",
             "message": "User Registration Successfully",
             "detailed_Message" : "N/A"}
         )),
         Err(e) => {
-            return api_error(e);
+            api_error(e)
         }
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct UserDetailsReq {
     pub user_id: Uuid,
 }
 
+#[utoipa::path(
+    get,
+    path = "/user/get_user",
+    tag = "users",
+    request_body = UserDetailsReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "User profile", body = UserDetailsResponse),
+        (status = 400, description = "User not found", body = StatusResponse),
+        (status = 401, description = "Missing JWT, or another user's record without the read_users permission", body = StatusResponse)
+    )
+)]
 pub async fn get_user_details(
     data: web::Data<AppState>,
     content: web::Json<UserDetailsReq>,
+    caller: Caller,
 ) -> impl Responder {
-    println!("Hello from the get user details");
-
-    let pool = data.db.lock().unwrap();
+    let pool = &data.db;
+    if !caller.may_access(content.user_id, Permission::ReadUsers) {
+        return HttpResponse::Unauthorized().json(json!({
+            "status": "Error",
+            "message" : "Unauthorized to access the user"
+        }));
+    }
 
-    match get_user_by_id(&pool, content.user_id.clone()).await {
+    match get_user_by_id(pool, content.user_id).await {
         Ok(v) => {
-            println!("Get User Successfully");
-            return HttpResponse::Ok().json(json!({
+            HttpResponse::Ok().json(json!({
                 "id":v.id,
                 "username":v.username,
                 "email":v.email,
                 "user_id":v.user_id,
                 "created_at":v.created_at,
                 "updated_at":v.updated_at
-            }));
+            }))
         }
         Err(e) => {
-            return api_error(e);
+            api_error(e)
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct UserUpdateReq {
     pub username: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/user/update_user",
+    tag = "users",
+    request_body = UserUpdateReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Username updated", body = StatusResponse),
+        (status = 400, description = "Invalid request", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT", body = StatusResponse)
+    )
+)]
 pub async fn user_update(
     data: web::Data<AppState>,
     content: web::Json<UserUpdateReq>,
     req: HttpRequest,
 ) -> impl Responder {
-    println!("Hello from the user_update");
-
-    let pool = data.db.lock().unwrap();
-    let id = req.extensions().get::<Uuid>().unwrap().clone();
-    match update_user(&pool, id, content.username.clone()).await {
+    let pool = &data.db;
+    let id = *req.extensions().get::<Uuid>().unwrap();
+    match update_user(pool, &data.compliance.sanctions, id, content.username.clone()).await {
         Ok(_) => {
-            println!("UserName update successully");
-            return HttpResponse::Ok().json(json!(
+            tracing::info!(user_id = %id, "UserName update successully");
+            HttpResponse::Ok().json(json!(
                 {
                     "status":"Success",
                     "message": "User Updated Successfully"
                 }
-            ));
+            ))
         }
         Err(e) => {
-            return api_error(e);
+            api_error(e)
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct GetTokenReq {
     pub email: String,
     pub password: String,
+    //the current code of the authenticator app or a recovery code, required once two-factor authentication is enabled
+    #[serde(default)]
+    #[schema(example = "287082")]
+    pub otp: Option<String>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/user/get_token",
+    tag = "users",
+    request_body = GetTokenReq,
+    responses(
+        (status = 200, description = "JWT issued", body = TokenResponse),
+        (status = 401, description = "Invalid credentials, or a missing or invalid one-time password of a user with two-factor authentication", body = StatusResponse),
+        (status = 429, description = "Too many failed logins of the address (code login_locked) or requests (code rate_limited), see Retry-After", body = ErrorEnvelope)
+    )
+)]
 pub async fn get_token(
     data: web::Data<AppState>,
     content: web::Json<GetTokenReq>,
 ) -> impl Responder {
-    println!("Hello from the Sign in");
-    let pool = data.db.lock().unwrap();
-    match get_user(&pool, content.email.clone()).await {
+    let pool = &data.db;
+    match login_locked_for(pool, &content.email).await {
+        Ok(Some(secs)) => return login_locked(secs),
+        Ok(None) => {}
+        Err(e) => return api_error(e),
+    }
+    match get_user(pool, content.email.clone()).await {
         Ok(v) => {
-            if v.password == content.password {
-                let token = match encode_jwt(v.user_id as Uuid) {
+            if verify_password(&v.password, &content.password) {
+                if let Err(e) = check_login(pool, v.user_id, content.otp.as_deref()).await {
+                    return match TransactionError::from_sqlx(&e) {
+                        Some(reason) => {
+                            if reason == TransactionError::InvalidOtp {
+                                count_login_failure(&data, &content.email).await;
+                            }
+                            HttpResponse::Unauthorized().json(json!(
+                                {
+                                    "status": "Error",
+                                    "message": reason.to_string()
+                                }
+                            ))
+                        }
+                        None => api_error(e),
+                    };
+                }
+                if let Err(e) = clear_login_failures(pool, &content.email).await {
+                    return api_error(e);
+                }
+                upgrade_password(&data, &v, &content.password).await;
+                let token = match encode_jwt(v.user_id as Uuid, v.role, &data.settings.auth) {
                     Ok(v) => v,
-                    Err(e) => {
+                    Err(_) => {
                         return HttpResponse::Unauthorized().json(json!(
                             {
                                 "status": "Error",
@@ -127,25 +214,27 @@ pub async fn get_token(
                         ))
                     }
                 };
-                return HttpResponse::Ok().json(json!(
+                HttpResponse::Ok().json(json!(
                     {
                         "status": "Success",
                         "message": "Successfully logged in",
                         "token":token,
                         "user_id":v.user_id
                     }
-                ));
+                ))
             } else {
-                return HttpResponse::Unauthorized().json(json!(
+                count_login_failure(&data, &content.email).await;
+                HttpResponse::Unauthorized().json(json!(
                     {
                         "status": "Error",
                         "message":"Invalid Credentials"
                     }
-                ));
+                ))
             }
         }
-        Err(e) => {
-            return HttpResponse::Unauthorized().json(json!({
+        Err(_) => {
+            count_login_failure(&data, &content.email).await;
+            HttpResponse::Unauthorized().json(json!({
                 "status": "Error",
                 "message":"Invalid Credentials"
             }))
@@ -153,38 +242,45 @@ pub async fn get_token(
     }
 }
 
+//a password of the rows written before hashing is hashed once it is known, the login goes on when that fails
+pub async fn upgrade_password(data: &AppState, user: &UserInfo, password: &str) {
+    if !needs_rehash(&user.password) {
+        return;
+    }
+    if let Err(e) = rehash_password(&data.db, user.user_id, &user.password, password).await {
+        error!(error = %e, "Error at hashing a stored password");
+    }
+}
+
+//a wrong password or one-time password towards the lockout, the login answers the same when counting fails
+pub async fn count_login_failure(data: &AppState, email: &str) {
+    if let Err(e) = record_login_failure(&data.db, email, &data.settings.rate_limit).await {
+        error!(error = %e, "Error at counting a failed login");
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Mutex;
-
     use actix_web::{test, web, App};
     use serde_json::{json, Value};
 
     use crate::{
         api::users::{get_token, get_user_details, user_register},
-        config::db::get_db,
-        utilities::utils::JwtMiddleware,
-        AppState,
+        utilities::{
+            test_harness::{login, TestDb},
+            utils::JwtMiddleware,
+        },
     };
 
     use super::user_update;
 
-
-    #[test]
+    #[actix_web::test]
     async fn test_user_register() {
-        //get the pool connection
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection = {:?}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
 
         let app = test::init_service(
             App::new()
-                .app_data(appdata)
+                .app_data(db.app_data())
                 .route("/user/register_user", web::post().to(user_register)),
         )
         .await;
@@ -201,46 +297,44 @@ mod tests {
             .to_request();
 
         let resp = test::call_service(&app, req).await;
-        println!("status of the user_register");
         assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
-        // let resp_body: Value = test::read_body_json(resp).await;
-        // println!("status of the user_register response body");
-        // assert_eq!(
-        //     resp_body,
-        //     json!(
-        //         {
-        //         "status": "Success",
-        //         "message": "User Registration Successfully",
-        //         "detailed_Message" : "N/A"
-        //         }
-        //     )
-        // );
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(
+            resp_body,
+            json!(
+                {
+                "status": "Success",
+                "message": "User Registration Successfully",
+                "detailed_Message" : "N/A"
+                }
+            )
+        );
+
+        //same email a second time is rejected
+        let req = test::TestRequest::post()
+            .uri("/user/register_user")
+            .set_json(&req_body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_get_token() {
-        println!("Hello from the test_get_token");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().email("test@test.com").create().await;
 
         let app = test::init_service(
             App::new()
-                .app_data(appdata)
+                .app_data(db.app_data())
                 .route("/user/get_token", web::get().to(get_token)),
         )
         .await;
 
         let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
+            "email": user.email,
+            "password": user.password
         });
 
         let req = test::TestRequest::get()
@@ -249,55 +343,40 @@ mod tests {
             .to_request();
 
         let resp = test::call_service(&app, req).await;
-
-        println!("Status of the get_token");
         assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
         let resp_body: Value = test::read_body_json(resp).await;
 
         let message = resp_body.get("message").unwrap();
         assert_eq!("Successfully logged in".to_string(), *message);
+
+        let req = test::TestRequest::get()
+            .uri("/user/get_token")
+            .set_json(json!({
+                "email": user.email,
+                "password": "wrong"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_update_user() {
-        println!("Hello from the test_update_user");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
+                .app_data(db.app_data())
                 .route("/user/get_token", web::get().to(get_token))
                 .route("/user/update_user", web::post().to(user_update)),
         )
         .await;
 
-        let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
-        });
-
-        let req = test::TestRequest::get()
-            .uri("/user/get_token")
-            .set_json(req_body)
-            .to_request();
+        let token = login(&app, &user.email, &user.password).await;
 
-        let resp = test::call_service(&app, req).await;
-
-        let resp_body: Value = test::read_body_json(resp).await;
-
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-        println!("token = {}", token);
         let req_body = json!({
             "username":"test_updated"
         });
@@ -323,56 +402,59 @@ mod tests {
         );
     }
 
-    #[test]
+    #[actix_web::test]
     async fn test_get_user() {
-        println!("Hello from the test get user");
-
-        let pool = match get_db().await {
-            Ok(v) => v,
-            Err(e) => panic!("Error at pool connection: {}", e),
-        };
-
-        let appdata = web::Data::new(AppState {
-            db: Mutex::new(pool),
-        });
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
 
         let app = test::init_service(
             App::new()
                 .wrap(JwtMiddleware)
-                .app_data(appdata)
-                .route("/user/get_token", web::get().to(get_token))
+                .app_data(db.app_data())
                 .route("/user/get_user", web::get().to(get_user_details)),
         )
         .await;
 
         let req_body = json!({
-            "email":"test@test.com",
-            "password":"test"
+            "user_id": user.user_id
         });
 
         let req = test::TestRequest::get()
-            .uri("/user/get_token")
+            .insert_header(user.bearer())
+            .uri("/user/get_user")
             .set_json(req_body)
             .to_request();
 
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
         let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body.get("email").unwrap(), &json!(user.email));
+        assert!(resp_body.get("password").is_none());
+    }
 
-        let token = resp_body.get("token").unwrap().to_string().clone();
-        let token = token.trim_start_matches("\"").trim_end_matches("\"");
-
-        let req_body = json!({
-            "email":"test@test.com"
-        });
+    #[actix_web::test]
+    async fn test_legacy_routes_are_deprecated() {
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
+        let app = db.app().await;
 
         let req = test::TestRequest::get()
-            .insert_header(("Authorization", format!("Bearer {}", token)))
-            .uri("/user/get_user")
-            .set_json(req_body)
+            .uri("/user/get_token")
+            .set_json(json!({"email": user.email, "password": user.password}))
             .to_request();
-
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Deprecation").unwrap(),
+            crate::utilities::utils::LEGACY_DEPRECATED_AT
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": user.email, "password": user.password}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get("Deprecation").is_none());
     }
 }
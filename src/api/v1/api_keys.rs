@@ -0,0 +1,235 @@
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::api_keys::{
+        create_api_key, list_api_keys, revoke_api_key, rotate_api_key, ApiKey, NewApiKey, Scope,
+    },
+    utilities::{
+        envelope::{db_failure, success},
+        rbac::Caller,
+    },
+    AppState,
+};
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyReq {
+    #[schema(example = "Billing backend")]
+    pub name: String,
+    #[schema(example = json!(["balance:read", "transactions:write"]))]
+    pub scopes: Vec<Scope>,
+    //without it the key is valid until it is revoked
+    #[schema(example = 365)]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct RotateQuery {
+    //minutes the old key keeps working, 0 or none revokes it right away
+    #[serde(default)]
+    #[param(example = 60)]
+    pub grace_minutes: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/api_keys",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "API keys of the authenticated user including revoked ones, newest first, without the keys themselves", body = Envelope<Vec<ApiKey>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 403, description = "Called with an API key, keys are managed with a JWT only, code insufficient_scope", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_user_api_keys(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_api_keys(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "API keys", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/api_keys",
+    tag = "v1",
+    request_body = ApiKeyReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "API key created, the key is shown this one time only", body = Envelope<NewApiKey>),
+        (status = 400, description = "Invalid name, scopes or expiry, or too many active keys, code invalid_request", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 403, description = "Called with an API key, code insufficient_scope", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_user_api_key(
+    data: web::Data<AppState>,
+    caller: Caller,
+    content: web::Json<ApiKeyReq>,
+) -> impl Responder {
+    match create_api_key(&data.db, caller.user_id, &content.name, &content.scopes, content.expires_in_days).await {
+        Ok(v) => success(StatusCode::CREATED, "API key created", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/api_keys/{id}/rotate",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "key_id"), RotateQuery),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "New key with the same name, scopes and expiry, shown this one time only", body = Envelope<NewApiKey>),
+        (status = 400, description = "Invalid grace_minutes, code invalid_request", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 403, description = "Called with an API key, code insufficient_scope", body = ErrorEnvelope),
+        (status = 404, description = "Unknown, revoked, expired or another user's key, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn rotate_user_api_key(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+    query: web::Query<RotateQuery>,
+) -> impl Responder {
+    match rotate_api_key(&data.db, caller.user_id, path.into_inner(), query.grace_minutes).await {
+        Ok(v) => success(StatusCode::CREATED, "API key rotated", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/api_keys/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "key_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "API key revoked, it stops working right away"),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 403, description = "Called with an API key, code insufficient_scope", body = ErrorEnvelope),
+        (status = 404, description = "Unknown, already revoked or another user's key, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn revoke_user_api_key(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    match revoke_api_key(&data.db, caller.user_id, path.into_inner()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::utilities::test_harness::TestDb;
+
+    #[actix_web::test]
+    async fn test_api_keys() {
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(100)).create().await;
+        let other = db.user().create().await;
+        let app = db.app().await;
+        let key_header = |key: &str| ("Authorization", format!("Bearer {}", key));
+
+        let req = test::TestRequest::post()
+            .uri("/v1/api_keys")
+            .insert_header(user.bearer())
+            .set_json(json!({"name": "Billing", "scopes": ["balance:write"]}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::post()
+            .uri("/v1/api_keys")
+            .insert_header(user.bearer())
+            .set_json(json!({"name": "Billing", "scopes": ["balance:read", "transactions:write"]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let key = resp_body["data"]["key"].as_str().unwrap().to_string();
+        let key_id = resp_body["data"]["api_key"]["key_id"].as_str().unwrap().to_string();
+        assert!(key.starts_with(resp_body["data"]["api_key"]["prefix"].as_str().unwrap()));
+        assert!(key.starts_with("sk_"));
+
+        //the key reaches the routes of its scopes, on the legacy routes as well
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(key_header(&key)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/balance/fetch_balance").insert_header(key_header(&key)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(key_header(&key))
+            .set_json(json!({"amount": dec!(10), "transaction_type": "deposit"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        //and nothing else, keys cannot manage keys
+        let req = test::TestRequest::get().uri("/v1/transactions").insert_header(key_header(&key)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "insufficient_scope");
+        let req = test::TestRequest::get().uri("/v1/api_keys").insert_header(key_header(&key)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(key_header("sk_unknown_key")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri("/v1/api_keys").insert_header(user.bearer()).to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+        assert!(resp_body["data"][0]["last_used_at"].is_string());
+        assert!(resp_body["data"][0].get("key_hash").is_none());
+
+        //another user can neither rotate nor revoke the key
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/api_keys/{}/rotate", key_id))
+            .insert_header(other.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/api_keys/{}", key_id))
+            .insert_header(other.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        //with a grace period both keys work until it ends
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/api_keys/{}/rotate?grace_minutes=60", key_id))
+            .insert_header(user.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let rotated = resp_body["data"]["key"].as_str().unwrap().to_string();
+        let rotated_id = resp_body["data"]["api_key"]["key_id"].as_str().unwrap().to_string();
+        assert_eq!(resp_body["data"]["api_key"]["scopes"], json!(["balance:read", "transactions:write"]));
+        for k in [&key, &rotated] {
+            let req = test::TestRequest::get().uri("/v1/balance").insert_header(key_header(k)).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        sqlx::query("UPDATE api_keys SET expires_at = expires_at - INTERVAL '2 hours' where expires_at IS NOT NULL")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(key_header(&key)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/api_keys/{}", rotated_id))
+            .insert_header(user.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(key_header(&rotated)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
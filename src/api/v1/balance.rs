@@ -0,0 +1,128 @@
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, Responder};
+use uuid::Uuid;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::{
+        balance::{get_balance, BalanceDetails},
+        limits::{account_limits, LimitHeadroom},
+    },
+    utilities::envelope::{db_failure, success},
+    AppState,
+};
+
+#[utoipa::path(
+    get,
+    path = "/v1/balance",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Balance of the authenticated user", body = Envelope<BalanceDetails>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_account_balance(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let pool = &data.db;
+    let uid = *req.extensions().get::<Uuid>().unwrap();
+    match get_balance(pool, uid).await {
+        Ok(v) => success(StatusCode::OK, "Balance", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/balance/limits",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "What is left of the limits of every limited transaction type, by type", body = Envelope<Vec<LimitHeadroom>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_account_limits(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let uid = *req.extensions().get::<Uuid>().unwrap();
+    match account_limits(&data.db, uid).await {
+        Ok(v) => success(StatusCode::OK, "Limits", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::utilities::{rbac::Role, test_harness::TestDb};
+
+    #[actix_web::test]
+    async fn test_transaction_limits() {
+        let db = TestDb::new().await;
+        let admin = db.user().role(Role::Admin).create().await;
+        let customer = db.user().balance(dec!(1000)).create().await;
+        let friend = db.user().create().await;
+        let app = db.app().await;
+
+        for (rule, status) in [
+            (json!({"transaction_type": "withdrawl", "per_transaction": "100.00", "daily": "150.00"}), StatusCode::CREATED),
+            (json!({"transaction_type": "transfer", "hourly_count": 2}), StatusCode::CREATED),
+            (json!({"transaction_type": "transfer", "tier": "premium", "monthly": "5000.00"}), StatusCode::CREATED),
+            (json!({"transaction_type": "deposit"}), StatusCode::BAD_REQUEST),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/admin/limit_rules")
+                .insert_header(admin.bearer())
+                .set_json(&rule)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", rule);
+        }
+
+        let book = |body: Value| {
+            let req = test::TestRequest::post()
+                .uri("/v1/transactions")
+                .insert_header(customer.bearer())
+                .set_json(body)
+                .to_request();
+            test::call_service(&app, req)
+        };
+        for (body, status, code) in [
+            (json!({"amount": "100.01", "transaction_type": "withdrawl"}), StatusCode::UNPROCESSABLE_ENTITY, "transaction_limit_exceeded"),
+            (json!({"amount": "100.00", "transaction_type": "withdrawl"}), StatusCode::CREATED, ""),
+            (json!({"amount": "50.01", "transaction_type": "withdrawl"}), StatusCode::UNPROCESSABLE_ENTITY, "daily_limit_exceeded"),
+            (json!({"amount": "1.00", "transaction_type": "transfer", "receiver": friend.user_id}), StatusCode::CREATED, ""),
+            (json!({"amount": "1.00", "transaction_type": "transfer", "receiver": friend.user_id}), StatusCode::CREATED, ""),
+            (json!({"amount": "1.00", "transaction_type": "transfer", "receiver": friend.user_id}), StatusCode::TOO_MANY_REQUESTS, "velocity_limit_exceeded"),
+        ] {
+            let resp = book(body.clone()).await;
+            assert_eq!(resp.status(), status, "{}", body);
+            if !code.is_empty() {
+                let resp_body: Value = test::read_body_json(resp).await;
+                assert_eq!(resp_body["code"], code);
+            }
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/v1/balance/limits")
+            .insert_header(customer.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let limits = resp_body["data"].as_array().unwrap();
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[0]["transaction_type"], "transfer");
+        assert_eq!(limits[0]["hourly_count"]["remaining"], 0);
+        assert_eq!(limits[1]["daily"]["remaining"], "50.00");
+        assert!(limits[1]["monthly"].is_null());
+
+        //the premium rule replaces the one for every tier
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/accounts/{}/tier", customer.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"tier": "premium"}))
+            .to_request();
+        test::call_service(&app, req).await;
+        let resp = book(json!({"amount": "1.00", "transaction_type": "transfer", "receiver": friend.user_id})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+}
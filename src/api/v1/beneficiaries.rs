@@ -0,0 +1,274 @@
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::beneficiaries::{
+        create_beneficiary, delete_beneficiary, get_beneficiary, list_beneficiaries,
+        update_beneficiary, Beneficiary, BeneficiaryFields,
+    },
+    utilities::{
+        envelope::{db_failure, success},
+        rbac::Caller,
+    },
+    AppState,
+};
+
+//the receiver is given either as user_id or as payee (e-mail, phone or @handle)
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BeneficiaryReq {
+    pub receiver: Option<Uuid>,
+    #[schema(example = "@alice")]
+    pub payee: Option<String>,
+    #[schema(example = "Landlord")]
+    pub nickname: String,
+    #[schema(example = "Rent")]
+    pub default_memo: Option<String>,
+    #[schema(value_type = Option<String>, example = "500.00")]
+    pub per_transfer_limit: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub daily_limit: Option<Decimal>,
+}
+
+//replaces every editable field, a missing memo or limit removes it
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BeneficiaryUpdateReq {
+    #[schema(example = "Landlord")]
+    pub nickname: String,
+    #[schema(example = "Rent")]
+    pub default_memo: Option<String>,
+    #[schema(value_type = Option<String>, example = "500.00")]
+    pub per_transfer_limit: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub daily_limit: Option<Decimal>,
+}
+
+impl From<BeneficiaryUpdateReq> for BeneficiaryFields {
+    fn from(v: BeneficiaryUpdateReq) -> Self {
+        BeneficiaryFields {
+            nickname: v.nickname,
+            default_memo: v.default_memo,
+            per_transfer_limit: v.per_transfer_limit,
+            daily_limit: v.daily_limit,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/beneficiaries",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Saved beneficiaries of the authenticated user, by nickname", body = Envelope<Vec<Beneficiary>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_user_beneficiaries(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_beneficiaries(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "Beneficiaries", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/beneficiaries",
+    tag = "v1",
+    request_body = BeneficiaryReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Beneficiary saved", body = Envelope<Beneficiary>),
+        (status = 400, description = "Invalid nickname, memo, limit or receiver", body = ErrorEnvelope),
+        (status = 404, description = "Unknown receiver (code not_found) or payee (code payee_not_found)", body = ErrorEnvelope),
+        (status = 409, description = "Nickname already used, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_user_beneficiary(
+    data: web::Data<AppState>,
+    content: web::Json<BeneficiaryReq>,
+    caller: Caller,
+) -> impl Responder {
+    let content = content.into_inner();
+    let fields = BeneficiaryFields {
+        nickname: content.nickname,
+        default_memo: content.default_memo,
+        per_transfer_limit: content.per_transfer_limit,
+        daily_limit: content.daily_limit,
+    };
+    match create_beneficiary(
+        &data.db,
+        caller.user_id,
+        content.receiver,
+        content.payee.as_deref(),
+        fields,
+    )
+    .await
+    {
+        Ok(v) => success(StatusCode::CREATED, "Beneficiary saved", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/beneficiaries/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "beneficiary_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The beneficiary", body = Envelope<Beneficiary>),
+        (status = 404, description = "Unknown or another user's beneficiary, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_user_beneficiary(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    match get_beneficiary(&data.db, caller.user_id, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "Beneficiary", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/beneficiaries/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "beneficiary_id")),
+    request_body = BeneficiaryUpdateReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Updated beneficiary", body = Envelope<Beneficiary>),
+        (status = 400, description = "Invalid nickname, memo or limit", body = ErrorEnvelope),
+        (status = 404, description = "Unknown or another user's beneficiary, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Nickname already used, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn update_user_beneficiary(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<BeneficiaryUpdateReq>,
+    caller: Caller,
+) -> impl Responder {
+    let fields = BeneficiaryFields::from(content.into_inner());
+    match update_beneficiary(&data.db, caller.user_id, path.into_inner(), fields).await {
+        Ok(v) => success(StatusCode::OK, "Beneficiary updated", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/beneficiaries/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "beneficiary_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "Beneficiary removed"),
+        (status = 404, description = "Unknown or another user's beneficiary, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn delete_user_beneficiary(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    match delete_beneficiary(&data.db, caller.user_id, path.into_inner()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::utilities::test_harness::TestDb;
+
+    #[actix_web::test]
+    async fn test_beneficiaries() {
+        let db = TestDb::new().await;
+        let owner = db.user().balance(dec!(100)).create().await;
+        let alice = db.user().email("alice@example.com").create().await;
+        let stranger = db.user().create().await;
+        let app = db.app().await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/beneficiaries")
+            .insert_header(owner.bearer())
+            .set_json(json!({"payee": "alice@example.com", "nickname": "Alice", "per_transfer_limit": "25.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["receiver_id"], alice.user_id.to_string());
+        let id = resp_body["data"]["beneficiary_id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/beneficiaries/{}", id))
+            .insert_header(stranger.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        //paying through the beneficiary applies its limit
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(owner.bearer())
+            .set_json(json!({"beneficiary_id": id, "amount": dec!(30)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "beneficiary_limit_exceeded");
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/v1/beneficiaries/{}", id))
+            .insert_header(owner.bearer())
+            .set_json(json!({"nickname": "Alice", "default_memo": "Lunch"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["per_transfer_limit"], Value::Null);
+
+        let req = test::TestRequest::post()
+            .uri("/transaction/operations")
+            .insert_header(owner.bearer())
+            .set_json(json!({"beneficiary_id": id, "amount": dec!(30), "transaction_type": "transfer"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        //the default memo was used
+        let req = test::TestRequest::get()
+            .uri("/v1/transactions?memo=lunch")
+            .insert_header(owner.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/beneficiaries")
+            .insert_header(owner.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/beneficiaries/{}", id))
+            .insert_header(owner.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+}
@@ -0,0 +1,226 @@
+use actix_web::{http::StatusCode, web, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::kyc::{
+        get_kyc_level, latest_kyc_submission, submit_kyc, Capabilities, KycLevel, KycSubmission,
+        KycSubmissionFields,
+    },
+    utilities::{
+        envelope::{db_failure, success},
+        rbac::Caller,
+    },
+    AppState,
+};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct KycStatus {
+    pub level: KycLevel,
+    //what the account may do at its level
+    pub capabilities: Capabilities,
+    //the latest submission, none before the first one
+    pub submission: Option<KycSubmission>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/kyc",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "KYC level, capabilities and latest submission of the authenticated user", body = Envelope<KycStatus>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_kyc_status(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    let level = match get_kyc_level(&data.db, caller.user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match latest_kyc_submission(&data.db, caller.user_id).await {
+        Ok(submission) => success(
+            StatusCode::OK,
+            "KYC status",
+            KycStatus {
+                level,
+                capabilities: level.capabilities(),
+                submission,
+            },
+        ),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/kyc",
+    tag = "v1",
+    request_body = KycSubmissionFields,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Submission received, it waits for a reviewer", body = Envelope<KycSubmission>),
+        (status = 400, description = "Invalid level, profile data or document", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 409, description = "A submission is already pending, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_kyc_submission(
+    data: web::Data<AppState>,
+    content: web::Json<KycSubmissionFields>,
+    caller: Caller,
+) -> impl Responder {
+    match submit_kyc(&data.db, caller.user_id, content.into_inner()).await {
+        Ok(v) => success(StatusCode::CREATED, "KYC submission received", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::{
+        models::kyc::KycLevel,
+        utilities::{rbac::Role, test_harness::TestDb},
+    };
+
+    #[actix_web::test]
+    async fn test_kyc_review() {
+        let db = TestDb::new().await;
+        let user = db.user().kyc(KycLevel::Unverified).balance(dec!(50)).create().await;
+        let reviewer = db.user().role(Role::Support).create().await;
+        let staff = db.user().kyc(KycLevel::Unverified).role(Role::Support).create().await;
+        let app = db.app().await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .set_json(json!({"amount": dec!(10), "transaction_type": "withdrawl"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "kyc_required");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .set_json(json!({"amount": dec!(150), "transaction_type": "deposit"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "kyc_limit_exceeded");
+
+        let submission = json!({
+            "level": "basic",
+            "legal_name": "Alice Smith",
+            "date_of_birth": "1990-04-01",
+            "address_line1": "1 Main Street",
+            "city": "Springfield",
+            "postal_code": "12345",
+            "country": "us"
+        });
+        let req = test::TestRequest::post()
+            .uri("/v1/kyc")
+            .insert_header(user.bearer())
+            .set_json(&submission)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["country"], "US");
+        let id = resp_body["data"]["submission_id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/v1/kyc")
+            .insert_header(user.bearer())
+            .set_json(&submission)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        //users cannot review themselves
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/approve", id))
+            .insert_header(user.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/kyc")
+            .insert_header(reviewer.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert!(resp_body["data"].as_array().unwrap().iter().any(|s| s["submission_id"] == id.as_str()));
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/reject", id))
+            .insert_header(reviewer.bearer())
+            .set_json(json!({"reason": " "}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/approve", id))
+            .insert_header(reviewer.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/reject", id))
+            .insert_header(reviewer.bearer())
+            .set_json(json!({"reason": "Too late"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/kyc")
+            .insert_header(user.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["level"], "basic");
+        assert_eq!(resp_body["data"]["submission"]["status"], "approved");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .set_json(json!({"amount": dec!(10), "transaction_type": "withdrawl"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        //reviewers cannot decide their own submission either
+        let req = test::TestRequest::post()
+            .uri("/v1/kyc")
+            .insert_header(staff.bearer())
+            .set_json(&submission)
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let own = resp_body["data"]["submission_id"].as_str().unwrap().to_string();
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/approve", own))
+            .insert_header(staff.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "forbidden");
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/kyc/{}/approve", own))
+            .insert_header(reviewer.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,30 @@
+use actix_web::{http::StatusCode, web, Responder};
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::notifications::{list_notifications, Notification},
+    utilities::{
+        envelope::{db_failure, success},
+        rbac::Caller,
+    },
+    AppState,
+};
+
+const NOTIFICATIONS_SHOWN: i64 = 50;
+
+#[utoipa::path(
+    get,
+    path = "/v1/notifications",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The latest 50 notifications of the authenticated user, newest first", body = Envelope<Vec<Notification>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_user_notifications(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_notifications(&data.db, caller.user_id, NOTIFICATIONS_SHOWN).await {
+        Ok(v) => success(StatusCode::OK, "Notifications", v),
+        Err(e) => db_failure(e),
+    }
+}
@@ -0,0 +1,729 @@
+use actix_web::{
+    error::{InternalError, UrlencodedError},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::openapi::{Envelope, ErrorEnvelope},
+    models::{
+        api_keys::Scope,
+        oauth::{
+            authenticate_client, authorize, client_credentials, exchange_code, list_clients,
+            list_consents, register_client, revoke_client, revoke_consent, token_active, Consent,
+            NewOAuthClient, OAuthClient,
+        },
+    },
+    utilities::{
+        auth::{decode_jwt, encode_oauth_jwt},
+        envelope::{db_failure, failure, success},
+        errors::TransactionError,
+        rbac::Caller,
+    },
+    AppState,
+};
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ClientReq {
+    #[schema(example = "Budget Planner")]
+    pub name: String,
+    //exact matches, https or http on localhost, optional for confidential clients that only use client_credentials
+    #[serde(default)]
+    #[schema(example = json!(["https://planner.example.com/callback"]))]
+    pub redirect_uris: Vec<String>,
+    #[schema(example = json!(["balance:read", "transactions:read"]))]
+    pub scopes: Vec<Scope>,
+    //for apps that cannot keep a secret, e.g. mobile and single page apps, they get no secret and no client_credentials grant
+    #[serde(default)]
+    pub public: bool,
+}
+
+//the request of the third-party app, approved on the consent screen of the logged in user
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AuthorizeReq {
+    #[schema(example = "code")]
+    pub response_type: String,
+    pub client_id: String,
+    #[schema(example = "https://planner.example.com/callback")]
+    pub redirect_uri: String,
+    //space separated, none grants every scope of the client
+    #[schema(example = "balance:read transactions:read")]
+    pub scope: Option<String>,
+    //handed back unchanged, protects the client against CSRF
+    pub state: Option<String>,
+    //BASE64URL(SHA256(code_verifier))
+    #[schema(example = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM")]
+    pub code_challenge: String,
+    #[schema(example = "S256")]
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Authorization {
+    pub code: String,
+    pub state: Option<String>,
+    //the redirect uri with code and state, where the consent screen sends the browser
+    #[schema(example = "https://planner.example.com/callback?code=3f9a...&state=xyz")]
+    pub redirect_to: String,
+}
+
+//form of the token endpoint, the client authenticates with HTTP Basic or client_id and client_secret
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OAuthTokenReq {
+    //authorization_code or client_credentials
+    #[schema(example = "authorization_code")]
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    //client_credentials only, space separated
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct IntrospectReq {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+//RFC 6749 section 5.1, not wrapped in the envelope so OAuth2 libraries understand it
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OAuthToken {
+    pub access_token: String,
+    #[schema(example = "Bearer")]
+    pub token_type: String,
+    #[schema(example = 3600)]
+    pub expires_in: i64,
+    #[schema(example = "balance:read transactions:read")]
+    pub scope: String,
+}
+
+//RFC 6749 section 5.2
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OAuthError {
+    #[schema(example = "invalid_grant")]
+    pub error: String,
+    pub error_description: String,
+}
+
+//RFC 7662 section 2.2, inactive tokens carry active only
+#[derive(Serialize, Deserialize, Default, ToSchema)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    //the user the token acts for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+}
+
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header(("Cache-Control", "no-store"))
+        .json(OAuthError {
+            error: error.to_string(),
+            error_description: description.to_string(),
+        })
+}
+
+fn oauth_failure(e: sqlx::Error) -> HttpResponse {
+    match TransactionError::from_sqlx(&e) {
+        Some(TransactionError::InvalidClient) => {
+            oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", &TransactionError::InvalidClient.to_string())
+        }
+        Some(v) => {
+            warn!(error = %v, "OAuth2 request rejected");
+            oauth_error(StatusCode::BAD_REQUEST, v.reason(), &v.to_string())
+        }
+        None => match e {
+            sqlx::Error::Encode(v) => oauth_error(StatusCode::BAD_REQUEST, "invalid_request", &v.to_string()),
+            e => {
+                error!(error = %e, "Error at the OAuth2 endpoints");
+                oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Internal server error")
+            }
+        },
+    }
+}
+
+//a form the token and introspection endpoints cannot read
+pub fn form_error(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    let response = oauth_error(StatusCode::BAD_REQUEST, "invalid_request", &err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+//client_id and client_secret of HTTP Basic (RFC 6749 section 2.3.1), or else of the form
+fn client_auth(req: &HttpRequest, client_id: Option<&str>, client_secret: Option<&str>) -> Option<(String, Option<String>)> {
+    let basic = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    if let Some(basic) = basic {
+        let (id, secret) = basic.split_once(':')?;
+        return Some((id.to_string(), Some(secret.to_string()).filter(|s| !s.is_empty())));
+    }
+    client_id.map(|id| (id.to_string(), client_secret.map(str::to_string)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/oauth/clients",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "OAuth2 clients registered by the authenticated user, newest first, without their secrets", body = Envelope<Vec<OAuthClient>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_oauth_clients(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_clients(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "OAuth2 clients", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/clients",
+    tag = "v1",
+    request_body = ClientReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Client registered, the secret of a confidential client is shown this one time only", body = Envelope<NewOAuthClient>),
+        (status = 400, description = "Invalid name, redirect uris or scopes, code invalid_request", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_oauth_client(
+    data: web::Data<AppState>,
+    caller: Caller,
+    content: web::Json<ClientReq>,
+) -> impl Responder {
+    match register_client(&data.db, caller.user_id, &content.name, &content.redirect_uris, &content.scopes, !content.public).await {
+        Ok(v) => success(StatusCode::CREATED, "OAuth2 client registered", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/oauth/clients/{id}",
+    tag = "v1",
+    params(("id" = String, Path, description = "client_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "Client revoked, every token it holds stops working"),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 404, description = "Unknown, already revoked or another user's client, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn revoke_oauth_client(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    caller: Caller,
+) -> impl Responder {
+    match revoke_client(&data.db, caller.user_id, &path.into_inner()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/authorize",
+    tag = "v1",
+    request_body = AuthorizeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "The user consented, a single-use authorization code for the token endpoint", body = Envelope<Authorization>),
+        (status = 400, description = "Unsupported response_type, missing PKCE, or a redirect uri or scope the client does not have, codes unsupported_response_type, invalid_request, invalid_redirect_uri and invalid_scope", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 404, description = "Unknown or revoked client, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn authorize_client(
+    data: web::Data<AppState>,
+    caller: Caller,
+    content: web::Json<AuthorizeReq>,
+) -> impl Responder {
+    if content.response_type != "code" {
+        return failure(StatusCode::BAD_REQUEST, "unsupported_response_type", "response_type must be code");
+    }
+    let ttl = Duration::seconds(data.settings.auth.oauth_code_ttl_secs);
+    let code = match authorize(
+        &data.db,
+        caller.user_id,
+        &content.client_id,
+        &content.redirect_uri,
+        content.scope.as_deref(),
+        &content.code_challenge,
+        content.code_challenge_method.as_deref(),
+        ttl,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+
+    //the redirect uri was registered as a valid URL
+    let mut redirect_to = match Url::parse(&content.redirect_uri) {
+        Ok(v) => v,
+        Err(_) => return failure(StatusCode::BAD_REQUEST, "invalid_redirect_uri", "Invalid redirect uri"),
+    };
+    redirect_to.query_pairs_mut().append_pair("code", &code);
+    if let Some(state) = &content.state {
+        redirect_to.query_pairs_mut().append_pair("state", state);
+    }
+    success(
+        StatusCode::CREATED,
+        "Authorization code issued",
+        Authorization {
+            code,
+            state: content.state.clone(),
+            redirect_to: redirect_to.to_string(),
+        },
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/oauth/consents",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Clients the authenticated user allowed to act on their behalf, by name", body = Envelope<Vec<Consent>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_oauth_consents(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_consents(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "OAuth2 consents", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/oauth/consents/{client_id}",
+    tag = "v1",
+    params(("client_id" = String, Path, description = "client_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 204, description = "Consent revoked, the tokens the client holds for the user stop working"),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 404, description = "No consent for this client, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn revoke_oauth_consent(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    caller: Caller,
+) -> impl Responder {
+    match revoke_consent(&data.db, caller.user_id, &path.into_inner()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/token",
+    tag = "v1",
+    request_body(content = OAuthTokenReq, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Access token, a JWT limited to the granted scopes", body = OAuthToken),
+        (status = 400, description = "Errors of RFC 6749 section 5.2: invalid_request, invalid_grant, invalid_scope, unauthorized_client, unsupported_grant_type", body = OAuthError),
+        (status = 401, description = "Unknown client or wrong secret, error invalid_client", body = OAuthError)
+    )
+)]
+pub async fn issue_oauth_token(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<OAuthTokenReq>,
+) -> HttpResponse {
+    let (client_id, client_secret) = match client_auth(&req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(v) => v,
+        None => return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication is required"),
+    };
+    let client = match authenticate_client(&data.db, &client_id, client_secret.as_deref()).await {
+        Ok(v) => v,
+        Err(e) => return oauth_failure(e),
+    };
+
+    let grant = match form.grant_type.as_str() {
+        "authorization_code" => match (&form.code, &form.redirect_uri, &form.code_verifier) {
+            (Some(code), Some(redirect_uri), Some(code_verifier)) => {
+                exchange_code(&data.db, &client, code, redirect_uri, code_verifier).await
+            }
+            _ => {
+                return oauth_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    "code, redirect_uri and code_verifier are required",
+                )
+            }
+        },
+        "client_credentials" => client_credentials(&data.db, &client, form.scope.as_deref()).await,
+        _ => {
+            return oauth_error(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "grant_type must be authorization_code or client_credentials",
+            )
+        }
+    };
+    let grant = match grant {
+        Ok(v) => v,
+        Err(e) => return oauth_failure(e),
+    };
+
+    match encode_oauth_jwt(grant.user_id, grant.role, &client.client_id, &form.grant_type, &grant.scopes, &data.settings.auth) {
+        Ok(access_token) => {
+            info!(client_id = client.client_id, user_id = %grant.user_id, grant_type = form.grant_type, "OAuth2 access token issued");
+            HttpResponse::Ok()
+                .insert_header(("Cache-Control", "no-store"))
+                .json(OAuthToken {
+                    access_token,
+                    token_type: String::from("Bearer"),
+                    expires_in: data.settings.auth.oauth_token_ttl_minutes * 60,
+                    scope: grant.scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(" "),
+                })
+        }
+        Err(e) => {
+            error!(error = %e, "Error at OAuth2 token encoding");
+            oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Internal server error")
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/oauth/introspect",
+    tag = "v1",
+    request_body(content = IntrospectReq, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "RFC 7662 introspection, active only for unexpired and unrevoked tokens issued to the calling client", body = Introspection),
+        (status = 401, description = "Unknown client or wrong secret, error invalid_client", body = OAuthError)
+    )
+)]
+pub async fn introspect_oauth_token(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<IntrospectReq>,
+) -> HttpResponse {
+    let (client_id, client_secret) = match client_auth(&req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(v) => v,
+        None => return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication is required"),
+    };
+    let client = match authenticate_client(&data.db, &client_id, client_secret.as_deref()).await {
+        Ok(v) => v,
+        Err(e) => return oauth_failure(e),
+    };
+
+    let inactive = HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(Introspection::default());
+    let claims = match decode_jwt(form.token.clone(), &data.settings.auth) {
+        Ok(v) if v.client_id.as_deref() == Some(client.client_id.as_str()) => v,
+        _ => return inactive,
+    };
+    match token_active(&data.db, &claims).await {
+        Ok(true) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "no-store"))
+            .json(Introspection {
+                active: true,
+                scope: claims.scope,
+                client_id: claims.client_id,
+                sub: Some(claims.sub),
+                exp: Some(claims.exp),
+                token_type: Some(String::from("Bearer")),
+            }),
+        Ok(false) => inactive,
+        Err(e) => oauth_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine,
+    };
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+
+    use crate::utilities::test_harness::TestDb;
+
+    #[actix_web::test]
+    async fn test_oauth_flows() {
+        let db = TestDb::new().await;
+        let partner = db.user().balance(dec!(500)).create().await;
+        let user = db.user().balance(dec!(100)).create().await;
+        let app = db.app().await;
+        let bearer = |token: &str| ("Authorization", format!("Bearer {}", token));
+        let redirect_uri = "https://planner.example.com/callback";
+
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/clients")
+            .insert_header(partner.bearer())
+            .set_json(json!({"name": "Planner", "redirect_uris": ["http://planner.example.com/callback"], "scopes": ["balance:read"], "public": true}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/clients")
+            .insert_header(partner.bearer())
+            .set_json(json!({"name": "Planner", "redirect_uris": [redirect_uri], "scopes": ["balance:read", "transactions:read"], "public": true}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert!(resp_body["data"]["client_secret"].is_null());
+        let public_id = resp_body["data"]["client"]["client_id"].as_str().unwrap().to_string();
+
+        //authorization code with PKCE, for the user on the consent screen
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let authorize = |redirect: &str, scope: &str| {
+            json!({"response_type": "code", "client_id": public_id, "redirect_uri": redirect, "scope": scope,
+                "state": "xyz", "code_challenge": challenge, "code_challenge_method": "S256"})
+        };
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/authorize")
+            .insert_header(user.bearer())
+            .set_json(authorize("https://evil.example.com/callback", "balance:read"))
+            .to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["code"], "invalid_redirect_uri");
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/authorize")
+            .insert_header(user.bearer())
+            .set_json(authorize(redirect_uri, "balance:read payouts:write"))
+            .to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["code"], "invalid_scope");
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/authorize")
+            .insert_header(user.bearer())
+            .set_json(authorize(redirect_uri, "balance:read transactions:read"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let code = resp_body["data"]["code"].as_str().unwrap().to_string();
+        assert_eq!(
+            resp_body["data"]["redirect_to"],
+            format!("{}?code={}&state=xyz", redirect_uri, code)
+        );
+
+        let exchange = |verifier: &str| {
+            [
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code.clone()),
+                ("redirect_uri", redirect_uri.to_string()),
+                ("code_verifier", verifier.to_string()),
+                ("client_id", public_id.clone()),
+            ]
+        };
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .set_form(exchange("a-verifier-that-does-not-match-the-challenge-at-all"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["error"], "invalid_grant");
+        let req = test::TestRequest::post().uri("/v1/oauth/token").set_form(exchange(verifier)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("cache-control").unwrap(), "no-store");
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["scope"], "balance:read transactions:read");
+        let user_token = resp_body["access_token"].as_str().unwrap().to_string();
+
+        //the token acts for the user, within its scopes
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&user_token)).to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["data"]["user_id"], user.user_id.to_string());
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(bearer(&user_token))
+            .set_json(json!({"amount": dec!(10), "transaction_type": "withdrawl"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get().uri("/v1/oauth/consents").insert_header(bearer(&user_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let introspect = |token: &str, client_id: &str| {
+            test::TestRequest::post()
+                .uri("/v1/oauth/introspect")
+                .set_form([("token", token), ("client_id", client_id)])
+                .to_request()
+        };
+        let resp_body: Value = test::read_body_json(test::call_service(&app, introspect(&user_token, &public_id)).await).await;
+        assert_eq!(resp_body["active"], true);
+        assert_eq!(resp_body["sub"], user.user_id.to_string());
+        assert_eq!(resp_body["scope"], "balance:read transactions:read");
+
+        //client credentials, a confidential client acts for its owner and authenticates with HTTP Basic
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/clients")
+            .insert_header(partner.bearer())
+            .set_json(json!({"name": "Reporting", "scopes": ["balance:read"]}))
+            .to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let confidential_id = resp_body["data"]["client"]["client_id"].as_str().unwrap().to_string();
+        let secret = resp_body["data"]["client_secret"].as_str().unwrap().to_string();
+        let basic = |secret: &str| ("Authorization", format!("Basic {}", STANDARD.encode(format!("{}:{}", confidential_id, secret))));
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .insert_header(basic("wrong"))
+            .set_form([("grant_type", "client_credentials")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .set_form([("grant_type", "client_credentials"), ("client_id", public_id.as_str())])
+            .to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["error"], "unauthorized_client");
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .insert_header(basic(&secret))
+            .set_form([("grant_type", "client_credentials")])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let partner_token = resp_body["access_token"].as_str().unwrap().to_string();
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&partner_token)).to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["data"]["user_id"], partner.user_id.to_string());
+        //tokens of other clients are not disclosed
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/introspect")
+            .insert_header(basic(&secret))
+            .set_form([("token", user_token.as_str())])
+            .to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body, json!({"active": false}));
+
+        //revoking the client ends its tokens
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/oauth/clients/{}", confidential_id))
+            .insert_header(partner.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&partner_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri("/v1/oauth/consents").insert_header(user.bearer()).to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["data"][0]["client_id"], public_id);
+        assert_eq!(resp_body["data"][0]["scopes"], json!(["balance:read", "transactions:read"]));
+
+        //a code used twice was probably stolen, the consent and the token it brought end
+        let req = test::TestRequest::post().uri("/v1/oauth/token").set_form(exchange(verifier)).to_request();
+        let resp_body: Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(resp_body["error"], "invalid_grant");
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&user_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(test::call_service(&app, introspect(&user_token, &public_id)).await).await;
+        assert_eq!(resp_body["active"], false);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/oauth/consents/{}", public_id))
+            .insert_header(user.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        //a new grant does not bring back the tokens of the revoked one, iat has whole seconds
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let mut tokens = vec![];
+        for scope in ["balance:read transactions:read", "balance:read"] {
+            let req = test::TestRequest::post()
+                .uri("/v1/oauth/authorize")
+                .insert_header(user.bearer())
+                .set_json(authorize(redirect_uri, scope))
+                .to_request();
+            let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+            let code = resp_body["data"]["code"].as_str().unwrap();
+            let req = test::TestRequest::post()
+                .uri("/v1/oauth/token")
+                .set_form([
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", redirect_uri),
+                    ("code_verifier", verifier),
+                    ("client_id", public_id.as_str()),
+                ])
+                .to_request();
+            let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+            let token = resp_body["access_token"].as_str().unwrap().to_string();
+            let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&token)).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+            tokens.push(token);
+        }
+        //the consent narrowed to balance:read, wider tokens stop working
+        for (token, status) in [
+            (&user_token, StatusCode::UNAUTHORIZED),
+            (&tokens[0], StatusCode::UNAUTHORIZED),
+            (&tokens[1], StatusCode::OK),
+        ] {
+            let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(token)).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+
+        //the owner who authorizes their own client depends on the consent like everyone else
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/authorize")
+            .insert_header(partner.bearer())
+            .set_json(authorize(redirect_uri, "balance:read"))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let code = resp_body["data"]["code"].as_str().unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .set_form([
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("code_verifier", verifier),
+                ("client_id", public_id.as_str()),
+            ])
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let owner_token = resp_body["access_token"].as_str().unwrap().to_string();
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&owner_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v1/oauth/consents/{}", public_id))
+            .insert_header(partner.bearer())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer(&owner_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(test::call_service(&app, introspect(&owner_token, &public_id)).await).await;
+        assert_eq!(resp_body["active"], false);
+    }
+}
@@ -0,0 +1,336 @@
+use std::str::FromStr;
+
+use actix_web::{
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        openapi::{Envelope, ErrorEnvelope, RowErrorsEnvelope},
+        transactions::otp_header,
+        v1::two_factor::step_up,
+    },
+    models::payouts::{
+        create_payout_batch, get_payout_batch, list_payout_batches, list_payout_items, InvalidRows,
+        PayoutBatch, PayoutItem, PayoutMode, PayoutRow, RowError,
+    },
+    utilities::{
+        envelope::{db_failure, failure, success},
+        rbac::{require, Authorized, Caller},
+    },
+    AppState,
+};
+
+//a CSV or JSON upload of max_items rows fits comfortably
+pub const MAX_UPLOAD_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PayoutReq {
+    #[serde(default)]
+    pub mode: PayoutMode,
+    #[schema(example = "payroll-2025-07")]
+    pub reference: Option<String>,
+    pub items: Vec<PayoutRow>,
+}
+
+//mode and reference of a CSV upload, a JSON upload carries them in the body
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+pub struct PayoutQuery {
+    //all_or_nothing or best_effort (the default)
+    pub mode: Option<PayoutMode>,
+    pub reference: Option<String>,
+}
+
+//a CSV row before the amount is parsed, so a bad amount is reported with its line
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    receiver: Option<Uuid>,
+    payee: Option<String>,
+    amount: String,
+    memo: Option<String>,
+    reference: Option<String>,
+}
+
+//a header line names the columns, amount and one of receiver or payee are required
+fn parse_csv(body: &[u8]) -> Result<Vec<PayoutRow>, sqlx::Error> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body);
+    let mut rows = vec![];
+    let mut invalid = vec![];
+    for (i, record) in reader.deserialize::<CsvRow>().enumerate() {
+        let row = record.map_err(|e| e.to_string()).and_then(|r| {
+            let amount = Decimal::from_str(&r.amount).map_err(|_| format!("Invalid amount {}", r.amount))?;
+            Ok(PayoutRow {
+                receiver: r.receiver,
+                payee: r.payee,
+                amount,
+                memo: r.memo,
+                reference: r.reference,
+            })
+        });
+        match row {
+            Ok(v) => rows.push(v),
+            Err(message) => invalid.push(RowError {
+                line: i + 1,
+                code: String::from("invalid_row"),
+                message,
+            }),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(InvalidRows(invalid).into());
+    }
+    Ok(rows)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/payouts",
+    tag = "v1",
+    params(
+        PayoutQuery,
+        ("X-OTP" = Option<String>, Header, description = "One-time password, required for batches whose total is above auth.step_up_amount unless the token is elevated")
+    ),
+    request_body(
+        description = "A JSON batch, or a CSV file with the header receiver,payee,amount,memo,reference (unused columns may be left out)",
+        content((PayoutReq = "application/json"), (String = "text/csv"))
+    ),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 202, description = "Every row passed the checks, the batch is booked in the background", body = Envelope<PayoutBatch>),
+        (status = 400, description = "Invalid rows, code invalid_rows with one error per row", body = RowErrorsEnvelope),
+        (status = 401, description = "A batch total above the step-up amount without an elevated token needs the X-OTP header (code step_up_required), whose one-time password was invalid (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong one-time passwords, code otp_locked", body = ErrorEnvelope),
+        (status = 403, description = "The role may not transact (code forbidden) or a batch total above the step-up amount needs two-factor authentication (code two_factor_required)", body = ErrorEnvelope),
+        (status = 422, description = "An all_or_nothing batch above the balance, code insufficient_balance", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_payout(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<PayoutQuery>,
+    body: web::Bytes,
+    auth: Authorized<require::Transact>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let is_csv = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/csv"));
+    let (mode, reference, rows) = if is_csv {
+        match parse_csv(&body) {
+            Ok(rows) => (query.mode.unwrap_or_default(), query.reference, rows),
+            Err(e) => return db_failure(e),
+        }
+    } else {
+        match serde_json::from_slice::<PayoutReq>(&body) {
+            Ok(v) => (v.mode, v.reference, v.items),
+            Err(e) => return failure(StatusCode::BAD_REQUEST, "invalid_body", &e.to_string()),
+        }
+    };
+    //every row is a transfer, the batch steps up on its total
+    let total: Decimal = rows.iter().map(|r| r.amount).sum();
+    if let Err(resp) = step_up(&data, &auth.caller, "transfer", total, otp_header(&req), db_failure).await {
+        return resp;
+    }
+    let max_items = data.settings.payouts.max_items;
+    match create_payout_batch(&data.db, auth.caller.user_id, mode, reference, rows, max_items).await {
+        Ok(v) => success(StatusCode::ACCEPTED, "Payout batch accepted", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/payouts",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Payout batches of the authenticated user, newest first", body = Envelope<Vec<PayoutBatch>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_payouts(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_payout_batches(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "Payout batches", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/payouts/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "batch_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Status and counters of the batch", body = Envelope<PayoutBatch>),
+        (status = 404, description = "Unknown or another user's batch, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_payout(data: web::Data<AppState>, path: web::Path<Uuid>, caller: Caller) -> impl Responder {
+    match get_payout_batch(&data.db, caller.user_id, path.into_inner()).await {
+        Ok(v) => success(StatusCode::OK, "Payout batch", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/payouts/{id}/items",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "batch_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Status of every row, in upload order", body = Envelope<Vec<PayoutItem>>),
+        (status = 404, description = "Unknown or another user's batch, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_payout_items(data: web::Data<AppState>, path: web::Path<Uuid>, caller: Caller) -> impl Responder {
+    let pool = &data.db;
+    let batch = match get_payout_batch(pool, caller.user_id, path.into_inner()).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match list_payout_items(pool, batch.batch_id).await {
+        Ok(v) => success(StatusCode::OK, "Payout items", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/payouts/{id}/results.csv",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "batch_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The rows with their status, transaction_id and error as a CSV download", body = String, content_type = "text/csv"),
+        (status = 404, description = "Unknown or another user's batch, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn download_payout_results(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    let pool = &data.db;
+    let batch = match get_payout_batch(pool, caller.user_id, path.into_inner()).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let items = match list_payout_items(pool, batch.batch_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for item in &items {
+        if let Err(e) = writer.serialize(item) {
+            return db_failure(sqlx::Error::Encode(Box::new(e)));
+        }
+    }
+    match writer.into_inner() {
+        Ok(v) => HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"payout-{}.csv\"", batch.batch_id),
+            ))
+            .body(v),
+        Err(e) => db_failure(sqlx::Error::Encode(Box::new(e.into_error()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use chrono::Duration;
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::{models::payouts::process_next_payout_batch, utilities::test_harness::TestDb};
+
+    #[actix_web::test]
+    async fn test_payouts_api() {
+        let db = TestDb::new().await;
+        let payer = db.user().balance(dec!(100)).create().await;
+        let alice = db.user().email("alice@example.com").create().await;
+        let bob = db.user().create().await;
+        let app = db.app().await;
+
+        //invalid rows are all reported, nothing is stored
+        let csv = format!("payee,amount\nalice@example.com,10.00\nnobody@example.com,5\n{},abc\n", alice.user_id);
+        let req = test::TestRequest::post()
+            .uri("/v1/payouts")
+            .insert_header(payer.bearer())
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload(csv)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_rows");
+        assert_eq!(resp_body["errors"][0]["line"], 3);
+
+        let csv = format!(
+            "receiver,payee,amount,memo,reference\n,alice@example.com,10.00,Salary,emp-1\n{},,20.00,,emp-2\n",
+            bob.user_id
+        );
+        let req = test::TestRequest::post()
+            .uri("/v1/payouts?mode=all_or_nothing&reference=payroll-07")
+            .insert_header(payer.bearer())
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload(csv)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["status"], "pending");
+        assert_eq!(resp_body["data"]["total_amount"], "30.00");
+        let id = resp_body["data"]["batch_id"].as_str().unwrap().to_string();
+
+        process_next_payout_batch(&db.pool, &db.compliance, Duration::minutes(5)).await.unwrap();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/payouts/{}", id))
+            .insert_header(payer.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["status"], "completed");
+        assert_eq!(resp_body["data"]["succeeded"], 2);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/payouts/{}/results.csv", id))
+            .insert_header(payer.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let lines: Vec<_> = body.lines().collect();
+        assert!(lines[0].starts_with("line,receiver_id,amount"));
+        assert!(lines[1].contains(&alice.user_id.to_string()) && lines[1].contains(",completed,"));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/payouts/{}/items", id))
+            .insert_header(bob.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        //the JSON form of the same upload
+        let req = test::TestRequest::post()
+            .uri("/v1/payouts")
+            .insert_header(payer.bearer())
+            .set_json(json!({"items": [{"receiver": bob.user_id, "amount": "1.00"}]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["mode"], "best_effort");
+    }
+}
@@ -0,0 +1,323 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        openapi::{Envelope, ErrorEnvelope},
+        transactions::otp_header,
+        v1::two_factor::step_up,
+    },
+    models::{
+        beneficiaries::resolve_target,
+        schedules::{
+            change_schedule, create_schedule, get_schedule, list_schedule_runs, list_schedules,
+            Frequency, NewSchedule, ScheduleAction, ScheduleRun, ScheduledPayment,
+        },
+    },
+    utilities::{
+        envelope::{db_failure, failure, success},
+        rbac::{require, Authorized, Caller},
+    },
+    AppState,
+};
+
+//the receiver is given as user_id, as payee or as a saved beneficiary, like for a transfer
+//times are UTC, e.g. 2025-07-01T09:00:00
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleReq {
+    pub receiver: Option<Uuid>,
+    #[schema(example = "@alice")]
+    pub payee: Option<String>,
+    pub beneficiary_id: Option<Uuid>,
+    #[schema(value_type = String, example = "25.00")]
+    pub amount: Decimal,
+    #[schema(example = "Rent")]
+    pub memo: Option<String>,
+    #[serde(default = "once")]
+    pub frequency: Frequency,
+    pub start_at: NaiveDateTime,
+    //recurring payments only, the last payment is planned on or before it
+    pub end_at: Option<NaiveDateTime>,
+    //recurring payments only, the number of payments to make
+    pub count: Option<i32>,
+}
+
+fn once() -> Frequency {
+    Frequency::Once
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ScheduleDetails {
+    pub schedule: ScheduledPayment,
+    //every attempt, oldest first
+    pub runs: Vec<ScheduleRun>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/scheduled_payments",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Scheduled payments of the authenticated user, newest first", body = Envelope<Vec<ScheduledPayment>>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_user_schedules(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match list_schedules(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "Scheduled payments", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/scheduled_payments",
+    tag = "v1",
+    request_body = ScheduleReq,
+    params(("X-OTP" = Option<String>, Header, description = "One-time password, required for payments above auth.step_up_amount unless the token is elevated")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Payment scheduled", body = Envelope<ScheduledPayment>),
+        (status = 400, description = "Invalid amount, receiver or schedule", body = ErrorEnvelope),
+        (status = 401, description = "A payment above the step-up amount without an elevated token needs the X-OTP header (code step_up_required), whose one-time password was invalid (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong one-time passwords, code otp_locked", body = ErrorEnvelope),
+        (status = 403, description = "The role may not transact (code forbidden) or a payment above the step-up amount needs two-factor authentication (code two_factor_required)", body = ErrorEnvelope),
+        (status = 404, description = "Unknown receiver or beneficiary (code not_found) or payee (code payee_not_found)", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_user_schedule(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    content: web::Json<ScheduleReq>,
+    auth: Authorized<require::Transact>,
+) -> impl Responder {
+    let pool = &data.db;
+    let uid = auth.caller.user_id;
+    let content = content.into_inner();
+    //the scheduler books without the user, so every payment steps up when it is scheduled
+    if let Err(resp) = step_up(&data, &auth.caller, "transfer", content.amount, otp_header(&req), db_failure).await {
+        return resp;
+    }
+    let (receiver, options) = match resolve_target(
+        pool,
+        uid,
+        content.receiver,
+        content.payee.as_deref(),
+        content.beneficiary_id,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    let receiver_id = match receiver {
+        Some(v) => v,
+        None => {
+            return failure(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "A receiver, payee or beneficiary_id is required",
+            )
+        }
+    };
+    let new = NewSchedule {
+        receiver_id,
+        amount: content.amount,
+        memo: content.memo.or(options.memo),
+        frequency: content.frequency,
+        start_at: content.start_at,
+        end_at: content.end_at,
+        max_executions: content.count,
+    };
+    match create_schedule(pool, uid, new).await {
+        Ok(v) => success(StatusCode::CREATED, "Payment scheduled", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/scheduled_payments/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "schedule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The scheduled payment and its runs", body = Envelope<ScheduleDetails>),
+        (status = 404, description = "Unknown or another user's scheduled payment, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_user_schedule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    let pool = &data.db;
+    let schedule = match get_schedule(pool, caller.user_id, path.into_inner()).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match list_schedule_runs(pool, schedule.schedule_id).await {
+        Ok(runs) => success(
+            StatusCode::OK,
+            "Scheduled payment",
+            ScheduleDetails { schedule, runs },
+        ),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/scheduled_payments/{id}/pause",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "schedule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Paused, no payment is made until it is resumed", body = Envelope<ScheduledPayment>),
+        (status = 404, description = "Unknown or another user's scheduled payment, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Not active, code invalid_schedule_state", body = ErrorEnvelope)
+    )
+)]
+pub async fn pause_schedule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    change(&data, caller, path.into_inner(), ScheduleAction::Pause).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/scheduled_payments/{id}/resume",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "schedule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Active again, payments that fell due while paused are skipped", body = Envelope<ScheduledPayment>),
+        (status = 404, description = "Unknown or another user's scheduled payment, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Not paused, code invalid_schedule_state", body = ErrorEnvelope)
+    )
+)]
+pub async fn resume_schedule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    change(&data, caller, path.into_inner(), ScheduleAction::Resume).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/scheduled_payments/{id}/cancel",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "schedule_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Cancelled for good", body = Envelope<ScheduledPayment>),
+        (status = 404, description = "Unknown or another user's scheduled payment, code not_found", body = ErrorEnvelope),
+        (status = 409, description = "Already ended, code invalid_schedule_state", body = ErrorEnvelope)
+    )
+)]
+pub async fn cancel_schedule(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    change(&data, caller, path.into_inner(), ScheduleAction::Cancel).await
+}
+
+async fn change(data: &AppState, caller: Caller, id: Uuid, action: ScheduleAction) -> HttpResponse {
+    match change_schedule(&data.db, caller.user_id, id, action).await {
+        Ok(v) => success(StatusCode::OK, "Scheduled payment updated", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use chrono::{Duration, Utc};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::utilities::test_harness::TestDb;
+
+    #[actix_web::test]
+    async fn test_scheduled_payments_api() {
+        let db = TestDb::new().await;
+        let owner = db.user().balance(dec!(100)).create().await;
+        let alice = db.user().email("alice@example.com").create().await;
+        let app = db.app().await;
+
+        let start = Utc::now().naive_utc() + Duration::days(1);
+        let req = test::TestRequest::post()
+            .uri("/v1/scheduled_payments")
+            .insert_header(owner.bearer())
+            .set_json(json!({
+                "payee": "alice@example.com",
+                "amount": "25.00",
+                "frequency": "monthly",
+                "start_at": start,
+                "count": 12
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["receiver_id"], alice.user_id.to_string());
+        assert_eq!(resp_body["data"]["status"], "active");
+        let id = resp_body["data"]["schedule_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        //single payments have no count, and nothing is planned in the past
+        for body in [
+            json!({"receiver": alice.user_id, "amount": "1.00", "start_at": start, "count": 2}),
+            json!({"receiver": alice.user_id, "amount": "1.00", "start_at": start - Duration::days(2)}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/v1/scheduled_payments")
+                .insert_header(owner.bearer())
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/scheduled_payments/{}/pause", id))
+            .insert_header(alice.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for (action, code) in [
+            ("pause", StatusCode::OK),
+            ("pause", StatusCode::CONFLICT),
+            ("resume", StatusCode::OK),
+            ("cancel", StatusCode::OK),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/v1/scheduled_payments/{}/{}", id, action))
+                .insert_header(owner.bearer())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), code, "{}", action);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/scheduled_payments/{}", id))
+            .insert_header(owner.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["schedule"]["status"], "cancelled");
+        assert_eq!(resp_body["data"]["runs"], json!([]));
+    }
+}
@@ -0,0 +1,603 @@
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        openapi::{Envelope, ErrorEnvelope},
+        transactions::{otp_header, TransactionDataReq},
+        v1::two_factor::step_up,
+    },
+    models::{
+        beneficiaries::resolve_target,
+        fees::{quote_fee, FeeQuote},
+        payees::{find_payee, PayeeDetails},
+        transactions::{
+            add_transaction_with, get_transaction, list_transactions_filtered, TransactionDetails,
+            TransactionFilter,
+        },
+    },
+    utilities::{
+        envelope::{db_failure, failure, success},
+        rbac::{require, Authorized, Caller, Permission},
+    },
+    AppState,
+};
+
+//the receiver is given as user_id, as payee (e-mail, phone or @handle) or as a saved beneficiary
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TransferReq {
+    pub receiver: Option<Uuid>,
+    #[schema(example = "@alice")]
+    pub payee: Option<String>,
+    pub beneficiary_id: Option<Uuid>,
+    #[schema(value_type = String, example = "10.50")]
+    pub amount: Decimal,
+    #[schema(example = "Dinner on Friday")]
+    pub memo: Option<String>,
+    #[schema(example = "order-1042")]
+    pub reference: Option<String>,
+    #[schema(value_type = Option<Object>, example = json!({"order_id": "1042"}))]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl From<TransferReq> for TransactionDataReq {
+    fn from(v: TransferReq) -> Self {
+        TransactionDataReq {
+            receiver: v.receiver,
+            payee: v.payee,
+            beneficiary_id: v.beneficiary_id,
+            amount: v.amount,
+            transaction_type: String::from("transfer"),
+            memo: v.memo,
+            reference: v.reference,
+            metadata: v.metadata,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ResolvePayeeReq {
+    #[schema(example = "alice@example.com")]
+    pub payee: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/payees/resolve",
+    tag = "v1",
+    request_body = ResolvePayeeReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Masked details of the payee, to be confirmed before paying", body = Envelope<PayeeDetails>),
+        (status = 400, description = "Not an e-mail, phone number or @handle, code invalid_payee", body = ErrorEnvelope),
+        (status = 404, description = "No account that can receive money matches, code payee_not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn resolve_payee(
+    data: web::Data<AppState>,
+    content: web::Json<ResolvePayeeReq>,
+) -> impl Responder {
+    match find_payee(&data.db, &content.payee).await {
+        Ok((_, details)) => success(StatusCode::OK, "Payee found", details),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuoteReq {
+    #[schema(value_type = String, example = "100.00")]
+    pub amount: Decimal,
+    #[schema(example = "transfer")]
+    pub transaction_type: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/transactions/quote",
+    tag = "v1",
+    request_body = QuoteReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The fee the authenticated user would pay, nothing is booked", body = Envelope<FeeQuote>),
+        (status = 400, description = "Invalid amount or transaction_type", body = ErrorEnvelope),
+        (status = 422, description = "A deposit that would not cover its fee, code fee_exceeds_amount", body = ErrorEnvelope)
+    )
+)]
+pub async fn quote_transaction(
+    data: web::Data<AppState>,
+    content: web::Json<QuoteReq>,
+    caller: Caller,
+) -> impl Responder {
+    match quote_fee(&data.db, caller.user_id, &content.transaction_type, content.amount).await {
+        Ok(v) => success(StatusCode::OK, "Fee quote", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/transactions",
+    tag = "v1",
+    params(TransactionFilter),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Every transaction the user sent or received that matches the filters", body = Envelope<Vec<TransactionDetails>>),
+        (status = 400, description = "Invalid filter, code invalid_query or invalid_request", body = ErrorEnvelope),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn list_user_transactions(
+    data: web::Data<AppState>,
+    filter: web::Query<TransactionFilter>,
+    req: HttpRequest,
+) -> impl Responder {
+    let pool = &data.db;
+    let uid = *req.extensions().get::<Uuid>().unwrap();
+    match list_transactions_filtered(pool, uid, &filter).await {
+        Ok(v) => success(StatusCode::OK, "Transactions", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/transactions/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "transaction_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "The transaction", body = Envelope<TransactionDetails>),
+        (status = 404, description = "No such transaction, or the user is neither sender nor receiver and lacks read_accounts", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_user_transaction(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    let pool = &data.db;
+    match get_transaction(pool, path.into_inner()).await {
+        //someone else's transaction looks the same as a missing one
+        Ok(v)
+            if caller.may_access(v.sender, Permission::ReadAccounts)
+                || caller.may_access(v.receiver, Permission::ReadAccounts) =>
+        {
+            success(StatusCode::OK, "Transaction", v)
+        }
+        Ok(_) => failure(StatusCode::NOT_FOUND, "not_found", "Requested item not found"),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/transactions",
+    tag = "v1",
+    request_body = TransactionDataReq,
+    params(("X-OTP" = Option<String>, Header, description = "One-time password, required for transfers above auth.step_up_amount unless the token is elevated")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Transaction booked", body = Envelope<TransactionDetails>),
+        (status = 202, description = "Transaction held for review by transaction monitoring, it stays pending until an analyst decides", body = Envelope<TransactionDetails>),
+        (status = 400, description = "Invalid amount, receiver or transaction type", body = ErrorEnvelope),
+        (status = 401, description = "A transfer above the step-up amount without an elevated token needs the X-OTP header (code step_up_required), whose one-time password was invalid (code invalid_otp)", body = ErrorEnvelope),
+        (status = 403, description = "The role may not transact (code forbidden) or the account is frozen (code account_frozen) or transaction monitoring blocked it (code transaction_blocked) or the KYC level does not allow the type (code kyc_required) or a transfer above the step-up amount needs two-factor authentication (code two_factor_required)", body = ErrorEnvelope),
+        (status = 422, description = "Insufficient balance for amount and fee (code insufficient_balance), the receiver cannot accept funds (code receiver_unavailable), a beneficiary limit is reached (code beneficiary_limit_exceeded), a deposit does not cover its fee (code fee_exceeds_amount) or a limit of the account is reached (code transaction_limit_exceeded, daily_limit_exceeded or monthly_limit_exceeded) or of its KYC level (code kyc_limit_exceeded)", body = ErrorEnvelope),
+        (status = 429, description = "Too many transactions of the type in the last hour (code velocity_limit_exceeded), or too many wrong one-time passwords (code otp_locked)", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_transaction(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    content: web::Json<TransactionDataReq>,
+    auth: Authorized<require::Transact>,
+) -> impl Responder {
+    book(&data, &auth.caller, otp_header(&req), content.into_inner()).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/transfers",
+    tag = "v1",
+    request_body = TransferReq,
+    params(("X-OTP" = Option<String>, Header, description = "One-time password, required for transfers above auth.step_up_amount unless the token is elevated")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Transfer booked", body = Envelope<TransactionDetails>),
+        (status = 202, description = "Transfer held for review by transaction monitoring, it stays pending until an analyst decides", body = Envelope<TransactionDetails>),
+        (status = 400, description = "Invalid amount, receiver or payee", body = ErrorEnvelope),
+        (status = 401, description = "A transfer above the step-up amount without an elevated token needs the X-OTP header (code step_up_required), whose one-time password was invalid (code invalid_otp)", body = ErrorEnvelope),
+        (status = 403, description = "The role may not transact (code forbidden) or the account is frozen (code account_frozen) or transaction monitoring blocked it (code transaction_blocked) or the KYC level does not allow the type (code kyc_required) or a transfer above the step-up amount needs two-factor authentication (code two_factor_required)", body = ErrorEnvelope),
+        (status = 404, description = "Unknown receiver or beneficiary (code not_found) or payee (code payee_not_found)", body = ErrorEnvelope),
+        (status = 422, description = "Insufficient balance for amount and fee (code insufficient_balance), the receiver cannot accept funds (code receiver_unavailable) or a beneficiary limit is reached (code beneficiary_limit_exceeded) or a limit of the account is reached (code transaction_limit_exceeded, daily_limit_exceeded or monthly_limit_exceeded) or of its KYC level (code kyc_limit_exceeded)", body = ErrorEnvelope),
+        (status = 429, description = "Too many transfers in the last hour (code velocity_limit_exceeded), or too many wrong one-time passwords (code otp_locked)", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_transfer(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    content: web::Json<TransferReq>,
+    auth: Authorized<require::Transact>,
+) -> impl Responder {
+    if content.receiver.is_none() && content.payee.is_none() && content.beneficiary_id.is_none() {
+        return failure(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "A receiver, payee or beneficiary_id is required",
+        );
+    }
+    book(&data, &auth.caller, otp_header(&req), content.into_inner().into()).await
+}
+
+async fn book(data: &AppState, caller: &Caller, otp: Option<&str>, mut content: TransactionDataReq) -> HttpResponse {
+    let pool = &data.db;
+    let sender = caller.user_id;
+    if let Err(resp) = step_up(data, caller, &content.transaction_type, content.amount, otp, db_failure).await {
+        return resp;
+    }
+    let (receiver, mut options) = match resolve_target(
+        pool,
+        sender,
+        content.receiver,
+        content.payee.as_deref(),
+        content.beneficiary_id,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    content.apply_details(&mut options);
+    let transaction_id = match add_transaction_with(
+        pool,
+        &data.compliance,
+        sender,
+        receiver,
+        content.amount,
+        content.transaction_type,
+        options,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match get_transaction(pool, transaction_id).await {
+        //held by transaction monitoring, an analyst completes or rejects it
+        Ok(v) if v.status == "pending" => success(StatusCode::ACCEPTED, "Transaction held for review", v),
+        Ok(v) => success(StatusCode::CREATED, "Transaction added successfully", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::{
+        models::balance::get_balance,
+        utilities::{rbac::Role, test_harness::TestDb},
+    };
+
+    #[actix_web::test]
+    async fn test_transactions_and_transfers() {
+        let db = TestDb::new().await;
+        let sender = db.user().balance(dec!(50)).create().await;
+        let receiver = db.user().create().await;
+        let stranger = db.user().create().await;
+        let app = db.app().await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(sender.bearer())
+            .set_json(json!({"amount": dec!(5), "transaction_type": "deposit"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["status"], "completed");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sender.bearer())
+            .set_json(json!({"receiver": receiver.user_id, "amount": dec!(15)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["transaction_type"], "transfer");
+        let transfer_id = resp_body["data"]["transaction_id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sender.bearer())
+            .set_json(json!({"receiver": receiver.user_id, "amount": dec!(1000)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "insufficient_balance");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sender.bearer())
+            .set_json(json!({"amount": dec!(1)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_request");
+
+        //the receiver sees the transfer, a stranger gets the same answer as for a missing one
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/transactions/{}", transfer_id))
+            .insert_header(receiver.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/transactions/{}", transfer_id))
+            .insert_header(stranger.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/transactions")
+            .insert_header(sender.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        //opening balance, deposit, transfer and the rejected transfer is never booked
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 3);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/balance")
+            .insert_header(sender.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["balance"], "40.00");
+    }
+
+    #[actix_web::test]
+    async fn test_transfer_by_payee() {
+        let db = TestDb::new().await;
+        let sender = db.user().balance(dec!(50)).create().await;
+        let alice = db.user().username("alice").email("alice@example.com").create().await;
+        let app = db.app().await;
+
+        let req = test::TestRequest::patch()
+            .uri(&format!("/v1/users/{}", alice.user_id))
+            .insert_header(alice.bearer())
+            .set_json(json!({"handle": "@Alice", "phone": "+49 151 12345678"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["handle"], "alice");
+        assert_eq!(resp_body["data"]["phone"], "+4915112345678");
+
+        //the handle is taken
+        let req = test::TestRequest::patch()
+            .uri(&format!("/v1/users/{}", sender.user_id))
+            .insert_header(sender.bearer())
+            .set_json(json!({"handle": "alice"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/payees/resolve")
+            .insert_header(sender.bearer())
+            .set_json(json!({"payee": "@alice"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["name"], "a***e");
+        assert_eq!(resp_body["data"]["matched_by"], "handle");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sender.bearer())
+            .set_json(json!({"payee": "alice@example.com", "amount": dec!(10)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["receiver"], alice.user_id.to_string());
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(sender.bearer())
+            .set_json(json!({"payee": "@alice", "receiver": alice.user_id, "amount": dec!(1)}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        //legacy route, unknown payees are 404 without further detail
+        let req = test::TestRequest::post()
+            .uri("/transaction/resolve_payee")
+            .insert_header(sender.bearer())
+            .set_json(json!({"payee": "@nobody"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri("/transaction/operations")
+            .insert_header(sender.bearer())
+            .set_json(json!({"payee": "+4915112345678", "amount": dec!(5), "transaction_type": "transfer"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_memo_reference_and_metadata() {
+        let db = TestDb::new().await;
+        let merchant = db.user().create().await;
+        let customer = db.user().balance(dec!(50)).create().await;
+        let app = db.app().await;
+
+        for (order, memo) in [("1042", "Coffee beans"), ("1043", "Tea_100%")] {
+            let req = test::TestRequest::post()
+                .uri("/v1/transfers")
+                .insert_header(customer.bearer())
+                .set_json(json!({
+                    "receiver": merchant.user_id,
+                    "amount": dec!(5),
+                    "memo": memo,
+                    "reference": format!("order-{}", order),
+                    "metadata": {"order_id": order, "items": [1, 2]}
+                }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["data"]["metadata"]["order_id"], order);
+        }
+
+        let list = |uri: String| {
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(merchant.bearer())
+                .to_request();
+            test::call_service(&app, req)
+        };
+        let resp_body: Value = test::read_body_json(list(String::from("/v1/transactions?reference=order-1043")).await).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(resp_body["data"][0]["memo"], "Tea_100%");
+        //% is matched literally
+        let resp_body: Value = test::read_body_json(list(String::from("/v1/transactions?memo=0%25")).await).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+        let resp_body: Value = test::read_body_json(list(String::from("/v1/transactions?memo=COFFEE")).await).await;
+        assert_eq!(resp_body["data"][0]["reference"], "order-1042");
+        let resp = list(format!("/transaction/list_trans?metadata={}", "%7B%22order_id%22%3A%221042%22%7D")).await;
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body.as_array().unwrap().len(), 1);
+        let resp = list(String::from("/v1/transactions?metadata=%5B1%5D")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        //metadata must be a small JSON object
+        for metadata in [json!([1, 2]), json!({"blob": "x".repeat(5000)})] {
+            let req = test::TestRequest::post()
+                .uri("/v1/transfers")
+                .insert_header(customer.bearer())
+                .set_json(json!({"receiver": merchant.user_id, "amount": dec!(1), "metadata": metadata}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["code"], "invalid_request");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fees() {
+        let db = TestDb::new().await;
+        let admin = db.user().role(Role::Admin).create().await;
+        let revenue = db.user().create().await;
+        let customer = db.user().balance(dec!(100)).create().await;
+        let merchant = db.user().create().await;
+        let app = db.app().await;
+
+        for (rule, status) in [
+            (json!({"transaction_type": "transfer", "flat": "0.25", "percent": "1.5", "max_fee": "10.00", "revenue_account": revenue.user_id}), StatusCode::CREATED),
+            (json!({"transaction_type": "transfer", "tier": "premium", "revenue_account": revenue.user_id}), StatusCode::CREATED),
+            (json!({"transaction_type": "deposit", "flat": "2.00", "revenue_account": revenue.user_id}), StatusCode::CREATED),
+            (json!({"transaction_type": "transfer", "flat": "1.00", "revenue_account": revenue.user_id}), StatusCode::CONFLICT),
+            (json!({"transaction_type": "transfer", "min_fee": "5", "max_fee": "1", "revenue_account": revenue.user_id}), StatusCode::BAD_REQUEST),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/admin/fee_rules")
+                .insert_header(admin.bearer())
+                .set_json(&rule)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", rule);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions/quote")
+            .insert_header(customer.bearer())
+            .set_json(json!({"amount": "50.00", "transaction_type": "transfer"}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["fee"], "1.00");
+        assert_eq!(resp_body["data"]["total"], "51.00");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(customer.bearer())
+            .set_json(json!({"receiver": merchant.user_id, "amount": "50.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["fee"], "1.00");
+        let parent = resp_body["data"]["transaction_id"].clone();
+
+        //the fee leg is listed with the payment it belongs to
+        let req = test::TestRequest::get()
+            .uri("/v1/transactions")
+            .insert_header(revenue.bearer())
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(resp_body["data"][0]["transaction_type"], "fee");
+        assert_eq!(resp_body["data"][0]["amount"], "1.00");
+        assert_eq!(resp_body["data"][0]["parent_transaction_id"], parent);
+        for (user, expected) in [(&customer, dec!(49)), (&merchant, dec!(50)), (&revenue, dec!(1))] {
+            assert_eq!(get_balance(&db.pool, user.user_id).await.unwrap().balance, expected);
+        }
+
+        //the balance must cover the fee too, and a deposit must cover its own
+        for (body, code) in [
+            (json!({"amount": "49.00", "transaction_type": "transfer", "receiver": merchant.user_id}), "insufficient_balance"),
+            (json!({"amount": "2.00", "transaction_type": "deposit"}), "fee_exceeds_amount"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/v1/transactions")
+                .insert_header(customer.bearer())
+                .set_json(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["code"], code);
+        }
+
+        //premium accounts have their own rule, without a fee
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/accounts/{}/tier", customer.user_id))
+            .insert_header(admin.bearer())
+            .set_json(json!({"tier": "Premium"}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["tier"], "premium");
+        let req = test::TestRequest::post()
+            .uri("/v1/transfers")
+            .insert_header(customer.bearer())
+            .set_json(json!({"receiver": merchant.user_id, "amount": "49.00"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(get_balance(&db.pool, revenue.user_id).await.unwrap().balance, dec!(1));
+
+        let req = test::TestRequest::get()
+            .uri("/admin/fee_rules")
+            .insert_header(customer.bearer())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,527 @@
+use std::future::Future;
+
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        openapi::{Envelope, ErrorEnvelope, StatusResponse},
+        users::count_login_failure,
+        v1::users::Session,
+    },
+    models::{
+        login_attempts::{clear_otp_failures, login_locked_for, otp_locked_for, record_otp_failure},
+        two_factor::{
+            check_step_up, confirm_enrollment, disable_two_factor, get_two_factor, needs_step_up,
+            regenerate_recovery_codes, start_enrollment, verify_otp, Enrollment, TwoFactorStatus,
+        },
+        users::get_user_by_id,
+    },
+    utilities::{
+        auth::{encode_elevated_jwt, verify_password},
+        envelope::{db_failure, failure, success},
+        errors::TransactionError,
+        mailer::Email,
+        rate_limit::{login_locked, otp_locked},
+        rbac::Caller,
+    },
+    AppState,
+};
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct OtpReq {
+    //the current code of the authenticator app
+    #[schema(example = "287082")]
+    pub otp: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordReq {
+    //the current password, a stolen JWT alone cannot add a second factor
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    //each works once instead of a one-time password at login or to disable two-factor authentication
+    //they are shown this one time only
+    #[schema(example = json!(["3f9a1-c07b2", "8d2e4-51af0"]))]
+    pub recovery_codes: Vec<String>,
+}
+
+//a one-time password of a logged in user, wrong codes count towards a lockout per user like the logins
+//so a stolen JWT cannot guess codes at the request rate
+pub async fn guard_otp<T>(
+    data: &AppState,
+    uid: Uuid,
+    attempt: impl Future<Output = Result<T, sqlx::Error>>,
+    refuse: fn(sqlx::Error) -> HttpResponse,
+) -> Result<T, HttpResponse> {
+    match otp_locked_for(&data.db, uid).await {
+        Ok(Some(secs)) => return Err(otp_locked(secs)),
+        Ok(None) => {}
+        Err(e) => return Err(refuse(e)),
+    }
+    let result = attempt.await;
+    let counted = match &result {
+        Ok(_) => clear_otp_failures(&data.db, uid).await,
+        Err(e) if TransactionError::from_sqlx(e) == Some(TransactionError::InvalidOtp) => {
+            record_otp_failure(&data.db, uid, &data.settings.rate_limit).await.map(|_| ())
+        }
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = counted {
+        error!(error = %e, "Error at counting a one-time password");
+    }
+    result.map_err(refuse)
+}
+
+//the step-up of a transfer, only an X-OTP that is checked counts towards the lockout
+pub async fn step_up(
+    data: &AppState,
+    caller: &Caller,
+    transaction_type: &str,
+    amount: Decimal,
+    otp: Option<&str>,
+    refuse: fn(sqlx::Error) -> HttpResponse,
+) -> Result<(), HttpResponse> {
+    let step_up_amount = data.settings.auth.step_up_amount;
+    let check = check_step_up(&data.db, caller, transaction_type, amount, otp, step_up_amount);
+    if otp.is_some() && needs_step_up(caller, transaction_type, amount, step_up_amount) {
+        guard_otp(data, caller.user_id, check, refuse).await
+    } else {
+        check.await.map_err(refuse)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/users/2fa",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Whether two-factor authentication is enabled and how many recovery codes are left", body = Envelope<TwoFactorStatus>),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_two_factor_status(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    match get_two_factor(&data.db, caller.user_id).await {
+        Ok(v) => success(StatusCode::OK, "Two-factor authentication status", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/2fa",
+    tag = "v1",
+    request_body = PasswordReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Enrollment started, scan the provisioning uri as QR code and confirm with the first code; a pending enrollment is replaced", body = Envelope<Enrollment>),
+        (status = 401, description = "Missing or invalid JWT, or a wrong password (code invalid_credentials)", body = ErrorEnvelope),
+        (status = 409, description = "Two-factor authentication is already enabled, code two_factor_enabled", body = ErrorEnvelope),
+        (status = 429, description = "Too many failed logins of the address, code login_locked, retry after Retry-After seconds", body = ErrorEnvelope)
+    )
+)]
+pub async fn enroll_two_factor(
+    data: web::Data<AppState>,
+    content: web::Json<PasswordReq>,
+    caller: Caller,
+) -> impl Responder {
+    let user = match get_user_by_id(&data.db, caller.user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    //wrong passwords count towards the lockout of the logins
+    match login_locked_for(&data.db, &user.email).await {
+        Ok(Some(secs)) => return login_locked(secs),
+        Ok(None) => {}
+        Err(e) => return db_failure(e),
+    }
+    if !verify_password(&user.password, &content.password) {
+        count_login_failure(&data, &user.email).await;
+        return failure(StatusCode::UNAUTHORIZED, "invalid_credentials", "Invalid Credentials");
+    }
+    match start_enrollment(&data.db, user.user_id, &user.email).await {
+        Ok(v) => success(StatusCode::CREATED, "Two-factor enrollment started", v),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/2fa/confirm",
+    tag = "v1",
+    request_body = OtpReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Two-factor authentication enabled, the recovery codes are shown this one time; the user is notified and e-mailed", body = Envelope<RecoveryCodes>),
+        (status = 401, description = "Missing or invalid JWT, or no pending enrollment or a wrong code (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong codes, code otp_locked, retry after Retry-After seconds", body = ErrorEnvelope)
+    )
+)]
+pub async fn confirm_two_factor(
+    data: web::Data<AppState>,
+    content: web::Json<OtpReq>,
+    caller: Caller,
+) -> impl Responder {
+    match guard_otp(&data, caller.user_id, confirm_enrollment(&data.db, caller.user_id, &content.otp), db_failure).await {
+        Ok(recovery_codes) => {
+            send_enabled_mail(&data, caller.user_id).await;
+            success(
+                StatusCode::OK,
+                "Two-factor authentication enabled",
+                RecoveryCodes { recovery_codes },
+            )
+        }
+        Err(resp) => resp,
+    }
+}
+
+//tells the owner of the address in case it was not them, 2FA stays enabled when the e-mail fails
+async fn send_enabled_mail(data: &AppState, uid: Uuid) {
+    let user = match get_user_by_id(&data.db, uid).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!(error = %e, "Error at loading the user for the two-factor e-mail");
+            return;
+        }
+    };
+    let mail = Email {
+        to: user.email,
+        subject: String::from("Two-factor authentication enabled"),
+        body: format!(
+            "Hello {},\n\ntwo-factor authentication was enabled for your account. If this was not you, reset your password and contact support.\n",
+            user.username
+        ),
+    };
+    if let Err(e) = data.mailer.send(&mail).await {
+        error!(error = %e, "Error at sending mail");
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/2fa/disable",
+    tag = "v1",
+    request_body = OtpReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Two-factor authentication disabled, the secret and recovery codes are deleted", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT, or neither a valid code nor an unused recovery code (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong codes, code otp_locked, retry after Retry-After seconds", body = ErrorEnvelope)
+    )
+)]
+pub async fn disable_two_factor_auth(
+    data: web::Data<AppState>,
+    content: web::Json<OtpReq>,
+    caller: Caller,
+) -> impl Responder {
+    match guard_otp(&data, caller.user_id, disable_two_factor(&data.db, caller.user_id, &content.otp), db_failure).await {
+        Ok(_) => success(StatusCode::OK, "Two-factor authentication disabled", ()),
+        Err(resp) => resp,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/2fa/recovery_codes",
+    tag = "v1",
+    request_body = OtpReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "New recovery codes, the previous ones no longer work", body = Envelope<RecoveryCodes>),
+        (status = 401, description = "Missing or invalid JWT, or two-factor authentication is not enabled or the code is wrong (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong codes, code otp_locked, retry after Retry-After seconds", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_recovery_codes(
+    data: web::Data<AppState>,
+    content: web::Json<OtpReq>,
+    caller: Caller,
+) -> impl Responder {
+    match guard_otp(&data, caller.user_id, regenerate_recovery_codes(&data.db, caller.user_id, &content.otp), db_failure).await {
+        Ok(recovery_codes) => success(StatusCode::OK, "Recovery codes regenerated", RecoveryCodes { recovery_codes }),
+        Err(resp) => resp,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/sessions/elevate",
+    tag = "v1",
+    request_body = OtpReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 201, description = "Elevated JWT issued, it lives auth.step_up_ttl_minutes and sends transfers above the step-up amount without a one-time password", body = Envelope<Session>),
+        (status = 401, description = "Missing or invalid JWT, or a wrong or already used code (code invalid_otp)", body = ErrorEnvelope),
+        (status = 403, description = "Two-factor authentication is not enabled, code two_factor_required", body = ErrorEnvelope),
+        (status = 429, description = "Too many wrong codes, code otp_locked, retry after Retry-After seconds", body = ErrorEnvelope)
+    )
+)]
+pub async fn elevate_session(
+    data: web::Data<AppState>,
+    content: web::Json<OtpReq>,
+    caller: Caller,
+) -> impl Responder {
+    if let Err(resp) = guard_otp(&data, caller.user_id, verify_otp(&data.db, caller.user_id, &content.otp), db_failure).await {
+        return resp;
+    }
+    match encode_elevated_jwt(caller.user_id, caller.role, &data.settings.auth) {
+        Ok(token) => success(
+            StatusCode::CREATED,
+            "Session elevated",
+            Session {
+                token,
+                token_type: String::from("Bearer"),
+                expires_in: data.settings.auth.step_up_ttl_minutes * 60,
+                user_id: caller.user_id,
+            },
+        ),
+        Err(_) => failure(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal server error",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use crate::utilities::{
+        test_harness::{test_settings, TestDb},
+        totp::code_at,
+    };
+
+    #[actix_web::test]
+    async fn test_two_factor_and_step_up() {
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(2000)).create().await;
+        let friend = db.user().create().await;
+        let mut settings = test_settings();
+        settings.auth.step_up_amount = dec!(100);
+        let app = db.app_with(settings).await;
+        let now = Utc::now().timestamp() as u64;
+
+        //a JWT alone does not enroll, the password is asked again
+        let enroll = |password: &str| {
+            test::TestRequest::post()
+                .uri("/v1/users/2fa")
+                .insert_header(user.bearer())
+                .set_json(json!({"password": password}))
+                .to_request()
+        };
+        let resp = test::call_service(&app, enroll("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_credentials");
+        let resp = test::call_service(&app, enroll(&user.password)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let secret = resp_body["data"]["secret"].as_str().unwrap().to_string();
+        assert!(resp_body["data"]["provisioning_uri"].as_str().unwrap().starts_with("otpauth://totp/payments_dodo:"));
+
+        //small transfers never step up, large ones need 2FA enabled first
+        let transfer = |amount| json!({"amount": amount, "transaction_type": "transfer", "receiver": friend.user_id});
+        let req = test::TestRequest::post().uri("/v1/transactions").insert_header(user.bearer()).set_json(transfer(dec!(100))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::post().uri("/v1/transactions").insert_header(user.bearer()).set_json(transfer(dec!(500))).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "two_factor_required");
+        //payout batches step up on their total, scheduled payments on every payment
+        let payout = json!({"items": [{"receiver": friend.user_id, "amount": dec!(60)}, {"receiver": friend.user_id, "amount": dec!(60)}]});
+        let schedule = json!({"receiver": friend.user_id, "amount": dec!(500), "start_at": "2099-07-01T09:00:00"});
+        let req = test::TestRequest::post().uri("/v1/payouts").insert_header(user.bearer()).set_json(&payout).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "two_factor_required");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users/2fa/confirm")
+            .insert_header(user.bearer())
+            .set_json(json!({"otp": "abcdef"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_otp");
+        //a wrong code at enrollment counts towards the lockout too
+        let failures: i32 = sqlx::query_scalar("SELECT failures FROM login_attempts where email = $1")
+            .bind(format!("otp:{}", user.user_id))
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(failures, 1);
+
+        let code = code_at(&secret, now).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/users/2fa/confirm")
+            .insert_header(user.bearer())
+            .set_json(json!({"otp": code}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let recovery_codes = resp_body["data"]["recovery_codes"].as_array().unwrap().clone();
+        assert_eq!(recovery_codes.len(), 10);
+        //the user hears of it in the app and by e-mail
+        let kinds: Vec<String> = sqlx::query_scalar("SELECT kind FROM notifications where user_id = $1")
+            .bind(user.user_id)
+            .fetch_all(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(kinds, vec!["two_factor_enabled"]);
+        let sent = db.outbox.sent();
+        assert!(sent.iter().any(|m| m.to == user.email && m.subject == "Two-factor authentication enabled"));
+
+        assert_eq!(test::call_service(&app, enroll(&user.password)).await.status(), StatusCode::CONFLICT);
+
+        //login needs the second factor now, a used code does not work twice
+        let login = |otp: Option<&str>| json!({"email": user.email, "password": user.password, "otp": otp});
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(None)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "otp_required");
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(Some(&code))).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_otp");
+        let recovery = recovery_codes[0].as_str().unwrap().to_uppercase();
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(Some(&recovery))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(Some(&recovery))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get().uri("/user/get_token").set_json(login(None)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        //step-up with the code of the next step, the current one is used up
+        let req = test::TestRequest::post().uri("/v1/transactions").insert_header(user.bearer()).set_json(transfer(dec!(500))).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "step_up_required");
+        for (uri, body) in [("/v1/payouts", &payout), ("/v1/scheduled_payments", &schedule)] {
+            let req = test::TestRequest::post().uri(uri).insert_header(user.bearer()).set_json(body).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            let resp_body: Value = test::read_body_json(resp).await;
+            assert_eq!(resp_body["code"], "step_up_required");
+        }
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .insert_header(("X-OTP", code_at(&secret, now + 30).unwrap()))
+            .set_json(transfer(dec!(500)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        //no further step is accepted within the window, the test starts the steps over
+        sqlx::query("UPDATE two_factor SET last_step = 0").execute(&db.pool).await.unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions/elevate")
+            .insert_header(user.bearer())
+            .set_json(json!({"otp": code}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["expires_in"], 300);
+        let elevated = ("Authorization", format!("Bearer {}", resp_body["data"]["token"].as_str().unwrap()));
+        let req = test::TestRequest::post().uri("/v1/transfers").insert_header(elevated.clone()).set_json(transfer(dec!(500))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::post().uri("/transaction/operations").insert_header(elevated.clone()).set_json(transfer(dec!(500))).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post().uri("/v1/payouts").insert_header(elevated.clone()).set_json(&payout).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+        let req = test::TestRequest::post().uri("/v1/scheduled_payments").insert_header(elevated).set_json(&schedule).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        //wrong codes lock the one-time passwords of the user, the right code and X-OTP included
+        let elevate = |otp: &str| {
+            test::TestRequest::post()
+                .uri("/v1/sessions/elevate")
+                .insert_header(user.bearer())
+                .set_json(json!({"otp": otp}))
+                .to_request()
+        };
+        for _ in 0..5 {
+            assert_eq!(test::call_service(&app, elevate("abcdef")).await.status(), StatusCode::UNAUTHORIZED);
+        }
+        let resp = test::call_service(&app, elevate(&code_at(&secret, now + 60).unwrap())).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("retry-after"));
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "otp_locked");
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .insert_header(("X-OTP", code_at(&secret, now + 60).unwrap()))
+            .set_json(transfer(dec!(500)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        let req = test::TestRequest::post()
+            .uri("/transaction/operations")
+            .insert_header(user.bearer())
+            .insert_header(("X-OTP", "abcdef"))
+            .set_json(transfer(dec!(500)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        let req = test::TestRequest::post()
+            .uri("/v1/users/2fa/recovery_codes")
+            .insert_header(user.bearer())
+            .set_json(json!({"otp": recovery_codes[1]}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        //transfers below the step-up amount check no code and are not locked
+        let req = test::TestRequest::post()
+            .uri("/v1/transactions")
+            .insert_header(user.bearer())
+            .insert_header(("X-OTP", "abcdef"))
+            .set_json(transfer(dec!(50)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        //the e-mail login is counted apart
+        let locked: i64 = sqlx::query_scalar("SELECT count(*) FROM login_attempts where email = $1 and locked_until is not null")
+            .bind(user.email.to_lowercase())
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(locked, 0);
+        sqlx::query("UPDATE login_attempts SET locked_until = locked_until - INTERVAL '1 hour' where email = $1")
+            .bind(format!("otp:{}", user.user_id))
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let req = test::TestRequest::get().uri("/v1/users/2fa").insert_header(user.bearer()).to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["enabled"], true);
+        assert_eq!(resp_body["data"]["recovery_codes_left"], 9);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users/2fa/disable")
+            .insert_header(user.bearer())
+            .set_json(json!({"otp": recovery_codes[1]}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/v1/users/2fa").insert_header(user.bearer()).to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_body["data"]["enabled"], false);
+        assert_eq!(resp_body["data"]["recovery_codes_left"], 0);
+    }
+}
@@ -0,0 +1,773 @@
+use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use chrono::{Duration, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        openapi::{Envelope, ErrorEnvelope, StatusResponse},
+        users::{count_login_failure, upgrade_password, GetTokenReq, UserRegisterReq},
+    },
+    config::settings::MailSettings,
+    models::{
+        kyc::KycLevel,
+        login_attempts::{clear_login_failures, login_locked_for},
+        payees::{normalize_handle, normalize_phone},
+        tokens::{issue_token, reset_password, verify_email, TokenPurpose},
+        two_factor::check_login,
+        users::{
+            get_user, get_user_by_id, register_user, update_payee_identifiers, update_user,
+            UserInfo,
+        },
+    },
+    utilities::{
+        auth::{encode_jwt, verify_password},
+        envelope::{db_failure, failure, success},
+        errors::TransactionError,
+        mailer::Email,
+        rate_limit::login_locked,
+        rbac::{Caller, Permission},
+        telemetry::mask_email,
+    },
+    AppState,
+};
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UserProfile {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    //E.164, e.g. +4915112345678
+    pub phone: Option<String>,
+    //without the @
+    pub handle: Option<String>,
+    //unverified, basic or full, see /v1/kyc
+    pub kyc_level: KycLevel,
+    //none until the address is verified, see /v1/users/verification
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<UserInfo> for UserProfile {
+    fn from(v: UserInfo) -> Self {
+        UserProfile {
+            user_id: v.user_id,
+            username: v.username,
+            email: v.email,
+            phone: v.phone,
+            handle: v.handle,
+            kyc_level: v.kyc_level,
+            email_verified_at: v.email_verified_at,
+            created_at: v.created_at,
+            updated_at: v.updated_at,
+        }
+    }
+}
+
+//only the given fields change, an empty phone or handle removes it
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ProfileUpdateReq {
+    pub username: Option<String>,
+    #[schema(example = "+4915112345678")]
+    pub phone: Option<String>,
+    #[schema(example = "@alice")]
+    pub handle: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CreatedUser {
+    pub user_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Session {
+    pub token: String,
+    #[schema(example = "Bearer")]
+    pub token_type: String,
+    //lifetime of the token in seconds
+    pub expires_in: i64,
+    pub user_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TokenReq {
+    //the token from the e-mail
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordResetReq {
+    #[schema(example = "alice@example.com")]
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordResetConfirmReq {
+    //the token from the e-mail
+    pub token: String,
+    pub password: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users",
+    tag = "v1",
+    request_body = UserRegisterReq,
+    responses(
+        (status = 201, description = "User registered, a verification e-mail is sent to the address", body = Envelope<CreatedUser>),
+        (status = 403, description = "The username matches the sanctions list, code screening_blocked", body = ErrorEnvelope),
+        (status = 409, description = "E-mail already registered, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_user(
+    data: web::Data<AppState>,
+    content: web::Json<UserRegisterReq>,
+) -> impl Responder {
+    let pool = &data.db;
+    let content = content.into_inner();
+    let (username, email) = (content.username.clone(), content.email.clone());
+    match register_user(pool, &data.compliance.sanctions, content.username, content.email, content.password).await {
+        Ok(user_id) => {
+            //the user can ask for another e-mail, a failed one does not undo the registration
+            let _ = send_verification(&data, user_id, &username, &email).await;
+            success(StatusCode::CREATED, "User registered", CreatedUser { user_id })
+        }
+        Err(e) => db_failure(e),
+    }
+}
+
+//the token goes out as a link when mail.link_base_url is set, as is otherwise
+fn token_text(settings: &MailSettings, page: &str, token: &str) -> String {
+    match settings.link_base_url.trim_end_matches('/') {
+        "" => token.to_string(),
+        base => format!("{}/{}?token={}", base, page, token),
+    }
+}
+
+fn mail_failure(e: String) -> HttpResponse {
+    error!(error = %e, "Error at sending mail");
+    failure(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "mail_unavailable",
+        "The e-mail could not be sent, try again later",
+    )
+}
+
+async fn send_verification(data: &AppState, user_id: Uuid, username: &str, email: &str) -> Result<(), HttpResponse> {
+    let hours = data.settings.auth.email_verification_ttl_hours;
+    let token = issue_token(&data.db, user_id, TokenPurpose::EmailVerification, Duration::hours(hours))
+        .await
+        .map_err(db_failure)?;
+    let mail = Email {
+        to: email.to_string(),
+        subject: String::from("Verify your e-mail address"),
+        body: format!(
+            "Hello {},\n\nconfirm your e-mail address with this token:\n\n{}\n\nIt expires in {} hours. If you did not register, ignore this e-mail.\n",
+            username,
+            token_text(&data.settings.mail, "verify-email", &token),
+            hours
+        ),
+    };
+    data.mailer.send(&mail).await.map_err(mail_failure)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/verification",
+    tag = "v1",
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 202, description = "A new verification e-mail was sent, earlier tokens no longer work", body = StatusResponse),
+        (status = 401, description = "Missing or invalid JWT", body = ErrorEnvelope),
+        (status = 409, description = "The address is already verified, code already_verified", body = ErrorEnvelope),
+        (status = 503, description = "The e-mail could not be sent, code mail_unavailable", body = ErrorEnvelope)
+    )
+)]
+pub async fn resend_verification(data: web::Data<AppState>, caller: Caller) -> impl Responder {
+    let user = match get_user_by_id(&data.db, caller.user_id).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    if user.email_verified_at.is_some() {
+        return failure(
+            StatusCode::CONFLICT,
+            "already_verified",
+            "The e-mail address is already verified",
+        );
+    }
+    match send_verification(&data, user.user_id, &user.username, &user.email).await {
+        Ok(_) => success(StatusCode::ACCEPTED, "Verification e-mail sent", ()),
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/users/verification/confirm",
+    tag = "v1",
+    request_body = TokenReq,
+    responses(
+        (status = 200, description = "E-mail address verified", body = Envelope<UserProfile>),
+        (status = 400, description = "Unknown, expired or used token, code invalid_token", body = ErrorEnvelope)
+    )
+)]
+pub async fn confirm_verification(
+    data: web::Data<AppState>,
+    content: web::Json<TokenReq>,
+) -> impl Responder {
+    let user_id = match verify_email(&data.db, &content.token).await {
+        Ok(v) => v,
+        Err(e) => return db_failure(e),
+    };
+    match get_user_by_id(&data.db, user_id).await {
+        Ok(v) => success(StatusCode::OK, "E-mail verified", UserProfile::from(v)),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/password_resets",
+    tag = "v1",
+    request_body = PasswordResetReq,
+    responses(
+        (status = 202, description = "A reset e-mail was sent when the address belongs to a user, the answer is the same either way", body = StatusResponse)
+    )
+)]
+pub async fn request_password_reset(
+    data: web::Data<AppState>,
+    content: web::Json<PasswordResetReq>,
+) -> impl Responder {
+    //unknown addresses and failures answer the same, so nobody learns which addresses are registered
+    let accepted = || success(StatusCode::ACCEPTED, "If the address is registered, a reset e-mail is on its way", ());
+    let user = match get_user(&data.db, content.email.trim().to_string()).await {
+        Ok(v) => v,
+        Err(_) => {
+            info!(email = %mask_email(&content.email), "Password reset for an unknown address");
+            return accepted();
+        }
+    };
+    //the token and the e-mail are left to a task, so a registered address answers as fast as an unknown one
+    let data = data.into_inner();
+    tokio::spawn(async move {
+        let minutes = data.settings.auth.password_reset_ttl_minutes;
+        let token = match issue_token(&data.db, user.user_id, TokenPurpose::PasswordReset, Duration::minutes(minutes)).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(error = %e, "Error at issuing a password reset token");
+                return;
+            }
+        };
+        let mail = Email {
+            to: user.email.clone(),
+            subject: String::from("Reset your password"),
+            body: format!(
+                "Hello {},\n\nset a new password with this token:\n\n{}\n\nIt expires in {} minutes and works once. If you did not ask for a reset, ignore this e-mail, your password stays the same.\n",
+                user.username,
+                token_text(&data.settings.mail, "reset-password", &token),
+                minutes
+            ),
+        };
+        if let Err(e) = data.mailer.send(&mail).await {
+            let _ = mail_failure(e);
+        }
+    });
+    accepted()
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/password_resets/confirm",
+    tag = "v1",
+    request_body = PasswordResetConfirmReq,
+    responses(
+        (status = 200, description = "Password changed, log in with the new one", body = StatusResponse),
+        (status = 400, description = "Unknown, expired or used token (code invalid_token) or empty password (code invalid_request)", body = ErrorEnvelope)
+    )
+)]
+pub async fn confirm_password_reset(
+    data: web::Data<AppState>,
+    content: web::Json<PasswordResetConfirmReq>,
+) -> impl Responder {
+    match reset_password(&data.db, &content.token, &content.password).await {
+        Ok(_) => success(StatusCode::OK, "Password changed", ()),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/sessions",
+    tag = "v1",
+    request_body = GetTokenReq,
+    responses(
+        (status = 201, description = "Logged in, JWT issued", body = Envelope<Session>),
+        (status = 401, description = "Invalid credentials (code invalid_credentials), or two-factor authentication is enabled and the one-time password is missing (code otp_required) or invalid (code invalid_otp)", body = ErrorEnvelope),
+        (status = 429, description = "Too many failed logins of the address (code login_locked) or requests (code rate_limited), see Retry-After", body = ErrorEnvelope)
+    )
+)]
+pub async fn create_session(
+    data: web::Data<AppState>,
+    content: web::Json<GetTokenReq>,
+) -> impl Responder {
+    let pool = &data.db;
+    let invalid = || {
+        failure(
+            StatusCode::UNAUTHORIZED,
+            "invalid_credentials",
+            "Invalid Credentials",
+        )
+    };
+    match login_locked_for(pool, &content.email).await {
+        Ok(Some(secs)) => return login_locked(secs),
+        Ok(None) => {}
+        Err(e) => return db_failure(e),
+    }
+    let user = match get_user(pool, content.email.clone()).await {
+        Ok(v) if verify_password(&v.password, &content.password) => v,
+        _ => {
+            count_login_failure(&data, &content.email).await;
+            return invalid();
+        }
+    };
+    if let Err(e) = check_login(pool, user.user_id, content.otp.as_deref()).await {
+        if TransactionError::from_sqlx(&e) == Some(TransactionError::InvalidOtp) {
+            count_login_failure(&data, &content.email).await;
+        }
+        return db_failure(e);
+    }
+    if let Err(e) = clear_login_failures(pool, &content.email).await {
+        return db_failure(e);
+    }
+    upgrade_password(&data, &user, &content.password).await;
+    match encode_jwt(user.user_id, user.role, &data.settings.auth) {
+        Ok(token) => success(
+            StatusCode::CREATED,
+            "Successfully logged in",
+            Session {
+                token,
+                token_type: String::from("Bearer"),
+                expires_in: data.settings.auth.token_ttl_minutes * 60,
+                user_id: user.user_id,
+            },
+        ),
+        Err(_) => invalid(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/users/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "user_id")),
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "User profile", body = Envelope<UserProfile>),
+        (status = 403, description = "Another user's profile without the read_users permission, code forbidden", body = ErrorEnvelope),
+        (status = 404, description = "No such user, code not_found", body = ErrorEnvelope)
+    )
+)]
+pub async fn get_user_profile(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    caller: Caller,
+) -> impl Responder {
+    let pool = &data.db;
+    let user_id = path.into_inner();
+    if !caller.may_access(user_id, Permission::ReadUsers) {
+        return forbidden();
+    }
+    match get_user_by_id(pool, user_id).await {
+        Ok(v) => success(StatusCode::OK, "User details", UserProfile::from(v)),
+        Err(e) => db_failure(e),
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/v1/users/{id}",
+    tag = "v1",
+    params(("id" = Uuid, Path, description = "user_id")),
+    request_body = ProfileUpdateReq,
+    security(("bearer_auth" = [])),
+    responses(
+        (status = 200, description = "Updated profile", body = Envelope<UserProfile>),
+        (status = 400, description = "Invalid phone number or handle, code invalid_request", body = ErrorEnvelope),
+        (status = 403, description = "Another user's profile, code forbidden, or the username matches the sanctions list, code screening_blocked", body = ErrorEnvelope),
+        (status = 409, description = "Phone number or handle already taken, code conflict", body = ErrorEnvelope)
+    )
+)]
+pub async fn update_user_profile(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    content: web::Json<ProfileUpdateReq>,
+    caller: Caller,
+) -> impl Responder {
+    let pool = &data.db;
+    let user_id = path.into_inner();
+    //profiles are only ever edited by their owner
+    if user_id != caller.user_id {
+        return forbidden();
+    }
+    let content = content.into_inner();
+    let phone = match content.phone.as_deref().map(|v| v.trim()) {
+        Some("") => Some(String::new()),
+        Some(v) => match normalize_phone(v) {
+            Ok(v) => Some(v),
+            Err(e) => return db_failure(e),
+        },
+        None => None,
+    };
+    let handle = match content.handle.as_deref().map(|v| v.trim()) {
+        Some("") => Some(String::new()),
+        Some(v) => match normalize_handle(v) {
+            Ok(v) => Some(v),
+            Err(e) => return db_failure(e),
+        },
+        None => None,
+    };
+    if let Some(username) = content.username {
+        if let Err(e) = update_user(pool, &data.compliance.sanctions, user_id, username).await {
+            return db_failure(e);
+        }
+    }
+    if phone.is_some() || handle.is_some() {
+        if let Err(e) = update_payee_identifiers(pool, user_id, phone, handle).await {
+            return db_failure(e);
+        }
+    }
+    match get_user_by_id(pool, user_id).await {
+        Ok(v) => success(StatusCode::OK, "User Updated Successfully", UserProfile::from(v)),
+        Err(e) => db_failure(e),
+    }
+}
+
+fn forbidden() -> HttpResponse {
+    failure(
+        StatusCode::FORBIDDEN,
+        "forbidden",
+        "Not allowed to access this user",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use crate::utilities::test_harness::TestDb;
+
+    #[actix_web::test]
+    async fn test_register_login_and_profile() {
+        let db = TestDb::new().await;
+        let other = db.user().create().await;
+        let app = db.app().await;
+
+        let register = json!({
+            "username": "alice",
+            "email": "alice@test.com",
+            "password": "secret"
+        });
+        let req = test::TestRequest::post()
+            .uri("/v1/users")
+            .set_json(&register)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["status"], "Success");
+        let user_id = resp_body["data"]["user_id"].as_str().unwrap().to_string();
+
+        //only the hash is stored
+        let stored: String = sqlx::query_scalar("SELECT password FROM users where user_id = $1")
+            .bind(Uuid::parse_str(&user_id).unwrap())
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert_ne!(stored, "secret");
+
+        //the same e-mail again
+        let req = test::TestRequest::post()
+            .uri("/v1/users")
+            .set_json(&register)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "conflict");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": "alice@test.com", "password": "wrong"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_credentials");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": "alice@test.com", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["token_type"], "Bearer");
+        assert_eq!(resp_body["data"]["user_id"], user_id.as_str());
+        let bearer = (
+            "Authorization",
+            format!("Bearer {}", resp_body["data"]["token"].as_str().unwrap()),
+        );
+
+        //a password stored before hashing still logs in and is hashed by it
+        sqlx::query("UPDATE users SET password = $1 where user_id = $2")
+            .bind(&other.password)
+            .bind(other.user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": other.email, "password": other.password}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let stored: String = sqlx::query_scalar("SELECT password FROM users where user_id = $1")
+            .bind(other.user_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+
+        let req = test::TestRequest::patch()
+            .uri(&format!("/v1/users/{}", user_id))
+            .insert_header(bearer.clone())
+            .set_json(json!({"username": "alice2"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/users/{}", user_id))
+            .insert_header(bearer.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["data"]["username"], "alice2");
+        assert_eq!(resp_body["data"]["email"], "alice@test.com");
+        assert!(resp_body["data"].get("password").is_none());
+
+        //someone else's profile, and a path that is not a uuid
+        let req = test::TestRequest::get()
+            .uri(&format!("/v1/users/{}", other.user_id))
+            .insert_header(bearer.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/users/not-a-uuid")
+            .insert_header(bearer)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "not_found");
+    }
+
+    //the 64 hex characters of the token in an e-mail
+    fn token_in(body: &str) -> String {
+        body.split_whitespace()
+            .find(|w| w.len() == 64 && w.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_email_verification_and_password_reset() {
+        let db = TestDb::new().await;
+        let app = db.app().await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users")
+            .set_json(json!({"username": "bob", "email": "bob@test.com", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let sent = db.outbox.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "bob@test.com");
+        let first = token_in(&sent[0].body);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": "bob@test.com", "password": "secret"}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let bearer = ("Authorization", format!("Bearer {}", resp_body["data"]["token"].as_str().unwrap()));
+        let user_id: Uuid = resp_body["data"]["user_id"].as_str().unwrap().parse().unwrap();
+
+        //credentials the reset has to end: the session, an API key, an OAuth2 consent, a client of bob and a locked login
+        let req = test::TestRequest::post()
+            .uri("/v1/api_keys")
+            .insert_header(bearer.clone())
+            .set_json(json!({"name": "Billing", "scopes": ["balance:read"]}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let key = ("Authorization", format!("Bearer {}", resp_body["data"]["key"].as_str().unwrap()));
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(key.clone()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        sqlx::query("INSERT INTO oauth_clients(client_id,owner_id,name,redirect_uris,scopes,created_at) Values ('planner',$1,'Planner','{}','{balance:read}',now())")
+            .bind(user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO oauth_consents(user_id,client_id,scopes,created_at,granted_at,updated_at) Values ($1,'planner','{balance:read}',now(),now(),now())")
+            .bind(user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/clients")
+            .insert_header(bearer.clone())
+            .set_json(json!({"name": "Ledger", "scopes": ["balance:read"]}))
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let client_id = resp_body["data"]["client"]["client_id"].as_str().unwrap().to_string();
+        let secret = resp_body["data"]["client_secret"].as_str().unwrap().to_string();
+        let req = test::TestRequest::post()
+            .uri("/v1/oauth/token")
+            .set_form([
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id.as_str()),
+                ("client_secret", secret.as_str()),
+            ])
+            .to_request();
+        let resp_body: Value = test::call_and_read_body_json(&app, req).await;
+        let client_token = ("Authorization", format!("Bearer {}", resp_body["access_token"].as_str().unwrap()));
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(client_token.clone()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        //a new e-mail replaces the first token
+        let req = test::TestRequest::post()
+            .uri("/v1/users/verification")
+            .insert_header(bearer.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let second = token_in(&db.outbox.sent()[1].body);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users/verification/confirm")
+            .set_json(json!({"token": first}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "invalid_token");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users/verification/confirm")
+            .set_json(json!({"token": second}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert!(resp_body["data"]["email_verified_at"].is_string());
+
+        let req = test::TestRequest::post()
+            .uri("/v1/users/verification")
+            .insert_header(bearer.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        //unknown addresses get the same answer and no e-mail
+        let req = test::TestRequest::post()
+            .uri("/v1/password_resets")
+            .set_json(json!({"email": "nobody@test.com"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(db.outbox.sent().len(), 2);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/password_resets")
+            .set_json(json!({"email": "bob@test.com"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        //the e-mail is sent by a task after the answer
+        for _ in 0..100 {
+            if db.outbox.sent().len() > 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let reset = token_in(&db.outbox.sent()[2].body);
+
+        for _ in 0..5 {
+            let req = test::TestRequest::post()
+                .uri("/v1/sessions")
+                .set_json(json!({"email": "bob@test.com", "password": "guess"}))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        //iat has whole seconds, the session must be older than the second of the reset
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let req = test::TestRequest::post()
+            .uri("/v1/password_resets/confirm")
+            .set_json(json!({"token": reset, "password": "new-secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/password_resets/confirm")
+            .set_json(json!({"token": reset, "password": "another"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        //the new password is hashed, the session, the key, the consent and the client are revoked and the lock is lifted
+        let stored: String = sqlx::query_scalar("SELECT password FROM users where user_id = $1")
+            .bind(user_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        for credential in [bearer, key, client_token] {
+            let req = test::TestRequest::get().uri("/v1/balance").insert_header(credential).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        }
+        let revoked: bool = sqlx::query_scalar("SELECT revoked_at IS NOT NULL FROM oauth_consents where user_id = $1")
+            .bind(user_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert!(revoked);
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": "bob@test.com", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .set_json(json!({"email": "bob@test.com", "password": "new-secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp_body: Value = test::read_body_json(resp).await;
+        let bearer = ("Authorization", format!("Bearer {}", resp_body["data"]["token"].as_str().unwrap()));
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(bearer).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
@@ -1,73 +1,346 @@
-use std::sync::Mutex;
-
 use actix_web::{web, App, HttpServer};
 use api::{
+    admin,
     balance::fetch_balance,
-    transactions::{fetch_transaction, list_transactions, transaction},
+    health::{healthz, readyz},
+    metrics::metrics,
+    openapi::ApiDoc,
+    transactions::{fetch_transaction, list_transactions, resolve_payee, transaction},
     users::{get_token, get_user_details, user_register, user_update},
+    v1,
 };
-use config::db::get_db;
+use config::{db::get_db, settings::Settings};
+use std::{sync::Arc, time::Duration};
+
 use sqlx::{Pool, Postgres};
-use utilities::utils::JwtMiddleware;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+use utilities::{
+    envelope,
+    mailer::{self, Mailer},
+    monitoring::{self, Compliance},
+    payouts,
+    rate_limit::{self, RateLimitStore, RateLimiter},
+    scheduler,
+    telemetry::RequestTracing,
+    utils::{legacy_deprecation, JwtMiddleware},
+    workers::Workers,
+};
 
 pub mod config {
     pub mod db;
+    pub mod settings;
 }
 pub mod api {
+    pub mod admin;
     pub mod balance;
+    pub mod health;
+    pub mod metrics;
+    pub mod openapi;
     pub mod transactions;
     pub mod users;
+    pub mod v1 {
+        pub mod api_keys;
+        pub mod balance;
+        pub mod beneficiaries;
+        pub mod kyc;
+        pub mod notifications;
+        pub mod oauth;
+        pub mod payouts;
+        pub mod schedules;
+        pub mod transactions;
+        pub mod two_factor;
+        pub mod users;
+    }
 }
 
 pub mod models {
+    pub mod api_keys;
     pub mod balance;
+    pub mod beneficiaries;
+    pub mod fees;
+    pub mod kyc;
+    pub mod limits;
+    pub mod login_attempts;
+    pub mod monitoring;
+    pub mod notifications;
+    pub mod oauth;
+    pub mod payees;
+    pub mod payouts;
+    pub mod schedules;
+    pub mod screening;
+    pub mod tokens;
     pub mod transactions;
+    pub mod two_factor;
     pub mod users;
 }
 
 pub mod utilities {
     pub mod auth;
+    pub mod envelope;
     pub mod errors;
+    pub mod mailer;
+    pub mod metrics;
+    pub mod monitoring;
+    pub mod payouts;
+    pub mod rate_limit;
+    pub mod rbac;
+    pub mod scheduler;
+    pub mod screening;
+    pub mod telemetry;
+    #[cfg(test)]
+    pub mod test_harness;
+    pub mod totp;
     pub mod utils;
+    pub mod workers;
 }
 
 pub struct AppState {
-    db: Mutex<Pool<Postgres>>,
+    db: Pool<Postgres>,
+    settings: Settings,
+    workers: Workers,
+    mailer: Arc<dyn Mailer>,
+    rate_limits: Arc<dyn RateLimitStore>,
+    //monitoring rules and sanctions list, shared with the workers that book
+    compliance: Arc<Compliance>,
+}
+
+//every route of the service, shared by the server and the tests
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics))
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .service(web::redirect("/docs", "/docs/"))
+        .service(SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", ApiDoc::openapi()))
+        .service(
+            web::scope("/v1")
+                .app_data(web::JsonConfig::default().error_handler(envelope::json_error))
+                .app_data(web::PathConfig::default().error_handler(envelope::path_error))
+                .app_data(web::QueryConfig::default().error_handler(envelope::query_error))
+                //the OAuth2 token endpoints answer form errors the RFC 6749 way
+                .app_data(web::FormConfig::default().error_handler(v1::oauth::form_error))
+                //raw bodies are only read by the payout upload
+                .app_data(web::PayloadConfig::new(v1::payouts::MAX_UPLOAD_BYTES))
+                .route("/users", web::post().to(v1::users::create_user))
+                .route("/sessions", web::post().to(v1::users::create_session))
+                .route("/users/verification", web::post().to(v1::users::resend_verification))
+                .route("/users/verification/confirm", web::post().to(v1::users::confirm_verification))
+                .route("/password_resets", web::post().to(v1::users::request_password_reset))
+                .route("/password_resets/confirm", web::post().to(v1::users::confirm_password_reset))
+                .route("/users/2fa", web::get().to(v1::two_factor::get_two_factor_status))
+                .route("/users/2fa", web::post().to(v1::two_factor::enroll_two_factor))
+                .route("/users/2fa/confirm", web::post().to(v1::two_factor::confirm_two_factor))
+                .route("/users/2fa/disable", web::post().to(v1::two_factor::disable_two_factor_auth))
+                .route("/users/2fa/recovery_codes", web::post().to(v1::two_factor::create_recovery_codes))
+                .route("/sessions/elevate", web::post().to(v1::two_factor::elevate_session))
+                .route("/api_keys", web::get().to(v1::api_keys::list_user_api_keys))
+                .route("/api_keys", web::post().to(v1::api_keys::create_user_api_key))
+                .route("/api_keys/{id}", web::delete().to(v1::api_keys::revoke_user_api_key))
+                .route("/api_keys/{id}/rotate", web::post().to(v1::api_keys::rotate_user_api_key))
+                .route("/oauth/clients", web::get().to(v1::oauth::list_oauth_clients))
+                .route("/oauth/clients", web::post().to(v1::oauth::create_oauth_client))
+                .route("/oauth/clients/{id}", web::delete().to(v1::oauth::revoke_oauth_client))
+                .route("/oauth/authorize", web::post().to(v1::oauth::authorize_client))
+                .route("/oauth/consents", web::get().to(v1::oauth::list_oauth_consents))
+                .route("/oauth/consents/{client_id}", web::delete().to(v1::oauth::revoke_oauth_consent))
+                .route("/oauth/token", web::post().to(v1::oauth::issue_oauth_token))
+                .route("/oauth/introspect", web::post().to(v1::oauth::introspect_oauth_token))
+                .route("/users/{id}", web::get().to(v1::users::get_user_profile))
+                .route("/users/{id}", web::patch().to(v1::users::update_user_profile))
+                .route("/balance", web::get().to(v1::balance::get_account_balance))
+                .route("/balance/limits", web::get().to(v1::balance::get_account_limits))
+                .route("/transactions", web::get().to(v1::transactions::list_user_transactions))
+                .route("/transactions", web::post().to(v1::transactions::create_transaction))
+                .route("/transactions/quote", web::post().to(v1::transactions::quote_transaction))
+                .route("/transactions/{id}", web::get().to(v1::transactions::get_user_transaction))
+                .route("/transfers", web::post().to(v1::transactions::create_transfer))
+                .route("/payees/resolve", web::post().to(v1::transactions::resolve_payee))
+                .route("/beneficiaries", web::get().to(v1::beneficiaries::list_user_beneficiaries))
+                .route("/beneficiaries", web::post().to(v1::beneficiaries::create_user_beneficiary))
+                .route("/beneficiaries/{id}", web::get().to(v1::beneficiaries::get_user_beneficiary))
+                .route("/beneficiaries/{id}", web::put().to(v1::beneficiaries::update_user_beneficiary))
+                .route("/beneficiaries/{id}", web::delete().to(v1::beneficiaries::delete_user_beneficiary))
+                .route("/scheduled_payments", web::get().to(v1::schedules::list_user_schedules))
+                .route("/scheduled_payments", web::post().to(v1::schedules::create_user_schedule))
+                .route("/scheduled_payments/{id}", web::get().to(v1::schedules::get_user_schedule))
+                .route("/scheduled_payments/{id}/pause", web::post().to(v1::schedules::pause_schedule))
+                .route("/scheduled_payments/{id}/resume", web::post().to(v1::schedules::resume_schedule))
+                .route("/scheduled_payments/{id}/cancel", web::post().to(v1::schedules::cancel_schedule))
+                .route("/payouts", web::get().to(v1::payouts::list_payouts))
+                .route("/payouts", web::post().to(v1::payouts::create_payout))
+                .route("/payouts/{id}", web::get().to(v1::payouts::get_payout))
+                .route("/payouts/{id}/items", web::get().to(v1::payouts::get_payout_items))
+                .route("/payouts/{id}/results.csv", web::get().to(v1::payouts::download_payout_results))
+                .route("/kyc", web::get().to(v1::kyc::get_kyc_status))
+                .route("/kyc", web::post().to(v1::kyc::create_kyc_submission))
+                .route("/notifications", web::get().to(v1::notifications::list_user_notifications)),
+        )
+        //staff only, each handler requires its own permission
+        .service(
+            web::scope("/admin")
+                .app_data(web::JsonConfig::default().error_handler(envelope::json_error))
+                .app_data(web::PathConfig::default().error_handler(envelope::path_error))
+                .app_data(web::QueryConfig::default().error_handler(envelope::query_error))
+                .route("/users", web::get().to(admin::find_users))
+                .route("/users/{id}", web::get().to(admin::get_any_user))
+                .route("/users/{id}/role", web::put().to(admin::change_role))
+                .route("/accounts/{id}", web::get().to(admin::get_account))
+                .route("/accounts/{id}/freeze", web::post().to(admin::freeze_account))
+                .route("/accounts/{id}/unfreeze", web::post().to(admin::unfreeze_account))
+                .route("/accounts/{id}/status", web::put().to(admin::update_account_status))
+                .route("/accounts/{id}/close", web::post().to(admin::close))
+                .route("/accounts/{id}/tier", web::put().to(admin::change_tier))
+                .route("/fee_rules", web::get().to(admin::get_fee_rules))
+                .route("/fee_rules", web::post().to(admin::add_fee_rule))
+                .route("/fee_rules/{id}", web::delete().to(admin::remove_fee_rule))
+                .route("/limit_rules", web::get().to(admin::get_limit_rules))
+                .route("/limit_rules", web::post().to(admin::add_limit_rule))
+                .route("/limit_rules/{id}", web::delete().to(admin::remove_limit_rule))
+                .route("/reviews", web::get().to(admin::get_reviews))
+                .route("/reviews/{id}", web::get().to(admin::get_review))
+                .route("/reviews/{id}/approve", web::post().to(admin::approve_review))
+                .route("/reviews/{id}/reject", web::post().to(admin::reject_review))
+                .route("/monitoring/rules", web::get().to(admin::get_monitoring_rules))
+                .route("/monitoring/reload", web::post().to(admin::reload_monitoring_rules))
+                .route("/screening/results", web::get().to(admin::get_screening_results))
+                .route("/screening/results/{id}", web::get().to(admin::get_screening))
+                .route("/screening/results/{id}/clear", web::post().to(admin::clear_screening))
+                .route("/screening/results/{id}/confirm", web::post().to(admin::confirm_screening))
+                .route("/screening/list", web::get().to(admin::get_sanctions_list))
+                .route("/screening/reload", web::post().to(admin::reload_sanctions_list))
+                .route("/kyc", web::get().to(admin::get_kyc_submissions))
+                .route("/kyc/{id}", web::get().to(admin::get_kyc))
+                .route("/kyc/{id}/approve", web::post().to(admin::approve_kyc))
+                .route("/kyc/{id}/reject", web::post().to(admin::reject_kyc)),
+        )
+        //legacy routes, kept as deprecated aliases of /v1
+        .service(
+            web::scope("/user")
+                .wrap(legacy_deprecation())
+                .route("/register_user", web::post().to(user_register))
+                .route("/get_token", web::get().to(get_token))
+                .route("/get_user", web::get().to(get_user_details))
+                .route("/update_user", web::post().to(user_update)),
+        )
+        .service(
+            web::scope("/balance")
+                .wrap(legacy_deprecation())
+                .route("/fetch_balance", web::get().to(fetch_balance)),
+        )
+        .service(
+            web::scope("/transaction")
+                .wrap(legacy_deprecation())
+                .route("/operations", web::post().to(transaction))
+                .route("/fetch_transaction", web::get().to(fetch_transaction))
+                .route("/resolve_payee", web::post().to(resolve_payee))
+                .route("/list_trans", web::get().to(list_transactions)),
+        );
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let settings = match Settings::load() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let _telemetry = utilities::telemetry::init(&settings.logging);
+    tracing::info!(settings = ?settings, "starting payments_dodo");
+
+    //a rules file with errors stops the start, later reloads keep the previous rules instead
+    let compliance = Arc::new(Compliance::default());
+    if let Err(e) = compliance.rules.reload(&settings.monitoring) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    if let Err(e) = compliance.sanctions.reload(&settings.screening) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    let mailer = match mailer::from_settings(&settings.mail) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
     //get the pool connection
-    let pool = match get_db().await {
+    let pool = match get_db(&settings.database).await {
         Ok(v) => v,
-        Err(e) => panic!("Error at pool connection = {:?}", e),
+        Err(e) => {
+            tracing::error!(error = %e, "Error at pool connection, exiting");
+            std::process::exit(1);
+        }
     };
 
+    let bind = (settings.server.host.clone(), settings.server.port);
+    let http_workers = settings.server.workers;
+    let shutdown_timeout = settings.server.shutdown_timeout_secs;
+    let workers = Workers::new();
+    if settings.scheduler.enabled {
+        let (pool, compliance, scheduler_settings) = (pool.clone(), compliance.clone(), settings.scheduler.clone());
+        workers.spawn(
+            scheduler::WORKER_NAME,
+            scheduler::max_silence(&settings.scheduler),
+            |handle| scheduler::run(pool, compliance, scheduler_settings, handle),
+        );
+    }
+    if settings.payouts.enabled {
+        let (pool, compliance, payout_settings) = (pool.clone(), compliance.clone(), settings.payouts.clone());
+        workers.spawn(
+            payouts::WORKER_NAME,
+            payouts::max_silence(&settings.payouts),
+            |handle| payouts::run(pool, compliance, payout_settings, handle),
+        );
+    }
+    if !settings.monitoring.rules_file.is_empty() {
+        let (compliance, monitoring_settings) = (compliance.clone(), settings.monitoring.clone());
+        workers.spawn(
+            monitoring::WORKER_NAME,
+            monitoring::max_silence(&settings.monitoring),
+            |handle| monitoring::run(compliance, monitoring_settings, handle),
+        );
+    }
+    let rate_limits = rate_limit::store_from_settings(&settings.rate_limit, pool.clone());
     let appdata = web::Data::new(AppState {
-        db: Mutex::new(pool),
+        db: pool.clone(),
+        settings,
+        workers: workers.clone(),
+        mailer,
+        rate_limits,
+        compliance,
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
+        //the per address limits run before JwtMiddleware, so bad tokens are limited too, the per user ones after it
         App::new()
+            .wrap(RateLimiter::per_caller())
             .wrap(JwtMiddleware)
+            .wrap(RateLimiter::per_address())
+            .wrap(RequestTracing)
             .app_data(appdata.clone())
-            .service(
-                web::scope("/user")
-                    .route("/register_user", web::post().to(user_register))
-                    .route("/get_token", web::get().to(get_token))
-                    .route("/get_user", web::get().to(get_user_details))
-                    .route("/update_user", web::post().to(user_update)),
-            )
-            .service(web::scope("/balance").route("/fetch_balance", web::get().to(fetch_balance)))
-            .service(
-                web::scope("/transaction")
-                    .route("/operations", web::post().to(transaction))
-                    .route("/fetch_transaction", web::get().to(fetch_transaction))
-                    .route("/list_trans", web::get().to(list_transactions)),
-            )
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+            .configure(routes)
+    });
+    let server = if http_workers > 0 {
+        server.workers(http_workers)
+    } else {
+        server
+    };
+
+    //on SIGINT/SIGTERM actix stops accepting and lets in-flight requests finish within the timeout
+    server
+        .shutdown_timeout(shutdown_timeout)
+        .bind(bind)?
+        .run()
+        .await?;
+
+    tracing::info!("HTTP server stopped, stopping background workers");
+    workers.shutdown(Duration::from_secs(shutdown_timeout)).await;
+    pool.close().await;
+    tracing::info!("Shutdown complete");
+    Ok(())
 }
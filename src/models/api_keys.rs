@@ -0,0 +1,359 @@
+//API keys of server-to-server integrations, sent as Authorization: Bearer sk_<prefix>_<secret> instead of a JWT
+//a key acts for the user who created it but only on the routes its scopes cover, see SCOPED_ROUTES
+//only the sha-256 of a key is stored, the key itself is shown once when it is created or rotated
+use std::{fmt, str::FromStr};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+use tracing::{error, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{models::tokens::hash_token, utilities::rbac::Role};
+
+pub const KEY_PREFIX: &str = "sk_";
+const MAX_NAME_LEN: usize = 100;
+//active keys per user
+pub const MAX_API_KEYS: i64 = 20;
+pub const MAX_EXPIRES_IN_DAYS: i64 = 3650;
+//a rotated key keeps working for at most a week, so integrations can switch over without downtime
+pub const MAX_GRACE_MINUTES: i64 = 7 * 24 * 60;
+//last_used_at is written at most once a minute per key, not on every request
+const LAST_USED_PRECISION_SECS: i64 = 60;
+
+//what an API key or an OAuth2 access token may do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum Scope {
+    #[serde(rename = "balance:read")]
+    BalanceRead,
+    #[serde(rename = "transactions:read")]
+    TransactionsRead,
+    //deposits, withdrawls and transfers
+    #[serde(rename = "transactions:write")]
+    TransactionsWrite,
+    #[serde(rename = "beneficiaries:read")]
+    BeneficiariesRead,
+    #[serde(rename = "beneficiaries:write")]
+    BeneficiariesWrite,
+    #[serde(rename = "payouts:read")]
+    PayoutsRead,
+    #[serde(rename = "payouts:write")]
+    PayoutsWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::BalanceRead => "balance:read",
+            Scope::TransactionsRead => "transactions:read",
+            Scope::TransactionsWrite => "transactions:write",
+            Scope::BeneficiariesRead => "beneficiaries:read",
+            Scope::BeneficiariesWrite => "beneficiaries:write",
+            Scope::PayoutsRead => "payouts:read",
+            Scope::PayoutsWrite => "payouts:write",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "balance:read" => Ok(Scope::BalanceRead),
+            "transactions:read" => Ok(Scope::TransactionsRead),
+            "transactions:write" => Ok(Scope::TransactionsWrite),
+            "beneficiaries:read" => Ok(Scope::BeneficiariesRead),
+            "beneficiaries:write" => Ok(Scope::BeneficiariesWrite),
+            "payouts:read" => Ok(Scope::PayoutsRead),
+            "payouts:write" => Ok(Scope::PayoutsWrite),
+            _ => Err(format!("Unknown scope {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ApiKey {
+    pub key_id: Uuid,
+    #[schema(example = "Billing backend")]
+    pub name: String,
+    //the start of the key, enough to recognise it
+    #[schema(example = "sk_3f9a1c07b2e4")]
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+//a created or rotated key, the only time the key is readable
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct NewApiKey {
+    #[schema(example = "sk_3f9a1c07b2e4_9b0c...")]
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
+//the user and scopes behind a valid key
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyOwner {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub role: Role,
+    pub scopes: Vec<Scope>,
+}
+
+//sk_ with 12 hex characters of prefix, then 256 random bits as 64 hex characters
+fn new_key() -> (String, String) {
+    let mut prefix = [0u8; 6];
+    OsRng.fill_bytes(&mut prefix);
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let prefix = format!("{}{}", KEY_PREFIX, hex::encode(prefix));
+    let key = format!("{}_{}", prefix, hex::encode(secret));
+    (prefix, key)
+}
+
+//unknown scopes are skipped, a scope that was removed from the code grants nothing
+pub fn parse_scopes(scopes: Vec<String>) -> Vec<Scope> {
+    scopes.iter().filter_map(|s| s.parse().ok()).collect()
+}
+
+fn api_key(v: &PgRow) -> ApiKey {
+    ApiKey {
+        key_id: v.get("key_id"),
+        name: v.get("name"),
+        prefix: v.get("prefix"),
+        scopes: parse_scopes(v.get("scopes")),
+        created_at: v.get("created_at"),
+        expires_at: v.get("expires_at"),
+        last_used_at: v.get("last_used_at"),
+        revoked_at: v.get("revoked_at"),
+    }
+}
+
+fn validate_name(name: &str) -> Result<String, sqlx::Error> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(sqlx::Error::Encode(
+            format!("Name must be 1 to {} characters", MAX_NAME_LEN).into(),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+pub fn validate_scopes(scopes: &[Scope]) -> Result<Vec<String>, sqlx::Error> {
+    if scopes.is_empty() {
+        return Err(sqlx::Error::Encode("At least one scope is required".into()));
+    }
+    let mut names: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+#[instrument(name = "db.list_api_keys", skip(pool))]
+pub async fn list_api_keys(pool: &Pool<Postgres>, uid: Uuid) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let qry = "SELECT * FROM api_keys where user_id = $1 ORDER BY created_at DESC, key_id";
+
+    match sqlx::query(qry).bind(uid).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(api_key).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_api_keys");
+            Err(e)
+        }
+    }
+}
+
+//a key without expires_in_days is valid until it is revoked
+#[instrument(name = "db.create_api_key", skip(pool, name))]
+pub async fn create_api_key(
+    pool: &Pool<Postgres>,
+    uid: Uuid,
+    name: &str,
+    scopes: &[Scope],
+    expires_in_days: Option<i64>,
+) -> Result<NewApiKey, sqlx::Error> {
+    let name = validate_name(name)?;
+    let scopes = validate_scopes(scopes)?;
+    if expires_in_days.is_some_and(|d| !(1..=MAX_EXPIRES_IN_DAYS).contains(&d)) {
+        return Err(sqlx::Error::Encode(
+            format!("expires_in_days must be 1 to {}", MAX_EXPIRES_IN_DAYS).into(),
+        ));
+    }
+    let now = Utc::now().naive_utc();
+
+    let active: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM api_keys where user_id = $1 and revoked_at IS NULL and (expires_at IS NULL or expires_at > $2)",
+    )
+    .bind(uid)
+    .bind(now)
+    .fetch_one(pool)
+    .await?;
+    if active >= MAX_API_KEYS {
+        return Err(sqlx::Error::Encode(
+            format!("No more than {} active API keys are allowed", MAX_API_KEYS).into(),
+        ));
+    }
+
+    let (prefix, key) = new_key();
+    let qry = "INSERT INTO api_keys(key_id,user_id,name,prefix,key_hash,scopes,created_at,expires_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8) RETURNING *";
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(uid)
+        .bind(name)
+        .bind(prefix)
+        .bind(hash_token(&key))
+        .bind(scopes)
+        .bind(now)
+        .bind(expires_in_days.map(|d| now + Duration::days(d)))
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => {
+            let api_key = api_key(&v);
+            info!(user_id = %uid, key_id = %api_key.key_id, prefix = api_key.prefix, "API key created");
+            Ok(NewApiKey { key, api_key })
+        }
+        Err(e) => {
+            error!(error = %e, "Error at create_api_key");
+            Err(e)
+        }
+    }
+}
+
+//replaces a key by a new one with the same name, scopes and expiry
+//the old key stops working right away, or after grace_minutes so the integration can switch over
+#[instrument(name = "db.rotate_api_key", skip(pool))]
+pub async fn rotate_api_key(
+    pool: &Pool<Postgres>,
+    uid: Uuid,
+    key_id: Uuid,
+    grace_minutes: i64,
+) -> Result<NewApiKey, sqlx::Error> {
+    if !(0..=MAX_GRACE_MINUTES).contains(&grace_minutes) {
+        return Err(sqlx::Error::Encode(
+            format!("grace_minutes must be 0 to {}", MAX_GRACE_MINUTES).into(),
+        ));
+    }
+    let now = Utc::now().naive_utc();
+    let (prefix, key) = new_key();
+
+    let mut tx = pool.begin().await?;
+    let rotated: Result<ApiKey, sqlx::Error> = async {
+        let qry = "SELECT * FROM api_keys where user_id = $1 and key_id = $2
+            and revoked_at IS NULL and (expires_at IS NULL or expires_at > $3) FOR UPDATE";
+        let old = sqlx::query(qry)
+            .bind(uid)
+            .bind(key_id)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let qry = "INSERT INTO api_keys(key_id,user_id,name,prefix,key_hash,scopes,created_at,expires_at)
+            Values ($1,$2,$3,$4,$5,$6,$7,$8) RETURNING *";
+        let new = sqlx::query(qry)
+            .bind(Uuid::new_v4())
+            .bind(uid)
+            .bind(old.get::<String, _>("name"))
+            .bind(prefix)
+            .bind(hash_token(&key))
+            .bind(old.get::<Vec<String>, _>("scopes"))
+            .bind(now)
+            .bind(old.get::<Option<NaiveDateTime>, _>("expires_at"))
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if grace_minutes == 0 {
+            sqlx::query("UPDATE api_keys SET revoked_at = $2 where key_id = $1")
+                .bind(key_id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        } else {
+            sqlx::query("UPDATE api_keys SET expires_at = LEAST(COALESCE(expires_at, $2), $2) where key_id = $1")
+                .bind(key_id)
+                .bind(now + Duration::minutes(grace_minutes))
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(api_key(&new))
+    }
+    .await;
+
+    match rotated {
+        Ok(api_key) => {
+            tx.commit().await?;
+            info!(user_id = %uid, old_key_id = %key_id, key_id = %api_key.key_id, grace_minutes, "API key rotated");
+            Ok(NewApiKey { key, api_key })
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.revoke_api_key", skip(pool))]
+pub async fn revoke_api_key(pool: &Pool<Postgres>, uid: Uuid, key_id: Uuid) -> Result<(), sqlx::Error> {
+    let qry = "UPDATE api_keys SET revoked_at = $3 where user_id = $1 and key_id = $2 and revoked_at IS NULL";
+
+    match sqlx::query(qry)
+        .bind(uid)
+        .bind(key_id)
+        .bind(Utc::now().naive_utc())
+        .execute(pool)
+        .await
+    {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => {
+            info!(user_id = %uid, key_id = %key_id, "API key revoked");
+            Ok(())
+        }
+        Err(e) => {
+            error!(error = %e, "Error at revoke_api_key");
+            Err(e)
+        }
+    }
+}
+
+//the owner of an active key, none for unknown, revoked and expired keys
+//records the use, at minute precision
+#[instrument(name = "db.authenticate_api_key", skip_all)]
+pub async fn authenticate_api_key(pool: &Pool<Postgres>, key: &str) -> Result<Option<ApiKeyOwner>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let qry = "SELECT k.key_id, k.user_id, k.scopes, u.role FROM api_keys k JOIN users u ON u.user_id = k.user_id
+        where k.key_hash = $1 and k.revoked_at IS NULL and (k.expires_at IS NULL or k.expires_at > $2)";
+    let row = sqlx::query(qry)
+        .bind(hash_token(key))
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+    let owner = match row {
+        Some(v) => ApiKeyOwner {
+            key_id: v.get("key_id"),
+            user_id: v.get("user_id"),
+            role: v.get::<String, _>("role").parse().unwrap_or_default(),
+            scopes: parse_scopes(v.get("scopes")),
+        },
+        None => return Ok(None),
+    };
+
+    sqlx::query("UPDATE api_keys SET last_used_at = $2 where key_id = $1 and (last_used_at IS NULL or last_used_at < $3)")
+        .bind(owner.key_id)
+        .bind(now)
+        .bind(now - Duration::seconds(LAST_USED_PRECISION_SECS))
+        .execute(pool)
+        .await?;
+    Ok(Some(owner))
+}
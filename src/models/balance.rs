@@ -1,52 +1,55 @@
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres, Row};
+use sqlx::{postgres::PgRow, Executor, PgConnection, Pool, Postgres, Row};
+use tracing::{debug, error, instrument};
 use uuid::Uuid;
 
-use crate::models::users::{get_user, get_user_by_id};
+use crate::{
+    models::{fees::normalize_tier, users::get_user_by_id},
+    utilities::errors::TransactionError,
+};
 
+#[instrument(name = "db.add_balance", skip(pool))]
 pub async fn add_balance_db(
     pool: &Pool<Postgres>,
     uuid: Uuid,
     amount: Decimal,
 ) -> Result<(), sqlx::Error> {
-    println!("Hello from the add_balance db");
-
     let update_at = Utc::now();
 
     let qry = "INSERT INTO account_balance (account_id,user_id,balance,updated_at) VALUES ($1,$2,$3,$4)";
 
-    match get_user_by_id(&pool, uuid).await {
+    match get_user_by_id(pool, uuid).await {
         Ok(v) => {
             let mut amt: Decimal = amount;
-            match get_balance(&pool, v.user_id).await {
+            match get_balance(pool, v.user_id).await {
                 Ok(v) => {
                     amt = v.balance + amount;
                 }
                 Err(e) => {
-                    println!("Error at get_baalnce = {}", e);
+                    debug!(error = %e, "No existing balance");
                 }
             };
             let accout_id = Uuid::new_v4();
-            match sqlx::query(&qry)
-            .bind(&accout_id)
+            match sqlx::query(qry)
+            .bind(accout_id)
                 .bind(v.user_id)
                 .bind(amt)
                 .bind(update_at)
                 .execute(pool)
                 .await
             {
-                Ok(v) => Ok(()),
+                Ok(_) => Ok(()),
                 Err(e) => {
-                    println!("Error at add_balance_dv : {:?}", e);
-                    return Err(e);
+                    error!(error = %e, "Error at add_balance_db");
+                    Err(e)
                 }
             }
         }
         Err(e) => {
-            println!("Error at add_balance_db : {:?}", e);
-            return Err(e);
+            error!(error = %e, "Error at add_balance_db");
+            Err(e)
         }
     }
 }
@@ -54,45 +57,277 @@ pub async fn add_balance_db(
 #[derive(Serialize, Deserialize, Clone, Copy)]
 #[derive(PartialEq)]
 #[derive(Debug)]
+#[derive(utoipa::ToSchema)]
 pub struct BalanceDetails {
     pub user_id: Uuid,
+    #[schema(value_type = String, example = "25.50")]
     pub balance: Decimal,
+    #[serde(default)]
+    pub status: AccountStatus,
 }
 
-pub async fn get_balance(pool: &Pool<Postgres>, uid: Uuid) -> Result<BalanceDetails, sqlx::Error> {
-    println!("Hello from the get_balance");
+//active -> everything allowed
+//frozen -> neither debits nor credits, e.g. a compromised account
+//debit_blocked -> money can come in but not go out
+//closed -> final, nothing moves and the status cannot change any more
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Frozen,
+    DebitBlocked,
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::DebitBlocked => "debit_blocked",
+            AccountStatus::Closed => "closed",
+        }
+    }
+
+    fn from_db(status: &str) -> AccountStatus {
+        match status {
+            "active" => AccountStatus::Active,
+            "debit_blocked" => AccountStatus::DebitBlocked,
+            "closed" => AccountStatus::Closed,
+            //unknown states must never let money move
+            _ => AccountStatus::Frozen,
+        }
+    }
+
+    pub fn can_debit(&self) -> bool {
+        *self == AccountStatus::Active
+    }
+
+    pub fn can_credit(&self) -> bool {
+        matches!(self, AccountStatus::Active | AccountStatus::DebitBlocked)
+    }
+}
+
+//the status of an account and its last change
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, utoipa::ToSchema)]
+pub struct AccountState {
+    pub user_id: Uuid,
+    pub status: AccountStatus,
+    pub reason: Option<String>,
+    //user_id of the staff member who made the last change
+    pub updated_by: Option<Uuid>,
+    pub updated_at: Option<NaiveDateTime>,
+}
 
+fn balance_details(v: &PgRow) -> BalanceDetails {
+    BalanceDetails {
+        balance: v.get("balance"),
+        user_id: v.get("user_id"),
+        status: AccountStatus::from_db(v.get("status")),
+    }
+}
+
+#[instrument(name = "db.get_balance", skip(pool))]
+pub async fn get_balance(pool: &Pool<Postgres>, uid: Uuid) -> Result<BalanceDetails, sqlx::Error> {
     let qry = "SELECT * FROM account_balance where user_id = $1";
 
-    match sqlx::query(&qry).bind(uid).fetch_one(pool).await {
-        Ok(v) => Ok(BalanceDetails {
-            balance: v.get("balance"),
-            user_id: v.get("user_id"),
-        }),
+    match sqlx::query(qry).bind(uid).fetch_one(pool).await {
+        Ok(v) => Ok(balance_details(&v)),
         Err(e) => {
-            println!("Error at get_balance");
-            return Err(e);
+            debug!(error = %e, "Error at get_balance");
+            Err(e)
         }
     }
 }
 
-pub async fn update_balance(
+//reads the balance and locks the row until the surrounding transaction ends
+#[instrument(name = "db.lock_balance", skip(conn))]
+pub async fn lock_balance(conn: &mut PgConnection, uid: Uuid) -> Result<BalanceDetails, sqlx::Error> {
+    let qry = "SELECT user_id, balance, status FROM account_balance where user_id = $1 FOR UPDATE";
+
+    match sqlx::query(qry).bind(uid).fetch_one(conn).await {
+        Ok(v) => Ok(balance_details(&v)),
+        Err(e) => {
+            debug!(error = %e, "Error at lock_balance");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.update_balance", skip(executor))]
+pub async fn update_balance<'e, E>(executor: E, uid: Uuid, new_bal: Decimal) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "UPDATE account_balance SET balance=$1, updated_at=$2 where user_id = $3";
+
+    match sqlx::query(qry)
+        .bind(new_bal)
+        .bind(Utc::now())
+        .bind(uid)
+        .execute(executor)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at update balance");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.get_account_state", skip(pool))]
+pub async fn get_account_state(pool: &Pool<Postgres>, uid: Uuid) -> Result<AccountState, sqlx::Error> {
+    let qry = "SELECT user_id, status, status_reason, status_updated_by, status_updated_at FROM account_balance where user_id = $1";
+
+    match sqlx::query(qry).bind(uid).fetch_one(pool).await {
+        Ok(v) => Ok(account_state(&v)),
+        Err(e) => {
+            debug!(error = %e, "Error at get_account_state");
+            Err(e)
+        }
+    }
+}
+
+//the tier picks the fee rules of the account
+#[instrument(name = "db.get_account_tier", skip(pool))]
+pub async fn get_account_tier(pool: &Pool<Postgres>, uid: Uuid) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar("SELECT tier FROM account_balance where user_id = $1")
+        .bind(uid)
+        .fetch_one(pool)
+        .await
+}
+
+#[instrument(name = "db.set_account_tier", skip(pool))]
+pub async fn set_account_tier(pool: &Pool<Postgres>, uid: Uuid, tier: &str) -> Result<String, sqlx::Error> {
+    let tier = normalize_tier(tier)?;
+    let qry = "UPDATE account_balance SET tier = $1, updated_at = $2 where user_id = $3 RETURNING tier";
+
+    match sqlx::query_scalar(qry).bind(tier).bind(Utc::now()).bind(uid).fetch_one(pool).await {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            debug!(error = %e, "Error at set_account_tier");
+            Err(e)
+        }
+    }
+}
+
+//takes the balance row lock, so the change waits for transactions in flight on the account
+//closing goes through close_account, which settles the balance first
+#[instrument(name = "db.set_account_status", skip(pool, reason))]
+pub async fn set_account_status(
     pool: &Pool<Postgres>,
     uid: Uuid,
-    new_bal: Decimal,
-) -> Result<(), sqlx::Error> {
-    println!("Hello from the update balance");
-
-    let qry = "UPDATE account_balance SET balance=$1 where user_id = $2";
+    status: AccountStatus,
+    reason: &str,
+    updated_by: Uuid,
+) -> Result<AccountState, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let changed = async {
+        let current = lock_balance(&mut tx, uid).await?;
+        if current.status == AccountStatus::Closed {
+            return Err(TransactionError::AccountClosed.into());
+        }
+        write_account_status(&mut tx, uid, current.status, status, reason, updated_by, None).await
+    }
+    .await;
 
-    match sqlx::query(qry).bind(new_bal).bind(uid).execute(pool).await {
-        Ok(_) => {
-            println!("Balance updated successfully");
-            Ok(())
+    match changed {
+        Ok(v) => {
+            tx.commit().await?;
+            Ok(v)
+        }
+        Err(e) => {
+            debug!(error = %e, "Error at set_account_status");
+            let _ = tx.rollback().await;
+            Err(e)
         }
+    }
+}
+
+//updates the status and appends it to the account's status history, inside the caller's transaction
+#[allow(clippy::too_many_arguments)]
+pub async fn write_account_status(
+    conn: &mut PgConnection,
+    uid: Uuid,
+    from: AccountStatus,
+    to: AccountStatus,
+    reason: &str,
+    updated_by: Uuid,
+    sweep_transaction_id: Option<Uuid>,
+) -> Result<AccountState, sqlx::Error> {
+    let update_qry = "
+        UPDATE account_balance
+        SET status=$1, status_reason=$2, status_updated_by=$3, status_updated_at=$4, updated_at=$4
+        where user_id = $5
+        RETURNING user_id, status, status_reason, status_updated_by, status_updated_at
+    ";
+    let history_qry = "
+        INSERT INTO account_status_changes (user_id, from_status, to_status, reason, changed_by, sweep_transaction_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+    ";
+
+    let row = sqlx::query(update_qry)
+        .bind(to.as_str())
+        .bind(reason)
+        .bind(updated_by)
+        .bind(Utc::now())
+        .bind(uid)
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query(history_qry)
+        .bind(uid)
+        .bind(from.as_str())
+        .bind(to.as_str())
+        .bind(reason)
+        .bind(updated_by)
+        .bind(sweep_transaction_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(account_state(&row))
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, utoipa::ToSchema)]
+pub struct StatusChange {
+    pub from_status: AccountStatus,
+    pub to_status: AccountStatus,
+    pub reason: String,
+    pub changed_by: Option<Uuid>,
+    //the transfer that moved the remaining balance away on closure
+    pub sweep_transaction_id: Option<Uuid>,
+    pub changed_at: NaiveDateTime,
+}
+
+#[instrument(name = "db.list_status_changes", skip(pool))]
+pub async fn list_status_changes(pool: &Pool<Postgres>, uid: Uuid) -> Result<Vec<StatusChange>, sqlx::Error> {
+    let qry = "SELECT * FROM account_status_changes where user_id = $1 ORDER BY id";
+
+    match sqlx::query(qry).bind(uid).fetch_all(pool).await {
+        Ok(v) => Ok(v
+            .iter()
+            .map(|r| StatusChange {
+                from_status: AccountStatus::from_db(r.get("from_status")),
+                to_status: AccountStatus::from_db(r.get("to_status")),
+                reason: r.get("reason"),
+                changed_by: r.get("changed_by"),
+                sweep_transaction_id: r.get("sweep_transaction_id"),
+                changed_at: r.get("changed_at"),
+            })
+            .collect()),
         Err(e) => {
-            println!("Error at update balance: {:?}", e);
-            return Err(e);
+            error!(error = %e, "Error at list_status_changes");
+            Err(e)
         }
     }
 }
+
+fn account_state(v: &PgRow) -> AccountState {
+    AccountState {
+        user_id: v.get("user_id"),
+        status: AccountStatus::from_db(v.get("status")),
+        reason: v.get("status_reason"),
+        updated_by: v.get("status_updated_by"),
+        updated_at: v.get("status_updated_at"),
+    }
+}
@@ -0,0 +1,292 @@
+//saved beneficiaries of a user, so a regular payee is picked from a list instead of typed again
+//a beneficiary can carry its own limits, they are checked on every transfer that references it
+use chrono::{NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+use tracing::{error, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        payees::resolve_receiver,
+        transactions::{validate_amount, TransactionOptions},
+        users::get_user_by_id,
+    },
+    utilities::errors::TransactionError,
+};
+
+const MAX_NICKNAME_LEN: usize = 50;
+const MAX_MEMO_LEN: usize = 140;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Beneficiary {
+    pub beneficiary_id: Uuid,
+    pub receiver_id: Uuid,
+    #[schema(example = "Landlord")]
+    pub nickname: String,
+    #[schema(example = "Rent")]
+    pub default_memo: Option<String>,
+    //no single transfer may exceed it
+    #[schema(value_type = Option<String>, example = "500.00")]
+    pub per_transfer_limit: Option<Decimal>,
+    //sum of the transfers to this receiver since midnight, held ones included
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub daily_limit: Option<Decimal>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+//the editable part of a beneficiary, the receiver is fixed once saved
+#[derive(Debug, Clone, Default)]
+pub struct BeneficiaryFields {
+    pub nickname: String,
+    pub default_memo: Option<String>,
+    pub per_transfer_limit: Option<Decimal>,
+    pub daily_limit: Option<Decimal>,
+}
+
+impl BeneficiaryFields {
+    fn validate(mut self) -> Result<BeneficiaryFields, sqlx::Error> {
+        self.nickname = self.nickname.trim().to_string();
+        if self.nickname.is_empty() || self.nickname.chars().count() > MAX_NICKNAME_LEN {
+            return Err(sqlx::Error::Encode(
+                format!("Nickname must be 1 to {} characters", MAX_NICKNAME_LEN).into(),
+            ));
+        }
+        self.default_memo = self
+            .default_memo
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty());
+        if self.default_memo.as_ref().is_some_and(|m| m.chars().count() > MAX_MEMO_LEN) {
+            return Err(sqlx::Error::Encode(
+                format!("Memo cannot be longer than {} characters", MAX_MEMO_LEN).into(),
+            ));
+        }
+        for limit in [self.per_transfer_limit, self.daily_limit].into_iter().flatten() {
+            validate_amount(limit)?;
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransferLimits {
+    pub per_transfer: Option<Decimal>,
+    pub daily: Option<Decimal>,
+}
+
+impl Beneficiary {
+    pub fn limits(&self) -> TransferLimits {
+        TransferLimits {
+            per_transfer: self.per_transfer_limit,
+            daily: self.daily_limit,
+        }
+    }
+}
+
+fn beneficiary(v: &PgRow) -> Beneficiary {
+    Beneficiary {
+        beneficiary_id: v.get("beneficiary_id"),
+        receiver_id: v.get("receiver_id"),
+        nickname: v.get("nickname"),
+        default_memo: v.get("default_memo"),
+        per_transfer_limit: v.get("per_transfer_limit"),
+        daily_limit: v.get("daily_limit"),
+        created_at: v.get("created_at"),
+        updated_at: v.get("updated_at"),
+    }
+}
+
+//the receiver is given either as user_id or as payee, like for a transfer
+#[instrument(name = "db.create_beneficiary", skip(pool, payee, fields))]
+pub async fn create_beneficiary(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    receiver: Option<Uuid>,
+    payee: Option<&str>,
+    fields: BeneficiaryFields,
+) -> Result<Beneficiary, sqlx::Error> {
+    let fields = fields.validate()?;
+    let receiver_id = match resolve_receiver(pool, receiver, payee).await? {
+        Some(v) => v,
+        None => return Err(sqlx::Error::Encode("A receiver or payee is required".into())),
+    };
+    if receiver_id == owner {
+        return Err(TransactionError::SameAccount.into());
+    }
+    get_user_by_id(pool, receiver_id).await?;
+
+    let qry = "INSERT INTO beneficiaries(beneficiary_id,owner_id,receiver_id,nickname,default_memo,per_transfer_limit,daily_limit,created_at,updated_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8,$8) RETURNING *;";
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(owner)
+        .bind(receiver_id)
+        .bind(fields.nickname)
+        .bind(fields.default_memo)
+        .bind(fields.per_transfer_limit)
+        .bind(fields.daily_limit)
+        .bind(Utc::now())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => Ok(beneficiary(&v)),
+        Err(e) => {
+            error!(error = %e, "Error at create_beneficiary");
+            Err(e)
+        }
+    }
+}
+
+//by nickname
+#[instrument(name = "db.list_beneficiaries", skip(pool))]
+pub async fn list_beneficiaries(pool: &Pool<Postgres>, owner: Uuid) -> Result<Vec<Beneficiary>, sqlx::Error> {
+    let qry = "SELECT * FROM beneficiaries where owner_id = $1 ORDER BY lower(nickname)";
+
+    match sqlx::query(qry).bind(owner).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(beneficiary).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_beneficiaries");
+            Err(e)
+        }
+    }
+}
+
+//another user's beneficiary is RowNotFound, the same as a missing one
+#[instrument(name = "db.get_beneficiary", skip(pool))]
+pub async fn get_beneficiary(pool: &Pool<Postgres>, owner: Uuid, id: Uuid) -> Result<Beneficiary, sqlx::Error> {
+    let qry = "SELECT * FROM beneficiaries where owner_id = $1 and beneficiary_id = $2";
+
+    match sqlx::query(qry).bind(owner).bind(id).fetch_one(pool).await {
+        Ok(v) => Ok(beneficiary(&v)),
+        Err(e) => Err(e),
+    }
+}
+
+#[instrument(name = "db.update_beneficiary", skip(pool, fields))]
+pub async fn update_beneficiary(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    id: Uuid,
+    fields: BeneficiaryFields,
+) -> Result<Beneficiary, sqlx::Error> {
+    let fields = fields.validate()?;
+    let qry = "UPDATE beneficiaries SET nickname=$1, default_memo=$2, per_transfer_limit=$3, daily_limit=$4, updated_at=$5
+        where owner_id = $6 and beneficiary_id = $7 RETURNING *;";
+
+    match sqlx::query(qry)
+        .bind(fields.nickname)
+        .bind(fields.default_memo)
+        .bind(fields.per_transfer_limit)
+        .bind(fields.daily_limit)
+        .bind(Utc::now())
+        .bind(owner)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => Ok(beneficiary(&v)),
+        Err(e) => Err(e),
+    }
+}
+
+#[instrument(name = "db.delete_beneficiary", skip(pool))]
+pub async fn delete_beneficiary(pool: &Pool<Postgres>, owner: Uuid, id: Uuid) -> Result<(), sqlx::Error> {
+    let qry = "DELETE FROM beneficiaries where owner_id = $1 and beneficiary_id = $2";
+
+    match sqlx::query(qry).bind(owner).bind(id).execute(pool).await {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at delete_beneficiary");
+            Err(e)
+        }
+    }
+}
+
+//the receiver of a payment and the options that come with it
+//a beneficiary stands for its receiver and brings its limits and default memo, it cannot be combined with receiver or payee
+pub async fn resolve_target(
+    pool: &Pool<Postgres>,
+    sender: Uuid,
+    receiver: Option<Uuid>,
+    payee: Option<&str>,
+    beneficiary_id: Option<Uuid>,
+) -> Result<(Option<Uuid>, TransactionOptions), sqlx::Error> {
+    match beneficiary_id {
+        Some(_) if receiver.is_some() || payee.is_some() => Err(sqlx::Error::Encode(
+            "Give only one of receiver, payee or beneficiary_id".into(),
+        )),
+        Some(id) => {
+            let b = get_beneficiary(pool, sender, id).await?;
+            let options = TransactionOptions {
+                limits: Some(b.limits()),
+                memo: b.default_memo,
+                ..Default::default()
+            };
+            Ok((Some(b.receiver_id), options))
+        }
+        None => Ok((
+            resolve_receiver(pool, receiver, payee).await?,
+            TransactionOptions::default(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        models::transactions::add_transaction_with,
+        utilities::{errors::TransactionError, test_harness::TestDb},
+    };
+
+    use super::{create_beneficiary, delete_beneficiary, list_beneficiaries, resolve_target, BeneficiaryFields};
+
+    #[actix_web::test]
+    async fn test_beneficiary_limits() {
+        let db = TestDb::new().await;
+        let owner = db.user().balance(dec!(100)).create().await;
+        let landlord = db.user().create().await;
+        let stranger = db.user().create().await;
+
+        let fields = BeneficiaryFields {
+            nickname: String::from(" Landlord "),
+            per_transfer_limit: Some(dec!(30)),
+            daily_limit: Some(dec!(50)),
+            ..Default::default()
+        };
+        let saved = create_beneficiary(&db.pool, owner.user_id, Some(landlord.user_id), None, fields.clone())
+            .await
+            .unwrap();
+        assert_eq!(saved.nickname, "Landlord");
+        //the nickname is unique per owner
+        assert!(create_beneficiary(&db.pool, owner.user_id, Some(landlord.user_id), None, fields)
+            .await
+            .is_err());
+
+        let (pool, compliance, sender, id) = (&db.pool, &*db.compliance, owner.user_id, saved.beneficiary_id);
+        let transfer = |amount| async move {
+            let (receiver, options) = resolve_target(pool, sender, None, None, Some(id)).await?;
+            add_transaction_with(pool, compliance, sender, receiver, amount, String::from("transfer"), options).await
+        };
+        let limit_error = |r: Result<_, sqlx::Error>| TransactionError::from_sqlx(&r.unwrap_err());
+        assert_eq!(limit_error(transfer(dec!(31)).await), Some(TransactionError::BeneficiaryLimitExceeded));
+        transfer(dec!(30)).await.unwrap();
+        transfer(dec!(20)).await.unwrap();
+        //51 today
+        assert_eq!(limit_error(transfer(dec!(1)).await), Some(TransactionError::BeneficiaryLimitExceeded));
+
+        //beneficiaries are private to their owner
+        assert!(matches!(
+            resolve_target(&db.pool, stranger.user_id, None, None, Some(saved.beneficiary_id)).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(delete_beneficiary(&db.pool, stranger.user_id, saved.beneficiary_id).await.is_err());
+        delete_beneficiary(&db.pool, owner.user_id, saved.beneficiary_id).await.unwrap();
+        assert!(list_beneficiaries(&db.pool, owner.user_id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+//fee engine, a fee rule prices one transaction type for every account or only for one account tier
+//the fee is flat + percent of the amount, from the band the amount falls into when the rule is tiered,
+//rounded to cents and kept within min_fee and max_fee
+//it is booked as a separate fee transaction from the payer to the rule's revenue account, in the same
+//database transaction as the payment it belongs to
+use chrono::{NaiveDateTime, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{postgres::PgRow, Executor, Pool, Postgres, Row};
+use tracing::{error, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{models::transactions::validate_amount, utilities::errors::TransactionError};
+
+pub const TRANSACTION_TYPES: [&str; 3] = ["deposit", "withdrawl", "transfer"];
+
+//one price band of a tiered rule, up_to is inclusive and left out on the last band
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FeeBand {
+    #[schema(value_type = Option<String>, example = "100.00")]
+    pub up_to: Option<Decimal>,
+    #[schema(value_type = String, example = "0.25")]
+    #[serde(default)]
+    pub flat: Decimal,
+    #[schema(value_type = String, example = "1.5")]
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FeeRule {
+    pub rule_id: Uuid,
+    #[schema(example = "transfer")]
+    pub transaction_type: String,
+    //None applies to every tier without a rule of its own
+    #[schema(example = "standard")]
+    pub tier: Option<String>,
+    #[schema(value_type = String, example = "0.25")]
+    pub flat: Decimal,
+    #[schema(value_type = String, example = "1.5")]
+    pub percent: Decimal,
+    #[schema(value_type = Option<String>, example = "0.50")]
+    pub min_fee: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "10.00")]
+    pub max_fee: Option<Decimal>,
+    //replace flat and percent when given
+    pub bands: Vec<FeeBand>,
+    //the account the fees are paid to
+    pub revenue_account: Uuid,
+    pub created_at: NaiveDateTime,
+}
+
+//a new rule, flat and percent default to zero
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FeeRuleFields {
+    #[schema(example = "transfer")]
+    pub transaction_type: String,
+    #[schema(example = "standard")]
+    pub tier: Option<String>,
+    #[schema(value_type = String, example = "0.25")]
+    #[serde(default)]
+    pub flat: Decimal,
+    #[schema(value_type = String, example = "1.5")]
+    #[serde(default)]
+    pub percent: Decimal,
+    #[schema(value_type = Option<String>, example = "0.50")]
+    pub min_fee: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "10.00")]
+    pub max_fee: Option<Decimal>,
+    #[serde(default)]
+    pub bands: Vec<FeeBand>,
+    pub revenue_account: Uuid,
+}
+
+//what a transaction would cost, total is what leaves the account for withdrawls and transfers
+//and what is credited for deposits, where the fee is taken from the deposited amount
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FeeQuote {
+    #[schema(example = "transfer")]
+    pub transaction_type: String,
+    #[schema(value_type = String, example = "100.00")]
+    pub amount: Decimal,
+    #[schema(value_type = String, example = "1.75")]
+    pub fee: Decimal,
+    #[schema(value_type = String, example = "101.75")]
+    pub total: Decimal,
+}
+
+//the fee of one booking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fee {
+    pub amount: Decimal,
+    pub revenue_account: Uuid,
+}
+
+//lowercase letters, digits and _, up to 20 characters
+pub fn normalize_tier(raw: &str) -> Result<String, sqlx::Error> {
+    let tier = raw.trim().to_lowercase();
+    let valid = (1..=20).contains(&tier.len())
+        && tier.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    match valid {
+        true => Ok(tier),
+        false => Err(sqlx::Error::Encode(
+            "A tier has 1 to 20 lowercase letters, digits or _".into(),
+        )),
+    }
+}
+
+fn price(flat: Decimal, percent: Decimal, amount: Decimal) -> Decimal {
+    (flat + amount * percent / Decimal::ONE_HUNDRED).round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+}
+
+impl FeeRule {
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        let fee = match self.bands.iter().find(|b| b.up_to.is_none_or(|up_to| amount <= up_to)) {
+            Some(band) => price(band.flat, band.percent, amount),
+            None => price(self.flat, self.percent, amount),
+        };
+        let fee = self.min_fee.map_or(fee, |min| fee.max(min));
+        let mut fee = self.max_fee.map_or(fee, |max| fee.min(max));
+        fee.rescale(2);
+        fee
+    }
+}
+
+impl FeeRuleFields {
+    fn validate(&mut self) -> Result<(), sqlx::Error> {
+        let invalid = |message: &str| Err(sqlx::Error::Encode(message.to_string().into()));
+        self.transaction_type = self.transaction_type.trim().to_lowercase();
+        if !TRANSACTION_TYPES.contains(&self.transaction_type.as_str()) {
+            return invalid("transaction_type must be deposit, withdrawl or transfer");
+        }
+        self.tier = self.tier.as_deref().map(normalize_tier).transpose()?;
+
+        let cents = [Some(self.flat), self.min_fee, self.max_fee]
+            .into_iter()
+            .chain(self.bands.iter().flat_map(|b| [Some(b.flat), b.up_to]))
+            .flatten();
+        for v in cents {
+            if v < Decimal::ZERO || v.normalize().scale() > 2 {
+                return invalid("Fees and band limits must not be negative and have at most two decimal places");
+            }
+        }
+        let percents = std::iter::once(self.percent).chain(self.bands.iter().map(|b| b.percent));
+        for p in percents {
+            if p < Decimal::ZERO || p > Decimal::ONE_HUNDRED || p.normalize().scale() > 4 {
+                return invalid("A percent is between 0 and 100 with at most four decimal places");
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_fee, self.max_fee) {
+            if min > max {
+                return invalid("min_fee must not be above max_fee");
+            }
+        }
+        //ascending limits, only the last band may be open
+        let limits: Vec<Option<Decimal>> = self.bands.iter().map(|b| b.up_to).collect();
+        let open_before_last = limits.iter().rev().skip(1).any(Option::is_none);
+        let ascending = limits.windows(2).all(|w| match (w[0], w[1]) {
+            (Some(a), Some(b)) => a < b,
+            _ => true,
+        });
+        if open_before_last || !ascending {
+            return invalid("Bands must be in ascending order of up_to and only the last one may leave it out");
+        }
+        Ok(())
+    }
+}
+
+fn fee_rule(v: &PgRow) -> FeeRule {
+    let bands: Value = v.get("bands");
+    FeeRule {
+        rule_id: v.get("rule_id"),
+        transaction_type: v.get("transaction_type"),
+        tier: v.get("tier"),
+        flat: v.get("flat"),
+        percent: v.get("percent"),
+        min_fee: v.get("min_fee"),
+        max_fee: v.get("max_fee"),
+        bands: serde_json::from_value(bands).unwrap_or_default(),
+        revenue_account: v.get("revenue_account"),
+        created_at: v.get("created_at"),
+    }
+}
+
+//the rule for the payer's tier wins over the one for every tier
+//the revenue account pays no fees, and a fee of zero is no fee
+pub async fn find_fee<'e, E>(
+    executor: E,
+    payer: Uuid,
+    transaction_type: &str,
+    amount: Decimal,
+) -> Result<Option<Fee>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "SELECT r.* FROM fee_rules r JOIN account_balance a ON a.user_id = $1
+        where r.transaction_type = $2 and (r.tier = a.tier or r.tier IS NULL)
+        ORDER BY r.tier IS NULL LIMIT 1";
+    let rule = sqlx::query(qry)
+        .bind(payer)
+        .bind(transaction_type)
+        .fetch_optional(executor)
+        .await?
+        .map(|r| fee_rule(&r));
+    Ok(rule
+        .filter(|r| r.revenue_account != payer)
+        .map(|r| Fee {
+            amount: r.fee(amount),
+            revenue_account: r.revenue_account,
+        })
+        .filter(|f| !f.amount.is_zero()))
+}
+
+#[instrument(name = "db.quote_fee", skip(pool))]
+pub async fn quote_fee(
+    pool: &Pool<Postgres>,
+    payer: Uuid,
+    transaction_type: &str,
+    amount: Decimal,
+) -> Result<FeeQuote, sqlx::Error> {
+    validate_amount(amount)?;
+    let transaction_type = transaction_type.to_lowercase();
+    if !TRANSACTION_TYPES.contains(&transaction_type.as_str()) {
+        return Err(sqlx::Error::TypeNotFound {
+            type_name: transaction_type,
+        });
+    }
+    let fee = find_fee(pool, payer, &transaction_type, amount)
+        .await?
+        .map_or(Decimal::ZERO, |f| f.amount);
+    let total = match transaction_type.as_str() {
+        "deposit" if fee >= amount => return Err(TransactionError::FeeExceedsAmount.into()),
+        "deposit" => amount - fee,
+        _ => amount + fee,
+    };
+    Ok(FeeQuote {
+        transaction_type,
+        amount,
+        fee,
+        total,
+    })
+}
+
+//by transaction type, the rules for every tier last
+#[instrument(name = "db.list_fee_rules", skip(pool))]
+pub async fn list_fee_rules(pool: &Pool<Postgres>) -> Result<Vec<FeeRule>, sqlx::Error> {
+    let qry = "SELECT * FROM fee_rules ORDER BY transaction_type, tier NULLS LAST";
+
+    match sqlx::query(qry).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(fee_rule).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_fee_rules");
+            Err(e)
+        }
+    }
+}
+
+//one rule per transaction type and tier, a second one is a unique violation
+#[instrument(name = "db.create_fee_rule", skip(pool, fields))]
+pub async fn create_fee_rule(pool: &Pool<Postgres>, mut fields: FeeRuleFields) -> Result<FeeRule, sqlx::Error> {
+    fields.validate()?;
+    let bands = serde_json::to_value(&fields.bands).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    let qry = "INSERT INTO fee_rules(rule_id,transaction_type,tier,flat,percent,min_fee,max_fee,bands,revenue_account,created_at)
+        SELECT $1,$2,$3,$4,$5,$6,$7,$8,user_id,$10 FROM account_balance where user_id = $9 RETURNING *;";
+
+    //an unknown revenue account inserts nothing and is RowNotFound
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(&fields.transaction_type)
+        .bind(&fields.tier)
+        .bind(fields.flat)
+        .bind(fields.percent)
+        .bind(fields.min_fee)
+        .bind(fields.max_fee)
+        .bind(bands)
+        .bind(fields.revenue_account)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => {
+            let rule = fee_rule(&v);
+            info!(rule_id = %rule.rule_id, transaction_type = rule.transaction_type, "Fee rule created");
+            Ok(rule)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[instrument(name = "db.delete_fee_rule", skip(pool))]
+pub async fn delete_fee_rule(pool: &Pool<Postgres>, id: Uuid) -> Result<(), sqlx::Error> {
+    let qry = "DELETE FROM fee_rules where rule_id = $1";
+
+    match sqlx::query(qry).bind(id).execute(pool).await {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at delete_fee_rule");
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    use super::{FeeBand, FeeRule};
+
+    #[test]
+    fn test_fee_calculation() {
+        let rule = FeeRule {
+            rule_id: Uuid::new_v4(),
+            transaction_type: String::from("transfer"),
+            tier: None,
+            flat: dec!(0.25),
+            percent: dec!(1.5),
+            min_fee: Some(dec!(0.50)),
+            max_fee: Some(dec!(10)),
+            bands: vec![],
+            revenue_account: Uuid::new_v4(),
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        assert_eq!(rule.fee(dec!(10)), dec!(0.50));
+        assert_eq!(rule.fee(dec!(100)), dec!(1.75));
+        assert_eq!(rule.fee(dec!(0.99)), dec!(0.50));
+        assert_eq!(rule.fee(dec!(33.33)), dec!(0.75));
+        assert_eq!(rule.fee(dec!(5000)), dec!(10));
+
+        let tiered = FeeRule {
+            bands: vec![
+                FeeBand {
+                    up_to: Some(dec!(100)),
+                    flat: dec!(0),
+                    percent: dec!(2),
+                },
+                FeeBand {
+                    up_to: None,
+                    flat: dec!(1),
+                    percent: dec!(0),
+                },
+            ],
+            min_fee: None,
+            max_fee: None,
+            ..rule
+        };
+        assert_eq!(tiered.fee(dec!(100)), dec!(2));
+        assert_eq!(tiered.fee(dec!(100.01)), dec!(1));
+    }
+}
@@ -0,0 +1,435 @@
+//know your customer, every user has a level that decides what the account may do
+//users ask for a higher level with a submission of their profile data and documents, a reviewer approves or rejects it
+//the capabilities of a level are checked by the booking with the sender's balance row locked, next to the limit rules
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgRow, PgConnection, Pool, Postgres, Row};
+use tracing::{debug, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{models::notifications::notify, utilities::errors::TransactionError};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KycLevel {
+    #[default]
+    Unverified,
+    //legal name, date of birth and address
+    Basic,
+    //additionally an identity document
+    Full,
+}
+
+impl KycLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KycLevel::Unverified => "unverified",
+            KycLevel::Basic => "basic",
+            KycLevel::Full => "full",
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            KycLevel::Unverified => Capabilities {
+                transaction_types: &["deposit", "transfer"],
+                per_transaction: Some(dec!(100.00)),
+                monthly: Some(dec!(500.00)),
+            },
+            KycLevel::Basic => Capabilities {
+                transaction_types: &["deposit", "withdrawl", "transfer"],
+                per_transaction: Some(dec!(2000.00)),
+                monthly: Some(dec!(10000.00)),
+            },
+            KycLevel::Full => Capabilities {
+                transaction_types: &["deposit", "withdrawl", "transfer"],
+                per_transaction: None,
+                monthly: None,
+            },
+        }
+    }
+}
+
+impl FromStr for KycLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unverified" => Ok(KycLevel::Unverified),
+            "basic" => Ok(KycLevel::Basic),
+            "full" => Ok(KycLevel::Full),
+            _ => Err(format!("Unknown KYC level {}", s)),
+        }
+    }
+}
+
+//what an account of a level may do, on top of the limit rules of its tier
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Capabilities {
+    //the transaction types the account may send
+    #[schema(value_type = Vec<String>, example = json!(["deposit", "transfer"]))]
+    pub transaction_types: &'static [&'static str],
+    #[schema(value_type = Option<String>, example = "100.00")]
+    pub per_transaction: Option<Decimal>,
+    //all transactions sent in the calendar month together
+    #[schema(value_type = Option<String>, example = "500.00")]
+    pub monthly: Option<Decimal>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct KycSubmission {
+    pub submission_id: Uuid,
+    pub user_id: Uuid,
+    pub level: KycLevel,
+    #[schema(example = "Alice Jane Doe")]
+    pub legal_name: String,
+    pub date_of_birth: NaiveDate,
+    #[schema(example = "Hauptstrasse 1")]
+    pub address_line1: String,
+    pub address_line2: Option<String>,
+    #[schema(example = "Berlin")]
+    pub city: String,
+    #[schema(example = "10115")]
+    pub postal_code: String,
+    //ISO 3166-1 alpha-2
+    #[schema(example = "DE")]
+    pub country: String,
+    //passport, national_id or driving_licence
+    #[schema(example = "passport")]
+    pub document_type: Option<String>,
+    //where the document is kept, e.g. the id of the uploaded scan
+    #[schema(example = "doc_7f3a9c")]
+    pub document_reference: Option<String>,
+    //pending, approved or rejected
+    #[schema(example = "pending")]
+    pub status: String,
+    pub submitted_at: NaiveDateTime,
+    pub reviewed_by: Option<Uuid>,
+    pub reviewed_at: Option<NaiveDateTime>,
+    //why it was rejected
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct KycSubmissionFields {
+    //basic or full
+    pub level: KycLevel,
+    #[schema(example = "Alice Jane Doe")]
+    pub legal_name: String,
+    #[schema(value_type = String, example = "1990-04-23")]
+    pub date_of_birth: NaiveDate,
+    #[schema(example = "Hauptstrasse 1")]
+    pub address_line1: String,
+    pub address_line2: Option<String>,
+    #[schema(example = "Berlin")]
+    pub city: String,
+    #[schema(example = "10115")]
+    pub postal_code: String,
+    #[schema(example = "DE")]
+    pub country: String,
+    //required for full
+    #[schema(example = "passport")]
+    pub document_type: Option<String>,
+    #[schema(example = "doc_7f3a9c")]
+    pub document_reference: Option<String>,
+}
+
+const DOCUMENT_TYPES: [&str; 3] = ["passport", "national_id", "driving_licence"];
+const MIN_AGE: i32 = 18;
+const MAX_TEXT_LEN: usize = 200;
+
+fn invalid(message: &str) -> sqlx::Error {
+    sqlx::Error::Encode(message.into())
+}
+
+impl KycSubmissionFields {
+    //trims every text, empty optional texts count as not given
+    pub fn validate(mut self, current: KycLevel, today: NaiveDate) -> Result<KycSubmissionFields, sqlx::Error> {
+        if self.level == KycLevel::Unverified {
+            return Err(invalid("Level must be basic or full"));
+        }
+        if self.level <= current {
+            return Err(invalid("The account is already verified at this level"));
+        }
+        for v in [&mut self.legal_name, &mut self.address_line1, &mut self.city, &mut self.postal_code] {
+            *v = v.trim().to_string();
+            if v.is_empty() || v.chars().count() > MAX_TEXT_LEN {
+                return Err(invalid("Legal name, address line, city and postal code are required, up to 200 characters each"));
+            }
+        }
+        for v in [&mut self.address_line2, &mut self.document_type, &mut self.document_reference] {
+            *v = v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+            if v.as_ref().is_some_and(|v| v.chars().count() > MAX_TEXT_LEN) {
+                return Err(invalid("Texts cannot be longer than 200 characters"));
+            }
+        }
+        self.country = self.country.trim().to_uppercase();
+        if self.country.len() != 2 || !self.country.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(invalid("Country must be a two letter ISO 3166 code"));
+        }
+        let birthday = (self.date_of_birth.month(), self.date_of_birth.day());
+        let age = today.year() - self.date_of_birth.year() - i32::from((today.month(), today.day()) < birthday);
+        if age < MIN_AGE || self.date_of_birth.year() < 1900 {
+            return Err(invalid("The account holder must be at least 18 years old"));
+        }
+        if let Some(t) = self.document_type.as_deref().filter(|t| !DOCUMENT_TYPES.contains(t)) {
+            return Err(invalid(&format!("Unknown document type {}, expected passport, national_id or driving_licence", t)));
+        }
+        if self.level == KycLevel::Full && (self.document_type.is_none() || self.document_reference.is_none()) {
+            return Err(invalid("Full verification needs a document_type and a document_reference"));
+        }
+        Ok(self)
+    }
+}
+
+fn kyc_submission(v: &PgRow) -> KycSubmission {
+    KycSubmission {
+        submission_id: v.get("submission_id"),
+        user_id: v.get("user_id"),
+        level: v.get::<String, _>("level").parse().unwrap_or_default(),
+        legal_name: v.get("legal_name"),
+        date_of_birth: v.get("date_of_birth"),
+        address_line1: v.get("address_line1"),
+        address_line2: v.get("address_line2"),
+        city: v.get("city"),
+        postal_code: v.get("postal_code"),
+        country: v.get("country"),
+        document_type: v.get("document_type"),
+        document_reference: v.get("document_reference"),
+        status: v.get("status"),
+        submitted_at: v.get("submitted_at"),
+        reviewed_by: v.get("reviewed_by"),
+        reviewed_at: v.get("reviewed_at"),
+        reason: v.get("reason"),
+    }
+}
+
+pub async fn get_kyc_level<'e, E>(executor: E, uid: Uuid) -> Result<KycLevel, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    let level: String = sqlx::query_scalar("SELECT kyc_level FROM users where user_id = $1")
+        .bind(uid)
+        .fetch_one(executor)
+        .await?;
+    //the column is constrained to the known levels, anything else gets the least capabilities
+    Ok(level.parse().unwrap_or_default())
+}
+
+pub async fn set_kyc_level<'e, E>(executor: E, uid: Uuid, level: KycLevel) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    sqlx::query("UPDATE users SET kyc_level = $1, updated_at = $2 where user_id = $3")
+        .bind(level.as_str())
+        .bind(Utc::now())
+        .bind(uid)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+//called by the booking with the sender's balance row locked
+pub async fn enforce_kyc(
+    conn: &mut PgConnection,
+    sender: Uuid,
+    transaction_type: &str,
+    amount: Decimal,
+) -> Result<(), sqlx::Error> {
+    let capabilities = get_kyc_level(&mut *conn, sender).await?.capabilities();
+    if !capabilities.transaction_types.contains(&transaction_type) {
+        return Err(TransactionError::KycLevelRequired.into());
+    }
+    if capabilities.per_transaction.is_some_and(|l| amount > l) {
+        return Err(TransactionError::KycLimitExceeded.into());
+    }
+    if let Some(monthly) = capabilities.monthly {
+        let qry = "SELECT COALESCE(SUM(amount), 0) FROM transactions where sender_id = $1 and status <> 'failed'
+            and transaction_type <> 'fee' and created_at >= date_trunc('month', LOCALTIMESTAMP)";
+        let sent: Decimal = sqlx::query_scalar(qry).bind(sender).fetch_one(&mut *conn).await?;
+        if sent + amount > monthly {
+            return Err(TransactionError::KycLimitExceeded.into());
+        }
+    }
+    Ok(())
+}
+
+//the latest submission of the user, none before the first one
+#[instrument(name = "db.latest_kyc_submission", skip(pool))]
+pub async fn latest_kyc_submission(pool: &Pool<Postgres>, uid: Uuid) -> Result<Option<KycSubmission>, sqlx::Error> {
+    let qry = "SELECT * FROM kyc_submissions where user_id = $1 ORDER BY submitted_at DESC, id DESC LIMIT 1";
+
+    sqlx::query(qry)
+        .bind(uid)
+        .fetch_optional(pool)
+        .await
+        .map(|v| v.as_ref().map(kyc_submission))
+}
+
+//a user has one pending submission at most, a second one is a unique violation
+#[instrument(name = "db.submit_kyc", skip(pool, fields))]
+pub async fn submit_kyc(pool: &Pool<Postgres>, uid: Uuid, fields: KycSubmissionFields) -> Result<KycSubmission, sqlx::Error> {
+    let current = get_kyc_level(pool, uid).await?;
+    let f = fields.validate(current, Utc::now().date_naive())?;
+    let qry = "INSERT INTO kyc_submissions(submission_id,user_id,level,legal_name,date_of_birth,address_line1,address_line2,city,postal_code,country,document_type,document_reference,submitted_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13) RETURNING *;";
+
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(uid)
+        .bind(f.level.as_str())
+        .bind(&f.legal_name)
+        .bind(f.date_of_birth)
+        .bind(&f.address_line1)
+        .bind(&f.address_line2)
+        .bind(&f.city)
+        .bind(&f.postal_code)
+        .bind(&f.country)
+        .bind(&f.document_type)
+        .bind(&f.document_reference)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => {
+            info!(level = f.level.as_str(), "KYC submitted");
+            Ok(kyc_submission(&v))
+        }
+        Err(e) => {
+            debug!(error = %e, "Error at submit_kyc");
+            Err(e)
+        }
+    }
+}
+
+//oldest first, the order they are worked through
+#[instrument(name = "db.list_kyc_submissions", skip(pool))]
+pub async fn list_kyc_submissions(pool: &Pool<Postgres>, status: &str) -> Result<Vec<KycSubmission>, sqlx::Error> {
+    let qry = "SELECT * FROM kyc_submissions where status = $1 ORDER BY submitted_at, id";
+
+    sqlx::query(qry)
+        .bind(status)
+        .fetch_all(pool)
+        .await
+        .map(|v| v.iter().map(kyc_submission).collect())
+}
+
+#[instrument(name = "db.get_kyc_submission", skip(pool))]
+pub async fn get_kyc_submission(pool: &Pool<Postgres>, id: Uuid) -> Result<KycSubmission, sqlx::Error> {
+    let qry = "SELECT * FROM kyc_submissions where submission_id = $1";
+
+    sqlx::query(qry).bind(id).fetch_one(pool).await.map(|v| kyc_submission(&v))
+}
+
+//approving raises the user to the submitted level, rejecting needs a reason the user is told
+#[instrument(name = "db.review_kyc", skip(pool, reason))]
+pub async fn review_kyc(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+    approve: bool,
+    reviewed_by: Uuid,
+    reason: Option<&str>,
+) -> Result<KycSubmission, sqlx::Error> {
+    let reason = reason.map(str::trim).filter(|r| !r.is_empty());
+    if !approve && reason.is_none() {
+        return Err(invalid("A reason is required to reject a submission"));
+    }
+    let status = match approve {
+        true => "approved",
+        false => "rejected",
+    };
+    let mut tx = pool.begin().await?;
+    let reviewed: Result<KycSubmission, sqlx::Error> = async {
+        let row = sqlx::query("SELECT * FROM kyc_submissions where submission_id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let submission = kyc_submission(&row);
+        if submission.status != "pending" {
+            return Err(TransactionError::ReviewClosed.into());
+        }
+        if submission.user_id == reviewed_by {
+            return Err(TransactionError::SelfReview.into());
+        }
+        if approve {
+            set_kyc_level(&mut *tx, submission.user_id, submission.level).await?;
+        }
+        let qry = "UPDATE kyc_submissions SET status = $1, reviewed_by = $2, reviewed_at = $3, reason = $4 where submission_id = $5 RETURNING *";
+        let row = sqlx::query(qry)
+            .bind(status)
+            .bind(reviewed_by)
+            .bind(Utc::now().naive_utc())
+            .bind(reason)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let (kind, message) = match approve {
+            true => ("kyc_approved", format!("Your account is verified at level {}", submission.level.as_str())),
+            false => ("kyc_rejected", format!("Your verification was rejected: {}", reason.unwrap_or_default())),
+        };
+        notify(&mut *tx, submission.user_id, kind, &message, json!({ "submission_id": id, "level": submission.level })).await?;
+        Ok(kyc_submission(&row))
+    }
+    .await;
+
+    match reviewed {
+        Ok(v) => {
+            tx.commit().await?;
+            info!(submission_id = %id, status, by = %reviewed_by, "KYC reviewed");
+            Ok(v)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{KycLevel, KycSubmissionFields};
+
+    #[test]
+    fn test_kyc_submission_checks() {
+        let today = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        let fields = KycSubmissionFields {
+            level: KycLevel::Basic,
+            legal_name: String::from(" Alice Doe "),
+            date_of_birth: NaiveDate::from_ymd_opt(2007, 5, 31).unwrap(),
+            address_line1: String::from("Hauptstrasse 1"),
+            address_line2: Some(String::from(" ")),
+            city: String::from("Berlin"),
+            postal_code: String::from("10115"),
+            country: String::from("de"),
+            document_type: None,
+            document_reference: None,
+        };
+        let checked = fields.clone().validate(KycLevel::Unverified, today).unwrap();
+        assert_eq!(checked.legal_name, "Alice Doe");
+        assert_eq!(checked.country, "DE");
+        assert_eq!(checked.address_line2, None);
+
+        let too_young = KycSubmissionFields {
+            date_of_birth: NaiveDate::from_ymd_opt(2007, 6, 2).unwrap(),
+            ..fields.clone()
+        };
+        let no_document = KycSubmissionFields {
+            level: KycLevel::Full,
+            ..fields.clone()
+        };
+        for (bad, current) in [(too_young, KycLevel::Unverified), (no_document, KycLevel::Unverified), (fields, KycLevel::Basic)] {
+            assert!(bad.validate(current, today).is_err());
+        }
+
+        assert!(!KycLevel::Unverified.capabilities().transaction_types.contains(&"withdrawl"));
+        assert_eq!(KycLevel::Full.capabilities().monthly, None);
+    }
+}
@@ -0,0 +1,329 @@
+//transaction limits, a limit rule caps one transaction type for every account or only for one account tier
+//the amount of a single transaction, the totals of the day and the month, and the number of
+//transactions in the last hour are checked while the sender's balance row is locked, so concurrent
+//bookings of one account cannot pass a limit together
+//days and months are calendar days and months of the database clock, like the beneficiary limits
+use chrono::{NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, Executor, PgConnection, Pool, Postgres, Row};
+use tracing::{error, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::fees::{normalize_tier, TRANSACTION_TYPES},
+    utilities::errors::TransactionError,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LimitRule {
+    pub rule_id: Uuid,
+    #[schema(example = "withdrawl")]
+    pub transaction_type: String,
+    //None applies to every tier without a rule of its own
+    #[schema(example = "standard")]
+    pub tier: Option<String>,
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub per_transaction: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "2500.00")]
+    pub daily: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "20000.00")]
+    pub monthly: Option<Decimal>,
+    //transactions in the last 60 minutes
+    #[schema(example = 10)]
+    pub hourly_count: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+//a new rule, a limit left out is not limited
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LimitRuleFields {
+    #[schema(example = "withdrawl")]
+    pub transaction_type: String,
+    #[schema(example = "standard")]
+    pub tier: Option<String>,
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub per_transaction: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "2500.00")]
+    pub daily: Option<Decimal>,
+    #[schema(value_type = Option<String>, example = "20000.00")]
+    pub monthly: Option<Decimal>,
+    #[schema(example = 10)]
+    pub hourly_count: Option<i32>,
+}
+
+//an amount limit of a period, used is what was booked in it so far
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AmountHeadroom {
+    #[schema(value_type = String, example = "2500.00")]
+    pub limit: Decimal,
+    #[schema(value_type = String, example = "400.00")]
+    pub used: Decimal,
+    #[schema(value_type = String, example = "2100.00")]
+    pub remaining: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CountHeadroom {
+    #[schema(example = 10)]
+    pub limit: i64,
+    #[schema(example = 3)]
+    pub used: i64,
+    #[schema(example = 7)]
+    pub remaining: i64,
+}
+
+//what is left of the limits of one transaction type, None where it is not limited
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LimitHeadroom {
+    #[schema(example = "withdrawl")]
+    pub transaction_type: String,
+    #[schema(value_type = Option<String>, example = "1000.00")]
+    pub per_transaction: Option<Decimal>,
+    pub daily: Option<AmountHeadroom>,
+    pub monthly: Option<AmountHeadroom>,
+    pub hourly_count: Option<CountHeadroom>,
+}
+
+//transactions of one type sent by the account in the current periods, held ones included since they complete when approved
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    pub daily: Decimal,
+    pub monthly: Decimal,
+    pub hourly_count: i64,
+}
+
+impl LimitRule {
+    //the first limit the transaction would break
+    pub fn check(&self, amount: Decimal, usage: Usage) -> Result<(), TransactionError> {
+        if self.per_transaction.is_some_and(|l| amount > l) {
+            return Err(TransactionError::TransactionLimitExceeded);
+        }
+        if self.daily.is_some_and(|l| usage.daily + amount > l) {
+            return Err(TransactionError::DailyLimitExceeded);
+        }
+        if self.monthly.is_some_and(|l| usage.monthly + amount > l) {
+            return Err(TransactionError::MonthlyLimitExceeded);
+        }
+        if self.hourly_count.is_some_and(|l| usage.hourly_count >= l as i64) {
+            return Err(TransactionError::VelocityLimitExceeded);
+        }
+        Ok(())
+    }
+
+    pub fn headroom(&self, usage: Usage) -> LimitHeadroom {
+        let amount = |limit: Decimal, used: Decimal| AmountHeadroom {
+            limit,
+            used,
+            remaining: (limit - used).max(Decimal::ZERO),
+        };
+        LimitHeadroom {
+            transaction_type: self.transaction_type.clone(),
+            per_transaction: self.per_transaction,
+            daily: self.daily.map(|l| amount(l, usage.daily)),
+            monthly: self.monthly.map(|l| amount(l, usage.monthly)),
+            hourly_count: self.hourly_count.map(|l| CountHeadroom {
+                limit: l as i64,
+                used: usage.hourly_count,
+                remaining: (l as i64 - usage.hourly_count).max(0),
+            }),
+        }
+    }
+}
+
+impl LimitRuleFields {
+    fn validate(&mut self) -> Result<(), sqlx::Error> {
+        let invalid = |message: &str| Err(sqlx::Error::Encode(message.to_string().into()));
+        self.transaction_type = self.transaction_type.trim().to_lowercase();
+        if !TRANSACTION_TYPES.contains(&self.transaction_type.as_str()) {
+            return invalid("transaction_type must be deposit, withdrawl or transfer");
+        }
+        self.tier = self.tier.as_deref().map(normalize_tier).transpose()?;
+
+        let amounts = [self.per_transaction, self.daily, self.monthly];
+        if amounts.iter().flatten().any(|v| *v <= Decimal::ZERO || v.normalize().scale() > 2) {
+            return invalid("Limits must be greater than zero with at most two decimal places");
+        }
+        if self.hourly_count.is_some_and(|c| c < 1) {
+            return invalid("hourly_count must be at least 1");
+        }
+        if amounts.iter().all(Option::is_none) && self.hourly_count.is_none() {
+            return invalid("A rule needs at least one limit");
+        }
+        Ok(())
+    }
+}
+
+fn limit_rule(v: &PgRow) -> LimitRule {
+    LimitRule {
+        rule_id: v.get("rule_id"),
+        transaction_type: v.get("transaction_type"),
+        tier: v.get("tier"),
+        per_transaction: v.get("per_transaction"),
+        daily: v.get("daily"),
+        monthly: v.get("monthly"),
+        hourly_count: v.get("hourly_count"),
+        created_at: v.get("created_at"),
+    }
+}
+
+//the rules of the account's tier win over the ones for every tier, one rule per transaction type
+async fn account_rules<'e, E>(executor: E, uid: Uuid, transaction_type: Option<&str>) -> Result<Vec<LimitRule>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "SELECT DISTINCT ON (r.transaction_type) r.* FROM limit_rules r JOIN account_balance a ON a.user_id = $1
+        where (r.tier = a.tier or r.tier IS NULL) and ($2::text IS NULL or r.transaction_type = $2)
+        ORDER BY r.transaction_type, r.tier IS NULL";
+
+    let rows = sqlx::query(qry).bind(uid).bind(transaction_type).fetch_all(executor).await?;
+    Ok(rows.iter().map(limit_rule).collect())
+}
+
+async fn usage<'e, E>(executor: E, uid: Uuid, transaction_type: &str) -> Result<Usage, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    //the last hour may reach into the previous month
+    let qry = "SELECT
+            COALESCE(SUM(amount) FILTER (where created_at >= CURRENT_DATE), 0) AS daily,
+            COALESCE(SUM(amount) FILTER (where created_at >= date_trunc('month', LOCALTIMESTAMP)), 0) AS monthly,
+            COUNT(*) FILTER (where created_at >= LOCALTIMESTAMP - INTERVAL '1 hour') AS hourly_count
+        FROM transactions
+        where sender_id = $1 and transaction_type = $2 and status <> 'failed'
+        and created_at >= LEAST(date_trunc('month', LOCALTIMESTAMP), LOCALTIMESTAMP - INTERVAL '1 hour')";
+
+    let v = sqlx::query(qry).bind(uid).bind(transaction_type).fetch_one(executor).await?;
+    Ok(Usage {
+        daily: v.get("daily"),
+        monthly: v.get("monthly"),
+        hourly_count: v.get("hourly_count"),
+    })
+}
+
+//called by the booking with the sender's balance row locked
+pub async fn enforce_limits(
+    conn: &mut PgConnection,
+    sender: Uuid,
+    transaction_type: &str,
+    amount: Decimal,
+) -> Result<(), sqlx::Error> {
+    let rule = match account_rules(&mut *conn, sender, Some(transaction_type)).await?.pop() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let usage = usage(&mut *conn, sender, transaction_type).await?;
+    rule.check(amount, usage).map_err(Into::into)
+}
+
+//the headroom of every limited transaction type of the account
+#[instrument(name = "db.account_limits", skip(pool))]
+pub async fn account_limits(pool: &Pool<Postgres>, uid: Uuid) -> Result<Vec<LimitHeadroom>, sqlx::Error> {
+    let mut headroom = vec![];
+    for rule in account_rules(pool, uid, None).await? {
+        let usage = usage(pool, uid, &rule.transaction_type).await?;
+        headroom.push(rule.headroom(usage));
+    }
+    Ok(headroom)
+}
+
+//by transaction type, the rules for every tier last
+#[instrument(name = "db.list_limit_rules", skip(pool))]
+pub async fn list_limit_rules(pool: &Pool<Postgres>) -> Result<Vec<LimitRule>, sqlx::Error> {
+    let qry = "SELECT * FROM limit_rules ORDER BY transaction_type, tier NULLS LAST";
+
+    match sqlx::query(qry).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(limit_rule).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_limit_rules");
+            Err(e)
+        }
+    }
+}
+
+//one rule per transaction type and tier, a second one is a unique violation
+#[instrument(name = "db.create_limit_rule", skip(pool, fields))]
+pub async fn create_limit_rule(pool: &Pool<Postgres>, mut fields: LimitRuleFields) -> Result<LimitRule, sqlx::Error> {
+    fields.validate()?;
+    let qry = "INSERT INTO limit_rules(rule_id,transaction_type,tier,per_transaction,daily,monthly,hourly_count,created_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8) RETURNING *;";
+
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(&fields.transaction_type)
+        .bind(&fields.tier)
+        .bind(fields.per_transaction)
+        .bind(fields.daily)
+        .bind(fields.monthly)
+        .bind(fields.hourly_count)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => {
+            let rule = limit_rule(&v);
+            info!(rule_id = %rule.rule_id, transaction_type = rule.transaction_type, "Limit rule created");
+            Ok(rule)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[instrument(name = "db.delete_limit_rule", skip(pool))]
+pub async fn delete_limit_rule(pool: &Pool<Postgres>, id: Uuid) -> Result<(), sqlx::Error> {
+    let qry = "DELETE FROM limit_rules where rule_id = $1";
+
+    match sqlx::query(qry).bind(id).execute(pool).await {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at delete_limit_rule");
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    use super::{LimitRule, Usage};
+    use crate::utilities::errors::TransactionError;
+
+    #[test]
+    fn test_limit_checks() {
+        let rule = LimitRule {
+            rule_id: Uuid::new_v4(),
+            transaction_type: String::from("withdrawl"),
+            tier: None,
+            per_transaction: Some(dec!(500)),
+            daily: Some(dec!(1000)),
+            monthly: Some(dec!(3000)),
+            hourly_count: Some(3),
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        let usage = Usage {
+            daily: dec!(600),
+            monthly: dec!(2600),
+            hourly_count: 2,
+        };
+        assert_eq!(rule.check(dec!(400), usage), Ok(()));
+        assert_eq!(rule.check(dec!(500.01), usage), Err(TransactionError::TransactionLimitExceeded));
+        assert_eq!(rule.check(dec!(400.01), usage), Err(TransactionError::DailyLimitExceeded));
+        let usage = Usage { daily: dec!(0), ..usage };
+        assert_eq!(rule.check(dec!(450), usage), Err(TransactionError::MonthlyLimitExceeded));
+        let usage = Usage { hourly_count: 3, ..usage };
+        assert_eq!(rule.check(dec!(1), usage), Err(TransactionError::VelocityLimitExceeded));
+
+        let headroom = rule.headroom(Usage {
+            daily: dec!(1200),
+            monthly: dec!(1200),
+            hourly_count: 1,
+        });
+        assert_eq!(headroom.daily.unwrap().remaining, dec!(0));
+        assert_eq!(headroom.monthly.unwrap().remaining, dec!(1800));
+        assert_eq!(headroom.hourly_count.unwrap().remaining, 2);
+    }
+}
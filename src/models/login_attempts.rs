@@ -0,0 +1,176 @@
+//brute-force protection of the logins, failed attempts are counted per e-mail address
+//after rate_limit.lockout_threshold failures in a row the address is locked, every further failure doubles the lock
+//a successful login starts over, and so does a failure after a quiet period of rate_limit.lockout_max_secs
+//wrong one-time passwords of a logged in user (elevate, X-OTP, 2FA settings) lock the same way, per user under otp:<user_id>
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{PgConnection, Pool, Postgres};
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use crate::{config::settings::RateLimitSettings, utilities::telemetry::mask_email};
+
+fn login_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+//e-mail addresses have an @, so the keys cannot collide
+fn otp_key(uid: Uuid) -> String {
+    format!("otp:{}", uid)
+}
+
+//seconds the address is locked after its failures-th failure in a row, none below the threshold
+pub fn lock_secs(failures: i32, settings: &RateLimitSettings) -> Option<i64> {
+    if failures < settings.lockout_threshold {
+        return None;
+    }
+    let doublings = (failures - settings.lockout_threshold).min(30) as u32;
+    Some(
+        settings
+            .lockout_base_secs
+            .saturating_mul(1 << doublings)
+            .min(settings.lockout_max_secs),
+    )
+}
+
+async fn locked_for(pool: &Pool<Postgres>, key: &str) -> Result<Option<i64>, sqlx::Error> {
+    let locked_until: Option<Option<NaiveDateTime>> = sqlx::query_scalar("SELECT locked_until FROM login_attempts where email = $1")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+    let now = Utc::now().naive_utc();
+    Ok(locked_until
+        .flatten()
+        .filter(|until| *until > now)
+        .map(|until| (until - now).num_seconds().max(1)))
+}
+
+async fn record_failure(pool: &Pool<Postgres>, key: &str, settings: &RateLimitSettings) -> Result<Option<i64>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let qry = "INSERT INTO login_attempts(email,failures,last_failure_at) Values ($1,1,$2)
+        ON CONFLICT (email) DO UPDATE SET
+            failures = CASE WHEN login_attempts.last_failure_at < $3 THEN 1 ELSE login_attempts.failures + 1 END,
+            last_failure_at = $2
+        RETURNING failures";
+    let failures: i32 = sqlx::query_scalar(qry)
+        .bind(key)
+        .bind(now)
+        .bind(now - Duration::seconds(settings.lockout_max_secs))
+        .fetch_one(pool)
+        .await?;
+
+    let lock = lock_secs(failures, settings);
+    if let Some(secs) = lock {
+        sqlx::query("UPDATE login_attempts SET locked_until = $2 where email = $1")
+            .bind(key)
+            .bind(now + Duration::seconds(secs))
+            .execute(pool)
+            .await?;
+        warn!(failures, lock_secs = secs, "Locked after repeated failures");
+    }
+    Ok(lock)
+}
+
+async fn clear_failures(pool: &Pool<Postgres>, key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_attempts where email = $1")
+        .bind(key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+//seconds until the address may try again, none when it is not locked
+#[instrument(name = "db.login_locked_for", skip_all)]
+pub async fn login_locked_for(pool: &Pool<Postgres>, email: &str) -> Result<Option<i64>, sqlx::Error> {
+    locked_for(pool, &login_key(email)).await
+}
+
+//counts a wrong password or one-time password, returns the lock in seconds when this failure locks the address
+#[instrument(name = "db.record_login_failure", skip(pool, email, settings), fields(email = %mask_email(email)))]
+pub async fn record_login_failure(pool: &Pool<Postgres>, email: &str, settings: &RateLimitSettings) -> Result<Option<i64>, sqlx::Error> {
+    record_failure(pool, &login_key(email), settings).await
+}
+
+#[instrument(name = "db.clear_login_failures", skip_all)]
+pub async fn clear_login_failures(pool: &Pool<Postgres>, email: &str) -> Result<(), sqlx::Error> {
+    clear_failures(pool, &login_key(email)).await
+}
+
+//a password reset proves the inbox, so both the login and the one-time password lockout end
+pub async fn clear_lockouts(conn: &mut PgConnection, uid: Uuid, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_attempts where email = $1 or email = $2")
+        .bind(login_key(email))
+        .bind(otp_key(uid))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[instrument(name = "db.otp_locked_for", skip(pool))]
+pub async fn otp_locked_for(pool: &Pool<Postgres>, uid: Uuid) -> Result<Option<i64>, sqlx::Error> {
+    locked_for(pool, &otp_key(uid)).await
+}
+
+#[instrument(name = "db.record_otp_failure", skip(pool, settings))]
+pub async fn record_otp_failure(pool: &Pool<Postgres>, uid: Uuid, settings: &RateLimitSettings) -> Result<Option<i64>, sqlx::Error> {
+    record_failure(pool, &otp_key(uid), settings).await
+}
+
+#[instrument(name = "db.clear_otp_failures", skip(pool))]
+pub async fn clear_otp_failures(pool: &Pool<Postgres>, uid: Uuid) -> Result<(), sqlx::Error> {
+    clear_failures(pool, &otp_key(uid)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value};
+
+    use super::lock_secs;
+    use crate::{
+        config::settings::RateLimitSettings,
+        utilities::test_harness::TestDb,
+    };
+
+    #[actix_web::test]
+    async fn test_login_lockout() {
+        let settings = RateLimitSettings::default();
+        assert_eq!(lock_secs(4, &settings), None);
+        assert_eq!(lock_secs(5, &settings), Some(30));
+        assert_eq!(lock_secs(6, &settings), Some(60));
+        assert_eq!(lock_secs(12, &settings), Some(3600));
+        assert_eq!(lock_secs(1000, &settings), Some(3600));
+
+        let db = TestDb::new().await;
+        let user = db.user().create().await;
+        let app = db.app().await;
+        let login = |password: &str| json!({"email": user.email, "password": password});
+
+        for _ in 0..5 {
+            let req = test::TestRequest::post().uri("/v1/sessions").set_json(login("wrong")).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        }
+        //the right password does not help while the address is locked, whatever its case
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(&user.password)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: i64 = resp.headers().get("retry-after").unwrap().to_str().unwrap().parse().unwrap();
+        assert!((1..=30).contains(&retry_after));
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "login_locked");
+        let req = test::TestRequest::get()
+            .uri("/user/get_token")
+            .set_json(json!({"email": user.email.to_uppercase(), "password": user.password}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        //once the lock ran out, a login succeeds and starts over
+        sqlx::query("UPDATE login_attempts SET locked_until = locked_until - INTERVAL '1 hour'")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(login(&user.password)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_attempts").fetch_one(&db.pool).await.unwrap();
+        assert_eq!(count, 0);
+    }
+}
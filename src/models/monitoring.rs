@@ -0,0 +1,358 @@
+//transaction monitoring, every booking is checked against the rules in use while the sender's row is locked
+//review holds the transaction as pending with the sender's money set aside, an analyst approves or rejects it
+//block refuses the transaction, both leave an alert behind
+use chrono::{NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{postgres::PgRow, Executor, PgConnection, Pool, Postgres, Row};
+use tracing::{error, info, instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        fees::Fee,
+        notifications::notify,
+        transactions::settle_held_transaction,
+    },
+    utilities::{
+        errors::TransactionError,
+        monitoring::{Action, Condition, MonitoringRule, Rules},
+    },
+};
+
+//the strongest action of the matching rules and their names
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Verdict {
+    pub action: Action,
+    pub rules: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    //held, waiting for an analyst
+    Open,
+    Approved,
+    Rejected,
+    //refused right away
+    Blocked,
+}
+
+impl AlertStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertStatus::Open => "open",
+            AlertStatus::Approved => "approved",
+            AlertStatus::Rejected => "rejected",
+            AlertStatus::Blocked => "blocked",
+        }
+    }
+
+    fn from_db(v: &str) -> AlertStatus {
+        match v {
+            "approved" => AlertStatus::Approved,
+            "rejected" => AlertStatus::Rejected,
+            "blocked" => AlertStatus::Blocked,
+            _ => AlertStatus::Open,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Alert {
+    pub alert_id: Uuid,
+    //the held transaction, none for blocked ones
+    pub transaction_id: Option<Uuid>,
+    pub user_id: Uuid,
+    pub receiver_id: Option<Uuid>,
+    #[schema(example = "transfer")]
+    pub transaction_type: String,
+    #[schema(value_type = String, example = "9500.00")]
+    pub amount: Decimal,
+    pub action: Action,
+    //names of the matching rules
+    pub rules: Vec<String>,
+    pub status: AlertStatus,
+    pub created_at: NaiveDateTime,
+    pub decided_by: Option<Uuid>,
+    pub decided_at: Option<NaiveDateTime>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct AlertFilter {
+    //open (the default), approved, rejected or blocked
+    pub status: Option<String>,
+}
+
+fn alert(v: &PgRow) -> Alert {
+    let rules: Value = v.get("rules");
+    let action: String = v.get("action");
+    Alert {
+        alert_id: v.get("alert_id"),
+        transaction_id: v.get("transaction_id"),
+        user_id: v.get("user_id"),
+        receiver_id: v.get("receiver_id"),
+        transaction_type: v.get("transaction_type"),
+        amount: v.get("amount"),
+        action: match action.as_str() {
+            "block" => Action::Block,
+            _ => Action::Review,
+        },
+        rules: serde_json::from_value(rules).unwrap_or_default(),
+        status: AlertStatus::from_db(v.get("status")),
+        created_at: v.get("created_at"),
+        decided_by: v.get("decided_by"),
+        decided_at: v.get("decided_at"),
+        note: v.get("note"),
+    }
+}
+
+//the booking the rules look at
+pub struct Candidate<'a> {
+    pub sender: Uuid,
+    pub receiver: Option<Uuid>,
+    pub transaction_type: &'a str,
+    pub amount: Decimal,
+}
+
+//rules with action allow are not looked at, they have no effect
+pub async fn evaluate(conn: &mut PgConnection, rules: &Rules, candidate: &Candidate<'_>) -> Result<Verdict, sqlx::Error> {
+    let rules = rules.current();
+    let mut verdict = Verdict::default();
+    if rules.rules.iter().all(|r| r.action == Action::Allow) {
+        return Ok(verdict);
+    }
+    let tier: String = sqlx::query_scalar("SELECT tier FROM account_balance where user_id = $1")
+        .bind(candidate.sender)
+        .fetch_one(&mut *conn)
+        .await?;
+    for rule in rules.rules.iter() {
+        if rule.action == Action::Allow || !rule.applies_to(candidate.transaction_type, &tier) {
+            continue;
+        }
+        if matches(conn, rule, candidate).await? {
+            verdict.action = verdict.action.max(rule.action);
+            verdict.rules.push(rule.name.clone());
+        }
+    }
+    Ok(verdict)
+}
+
+async fn matches(conn: &mut PgConnection, rule: &MonitoringRule, c: &Candidate<'_>) -> Result<bool, sqlx::Error> {
+    //the sender's earlier transactions of the types the rule looks at, refused ones and fee legs left out
+    let types = Some(rule.transaction_types.clone()).filter(|t| !t.is_empty());
+    let earlier = "FROM transactions where sender_id = $1 and status <> 'failed' and transaction_type <> 'fee'
+        and ($2::text[] IS NULL or transaction_type = ANY($2))";
+    match &rule.condition {
+        Condition::Velocity { count, window_minutes } => {
+            let qry = format!("SELECT COUNT(*) {} and created_at >= LOCALTIMESTAMP - make_interval(mins => $3)", earlier);
+            let seen: i64 = sqlx::query_scalar(&qry)
+                .bind(c.sender)
+                .bind(types)
+                .bind(*window_minutes as i32)
+                .fetch_one(&mut *conn)
+                .await?;
+            Ok(seen + 1 > *count)
+        }
+        Condition::Structuring {
+            threshold,
+            margin_percent,
+            count,
+            window_hours,
+        } => {
+            let lower = threshold - threshold * margin_percent / Decimal::ONE_HUNDRED;
+            if c.amount < lower || c.amount >= *threshold {
+                return Ok(false);
+            }
+            let qry = format!(
+                "SELECT COUNT(*) {} and amount >= $3 and amount < $4 and created_at >= LOCALTIMESTAMP - make_interval(hours => $5)",
+                earlier
+            );
+            let seen: i64 = sqlx::query_scalar(&qry)
+                .bind(c.sender)
+                .bind(types)
+                .bind(lower)
+                .bind(threshold)
+                .bind(*window_hours as i32)
+                .fetch_one(&mut *conn)
+                .await?;
+            Ok(seen + 1 >= *count)
+        }
+        Condition::NewPayeeLargeAmount { amount } => {
+            let receiver = match c.receiver {
+                Some(r) if c.amount >= *amount => r,
+                _ => return Ok(false),
+            };
+            let qry = "SELECT EXISTS (SELECT 1 FROM transactions where sender_id = $1 and receiver_id = $2
+                and transaction_type = 'transfer' and status = 'completed')";
+            let paid_before: bool = sqlx::query_scalar(qry)
+                .bind(c.sender)
+                .bind(receiver)
+                .fetch_one(&mut *conn)
+                .await?;
+            Ok(!paid_before)
+        }
+        Condition::RapidInAndOut { window_minutes, percent } => {
+            if c.transaction_type == "deposit" {
+                return Ok(false);
+            }
+            let qry = "SELECT COALESCE(SUM(amount), 0) FROM transactions where status = 'completed'
+                and ((receiver_id = $1 and transaction_type = 'transfer') or (sender_id = $1 and transaction_type = 'deposit'))
+                and created_at >= LOCALTIMESTAMP - make_interval(mins => $2)";
+            let came_in: Decimal = sqlx::query_scalar(qry)
+                .bind(c.sender)
+                .bind(*window_minutes as i32)
+                .fetch_one(&mut *conn)
+                .await?;
+            Ok(came_in > Decimal::ZERO && c.amount >= came_in * percent / Decimal::ONE_HUNDRED)
+        }
+        Condition::RoundAmount { multiple, min_amount } => Ok(c.amount >= *min_amount && (c.amount % multiple).is_zero()),
+    }
+}
+
+const INSERT_ALERT: &str = "INSERT INTO monitoring_alerts(alert_id,transaction_id,user_id,receiver_id,transaction_type,amount,action,rules,status,revenue_account,created_at)
+    Values ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11);";
+
+//written with the held transaction, in its database transaction
+pub async fn record_review<'e, E>(
+    executor: E,
+    transaction_id: Uuid,
+    candidate: &Candidate<'_>,
+    verdict: &Verdict,
+    fee: Option<Fee>,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query(INSERT_ALERT)
+        .bind(Uuid::new_v4())
+        .bind(transaction_id)
+        .bind(candidate.sender)
+        .bind(candidate.receiver)
+        .bind(candidate.transaction_type)
+        .bind(candidate.amount)
+        .bind(Action::Review.as_str())
+        .bind(json!(verdict.rules))
+        .bind(AlertStatus::Open.as_str())
+        .bind(fee.map(|f| f.revenue_account))
+        .bind(Utc::now().naive_utc())
+        .execute(executor)
+        .await?;
+    warn!(transaction_id = %transaction_id, rules = ?verdict.rules, "Transaction held for review");
+    Ok(())
+}
+
+//the refused booking is rolled back, so the alert is written on its own
+//returns the error the booking answers with
+pub async fn record_block(pool: &Pool<Postgres>, candidate: &Candidate<'_>, verdict: &Verdict) -> sqlx::Error {
+    let recorded = sqlx::query(INSERT_ALERT)
+        .bind(Uuid::new_v4())
+        .bind(None::<Uuid>)
+        .bind(candidate.sender)
+        .bind(candidate.receiver)
+        .bind(candidate.transaction_type)
+        .bind(candidate.amount)
+        .bind(Action::Block.as_str())
+        .bind(json!(verdict.rules))
+        .bind(AlertStatus::Blocked.as_str())
+        .bind(None::<Uuid>)
+        .bind(Utc::now().naive_utc())
+        .execute(pool)
+        .await;
+    match recorded {
+        Ok(_) => warn!(user_id = %candidate.sender, rules = ?verdict.rules, "Transaction blocked"),
+        Err(e) => error!(error = %e, "Error at recording the blocked transaction"),
+    }
+    TransactionError::TransactionBlocked.into()
+}
+
+//oldest first, the order they are worked through
+#[instrument(name = "db.list_alerts", skip(pool))]
+pub async fn list_alerts(pool: &Pool<Postgres>, status: AlertStatus) -> Result<Vec<Alert>, sqlx::Error> {
+    let qry = "SELECT * FROM monitoring_alerts where status = $1 ORDER BY created_at, id";
+
+    match sqlx::query(qry).bind(status.as_str()).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(alert).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_alerts");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.get_alert", skip(pool))]
+pub async fn get_alert(pool: &Pool<Postgres>, id: Uuid) -> Result<Alert, sqlx::Error> {
+    let qry = "SELECT * FROM monitoring_alerts where alert_id = $1";
+
+    sqlx::query(qry).bind(id).fetch_one(pool).await.map(|v| alert(&v))
+}
+
+//approving books the held transaction, rejecting gives the money set aside back to the sender
+//the alert row is locked, so two analysts cannot decide the same review
+#[instrument(name = "db.decide_alert", skip(pool, note))]
+pub async fn decide_alert(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+    approve: bool,
+    decided_by: Uuid,
+    note: Option<&str>,
+) -> Result<Alert, sqlx::Error> {
+    let status = match approve {
+        true => AlertStatus::Approved,
+        false => AlertStatus::Rejected,
+    };
+    let mut tx = pool.begin().await?;
+    let decided: Result<Alert, sqlx::Error> = async {
+        let row = sqlx::query("SELECT * FROM monitoring_alerts where alert_id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let held = alert(&row);
+        let transaction_id = match held.transaction_id {
+            Some(v) if held.status == AlertStatus::Open => v,
+            _ => return Err(TransactionError::ReviewClosed.into()),
+        };
+        //four eyes, nobody releases or returns their own money
+        let sender: Uuid = sqlx::query_scalar("SELECT sender_id FROM transactions where transaction_id = $1")
+            .bind(transaction_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if sender == decided_by {
+            return Err(TransactionError::SelfReview.into());
+        }
+        settle_held_transaction(&mut tx, transaction_id, approve, row.get("revenue_account")).await?;
+
+        let qry = "UPDATE monitoring_alerts SET status = $1, decided_by = $2, decided_at = $3, note = $4 where alert_id = $5 RETURNING *";
+        let row = sqlx::query(qry)
+            .bind(status.as_str())
+            .bind(decided_by)
+            .bind(Utc::now().naive_utc())
+            .bind(note.map(str::trim).filter(|n| !n.is_empty()))
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let (kind, message) = match approve {
+            true => ("transaction_approved", "Your transaction was reviewed and completed"),
+            false => ("transaction_rejected", "Your transaction was reviewed and rejected, the amount was returned"),
+        };
+        notify(&mut *tx, held.user_id, kind, message, json!({ "transaction_id": transaction_id })).await?;
+        Ok(alert(&row))
+    }
+    .await;
+
+    match decided {
+        Ok(v) => {
+            tx.commit().await?;
+            info!(alert_id = %id, status = status.as_str(), by = %decided_by, "Review decided");
+            Ok(v)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
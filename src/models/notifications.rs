@@ -0,0 +1,78 @@
+//in-app notifications, written by the background workers for things a user did not trigger directly
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, Pool, Postgres, Row};
+use tracing::{error, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Notification {
+    pub notification_id: Uuid,
+    #[schema(example = "scheduled_payment_retry")]
+    pub kind: String,
+    pub message: String,
+    //ids the notification is about, e.g. the schedule_id
+    #[schema(value_type = Object)]
+    pub data: Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[instrument(name = "db.notify", skip(executor, message, data))]
+pub async fn notify<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    kind: &str,
+    message: &str,
+    data: Value,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "INSERT INTO notifications(notification_id,user_id,kind,message,data,created_at) Values ($1,$2,$3,$4,$5,$6);";
+
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(kind)
+        .bind(message)
+        .bind(data)
+        .bind(Utc::now().naive_utc())
+        .execute(executor)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at notify");
+            Err(e)
+        }
+    }
+}
+
+//newest first
+#[instrument(name = "db.list_notifications", skip(pool))]
+pub async fn list_notifications(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Notification>, sqlx::Error> {
+    let qry = "SELECT * FROM notifications where user_id = $1 ORDER BY id DESC LIMIT $2";
+
+    match sqlx::query(qry).bind(user_id).bind(limit).fetch_all(pool).await {
+        Ok(v) => Ok(v
+            .iter()
+            .map(|r| Notification {
+                notification_id: r.get("notification_id"),
+                kind: r.get("kind"),
+                message: r.get("message"),
+                data: r.get("data"),
+                created_at: r.get("created_at"),
+            })
+            .collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_notifications");
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,491 @@
+//OAuth2 authorization server for third-party apps (RFC 6749), the access tokens are JWTs of utilities::auth with a client_id and scope
+//the authorization code grant with PKCE (RFC 7636, S256 only) acts for a user who consented,
+//the client credentials grant lets a confidential client act for the user who registered it
+//client secrets and authorization codes are stored as their sha-256 only, like the other tokens
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+use tracing::{error, info, instrument, warn};
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        api_keys::{parse_scopes, validate_scopes, Scope},
+        tokens::hash_token,
+        users::get_role,
+    },
+    utilities::{auth::Claims, errors::TransactionError, rbac::Role},
+};
+
+pub const CLIENT_ID_PREFIX: &str = "client_";
+const MAX_NAME_LEN: usize = 100;
+const MAX_REDIRECT_URIS: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct OAuthClient {
+    #[schema(example = "client_5b2f0c1e9a7d4e36b8c1")]
+    pub client_id: String,
+    #[schema(example = "Budget Planner")]
+    pub name: String,
+    #[schema(example = json!(["https://planner.example.com/callback"]))]
+    pub redirect_uris: Vec<String>,
+    //the most a token of this client may be granted
+    pub scopes: Vec<Scope>,
+    //has a secret, public clients such as mobile apps rely on PKCE alone
+    pub confidential: bool,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+//a registered client, the only time the secret is readable
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct NewOAuthClient {
+    //none for public clients
+    #[schema(example = "cs_9b0c...")]
+    pub client_secret: Option<String>,
+    pub client: OAuthClient,
+}
+
+//a client the user allowed to act on their behalf
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Consent {
+    pub client_id: String,
+    #[schema(example = "Budget Planner")]
+    pub client_name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: NaiveDateTime,
+    //when the current grant started, after the last revocation
+    pub granted_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+//a client that proved who it is at the token or introspection endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedClient {
+    pub client_id: String,
+    pub owner_id: Uuid,
+    pub confidential: bool,
+    pub scopes: Vec<Scope>,
+}
+
+//the user and scopes an access token is issued for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub scopes: Vec<Scope>,
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn oauth_client(v: &PgRow) -> OAuthClient {
+    OAuthClient {
+        client_id: v.get("client_id"),
+        name: v.get("name"),
+        redirect_uris: v.get("redirect_uris"),
+        scopes: parse_scopes(v.get("scopes")),
+        confidential: v.get::<Option<String>, _>("secret_hash").is_some(),
+        created_at: v.get("created_at"),
+        revoked_at: v.get("revoked_at"),
+    }
+}
+
+//https, or http on the loopback address for native apps (RFC 8252), never with a fragment
+fn validate_redirect_uri(uri: &str) -> Result<(), sqlx::Error> {
+    let url = Url::parse(uri).map_err(|_| sqlx::Error::Encode(format!("Redirect uri {} is not a valid URL", uri).into()))?;
+    let loopback = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if url.fragment().is_some() || !(url.scheme() == "https" || url.scheme() == "http" && loopback) {
+        return Err(sqlx::Error::Encode(
+            format!("Redirect uri {} must use https, or http on localhost, and have no fragment", uri).into(),
+        ));
+    }
+    Ok(())
+}
+
+//the space separated scope parameter, none asks for every scope of the client
+//scopes beyond those of the client are refused instead of silently dropped
+pub fn requested_scopes(scope: Option<&str>, allowed: &[Scope]) -> Result<Vec<Scope>, sqlx::Error> {
+    let mut scopes = Vec::new();
+    for name in scope.unwrap_or_default().split_whitespace() {
+        let scope: Scope = name.parse().map_err(|_| TransactionError::InvalidScope)?;
+        if !allowed.contains(&scope) {
+            return Err(TransactionError::InvalidScope.into());
+        }
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        scopes = allowed.to_vec();
+    }
+    Ok(scopes)
+}
+
+//BASE64URL(SHA256(code_verifier)) has to equal the challenge of the authorization request
+pub fn pkce_matches(code_verifier: &str, code_challenge: &str) -> bool {
+    (43..=128).contains(&code_verifier.len()) && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+fn validate_code_challenge(code_challenge: &str, method: Option<&str>) -> Result<(), sqlx::Error> {
+    if method != Some("S256") {
+        return Err(sqlx::Error::Encode("code_challenge_method must be S256".into()));
+    }
+    let valid = (43..=128).contains(&code_challenge.len())
+        && code_challenge.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+    if !valid {
+        return Err(sqlx::Error::Encode("code_challenge must be 43 to 128 characters of base64url".into()));
+    }
+    Ok(())
+}
+
+//public clients cannot keep a secret, they need a redirect uri for the authorization code grant
+#[instrument(name = "db.register_client", skip(pool, name, redirect_uris))]
+pub async fn register_client(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    name: &str,
+    redirect_uris: &[String],
+    scopes: &[Scope],
+    confidential: bool,
+) -> Result<NewOAuthClient, sqlx::Error> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(sqlx::Error::Encode(
+            format!("Name must be 1 to {} characters", MAX_NAME_LEN).into(),
+        ));
+    }
+    if redirect_uris.len() > MAX_REDIRECT_URIS || !confidential && redirect_uris.is_empty() {
+        return Err(sqlx::Error::Encode(
+            format!("A client has up to {} redirect uris, a public client at least one", MAX_REDIRECT_URIS).into(),
+        ));
+    }
+    for uri in redirect_uris {
+        validate_redirect_uri(uri)?;
+    }
+    let scopes = validate_scopes(scopes)?;
+
+    let client_id = format!("{}{}", CLIENT_ID_PREFIX, random_hex(10));
+    let client_secret = confidential.then(|| format!("cs_{}", random_hex(32)));
+    let qry = "INSERT INTO oauth_clients(client_id,owner_id,name,secret_hash,redirect_uris,scopes,created_at)
+        Values ($1,$2,$3,$4,$5,$6,$7) RETURNING *";
+    match sqlx::query(qry)
+        .bind(&client_id)
+        .bind(owner)
+        .bind(name)
+        .bind(client_secret.as_deref().map(hash_token))
+        .bind(redirect_uris)
+        .bind(scopes)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => {
+            info!(owner_id = %owner, client_id, confidential, "OAuth client registered");
+            Ok(NewOAuthClient {
+                client_secret,
+                client: oauth_client(&v),
+            })
+        }
+        Err(e) => {
+            error!(error = %e, "Error at register_client");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.list_clients", skip(pool))]
+pub async fn list_clients(pool: &Pool<Postgres>, owner: Uuid) -> Result<Vec<OAuthClient>, sqlx::Error> {
+    let qry = "SELECT * FROM oauth_clients where owner_id = $1 ORDER BY created_at DESC, client_id";
+
+    match sqlx::query(qry).bind(owner).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(oauth_client).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_clients");
+            Err(e)
+        }
+    }
+}
+
+//ends every token of the client, for users who consented as well as its own
+#[instrument(name = "db.revoke_client", skip(pool))]
+pub async fn revoke_client(pool: &Pool<Postgres>, owner: Uuid, client_id: &str) -> Result<(), sqlx::Error> {
+    let qry = "UPDATE oauth_clients SET revoked_at = $3 where owner_id = $1 and client_id = $2 and revoked_at IS NULL";
+
+    match sqlx::query(qry)
+        .bind(owner)
+        .bind(client_id)
+        .bind(Utc::now().naive_utc())
+        .execute(pool)
+        .await
+    {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => {
+            info!(owner_id = %owner, client_id, "OAuth client revoked");
+            Ok(())
+        }
+        Err(e) => {
+            error!(error = %e, "Error at revoke_client");
+            Err(e)
+        }
+    }
+}
+
+//a confidential client has to send its secret, a public one must not have any
+pub async fn authenticate_client(
+    pool: &Pool<Postgres>,
+    client_id: &str,
+    client_secret: Option<&str>,
+) -> Result<AuthenticatedClient, sqlx::Error> {
+    let qry = "SELECT owner_id, secret_hash, scopes FROM oauth_clients where client_id = $1 and revoked_at IS NULL";
+    let row = sqlx::query(qry)
+        .bind(client_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(TransactionError::InvalidClient)?;
+    let secret_hash: Option<String> = row.get("secret_hash");
+    let authenticated = match (&secret_hash, client_secret) {
+        (Some(hash), Some(secret)) => *hash == hash_token(secret),
+        (None, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        warn!(client_id, "OAuth client authentication failed");
+        return Err(TransactionError::InvalidClient.into());
+    }
+    Ok(AuthenticatedClient {
+        client_id: client_id.to_string(),
+        owner_id: row.get("owner_id"),
+        confidential: secret_hash.is_some(),
+        scopes: parse_scopes(row.get("scopes")),
+    })
+}
+
+//the consent screen of the user approved the request, records the consent and returns a single-use code
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "db.authorize", skip(pool, redirect_uri, scope, code_challenge))]
+pub async fn authorize(
+    pool: &Pool<Postgres>,
+    uid: Uuid,
+    client_id: &str,
+    redirect_uri: &str,
+    scope: Option<&str>,
+    code_challenge: &str,
+    code_challenge_method: Option<&str>,
+    ttl: Duration,
+) -> Result<String, sqlx::Error> {
+    let qry = "SELECT redirect_uris, scopes FROM oauth_clients where client_id = $1 and revoked_at IS NULL";
+    let client = sqlx::query(qry).bind(client_id).fetch_one(pool).await?;
+    let redirect_uris: Vec<String> = client.get("redirect_uris");
+    if !redirect_uris.iter().any(|u| u == redirect_uri) {
+        return Err(TransactionError::InvalidRedirectUri.into());
+    }
+    let scopes = requested_scopes(scope, &parse_scopes(client.get("scopes")))?;
+    validate_code_challenge(code_challenge, code_challenge_method)?;
+    let scopes: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+    let now = Utc::now().naive_utc();
+    let code = random_hex(32);
+
+    let mut tx = pool.begin().await?;
+    let authorized: Result<(), sqlx::Error> = async {
+        //the consent holds the scopes the user approved last, tokens with other scopes stop working
+        //a revoked consent starts a new grant, the tokens of the old one stay revoked
+        let qry = "INSERT INTO oauth_consents(user_id,client_id,scopes,created_at,granted_at,updated_at) Values ($1,$2,$3,$4,$4,$4)
+            ON CONFLICT (user_id, client_id) DO UPDATE SET
+                scopes = EXCLUDED.scopes,
+                granted_at = CASE WHEN oauth_consents.revoked_at IS NULL THEN oauth_consents.granted_at ELSE EXCLUDED.granted_at END,
+                updated_at = EXCLUDED.updated_at,
+                revoked_at = NULL";
+        sqlx::query(qry)
+            .bind(uid)
+            .bind(client_id)
+            .bind(&scopes)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        let qry = "INSERT INTO oauth_codes(code_hash,client_id,user_id,redirect_uri,scopes,code_challenge,created_at,expires_at)
+            Values ($1,$2,$3,$4,$5,$6,$7,$8)";
+        sqlx::query(qry)
+            .bind(hash_token(&code))
+            .bind(client_id)
+            .bind(uid)
+            .bind(redirect_uri)
+            .bind(&scopes)
+            .bind(code_challenge)
+            .bind(now)
+            .bind(now + ttl)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+    .await;
+
+    match authorized {
+        Ok(_) => {
+            tx.commit().await?;
+            info!(user_id = %uid, client_id, "OAuth authorization code issued");
+            Ok(code)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the authorization code grant, the code works once, for the client it was issued to and with the verifier of its challenge
+//a code used twice was probably intercepted, so the consent behind it is revoked as RFC 6749 section 4.1.2 asks
+#[instrument(name = "db.exchange_code", skip(pool, client, code, redirect_uri, code_verifier), fields(client_id = client.client_id))]
+pub async fn exchange_code(
+    pool: &Pool<Postgres>,
+    client: &AuthenticatedClient,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<Grant, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let exchanged: Result<Option<Grant>, sqlx::Error> = async {
+        let qry = "SELECT c.*, u.role FROM oauth_codes c JOIN users u ON u.user_id = c.user_id
+            where c.code_hash = $1 and c.client_id = $2 FOR UPDATE OF c";
+        let row = sqlx::query(qry)
+            .bind(hash_token(code))
+            .bind(&client.client_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(TransactionError::InvalidGrant)?;
+        let user_id: Uuid = row.get("user_id");
+
+        if row.get::<Option<NaiveDateTime>, _>("used_at").is_some() {
+            warn!(user_id = %user_id, "Authorization code used twice, consent revoked");
+            sqlx::query("UPDATE oauth_consents SET revoked_at = $3 where user_id = $1 and client_id = $2 and revoked_at IS NULL")
+                .bind(user_id)
+                .bind(&client.client_id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            return Ok(None);
+        }
+        let expires_at: NaiveDateTime = row.get("expires_at");
+        let expected_uri: String = row.get("redirect_uri");
+        if expires_at <= now || expected_uri != redirect_uri || !pkce_matches(code_verifier, row.get("code_challenge")) {
+            return Err(TransactionError::InvalidGrant.into());
+        }
+        sqlx::query("UPDATE oauth_codes SET used_at = $2 where code_hash = $1")
+            .bind(hash_token(code))
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        Ok(Some(Grant {
+            user_id,
+            role: row.get::<String, _>("role").parse().unwrap_or_default(),
+            scopes: parse_scopes(row.get("scopes")),
+        }))
+    }
+    .await;
+
+    match exchanged {
+        Ok(grant) => {
+            tx.commit().await?;
+            grant.ok_or_else(|| TransactionError::InvalidGrant.into())
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the client credentials grant, confidential clients act for the user who registered them
+#[instrument(name = "db.client_credentials", skip(pool, client, scope), fields(client_id = client.client_id))]
+pub async fn client_credentials(
+    pool: &Pool<Postgres>,
+    client: &AuthenticatedClient,
+    scope: Option<&str>,
+) -> Result<Grant, sqlx::Error> {
+    if !client.confidential {
+        return Err(TransactionError::UnauthorizedClient.into());
+    }
+    let scopes = requested_scopes(scope, &client.scopes)?;
+    Ok(Grant {
+        user_id: client.owner_id,
+        role: get_role(pool, client.owner_id).await?,
+        scopes,
+    })
+}
+
+//an access token stays valid while its client is not revoked and its scopes are still granted, by the client to its owner
+//for client_credentials tokens or by the consent of the user for all others, whose grant must not be younger than the token
+//iat has whole seconds, so the grant is compared by the second it started in
+pub async fn token_active(pool: &Pool<Postgres>, claims: &Claims) -> Result<bool, sqlx::Error> {
+    let scopes: Vec<&str> = claims.scope.as_deref().unwrap_or_default().split_whitespace().collect();
+    let qry = "SELECT EXISTS(SELECT 1 FROM oauth_clients c where c.client_id = $1 and c.revoked_at IS NULL
+        and (($5 and c.owner_id = $2 and $3 <@ c.scopes)
+            or (NOT $5 and EXISTS(SELECT 1 FROM oauth_consents k where k.client_id = $1 and k.user_id = $2 and k.revoked_at IS NULL
+                and $3 <@ k.scopes and EXTRACT(EPOCH FROM date_trunc('second', k.granted_at)) <= $4))))";
+    sqlx::query_scalar(qry)
+        .bind(claims.client_id.as_deref().unwrap_or_default())
+        .bind(claims.sub)
+        .bind(&scopes)
+        .bind(claims.iat as i64)
+        .bind(claims.grant.as_deref() == Some("client_credentials"))
+        .fetch_one(pool)
+        .await
+}
+
+#[instrument(name = "db.list_consents", skip(pool))]
+pub async fn list_consents(pool: &Pool<Postgres>, uid: Uuid) -> Result<Vec<Consent>, sqlx::Error> {
+    let qry = "SELECT k.client_id, c.name, k.scopes, k.created_at, k.granted_at, k.updated_at FROM oauth_consents k
+        JOIN oauth_clients c ON c.client_id = k.client_id
+        where k.user_id = $1 and k.revoked_at IS NULL and c.revoked_at IS NULL ORDER BY c.name";
+
+    match sqlx::query(qry).bind(uid).fetch_all(pool).await {
+        Ok(v) => Ok(v
+            .iter()
+            .map(|r| Consent {
+                client_id: r.get("client_id"),
+                client_name: r.get("name"),
+                scopes: parse_scopes(r.get("scopes")),
+                created_at: r.get("created_at"),
+                granted_at: r.get("granted_at"),
+                updated_at: r.get("updated_at"),
+            })
+            .collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_consents");
+            Err(e)
+        }
+    }
+}
+
+//ends every token the client holds for the user, a new authorization asks again
+#[instrument(name = "db.revoke_consent", skip(pool))]
+pub async fn revoke_consent(pool: &Pool<Postgres>, uid: Uuid, client_id: &str) -> Result<(), sqlx::Error> {
+    let qry = "UPDATE oauth_consents SET revoked_at = $3 where user_id = $1 and client_id = $2 and revoked_at IS NULL";
+
+    match sqlx::query(qry)
+        .bind(uid)
+        .bind(client_id)
+        .bind(Utc::now().naive_utc())
+        .execute(pool)
+        .await
+    {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => {
+            info!(user_id = %uid, client_id, "OAuth consent revoked");
+            Ok(())
+        }
+        Err(e) => {
+            error!(error = %e, "Error at revoke_consent");
+            Err(e)
+        }
+    }
+}
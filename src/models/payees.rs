@@ -0,0 +1,282 @@
+//payees are addressed by what people know about each other: an e-mail, a phone number or an @handle
+//a lookup only ever answers with masked details or the one generic PayeeNotFound, whether the account
+//does not exist or cannot receive money, so it cannot be used to probe for accounts
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use tracing::instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::utilities::{errors::TransactionError, telemetry::mask_email};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayeeRef {
+    Email(String),
+    //E.164, e.g. +4915112345678
+    Phone(String),
+    //lowercase, without the @
+    Handle(String),
+}
+
+impl PayeeRef {
+    pub fn parse(input: &str) -> Result<PayeeRef, sqlx::Error> {
+        let input = input.trim();
+        if input.starts_with('@') {
+            return normalize_handle(input)
+                .map(PayeeRef::Handle)
+                .map_err(|_| TransactionError::InvalidPayee.into());
+        }
+        if let Some((local, domain)) = input.split_once('@') {
+            if local.is_empty() || !domain.contains('.') || domain.contains('@') {
+                return Err(TransactionError::InvalidPayee.into());
+            }
+            return Ok(PayeeRef::Email(input.to_string()));
+        }
+        if input.starts_with('+') || input.starts_with(|c: char| c.is_ascii_digit()) {
+            return normalize_phone(input)
+                .map(PayeeRef::Phone)
+                .map_err(|_| TransactionError::InvalidPayee.into());
+        }
+        Err(TransactionError::InvalidPayee.into())
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PayeeRef::Email(_) => "email",
+            PayeeRef::Phone(_) => "phone",
+            PayeeRef::Handle(_) => "handle",
+        }
+    }
+}
+
+//3 to 30 letters, digits or underscores, starting with a letter, the @ is optional
+pub fn normalize_handle(raw: &str) -> Result<String, sqlx::Error> {
+    let handle = raw.trim().trim_start_matches('@').to_lowercase();
+    let valid = (3..=30).contains(&handle.len())
+        && handle.starts_with(|c: char| c.is_ascii_lowercase())
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(handle)
+    } else {
+        Err(sqlx::Error::Encode(
+            "Handle must be 3 to 30 letters, digits or underscores and start with a letter".into(),
+        ))
+    }
+}
+
+//international format, spaces, dashes, dots and brackets are dropped and a leading 00 becomes +
+pub fn normalize_phone(raw: &str) -> Result<String, sqlx::Error> {
+    let compact: String = raw
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+    let digits = match compact.strip_prefix('+').or_else(|| compact.strip_prefix("00")) {
+        Some(d) => d,
+        None => {
+            return Err(sqlx::Error::Encode(
+                "Phone numbers need the country code, e.g. +4915112345678".into(),
+            ))
+        }
+    };
+    let valid = (8..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.chars().all(|c| c.is_ascii_digit());
+    if valid {
+        Ok(format!("+{}", digits))
+    } else {
+        Err(sqlx::Error::Encode(
+            "Phone numbers need the country code, e.g. +4915112345678".into(),
+        ))
+    }
+}
+
+//what the sender sees before confirming a payment
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct PayeeDetails {
+    #[schema(example = "a***e")]
+    pub name: String,
+    //the identifier that matched, masked unless it is a handle
+    #[schema(example = "a***@example.com")]
+    pub identifier: String,
+    #[schema(example = "email")]
+    pub matched_by: String,
+}
+
+fn mask_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    match chars.len() {
+        0 => String::from("***"),
+        1 | 2 => format!("{}***", chars[0]),
+        n => format!("{}***{}", chars[0], chars[n - 1]),
+    }
+}
+
+fn mask_phone(phone: &str) -> String {
+    let digits = phone.trim_start_matches('+');
+    let visible = digits.len().saturating_sub(4);
+    format!("+{}{}", "*".repeat(visible), &digits[visible..])
+}
+
+//user_id and masked details of an account that can receive money
+#[instrument(name = "db.find_payee", skip_all)]
+pub async fn find_payee(pool: &Pool<Postgres>, payee: &str) -> Result<(Uuid, PayeeDetails), sqlx::Error> {
+    let payee = PayeeRef::parse(payee)?;
+    let (condition, value) = match &payee {
+        //an unverified or duplicate address may belong to someone else
+        PayeeRef::Email(v) => ("lower(u.email) = lower($1) AND u.email_verified_at IS NOT NULL AND NOT u.email_duplicate", v),
+        PayeeRef::Phone(v) => ("u.phone = $1", v),
+        PayeeRef::Handle(v) => ("u.handle = $1", v),
+    };
+    let qry = format!(
+        "SELECT u.user_id, u.username, u.email, u.phone, u.handle FROM users u
+        JOIN account_balance b ON b.user_id = u.user_id
+        WHERE {} AND b.status IN ('active', 'debit_blocked')",
+        condition
+    );
+
+    let row = match sqlx::query(&qry).bind(value).fetch_optional(pool).await? {
+        Some(v) => v,
+        None => return Err(TransactionError::PayeeNotFound.into()),
+    };
+    let identifier = match &payee {
+        PayeeRef::Email(_) => mask_email(row.get("email")),
+        PayeeRef::Phone(_) => mask_phone(row.get("phone")),
+        PayeeRef::Handle(_) => format!("@{}", row.get::<String, _>("handle")),
+    };
+    Ok((
+        row.get("user_id"),
+        PayeeDetails {
+            name: mask_name(row.get("username")),
+            identifier,
+            matched_by: payee.kind().to_string(),
+        },
+    ))
+}
+
+//the receiver of a payment, given either as user_id or as payee
+pub async fn resolve_receiver(
+    pool: &Pool<Postgres>,
+    receiver: Option<Uuid>,
+    payee: Option<&str>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    match (receiver, payee) {
+        (Some(_), Some(_)) => Err(sqlx::Error::Encode(
+            "Give either receiver or payee, not both".into(),
+        )),
+        (None, Some(p)) => Ok(Some(find_payee(pool, p).await?.0)),
+        (receiver, None) => Ok(receiver),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        models::{
+            balance::{set_account_status, AccountStatus},
+            users::{get_user, register_user, update_payee_identifiers},
+        },
+        utilities::{errors::TransactionError, test_harness::TestDb},
+    };
+
+    use super::{find_payee, normalize_phone, PayeeRef};
+
+    #[test]
+    fn test_payee_parsing() {
+        assert_eq!(
+            PayeeRef::parse(" @Alice_1 ").unwrap(),
+            PayeeRef::Handle(String::from("alice_1"))
+        );
+        assert_eq!(
+            PayeeRef::parse("Alice@Example.com").unwrap(),
+            PayeeRef::Email(String::from("Alice@Example.com"))
+        );
+        assert_eq!(
+            PayeeRef::parse("0049 (151) 123-45678").unwrap(),
+            PayeeRef::Phone(String::from("+4915112345678"))
+        );
+        assert!(normalize_phone("015112345678").is_err());
+        for invalid in ["@a", "alice", "a@b", "+12", ""] {
+            assert!(PayeeRef::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_find_payee_does_not_enumerate() {
+        let db = TestDb::new().await;
+        let alice = db.user().username("alice").email("alice@example.com").create().await;
+        let staff = db.user().create().await;
+        update_payee_identifiers(
+            &db.pool,
+            alice.user_id,
+            Some(String::from("+4915112345678")),
+            Some(String::from("alice")),
+        )
+        .await
+        .unwrap();
+
+        let (user_id, details) = find_payee(&db.pool, "ALICE@example.com").await.unwrap();
+        assert_eq!(user_id, alice.user_id);
+        assert_eq!(details.name, "a***e");
+        assert_eq!(details.identifier, "a***@example.com");
+        let (_, details) = find_payee(&db.pool, "+49 151 12345678").await.unwrap();
+        assert_eq!(details.identifier, "+*********5678");
+        let (_, details) = find_payee(&db.pool, "@Alice").await.unwrap();
+        assert_eq!(details.identifier, "@alice");
+
+        //a frozen account looks exactly like a missing one
+        set_account_status(&db.pool, alice.user_id, AccountStatus::Frozen, "test", staff.user_id)
+            .await
+            .unwrap();
+        for payee in ["alice@example.com", "nobody@example.com"] {
+            let e = find_payee(&db.pool, payee).await.unwrap_err();
+            assert_eq!(TransactionError::from_sqlx(&e), Some(TransactionError::PayeeNotFound));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_email_payee_ignores_case_variants() {
+        let db = TestDb::new().await;
+        let carol = db.user().email("Carol@Example.com").unverified_email().create().await;
+        let other = db.user().create().await;
+        let stored = get_user(&db.pool, String::from("carol@example.com")).await.unwrap();
+        assert_eq!(stored.user_id, carol.user_id);
+        assert_eq!(stored.email, "carol@example.com");
+        let e = register_user(
+            &db.pool,
+            &db.compliance.sanctions,
+            String::from("mallory"),
+            String::from("CAROL@example.com"),
+            String::from("secret"),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e, sqlx::Error::Database(ref e) if e.is_unique_violation()));
+
+        //an unverified address is not resolved
+        let e = find_payee(&db.pool, "carol@example.com").await.unwrap_err();
+        assert_eq!(TransactionError::from_sqlx(&e), Some(TransactionError::PayeeNotFound));
+
+        //a case variant taken before e-mails were unique regardless of case, as the migration flags it
+        sqlx::query("UPDATE users SET email = 'CAROL@example.com', email_duplicate = true where user_id = $1")
+            .bind(other.user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let e = find_payee(&db.pool, "CAROL@example.com").await.unwrap_err();
+        assert_eq!(TransactionError::from_sqlx(&e), Some(TransactionError::PayeeNotFound));
+        sqlx::query("UPDATE users SET email_verified_at = now() where user_id = $1")
+            .bind(carol.user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        for payee in ["carol@example.com", "CAROL@example.com"] {
+            assert_eq!(find_payee(&db.pool, payee).await.unwrap().0, carol.user_id);
+        }
+        //both still log in with their own address
+        assert_eq!(get_user(&db.pool, String::from("CAROL@example.com")).await.unwrap().user_id, other.user_id);
+        assert_eq!(get_user(&db.pool, String::from("Carol@Example.com")).await.unwrap().user_id, carol.user_id);
+    }
+}
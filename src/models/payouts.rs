@@ -0,0 +1,658 @@
+//bulk payouts from one funding account, e.g. a payroll run
+//every row is checked when the batch is uploaded, the payouts worker books the rows afterwards:
+//best_effort books each row on its own, all_or_nothing books every row in one database transaction
+//a batch is claimed with a lease, so a worker that dies mid-batch leaves it to the next one, and the
+//idempotency key payout:<batch_id>:<line> makes sure a row is never paid twice
+use std::{error::Error, fmt};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+use tracing::{error, info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        balance::get_balance,
+        notifications::notify,
+        payees::resolve_receiver,
+        transactions::{
+            add_transaction_with, add_transfers_atomically, find_idempotent_transaction, validate_amount,
+            BatchFailure, BatchTransfer, TransactionOptions,
+        },
+    },
+    utilities::{errors::TransactionError, monitoring::Compliance},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutMode {
+    //every row is booked or none is, the first failing row fails the batch
+    AllOrNothing,
+    //failing rows are reported, the others are booked
+    #[default]
+    BestEffort,
+}
+
+impl PayoutMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayoutMode::AllOrNothing => "all_or_nothing",
+            PayoutMode::BestEffort => "best_effort",
+        }
+    }
+
+    fn from_db(v: &str) -> PayoutMode {
+        match v {
+            "all_or_nothing" => PayoutMode::AllOrNothing,
+            _ => PayoutMode::BestEffort,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Pending,
+    Processing,
+    //every row was booked
+    Completed,
+    //some rows failed, best_effort only
+    PartiallyCompleted,
+    //no row was booked
+    Failed,
+}
+
+impl BatchStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchStatus::Pending => "pending",
+            BatchStatus::Processing => "processing",
+            BatchStatus::Completed => "completed",
+            BatchStatus::PartiallyCompleted => "partially_completed",
+            BatchStatus::Failed => "failed",
+        }
+    }
+
+    fn from_db(v: &str) -> BatchStatus {
+        match v {
+            "pending" => BatchStatus::Pending,
+            "processing" => BatchStatus::Processing,
+            "completed" => BatchStatus::Completed,
+            "partially_completed" => BatchStatus::PartiallyCompleted,
+            _ => BatchStatus::Failed,
+        }
+    }
+}
+
+//one row of an upload, the receiver is given either as user_id or as payee (e-mail, phone or @handle)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PayoutRow {
+    pub receiver: Option<Uuid>,
+    #[schema(example = "@alice")]
+    pub payee: Option<String>,
+    #[schema(value_type = String, example = "1250.00")]
+    pub amount: Decimal,
+    #[schema(example = "Salary July")]
+    pub memo: Option<String>,
+    #[schema(example = "emp-0042")]
+    pub reference: Option<String>,
+}
+
+//why a row of an upload was rejected, line counts the rows from 1 without a CSV header
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RowError {
+    pub line: usize,
+    #[schema(example = "payee_not_found")]
+    pub code: String,
+    pub message: String,
+}
+
+//the rows of an upload that did not pass the checks, carried in sqlx::Error::Encode like TransactionError
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidRows(pub Vec<RowError>);
+
+impl InvalidRows {
+    pub fn from_sqlx(error: &sqlx::Error) -> Option<&InvalidRows> {
+        match error {
+            sqlx::Error::Encode(e) => e.downcast_ref::<InvalidRows>(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InvalidRows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rows of the batch are invalid, nothing was booked", self.0.len())
+    }
+}
+
+impl Error for InvalidRows {}
+
+impl From<InvalidRows> for sqlx::Error {
+    fn from(e: InvalidRows) -> Self {
+        sqlx::Error::Encode(Box::new(e))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PayoutBatch {
+    pub batch_id: Uuid,
+    pub mode: PayoutMode,
+    pub reference: Option<String>,
+    pub status: BatchStatus,
+    pub item_count: i32,
+    #[schema(value_type = String, example = "25000.00")]
+    pub total_amount: Decimal,
+    //booked rows so far
+    pub succeeded: i32,
+    //rejected rows so far, skipped rows of a failed all_or_nothing batch are not counted
+    pub failed: i32,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PayoutItem {
+    pub line: i32,
+    pub receiver_id: Uuid,
+    #[schema(value_type = String, example = "1250.00")]
+    pub amount: Decimal,
+    pub memo: Option<String>,
+    pub reference: Option<String>,
+    //pending, completed, failed or skipped (not booked because another row failed an all_or_nothing batch)
+    #[schema(example = "completed")]
+    pub status: String,
+    pub transaction_id: Option<Uuid>,
+    //reason of a failed row, e.g. insufficient_balance
+    pub error: Option<String>,
+    pub processed_at: Option<NaiveDateTime>,
+}
+
+fn payout_batch(v: &PgRow) -> PayoutBatch {
+    PayoutBatch {
+        batch_id: v.get("batch_id"),
+        mode: PayoutMode::from_db(v.get("mode")),
+        reference: v.get("reference"),
+        status: BatchStatus::from_db(v.get("status")),
+        item_count: v.get("item_count"),
+        total_amount: v.get("total_amount"),
+        succeeded: v.get("succeeded"),
+        failed: v.get("failed"),
+        created_at: v.get("created_at"),
+        completed_at: v.get("completed_at"),
+    }
+}
+
+fn payout_item(v: &PgRow) -> PayoutItem {
+    PayoutItem {
+        line: v.get("line"),
+        receiver_id: v.get("receiver_id"),
+        amount: v.get("amount"),
+        memo: v.get("memo"),
+        reference: v.get("reference"),
+        status: v.get("status"),
+        transaction_id: v.get("transaction_id"),
+        error: v.get("error"),
+        processed_at: v.get("processed_at"),
+    }
+}
+
+fn row_error(line: usize, e: &sqlx::Error) -> Option<RowError> {
+    let (code, message) = match (TransactionError::from_sqlx(e), e) {
+        (Some(t), _) => (t.reason(), t.to_string()),
+        (None, sqlx::Error::RowNotFound) => ("receiver_not_found", String::from("Receiver not found")),
+        (None, sqlx::Error::Encode(e)) => ("invalid_request", e.to_string()),
+        _ => return None,
+    };
+    Some(RowError {
+        line,
+        code: code.to_string(),
+        message,
+    })
+}
+
+//the receiver of a row, after the same checks a single transfer makes before booking
+async fn check_row(pool: &Pool<Postgres>, owner: Uuid, row: &PayoutRow) -> Result<Uuid, sqlx::Error> {
+    validate_amount(row.amount)?;
+    let options = TransactionOptions {
+        memo: row.memo.clone(),
+        reference: row.reference.clone(),
+        ..Default::default()
+    };
+    options.validate()?;
+    let receiver = match resolve_receiver(pool, row.receiver, row.payee.as_deref()).await? {
+        Some(v) => v,
+        None => return Err(sqlx::Error::Encode("A receiver or payee is required".into())),
+    };
+    if receiver == owner {
+        return Err(TransactionError::SameAccount.into());
+    }
+    if !get_balance(pool, receiver).await?.status.can_credit() {
+        return Err(TransactionError::ReceiverUnavailable.into());
+    }
+    Ok(receiver)
+}
+
+//stores a batch for the payouts worker once every row passed the checks, otherwise nothing is stored
+//an all_or_nothing batch must also be covered by the current balance
+#[instrument(name = "db.create_payout_batch", skip(pool, reference, rows), fields(rows = rows.len()))]
+pub async fn create_payout_batch(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    mode: PayoutMode,
+    reference: Option<String>,
+    rows: Vec<PayoutRow>,
+    max_items: usize,
+) -> Result<PayoutBatch, sqlx::Error> {
+    if rows.is_empty() {
+        return Err(sqlx::Error::Encode("A batch needs at least one row".into()));
+    }
+    if rows.len() > max_items {
+        return Err(sqlx::Error::Encode(
+            format!("A batch cannot have more than {} rows", max_items).into(),
+        ));
+    }
+    let reference = reference.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+    TransactionOptions {
+        reference: reference.clone(),
+        ..Default::default()
+    }
+    .validate()?;
+
+    let mut receivers = Vec::with_capacity(rows.len());
+    let mut invalid = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        match check_row(pool, owner, row).await {
+            Ok(v) => receivers.push(v),
+            Err(e) => match row_error(i + 1, &e) {
+                Some(v) => invalid.push(v),
+                None => return Err(e),
+            },
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(InvalidRows(invalid).into());
+    }
+    let total: Decimal = rows.iter().map(|r| r.amount).sum();
+    if mode == PayoutMode::AllOrNothing && get_balance(pool, owner).await?.balance < total {
+        return Err(TransactionError::InsufficientBalance.into());
+    }
+
+    let trimmed = |v: &Option<String>| v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(String::from);
+    let lines: Vec<i32> = (1..=rows.len() as i32).collect();
+    let amounts: Vec<Decimal> = rows.iter().map(|r| r.amount).collect();
+    let memos: Vec<Option<String>> = rows.iter().map(|r| trimmed(&r.memo)).collect();
+    let references: Vec<Option<String>> = rows.iter().map(|r| trimmed(&r.reference)).collect();
+
+    let batch_id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+    let created = async {
+        let qry = "INSERT INTO payout_batches(batch_id,owner_id,mode,reference,item_count,total_amount,created_at)
+            Values ($1,$2,$3,$4,$5,$6,$7) RETURNING *;";
+        let batch = sqlx::query(qry)
+            .bind(batch_id)
+            .bind(owner)
+            .bind(mode.as_str())
+            .bind(&reference)
+            .bind(rows.len() as i32)
+            .bind(total)
+            .bind(Utc::now().naive_utc())
+            .fetch_one(&mut *tx)
+            .await?;
+        let qry = "INSERT INTO payout_items(batch_id,line,receiver_id,amount,memo,reference)
+            SELECT $1, * FROM UNNEST($2::INT[], $3::UUID[], $4::DECIMAL[], $5::VARCHAR[], $6::VARCHAR[]);";
+        sqlx::query(qry)
+            .bind(batch_id)
+            .bind(&lines)
+            .bind(&receivers)
+            .bind(&amounts)
+            .bind(&memos)
+            .bind(&references)
+            .execute(&mut *tx)
+            .await?;
+        Ok::<_, sqlx::Error>(payout_batch(&batch))
+    }
+    .await;
+
+    match created {
+        Ok(v) => {
+            tx.commit().await?;
+            info!(batch_id = %batch_id, "Payout batch created");
+            Ok(v)
+        }
+        Err(e) => {
+            error!(error = %e, "Error at create_payout_batch");
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.list_payout_batches", skip(pool))]
+pub async fn list_payout_batches(pool: &Pool<Postgres>, owner: Uuid) -> Result<Vec<PayoutBatch>, sqlx::Error> {
+    let qry = "SELECT * FROM payout_batches where owner_id = $1 ORDER BY id DESC";
+
+    match sqlx::query(qry).bind(owner).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(payout_batch).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_payout_batches");
+            Err(e)
+        }
+    }
+}
+
+//another user's batch is RowNotFound, the same as a missing one
+#[instrument(name = "db.get_payout_batch", skip(pool))]
+pub async fn get_payout_batch(pool: &Pool<Postgres>, owner: Uuid, id: Uuid) -> Result<PayoutBatch, sqlx::Error> {
+    let qry = "SELECT * FROM payout_batches where owner_id = $1 and batch_id = $2";
+
+    match sqlx::query(qry).bind(owner).bind(id).fetch_one(pool).await {
+        Ok(v) => Ok(payout_batch(&v)),
+        Err(e) => Err(e),
+    }
+}
+
+//in upload order
+#[instrument(name = "db.list_payout_items", skip(pool))]
+pub async fn list_payout_items(pool: &Pool<Postgres>, id: Uuid) -> Result<Vec<PayoutItem>, sqlx::Error> {
+    let qry = "SELECT * FROM payout_items where batch_id = $1 ORDER BY line";
+
+    match sqlx::query(qry).bind(id).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(payout_item).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_payout_items");
+            Err(e)
+        }
+    }
+}
+
+//claims the oldest open batch and books its rows, None when there is nothing to do
+//a batch stays claimed for lease and every booked row renews it, an expired claim is taken over
+#[instrument(name = "db.process_next_payout_batch", skip(pool, compliance))]
+pub async fn process_next_payout_batch(pool: &Pool<Postgres>, compliance: &Compliance, lease: Duration) -> Result<Option<PayoutBatch>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let qry = "UPDATE payout_batches SET status = 'processing', locked_until = $2
+        where id = (SELECT id FROM payout_batches
+            where status = 'pending' or (status = 'processing' and locked_until < $1)
+            ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED)
+        RETURNING *";
+    let row = match sqlx::query(qry).bind(now).bind(now + lease).fetch_optional(pool).await? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let batch = payout_batch(&row);
+    let owner: Uuid = row.get("owner_id");
+    info!(batch_id = %batch.batch_id, mode = batch.mode.as_str(), "Processing payout batch");
+
+    let items = sqlx::query("SELECT * FROM payout_items where batch_id = $1 and status = 'pending' ORDER BY line")
+        .bind(batch.batch_id)
+        .fetch_all(pool)
+        .await?;
+    let items: Vec<PayoutItem> = items.iter().map(payout_item).collect();
+    match batch.mode {
+        PayoutMode::BestEffort => pay_each(pool, compliance, &batch, owner, &items, lease).await?,
+        PayoutMode::AllOrNothing => pay_all(pool, compliance, &batch, owner, &items).await?,
+    }
+    finish_batch(pool, batch.batch_id, owner).await.map(Some)
+}
+
+fn item_options(batch_id: Uuid, item: &PayoutItem) -> TransactionOptions {
+    TransactionOptions {
+        memo: item.memo.clone(),
+        reference: item.reference.clone(),
+        metadata: Some(json!({"payout_batch_id": batch_id, "line": item.line})),
+        idempotency_key: Some(format!("payout:{}:{}", batch_id, item.line)),
+        ..Default::default()
+    }
+}
+
+async fn record_item(
+    pool: &Pool<Postgres>,
+    batch_id: Uuid,
+    line: i32,
+    status: &str,
+    transaction_id: Option<Uuid>,
+    error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let qry = "UPDATE payout_items SET status = $1, transaction_id = $2, error = $3, processed_at = $4
+        where batch_id = $5 and line = $6";
+    sqlx::query(qry)
+        .bind(status)
+        .bind(transaction_id)
+        .bind(error)
+        .bind(Utc::now().naive_utc())
+        .bind(batch_id)
+        .bind(line)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn pay_each(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    batch: &PayoutBatch,
+    owner: Uuid,
+    items: &[PayoutItem],
+    lease: Duration,
+) -> Result<(), sqlx::Error> {
+    for item in items {
+        let options = item_options(batch.batch_id, item);
+        //a crash after booking but before this bookkeeping must not pay twice
+        let key = options.idempotency_key.clone().unwrap_or_default();
+        let booked = match find_idempotent_transaction(pool, &key).await? {
+            Some(id) => Ok(id),
+            None => {
+                add_transaction_with(pool, compliance, owner, Some(item.receiver_id), item.amount, String::from("transfer"), options)
+                    .await
+            }
+        };
+        let (succeeded, failed) = match booked {
+            Ok(id) => {
+                record_item(pool, batch.batch_id, item.line, "completed", Some(id), None).await?;
+                (1, 0)
+            }
+            Err(e) => match TransactionError::from_sqlx(&e) {
+                Some(t) => {
+                    warn!(batch_id = %batch.batch_id, line = item.line, error = %t, "Payout row failed");
+                    record_item(pool, batch.batch_id, item.line, "failed", None, Some(t.reason())).await?;
+                    (0, 1)
+                }
+                //an infrastructure problem, the row is tried again once the claim expired
+                None => return Err(e),
+            },
+        };
+        let qry = "UPDATE payout_batches SET succeeded = succeeded + $1, failed = failed + $2, locked_until = $3 where batch_id = $4";
+        sqlx::query(qry)
+            .bind(succeeded)
+            .bind(failed)
+            .bind(Utc::now().naive_utc() + lease)
+            .bind(batch.batch_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn pay_all(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    batch: &PayoutBatch,
+    owner: Uuid,
+    items: &[PayoutItem],
+) -> Result<(), sqlx::Error> {
+    let transfers: Vec<BatchTransfer> = items
+        .iter()
+        .map(|item| BatchTransfer {
+            receiver: item.receiver_id,
+            amount: item.amount,
+            options: item_options(batch.batch_id, item),
+        })
+        .collect();
+
+    //the rows were booked together before a crash, only the bookkeeping is missing
+    let mut booked = Vec::with_capacity(items.len());
+    for t in &transfers {
+        match find_idempotent_transaction(pool, t.options.idempotency_key.as_deref().unwrap_or_default()).await? {
+            Some(id) => booked.push(id),
+            None => break,
+        }
+    }
+    let outcome = if booked.len() == transfers.len() {
+        Ok(booked)
+    } else {
+        add_transfers_atomically(pool, compliance, owner, &transfers).await
+    };
+
+    let qry = "UPDATE payout_batches SET succeeded = $1, failed = $2 where batch_id = $3";
+    match outcome {
+        Ok(ids) => {
+            for (item, id) in items.iter().zip(ids) {
+                record_item(pool, batch.batch_id, item.line, "completed", Some(id), None).await?;
+            }
+            sqlx::query(qry)
+                .bind(items.len() as i32)
+                .bind(0)
+                .bind(batch.batch_id)
+                .execute(pool)
+                .await?;
+        }
+        Err(BatchFailure { index: Some(i), error }) if TransactionError::from_sqlx(&error).is_some() => {
+            let reason = TransactionError::from_sqlx(&error).map(|t| t.reason());
+            warn!(batch_id = %batch.batch_id, line = items[i].line, error = %error, "Payout batch failed");
+            for (j, item) in items.iter().enumerate() {
+                if j == i {
+                    record_item(pool, batch.batch_id, item.line, "failed", None, reason).await?;
+                } else {
+                    record_item(pool, batch.batch_id, item.line, "skipped", None, None).await?;
+                }
+            }
+            sqlx::query(qry)
+                .bind(0)
+                .bind(1)
+                .bind(batch.batch_id)
+                .execute(pool)
+                .await?;
+        }
+        //an infrastructure problem, the batch is tried again once the claim expired
+        Err(e) => return Err(e.error),
+    }
+    Ok(())
+}
+
+//settles the status from the counters and notifies the owner
+async fn finish_batch(pool: &Pool<Postgres>, batch_id: Uuid, owner: Uuid) -> Result<PayoutBatch, sqlx::Error> {
+    let qry = "UPDATE payout_batches SET
+            status = CASE WHEN failed = 0 THEN 'completed' WHEN succeeded = 0 THEN 'failed' ELSE 'partially_completed' END,
+            completed_at = $1, locked_until = NULL
+        where batch_id = $2 RETURNING *";
+    let batch = payout_batch(
+        &sqlx::query(qry)
+            .bind(Utc::now().naive_utc())
+            .bind(batch_id)
+            .fetch_one(pool)
+            .await?,
+    );
+    let message = format!(
+        "Payout batch {}: {} of {} payments booked",
+        batch.status.as_str().replace('_', " "),
+        batch.succeeded,
+        batch.item_count
+    );
+    let data = json!({"batch_id": batch_id, "status": batch.status, "succeeded": batch.succeeded, "failed": batch.failed});
+    notify(pool, owner, "payout_batch_finished", &message, data).await?;
+    info!(batch_id = %batch_id, status = batch.status.as_str(), "Payout batch finished");
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    use crate::{
+        models::balance::get_balance,
+        utilities::{errors::TransactionError, test_harness::TestDb},
+    };
+
+    use super::{
+        create_payout_batch, list_payout_items, process_next_payout_batch, BatchStatus, InvalidRows, PayoutMode,
+        PayoutRow,
+    };
+
+    fn row(receiver: Uuid, amount: rust_decimal::Decimal) -> PayoutRow {
+        PayoutRow {
+            receiver: Some(receiver),
+            payee: None,
+            amount,
+            memo: None,
+            reference: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_payout_modes() {
+        let db = TestDb::new().await;
+        let payer = db.user().balance(dec!(100)).create().await;
+        let alice = db.user().create().await;
+        let bob = db.user().create().await;
+        let lease = Duration::minutes(5);
+
+        //every row is checked before anything is stored
+        let rows = vec![
+            row(alice.user_id, dec!(10)),
+            row(payer.user_id, dec!(10)),
+            row(Uuid::new_v4(), dec!(10)),
+            row(bob.user_id, dec!(0.001)),
+        ];
+        let err = create_payout_batch(&db.pool, payer.user_id, PayoutMode::BestEffort, None, rows, 100)
+            .await
+            .unwrap_err();
+        let codes: Vec<_> = InvalidRows::from_sqlx(&err).unwrap().0.iter().map(|e| (e.line, e.code.clone())).collect();
+        assert_eq!(
+            codes,
+            [(2, "same_account".into()), (3, "receiver_not_found".into()), (4, "too_many_decimals".into())]
+        );
+
+        //all_or_nothing must be covered up front
+        let rows = vec![row(alice.user_id, dec!(60)), row(bob.user_id, dec!(60))];
+        let err = create_payout_batch(&db.pool, payer.user_id, PayoutMode::AllOrNothing, None, rows.clone(), 100)
+            .await
+            .unwrap_err();
+        assert_eq!(TransactionError::from_sqlx(&err), Some(TransactionError::InsufficientBalance));
+
+        //best_effort books what the balance covers
+        let batch = create_payout_batch(&db.pool, payer.user_id, PayoutMode::BestEffort, None, rows, 100)
+            .await
+            .unwrap();
+        assert_eq!(batch.status, BatchStatus::Pending);
+        let done = process_next_payout_batch(&db.pool, &db.compliance, lease).await.unwrap().unwrap();
+        assert_eq!((done.status, done.succeeded, done.failed), (BatchStatus::PartiallyCompleted, 1, 1));
+        let items = list_payout_items(&db.pool, batch.batch_id).await.unwrap();
+        assert_eq!(items[1].error.as_deref(), Some("insufficient_balance"));
+        assert!(process_next_payout_batch(&db.pool, &db.compliance, lease).await.unwrap().is_none());
+
+        //all_or_nothing books nothing when one row fails at execution
+        let rows = vec![row(alice.user_id, dec!(20)), row(bob.user_id, dec!(20))];
+        let batch = create_payout_batch(&db.pool, payer.user_id, PayoutMode::AllOrNothing, None, rows, 100)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE account_balance SET status = 'frozen' where user_id = $1")
+            .bind(bob.user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let done = process_next_payout_batch(&db.pool, &db.compliance, lease).await.unwrap().unwrap();
+        assert_eq!(done.status, BatchStatus::Failed);
+        let items = list_payout_items(&db.pool, batch.batch_id).await.unwrap();
+        let statuses: Vec<_> = items.iter().map(|i| i.status.as_str()).collect();
+        assert_eq!(statuses, ["skipped", "failed"]);
+        assert_eq!(items[1].error.as_deref(), Some("receiver_unavailable"));
+        assert_eq!(get_balance(&db.pool, payer.user_id).await.unwrap().balance, dec!(40));
+    }
+}
@@ -0,0 +1,590 @@
+//scheduled and recurring payments (standing orders)
+//occurrences are counted from start_at, so a monthly payment on the 31st stays on the last day of shorter months
+//instead of drifting; the worker books each due occurrence through add_transaction under an idempotency key
+use chrono::{Duration, Months, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgRow, PgConnection, Pool, Postgres, Row};
+use tracing::{error, info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        notifications::notify,
+        transactions::{
+            add_transaction_with, find_idempotent_transaction, validate_amount, TransactionOptions,
+            MAX_MEMO_LEN,
+        },
+        users::get_user_by_id,
+    },
+    utilities::{errors::TransactionError, monitoring::Compliance},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Frequency {
+    Once,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Frequency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Frequency::Once => "once",
+            Frequency::Daily => "daily",
+            Frequency::Weekly => "weekly",
+            Frequency::Monthly => "monthly",
+        }
+    }
+
+    fn from_db(v: &str) -> Frequency {
+        match v {
+            "daily" => Frequency::Daily,
+            "weekly" => Frequency::Weekly,
+            "monthly" => Frequency::Monthly,
+            _ => Frequency::Once,
+        }
+    }
+
+    //the n-th planned payment, counted from 0, None after the only one of a single payment
+    pub fn occurrence(&self, start: NaiveDateTime, n: i32) -> Option<NaiveDateTime> {
+        match self {
+            Frequency::Once => (n == 0).then_some(start),
+            Frequency::Daily => start.checked_add_signed(Duration::days(n.into())),
+            Frequency::Weekly => start.checked_add_signed(Duration::weeks(n.into())),
+            Frequency::Monthly => start.checked_add_months(Months::new(n.try_into().ok()?)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    Active,
+    Paused,
+    Cancelled,
+    //every planned payment is done
+    Completed,
+    //a single payment that could not be booked, even after the retries
+    Failed,
+}
+
+impl ScheduleStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScheduleStatus::Active => "active",
+            ScheduleStatus::Paused => "paused",
+            ScheduleStatus::Cancelled => "cancelled",
+            ScheduleStatus::Completed => "completed",
+            ScheduleStatus::Failed => "failed",
+        }
+    }
+
+    fn from_db(v: &str) -> ScheduleStatus {
+        match v {
+            "active" => ScheduleStatus::Active,
+            "paused" => ScheduleStatus::Paused,
+            "completed" => ScheduleStatus::Completed,
+            "failed" => ScheduleStatus::Failed,
+            _ => ScheduleStatus::Cancelled,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScheduledPayment {
+    pub schedule_id: Uuid,
+    pub receiver_id: Uuid,
+    #[schema(value_type = String, example = "25.00")]
+    pub amount: Decimal,
+    pub memo: Option<String>,
+    pub frequency: Frequency,
+    pub start_at: NaiveDateTime,
+    //no payment is planned after it
+    pub end_at: Option<NaiveDateTime>,
+    //the schedule completes after this many booked payments
+    pub max_executions: Option<i32>,
+    pub executions: i32,
+    //the planned time of the pending payment
+    pub occurrence_at: NaiveDateTime,
+    //when the worker books it, later than occurrence_at while retrying, None once the schedule ended
+    pub next_run_at: Option<NaiveDateTime>,
+    //failed attempts of the pending payment
+    pub attempt: i32,
+    pub status: ScheduleStatus,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScheduleRun {
+    pub occurrence_at: NaiveDateTime,
+    pub attempt: i32,
+    #[schema(example = "completed")]
+    pub status: String,
+    pub transaction_id: Option<Uuid>,
+    //reason of a failed attempt, e.g. insufficient_balance
+    pub error: Option<String>,
+    pub ran_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewSchedule {
+    pub receiver_id: Uuid,
+    pub amount: Decimal,
+    pub memo: Option<String>,
+    pub frequency: Frequency,
+    pub start_at: NaiveDateTime,
+    pub end_at: Option<NaiveDateTime>,
+    pub max_executions: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: i32,
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+fn scheduled_payment(v: &PgRow) -> ScheduledPayment {
+    ScheduledPayment {
+        schedule_id: v.get("schedule_id"),
+        receiver_id: v.get("receiver_id"),
+        amount: v.get("amount"),
+        memo: v.get("memo"),
+        frequency: Frequency::from_db(v.get("frequency")),
+        start_at: v.get("start_at"),
+        end_at: v.get("end_at"),
+        max_executions: v.get("max_executions"),
+        executions: v.get("executions"),
+        occurrence_at: v.get("occurrence_at"),
+        next_run_at: v.get("next_run_at"),
+        attempt: v.get("attempt"),
+        status: ScheduleStatus::from_db(v.get("status")),
+        created_at: v.get("created_at"),
+        updated_at: v.get("updated_at"),
+    }
+}
+
+//the payment after occurrence n, or None when the schedule ends with n
+fn next_occurrence(s: &ScheduledPayment, n: i32, executions: i32) -> Option<(i32, NaiveDateTime)> {
+    if s.max_executions.is_some_and(|max| executions >= max) {
+        return None;
+    }
+    let at = s.frequency.occurrence(s.start_at, n + 1)?;
+    if s.end_at.is_some_and(|end| at > end) {
+        return None;
+    }
+    Some((n + 1, at))
+}
+
+#[instrument(name = "db.create_schedule", skip(pool, new))]
+pub async fn create_schedule(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    new: NewSchedule,
+) -> Result<ScheduledPayment, sqlx::Error> {
+    validate_amount(new.amount)?;
+    let memo = new.memo.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+    if memo.as_ref().is_some_and(|m| m.chars().count() > MAX_MEMO_LEN) {
+        return Err(sqlx::Error::Encode(
+            format!("Memo cannot be longer than {} characters", MAX_MEMO_LEN).into(),
+        ));
+    }
+    if new.receiver_id == owner {
+        return Err(TransactionError::SameAccount.into());
+    }
+    //a minute of slack for clocks and requests that say "now"
+    if new.start_at < Utc::now().naive_utc() - Duration::minutes(1) {
+        return Err(sqlx::Error::Encode("start_at must not be in the past".into()));
+    }
+    if new.frequency == Frequency::Once && (new.end_at.is_some() || new.max_executions.is_some()) {
+        return Err(sqlx::Error::Encode(
+            "end_at and count only apply to recurring payments".into(),
+        ));
+    }
+    if new.end_at.is_some_and(|end| end < new.start_at) {
+        return Err(sqlx::Error::Encode("end_at must not be before start_at".into()));
+    }
+    if new.max_executions.is_some_and(|max| max < 1) {
+        return Err(sqlx::Error::Encode("count must be at least 1".into()));
+    }
+    get_user_by_id(pool, new.receiver_id).await?;
+
+    let qry = "INSERT INTO scheduled_payments(schedule_id,owner_id,receiver_id,amount,memo,frequency,start_at,end_at,max_executions,occurrence_at,next_run_at,created_at,updated_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8,$9,$7,$7,$10,$10) RETURNING *;";
+    match sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(owner)
+        .bind(new.receiver_id)
+        .bind(new.amount)
+        .bind(memo)
+        .bind(new.frequency.as_str())
+        .bind(new.start_at)
+        .bind(new.end_at)
+        .bind(new.max_executions)
+        .bind(Utc::now().naive_utc())
+        .fetch_one(pool)
+        .await
+    {
+        Ok(v) => Ok(scheduled_payment(&v)),
+        Err(e) => {
+            error!(error = %e, "Error at create_schedule");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.list_schedules", skip(pool))]
+pub async fn list_schedules(pool: &Pool<Postgres>, owner: Uuid) -> Result<Vec<ScheduledPayment>, sqlx::Error> {
+    let qry = "SELECT * FROM scheduled_payments where owner_id = $1 ORDER BY id DESC";
+
+    match sqlx::query(qry).bind(owner).fetch_all(pool).await {
+        Ok(v) => Ok(v.iter().map(scheduled_payment).collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_schedules");
+            Err(e)
+        }
+    }
+}
+
+//another user's schedule is RowNotFound, the same as a missing one
+#[instrument(name = "db.get_schedule", skip(pool))]
+pub async fn get_schedule(pool: &Pool<Postgres>, owner: Uuid, id: Uuid) -> Result<ScheduledPayment, sqlx::Error> {
+    let qry = "SELECT * FROM scheduled_payments where owner_id = $1 and schedule_id = $2";
+
+    match sqlx::query(qry).bind(owner).bind(id).fetch_one(pool).await {
+        Ok(v) => Ok(scheduled_payment(&v)),
+        Err(e) => Err(e),
+    }
+}
+
+//oldest first
+#[instrument(name = "db.list_schedule_runs", skip(pool))]
+pub async fn list_schedule_runs(pool: &Pool<Postgres>, id: Uuid) -> Result<Vec<ScheduleRun>, sqlx::Error> {
+    let qry = "SELECT * FROM scheduled_payment_runs where schedule_id = $1 ORDER BY id";
+
+    match sqlx::query(qry).bind(id).fetch_all(pool).await {
+        Ok(v) => Ok(v
+            .iter()
+            .map(|r| ScheduleRun {
+                occurrence_at: r.get("occurrence_at"),
+                attempt: r.get("attempt"),
+                status: r.get("status"),
+                transaction_id: r.get("transaction_id"),
+                error: r.get("error"),
+                ran_at: r.get("ran_at"),
+            })
+            .collect()),
+        Err(e) => {
+            error!(error = %e, "Error at list_schedule_runs");
+            Err(e)
+        }
+    }
+}
+
+//pause and resume switch between active and paused, cancel ends an active or paused schedule for good
+//payments that fell due while paused are skipped, the schedule resumes with the next one from now on
+#[instrument(name = "db.change_schedule", skip(pool))]
+pub async fn change_schedule(
+    pool: &Pool<Postgres>,
+    owner: Uuid,
+    id: Uuid,
+    action: ScheduleAction,
+) -> Result<ScheduledPayment, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let changed = async {
+        let qry = "SELECT * FROM scheduled_payments where owner_id = $1 and schedule_id = $2 FOR UPDATE";
+        let row = sqlx::query(qry).bind(owner).bind(id).fetch_one(&mut *tx).await?;
+        let s = scheduled_payment(&row);
+        let n: i32 = row.get("occurrence_index");
+
+        let (status, n, occurrence_at, next_run_at, attempt) = match (action, s.status) {
+            (ScheduleAction::Pause, ScheduleStatus::Active) => {
+                (ScheduleStatus::Paused, n, s.occurrence_at, s.next_run_at, s.attempt)
+            }
+            (ScheduleAction::Resume, ScheduleStatus::Paused) => {
+                let now = Utc::now().naive_utc();
+                //a pending retry or a payment that is still ahead keeps its place
+                if s.next_run_at.is_some_and(|r| r >= now) {
+                    (ScheduleStatus::Active, n, s.occurrence_at, s.next_run_at, s.attempt)
+                } else {
+                    let mut next = Some((n, s.occurrence_at));
+                    while let Some((i, _)) = next.filter(|(_, at)| *at < now) {
+                        next = next_occurrence(&s, i, s.executions);
+                    }
+                    match next {
+                        Some((i, at)) => (ScheduleStatus::Active, i, at, Some(at), 0),
+                        None => (ScheduleStatus::Completed, n, s.occurrence_at, None, 0),
+                    }
+                }
+            }
+            (ScheduleAction::Cancel, ScheduleStatus::Active | ScheduleStatus::Paused) => {
+                (ScheduleStatus::Cancelled, n, s.occurrence_at, None, s.attempt)
+            }
+            _ => return Err(TransactionError::InvalidScheduleState.into()),
+        };
+
+        let qry = "UPDATE scheduled_payments SET status=$1, occurrence_index=$2, occurrence_at=$3, next_run_at=$4, attempt=$5, updated_at=$6
+            where schedule_id = $7 RETURNING *;";
+        let row = sqlx::query(qry)
+            .bind(status.as_str())
+            .bind(n)
+            .bind(occurrence_at)
+            .bind(next_run_at)
+            .bind(attempt)
+            .bind(Utc::now().naive_utc())
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        Ok((status, row))
+    }
+    .await;
+
+    //rejections roll back right away, a dropped transaction keeps its row locks until the connection is reused
+    let (status, row) = match changed {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = tx.rollback().await;
+            return Err(e);
+        }
+    };
+    tx.commit().await?;
+    info!(schedule_id = %id, status = status.as_str(), "Schedule changed");
+    Ok(scheduled_payment(&row))
+}
+
+//books every payment due at now, up to limit, and returns how many were handled
+//each schedule row stays locked while its payment is booked, so several workers never run the same one
+#[instrument(name = "db.process_due_schedules", skip(pool, compliance, policy))]
+pub async fn process_due_schedules(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    now: NaiveDateTime,
+    policy: &RetryPolicy,
+    limit: usize,
+) -> Result<usize, sqlx::Error> {
+    let mut processed = 0;
+    while processed < limit {
+        let mut tx = pool.begin().await?;
+        let qry = "SELECT * FROM scheduled_payments where status = 'active' and next_run_at <= $1
+            ORDER BY next_run_at LIMIT 1 FOR UPDATE SKIP LOCKED";
+        let executed = match sqlx::query(qry).bind(now).fetch_optional(&mut *tx).await {
+            Ok(Some(row)) => execute_occurrence(pool, compliance, &mut tx, &row, now, policy).await.map(|_| true),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        };
+        match executed {
+            Ok(true) => tx.commit().await?,
+            Ok(false) => {
+                tx.commit().await?;
+                break;
+            }
+            Err(e) => {
+                let _ = tx.rollback().await;
+                return Err(e);
+            }
+        }
+        processed += 1;
+    }
+    Ok(processed)
+}
+
+async fn execute_occurrence(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    conn: &mut PgConnection,
+    row: &PgRow,
+    now: NaiveDateTime,
+    policy: &RetryPolicy,
+) -> Result<(), sqlx::Error> {
+    let s = scheduled_payment(row);
+    let owner: Uuid = row.get("owner_id");
+    let n: i32 = row.get("occurrence_index");
+    let key = format!("schedule:{}:{}", s.schedule_id, n);
+
+    //a crash after booking but before this bookkeeping must not pay twice
+    let booked = match find_idempotent_transaction(pool, &key).await? {
+        Some(id) => Ok(id),
+        None => {
+            let options = TransactionOptions {
+                memo: s.memo.clone(),
+                metadata: Some(json!({"schedule_id": s.schedule_id})),
+                idempotency_key: Some(key),
+                ..Default::default()
+            };
+            add_transaction_with(pool, compliance, owner, Some(s.receiver_id), s.amount, String::from("transfer"), options)
+                .await
+        }
+    };
+
+    let (status, transaction_id, reason) = match booked {
+        Ok(id) => ("completed", Some(id), None),
+        Err(e) => match TransactionError::from_sqlx(&e) {
+            Some(t) => ("failed", None, Some(t)),
+            //an infrastructure problem, the payment is tried again on the next poll
+            None => return Err(e),
+        },
+    };
+    let qry = "INSERT INTO scheduled_payment_runs(schedule_id,occurrence_index,occurrence_at,attempt,status,transaction_id,error,ran_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8);";
+    sqlx::query(qry)
+        .bind(s.schedule_id)
+        .bind(n)
+        .bind(s.occurrence_at)
+        .bind(s.attempt)
+        .bind(status)
+        .bind(transaction_id)
+        .bind(reason.map(|r| r.reason()))
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+
+    let data = json!({"schedule_id": s.schedule_id, "occurrence_at": s.occurrence_at});
+    let (executions, advance) = match reason {
+        None => (s.executions + 1, true),
+        Some(reason) if s.attempt < policy.max_retries => {
+            let retry_at = now + policy.delay;
+            warn!(schedule_id = %s.schedule_id, attempt = s.attempt, reason = reason.reason(), "Scheduled payment failed, retrying");
+            let message = format!(
+                "Scheduled payment of {} failed ({}), retrying at {}",
+                s.amount, reason, retry_at
+            );
+            notify(&mut *conn, owner, "scheduled_payment_retry", &message, data).await?;
+            let qry = "UPDATE scheduled_payments SET attempt=$1, next_run_at=$2, updated_at=$3 where schedule_id = $4";
+            sqlx::query(qry)
+                .bind(s.attempt + 1)
+                .bind(retry_at)
+                .bind(now)
+                .bind(s.schedule_id)
+                .execute(&mut *conn)
+                .await?;
+            return Ok(());
+        }
+        Some(reason) => {
+            warn!(schedule_id = %s.schedule_id, reason = reason.reason(), "Scheduled payment skipped after retries");
+            let message = format!(
+                "Scheduled payment of {} planned for {} was not made: {}",
+                s.amount, s.occurrence_at, reason
+            );
+            notify(&mut *conn, owner, "scheduled_payment_failed", &message, data).await?;
+            (s.executions, false)
+        }
+    };
+
+    let (status, n, occurrence_at, next_run_at) = match next_occurrence(&s, n, executions) {
+        Some((i, at)) => (ScheduleStatus::Active, i, at, Some(at)),
+        //a single payment that was never booked
+        None if !advance && s.frequency == Frequency::Once => (ScheduleStatus::Failed, n, s.occurrence_at, None),
+        None => (ScheduleStatus::Completed, n, s.occurrence_at, None),
+    };
+    let qry = "UPDATE scheduled_payments SET executions=$1, occurrence_index=$2, occurrence_at=$3, next_run_at=$4, attempt=0, status=$5, updated_at=$6
+        where schedule_id = $7";
+    sqlx::query(qry)
+        .bind(executions)
+        .bind(n)
+        .bind(occurrence_at)
+        .bind(next_run_at)
+        .bind(status.as_str())
+        .bind(now)
+        .bind(s.schedule_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate, SubsecRound, Utc};
+    use rust_decimal_macros::dec;
+
+    use crate::{
+        models::{balance::get_balance, notifications::list_notifications},
+        utilities::test_harness::TestDb,
+    };
+
+    use super::{
+        change_schedule, create_schedule, get_schedule, list_schedule_runs, process_due_schedules,
+        Frequency, NewSchedule, RetryPolicy, ScheduleAction, ScheduleStatus,
+    };
+
+    #[test]
+    fn test_monthly_occurrences_do_not_drift() {
+        let start = NaiveDate::from_ymd_opt(2025, 1, 31).unwrap().and_hms_opt(9, 0, 0).unwrap();
+        let day = |n| Frequency::Monthly.occurrence(start, n).unwrap().date().to_string();
+        assert_eq!(day(1), "2025-02-28");
+        assert_eq!(day(2), "2025-03-31");
+        assert_eq!(day(13), "2026-02-28");
+        assert_eq!(Frequency::Once.occurrence(start, 1), None);
+        assert_eq!(Frequency::Weekly.occurrence(start, 2).unwrap().date().to_string(), "2025-02-14");
+    }
+
+    #[actix_web::test]
+    async fn test_standing_order_with_retries() {
+        let db = TestDb::new().await;
+        let owner = db.user().balance(dec!(15)).create().await;
+        let landlord = db.user().create().await;
+        let policy = RetryPolicy {
+            max_retries: 1,
+            delay: Duration::hours(1),
+        };
+
+        //postgres keeps microseconds
+        let start = Utc::now().naive_utc().trunc_subsecs(0);
+        let new = NewSchedule {
+            receiver_id: landlord.user_id,
+            amount: dec!(10),
+            memo: Some(String::from("Rent")),
+            frequency: Frequency::Daily,
+            start_at: start,
+            end_at: None,
+            max_executions: Some(2),
+        };
+        let s = create_schedule(&db.pool, owner.user_id, new.clone()).await.unwrap();
+        let id = s.schedule_id;
+
+        //first day, then a second poll at the same time must not pay again
+        assert_eq!(process_due_schedules(&db.pool, &db.compliance, start, &policy, 10).await.unwrap(), 1);
+        assert_eq!(process_due_schedules(&db.pool, &db.compliance, start, &policy, 10).await.unwrap(), 0);
+        assert_eq!(get_balance(&db.pool, landlord.user_id).await.unwrap().balance, dec!(10));
+
+        //second day, 5 left: one retry, then the payment is skipped and the schedule is done
+        let day2 = start + Duration::days(1);
+        process_due_schedules(&db.pool, &db.compliance, day2, &policy, 10).await.unwrap();
+        let s = get_schedule(&db.pool, owner.user_id, id).await.unwrap();
+        assert_eq!((s.attempt, s.next_run_at), (1, Some(day2 + Duration::hours(1))));
+        process_due_schedules(&db.pool, &db.compliance, day2 + Duration::hours(1), &policy, 10).await.unwrap();
+        let s = get_schedule(&db.pool, owner.user_id, id).await.unwrap();
+        assert_eq!((s.executions, s.status), (1, ScheduleStatus::Active));
+        assert_eq!(s.occurrence_at, start + Duration::days(2));
+
+        let runs = list_schedule_runs(&db.pool, id).await.unwrap();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[1].error.as_deref(), Some("insufficient_balance"));
+        let kinds: Vec<String> = list_notifications(&db.pool, owner.user_id, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|n| n.kind)
+            .collect();
+        assert_eq!(kinds, vec!["scheduled_payment_failed", "scheduled_payment_retry"]);
+
+        //a paused schedule is not executed, a cancelled one cannot be resumed
+        change_schedule(&db.pool, owner.user_id, id, ScheduleAction::Pause).await.unwrap();
+        assert_eq!(process_due_schedules(&db.pool, &db.compliance, start + Duration::days(2), &policy, 10).await.unwrap(), 0);
+        let s = change_schedule(&db.pool, owner.user_id, id, ScheduleAction::Cancel).await.unwrap();
+        assert_eq!((s.status, s.next_run_at), (ScheduleStatus::Cancelled, None));
+        assert!(change_schedule(&db.pool, owner.user_id, id, ScheduleAction::Resume).await.is_err());
+        assert!(change_schedule(&db.pool, landlord.user_id, id, ScheduleAction::Pause).await.is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//sanctions screening results, every screening is recorded for audit while a list is loaded
+//registration: a blocked name is refused, a name under review gets a frozen account until an analyst clears or confirms it
+//transfer: the receiver's name is screened with the monitoring rules, hits are held or blocked like them
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, Executor, PgConnection, Pool, Postgres, Row};
+use tracing::{debug, info, instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        balance::{lock_balance, set_account_status, write_account_status, AccountStatus},
+        monitoring::Verdict,
+    },
+    utilities::{
+        errors::TransactionError,
+        monitoring::Action,
+        screening::{Sanctions, Screening},
+    },
+};
+
+//the name of the screening in the rules of a monitoring alert
+pub const SCREENING_RULE: &str = "sanctions_screening";
+
+const FREEZE_REASON: &str = "Possible sanctions list match, pending review";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScreeningResult {
+    pub result_id: Uuid,
+    //registration or transfer
+    pub context: String,
+    pub screened_name: String,
+    //the screened user, none for a refused registration
+    pub user_id: Option<Uuid>,
+    //the payer of a screened transfer
+    pub sender_id: Option<Uuid>,
+    pub transaction_id: Option<Uuid>,
+    pub action: Action,
+    //the closest list entry, set from review_score on
+    pub entry_id: Option<String>,
+    pub matched_name: Option<String>,
+    pub program: Option<String>,
+    pub score: Option<f64>,
+    pub list_source: Option<String>,
+    //open, cleared or confirmed for registrations under review
+    pub status: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub decided_by: Option<Uuid>,
+    pub decided_at: Option<NaiveDateTime>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct ScreeningFilter {
+    //open, cleared or confirmed
+    pub status: Option<String>,
+    //allow, review or block
+    pub action: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+fn screening_result(v: &PgRow) -> ScreeningResult {
+    let action: String = v.get("action");
+    ScreeningResult {
+        result_id: v.get("result_id"),
+        context: v.get("context"),
+        screened_name: v.get("screened_name"),
+        user_id: v.get("user_id"),
+        sender_id: v.get("sender_id"),
+        transaction_id: v.get("transaction_id"),
+        action: match action.as_str() {
+            "block" => Action::Block,
+            "review" => Action::Review,
+            _ => Action::Allow,
+        },
+        entry_id: v.get("entry_id"),
+        matched_name: v.get("matched_name"),
+        program: v.get("program"),
+        score: v.get("score"),
+        list_source: v.get("list_source"),
+        status: v.get("status"),
+        created_at: v.get("created_at"),
+        decided_by: v.get("decided_by"),
+        decided_at: v.get("decided_at"),
+        note: v.get("note"),
+    }
+}
+
+//what a screening was about
+pub struct Subject<'a> {
+    pub context: &'static str,
+    pub name: &'a str,
+    pub user_id: Option<Uuid>,
+    pub sender_id: Option<Uuid>,
+    pub transaction_id: Option<Uuid>,
+}
+
+async fn record<'e, E>(executor: E, subject: &Subject<'_>, screening: &Screening) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "INSERT INTO screening_results(result_id,context,screened_name,user_id,sender_id,transaction_id,action,entry_id,matched_name,program,score,list_source,status,created_at)
+        Values ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14);";
+    let hit = screening.hit.as_ref();
+    //only names of users wait for a decision here, held transfers are decided through their monitoring alert
+    let status = (subject.context != "transfer" && screening.action == Action::Review).then_some("open");
+    sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(subject.context)
+        .bind(subject.name)
+        .bind(subject.user_id)
+        .bind(subject.sender_id)
+        .bind(subject.transaction_id)
+        .bind(screening.action.as_str())
+        .bind(hit.map(|h| &h.entry_id))
+        .bind(hit.map(|h| &h.name))
+        .bind(hit.map(|h| &h.program))
+        .bind(hit.map(|h| h.score))
+        .bind(&screening.source)
+        .bind(status)
+        .bind(Utc::now().naive_utc())
+        .execute(executor)
+        .await?;
+    if screening.action != Action::Allow {
+        warn!(context = subject.context, action = screening.action.as_str(), hit = ?hit, "Sanctions screening hit");
+    }
+    Ok(())
+}
+
+//a blocked name is recorded and refused, none while no list is loaded
+async fn screen_name(
+    pool: &Pool<Postgres>,
+    sanctions: &Sanctions,
+    context: &'static str,
+    user_id: Option<Uuid>,
+    username: &str,
+) -> Result<Option<Screening>, sqlx::Error> {
+    let list = sanctions.current();
+    if list.is_empty() {
+        return Ok(None);
+    }
+    let screening = list.screen(username);
+    if screening.action == Action::Block {
+        let subject = Subject {
+            context,
+            name: username,
+            user_id,
+            sender_id: None,
+            transaction_id: None,
+        };
+        record(pool, &subject, &screening).await?;
+        return Err(TransactionError::ScreeningBlocked.into());
+    }
+    Ok(Some(screening))
+}
+
+//under review the account is frozen until an analyst decides
+async fn record_name(
+    pool: &Pool<Postgres>,
+    context: &'static str,
+    user_id: Uuid,
+    username: &str,
+    screening: &Screening,
+) -> Result<(), sqlx::Error> {
+    let subject = Subject {
+        context,
+        name: username,
+        user_id: Some(user_id),
+        sender_id: None,
+        transaction_id: None,
+    };
+    record(pool, &subject, screening).await?;
+    if screening.action == Action::Review {
+        set_account_status(pool, user_id, AccountStatus::Frozen, FREEZE_REASON, user_id).await?;
+    }
+    Ok(())
+}
+
+//screens the username before the user is created
+pub async fn screen_registration(pool: &Pool<Postgres>, sanctions: &Sanctions, username: &str) -> Result<Option<Screening>, sqlx::Error> {
+    screen_name(pool, sanctions, "registration", None, username).await
+}
+
+//records the screening of a created user
+pub async fn record_registration(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    username: &str,
+    screening: &Screening,
+) -> Result<(), sqlx::Error> {
+    record_name(pool, "registration", user_id, username, screening).await
+}
+
+//screens the new username before the user is renamed
+pub async fn screen_rename(pool: &Pool<Postgres>, sanctions: &Sanctions, user_id: Uuid, username: &str) -> Result<Option<Screening>, sqlx::Error> {
+    screen_name(pool, sanctions, "rename", Some(user_id), username).await
+}
+
+//records the screening of a renamed user
+pub async fn record_rename(pool: &Pool<Postgres>, user_id: Uuid, username: &str, screening: &Screening) -> Result<(), sqlx::Error> {
+    record_name(pool, "rename", user_id, username, screening).await
+}
+
+//the receiver of a transfer and how its name screened
+#[derive(Debug, Clone)]
+pub struct ReceiverScreening {
+    pub user_id: Uuid,
+    pub name: String,
+    pub screening: Screening,
+}
+
+impl ReceiverScreening {
+    //a hit holds or blocks the transfer next to the monitoring rules
+    pub fn apply(&self, verdict: &mut Verdict) {
+        if self.screening.action > Action::Allow {
+            verdict.action = verdict.action.max(self.screening.action);
+            verdict.rules.push(SCREENING_RULE.to_string());
+        }
+    }
+
+    pub async fn record<'e, E>(&self, executor: E, sender: Uuid, transaction_id: Option<Uuid>) -> Result<(), sqlx::Error>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let subject = Subject {
+            context: "transfer",
+            name: &self.name,
+            user_id: Some(self.user_id),
+            sender_id: Some(sender),
+            transaction_id,
+        };
+        record(executor, &subject, &self.screening).await
+    }
+}
+
+//none while no list is loaded
+pub async fn screen_receiver(conn: &mut PgConnection, sanctions: &Sanctions, receiver: Uuid) -> Result<Option<ReceiverScreening>, sqlx::Error> {
+    let list = sanctions.current();
+    if list.is_empty() {
+        return Ok(None);
+    }
+    let name: String = sqlx::query_scalar("SELECT username FROM users where user_id = $1")
+        .bind(receiver)
+        .fetch_one(conn)
+        .await?;
+    Ok(Some(ReceiverScreening {
+        user_id: receiver,
+        screening: list.screen(&name),
+        name,
+    }))
+}
+
+//newest first
+#[instrument(name = "db.list_screening_results", skip(pool))]
+pub async fn list_screening_results(pool: &Pool<Postgres>, filter: &ScreeningFilter, limit: i64, offset: i64) -> Result<Vec<ScreeningResult>, sqlx::Error> {
+    let qry = "SELECT * FROM screening_results
+        where ($1::text IS NULL or status = $1) and ($2::text IS NULL or action = $2) and ($3::uuid IS NULL or user_id = $3)
+        ORDER BY created_at DESC, id DESC LIMIT $4 OFFSET $5";
+
+    match sqlx::query(qry)
+        .bind(&filter.status)
+        .bind(&filter.action)
+        .bind(filter.user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(v) => Ok(v.iter().map(screening_result).collect()),
+        Err(e) => {
+            debug!(error = %e, "Error at list_screening_results");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.get_screening_result", skip(pool))]
+pub async fn get_screening_result(pool: &Pool<Postgres>, id: Uuid) -> Result<ScreeningResult, sqlx::Error> {
+    let qry = "SELECT * FROM screening_results where result_id = $1";
+
+    sqlx::query(qry).bind(id).fetch_one(pool).await.map(|v| screening_result(&v))
+}
+
+//clearing unfreezes the account frozen at registration or rename, confirming keeps it frozen
+#[instrument(name = "db.decide_screening", skip(pool, note))]
+pub async fn decide_screening(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+    clear: bool,
+    decided_by: Uuid,
+    note: Option<&str>,
+) -> Result<ScreeningResult, sqlx::Error> {
+    let status = match clear {
+        true => "cleared",
+        false => "confirmed",
+    };
+    let mut tx = pool.begin().await?;
+    let decided: Result<ScreeningResult, sqlx::Error> = async {
+        let row = sqlx::query("SELECT * FROM screening_results where result_id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let result = screening_result(&row);
+        let user_id = match result.user_id {
+            Some(v) if result.status.as_deref() == Some("open") => v,
+            _ => return Err(TransactionError::ReviewClosed.into()),
+        };
+        if user_id == decided_by {
+            return Err(TransactionError::SelfReview.into());
+        }
+        if clear {
+            //only the freeze of the screening is lifted, not one an admin set since
+            let account = lock_balance(&mut tx, user_id).await?;
+            let reason: Option<String> = sqlx::query_scalar("SELECT status_reason FROM account_balance where user_id = $1")
+                .bind(user_id)
+                .fetch_one(&mut *tx)
+                .await?;
+            if account.status == AccountStatus::Frozen && reason.as_deref() == Some(FREEZE_REASON) {
+                write_account_status(&mut tx, user_id, account.status, AccountStatus::Active, "Sanctions screening cleared", decided_by, None).await?;
+            }
+        }
+        let qry = "UPDATE screening_results SET status = $1, decided_by = $2, decided_at = $3, note = $4 where result_id = $5 RETURNING *";
+        let row = sqlx::query(qry)
+            .bind(status)
+            .bind(decided_by)
+            .bind(Utc::now().naive_utc())
+            .bind(note.map(str::trim).filter(|n| !n.is_empty()))
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        Ok(screening_result(&row))
+    }
+    .await;
+
+    match decided {
+        Ok(v) => {
+            tx.commit().await?;
+            info!(result_id = %id, status, by = %decided_by, "Screening decided");
+            Ok(v)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//single-use tokens sent by e-mail, to verify the address of a user and to reset a forgotten password
+//only the sha-256 of a token is stored, the token itself exists in the e-mail alone
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, Pool, Postgres};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    models::login_attempts::clear_lockouts,
+    utilities::{auth::hash_password, errors::TransactionError},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::PasswordReset => "password_reset",
+        }
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+//256 random bits as 64 hex characters
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+//a new token replaces the unused ones of the same purpose, only the latest e-mail works
+#[instrument(name = "db.issue_token", skip(pool))]
+pub async fn issue_token(pool: &Pool<Postgres>, uid: Uuid, purpose: TokenPurpose, ttl: Duration) -> Result<String, sqlx::Error> {
+    let token = new_token();
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let issued: Result<(), sqlx::Error> = async {
+        sqlx::query("DELETE FROM user_tokens where user_id = $1 and purpose = $2 and used_at IS NULL")
+            .bind(uid)
+            .bind(purpose.as_str())
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO user_tokens(user_id,purpose,token_hash,created_at,expires_at) Values ($1,$2,$3,$4,$5)")
+            .bind(uid)
+            .bind(purpose.as_str())
+            .bind(hash_token(&token))
+            .bind(now)
+            .bind(now + ttl)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+    .await;
+
+    match issued {
+        Ok(_) => {
+            tx.commit().await?;
+            info!(user_id = %uid, purpose = purpose.as_str(), "Token issued");
+            Ok(token)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//marks the token used and returns its user, an unknown, expired or used token is InvalidToken
+async fn consume_token(conn: &mut PgConnection, token: &str, purpose: TokenPurpose) -> Result<Uuid, sqlx::Error> {
+    let qry = "UPDATE user_tokens SET used_at = $3
+        where token_hash = $1 and purpose = $2 and used_at IS NULL and expires_at > $3 RETURNING user_id";
+    let uid: Option<Uuid> = sqlx::query_scalar(qry)
+        .bind(hash_token(token.trim()))
+        .bind(purpose.as_str())
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(conn)
+        .await?;
+    uid.ok_or_else(|| TransactionError::InvalidToken.into())
+}
+
+#[instrument(name = "db.verify_email", skip_all)]
+pub async fn verify_email(pool: &Pool<Postgres>, token: &str) -> Result<Uuid, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let verified: Result<Uuid, sqlx::Error> = async {
+        let uid = consume_token(&mut tx, token, TokenPurpose::EmailVerification).await?;
+        sqlx::query("UPDATE users SET email_verified_at = COALESCE(email_verified_at, $2) where user_id = $1")
+            .bind(uid)
+            .bind(Utc::now().naive_utc())
+            .execute(&mut *tx)
+            .await?;
+        Ok(uid)
+    }
+    .await;
+
+    match verified {
+        Ok(uid) => {
+            tx.commit().await?;
+            info!(user_id = %uid, "E-mail verified");
+            Ok(uid)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the reset link reached the user's inbox, so it verifies the address as well
+//whoever knew the old password may have left credentials behind: API keys and OAuth2 consents are revoked,
+//unused authorization codes deleted and the lockouts lifted
+#[instrument(name = "db.reset_password", skip_all)]
+pub async fn reset_password(pool: &Pool<Postgres>, token: &str, password: &str) -> Result<Uuid, sqlx::Error> {
+    if password.is_empty() {
+        return Err(sqlx::Error::Encode("Password must not be empty".into()));
+    }
+    let hash = hash_password(password).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let reset: Result<Uuid, sqlx::Error> = async {
+        let uid = consume_token(&mut tx, token, TokenPurpose::PasswordReset).await?;
+        let email: String = sqlx::query_scalar("UPDATE users SET password = $2, updated_at = $3, password_changed_at = $3, email_verified_at = COALESCE(email_verified_at, $3) where user_id = $1 RETURNING email")
+            .bind(uid)
+            .bind(&hash)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE api_keys SET revoked_at = $2 where user_id = $1 and revoked_at IS NULL")
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE oauth_consents SET revoked_at = $2 where user_id = $1 and revoked_at IS NULL")
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        //the clients of the user act with its own account through client credentials
+        sqlx::query("UPDATE oauth_clients SET revoked_at = $2 where owner_id = $1 and revoked_at IS NULL")
+            .bind(uid)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM oauth_codes where user_id = $1 and used_at IS NULL")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        clear_lockouts(&mut tx, uid, &email).await?;
+        Ok(uid)
+    }
+    .await;
+
+    match reset {
+        Ok(uid) => {
+            tx.commit().await?;
+            info!(user_id = %uid, "Password reset");
+            Ok(uid)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
@@ -1,236 +1,1146 @@
 use chrono::{DateTime, NaiveDateTime, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use sqlx::{pool, Pool, Postgres, Row};
+use serde_json::Value;
+use sqlx::{postgres::PgRow, Executor, PgConnection, Pool, Postgres, Row};
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
-use crate::models::{
-    balance::{get_balance, update_balance},
-    users::{get_user, get_user_by_id},
+use crate::{
+    models::{
+        balance::{
+            lock_balance, update_balance, write_account_status, AccountState, AccountStatus,
+            BalanceDetails,
+        },
+        beneficiaries::TransferLimits,
+        fees::{find_fee, Fee},
+        kyc::enforce_kyc,
+        limits::enforce_limits,
+        monitoring::{evaluate, record_block, record_review, Candidate, Verdict},
+        screening::{screen_receiver, ReceiverScreening},
+    },
+    utilities::{
+        errors::TransactionError,
+        metrics,
+        monitoring::{Action, Compliance},
+    },
 };
 
+//amounts are stored with two decimal places, anything else would be rounded silently by postgres
+pub fn validate_amount(amount: Decimal) -> Result<(), sqlx::Error> {
+    if amount <= Decimal::ZERO {
+        return Err(TransactionError::NonPositiveAmount.into());
+    }
+    if amount.normalize().scale() > 2 {
+        return Err(TransactionError::TooManyDecimals.into());
+    }
+    Ok(())
+}
+
+pub const MAX_MEMO_LEN: usize = 140;
+const MAX_REFERENCE_LEN: usize = 64;
+const MAX_METADATA_BYTES: usize = 4096;
+
+//optional parts of a booking, add_transaction books without any
+#[derive(Debug, Clone, Default)]
+pub struct TransactionOptions {
+    //limits of the beneficiary the transfer is paid to
+    pub limits: Option<TransferLimits>,
+    pub memo: Option<String>,
+    //client supplied, e.g. an order id, not required to be unique
+    pub reference: Option<String>,
+    //a JSON object, stored as JSONB
+    pub metadata: Option<Value>,
+    //set by the workers, a key is booked at most once (failed attempts do not count)
+    pub idempotency_key: Option<String>,
+}
+
+impl TransactionOptions {
+    //empty memo and reference count as not given
+    pub fn validate(&self) -> Result<(Option<&str>, Option<&str>), sqlx::Error> {
+        let memo = self.memo.as_deref().map(str::trim).filter(|m| !m.is_empty());
+        if memo.is_some_and(|m| m.chars().count() > MAX_MEMO_LEN) {
+            return Err(sqlx::Error::Encode(
+                format!("Memo cannot be longer than {} characters", MAX_MEMO_LEN).into(),
+            ));
+        }
+        let reference = self.reference.as_deref().map(str::trim).filter(|r| !r.is_empty());
+        if reference.is_some_and(|r| r.chars().count() > MAX_REFERENCE_LEN) {
+            return Err(sqlx::Error::Encode(
+                format!("Reference cannot be longer than {} characters", MAX_REFERENCE_LEN).into(),
+            ));
+        }
+        match &self.metadata {
+            None => {}
+            Some(Value::Object(_)) if self.metadata_size() <= MAX_METADATA_BYTES => {}
+            Some(Value::Object(_)) => {
+                return Err(sqlx::Error::Encode(
+                    format!("Metadata cannot be larger than {} bytes", MAX_METADATA_BYTES).into(),
+                ))
+            }
+            Some(_) => return Err(sqlx::Error::Encode("Metadata must be a JSON object".into())),
+        }
+        Ok((memo, reference))
+    }
+
+    fn metadata_size(&self) -> usize {
+        self.metadata.as_ref().map_or(0, |m| m.to_string().len())
+    }
+}
+
+//books a deposit, withdrawl or transfer
+//the balance rows are locked and every write happens in one database transaction, so a failure leaves no partial effect
 pub async fn add_transaction(
     pool: &Pool<Postgres>,
+    compliance: &Compliance,
     sender: Uuid,
     receiver: Option<Uuid>,
     amount: Decimal,
     transaction_type: String,
-) -> Result<(), sqlx::Error> {
-    println!("Hello from the add transactions");
-    println!("receiverr = {:?}", receiver);
-    let sender_details = match get_user_by_id(pool, sender.clone()).await {
-        Ok(v) => match get_balance(pool, v.user_id).await {
-            Ok(v) => v,
-            Err(e) => return Err(e),
-        },
-        Err(e) => {
-            return Err(e);
-        }
-    };
+) -> Result<Uuid, sqlx::Error> {
+    add_transaction_with(pool, compliance, sender, receiver, amount, transaction_type, TransactionOptions::default()).await
+}
 
-    let mut receiver_details = sender_details;
+#[instrument(name = "db.add_transaction", skip(pool, compliance, options), fields(transaction_id))]
+pub async fn add_transaction_with(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    sender: Uuid,
+    receiver: Option<Uuid>,
+    amount: Decimal,
+    transaction_type: String,
+    options: TransactionOptions,
+) -> Result<Uuid, sqlx::Error> {
+    let transaction_type = transaction_type.to_lowercase();
+    let result = book_transaction(pool, compliance, sender, receiver, amount, &transaction_type, &options).await;
+    metrics::record_transaction(&transaction_type, amount, &result);
+    result
+}
 
-    let mut send_update_balance = sender_details.balance - amount;
-    let mut recev_update_balance = receiver_details.balance + amount;
-    let mut flag = true;
+//a transfer booked by add_transfers_atomically
+#[derive(Debug, Clone)]
+pub struct BatchTransfer {
+    pub receiver: Uuid,
+    pub amount: Decimal,
+    pub options: TransactionOptions,
+}
 
-    match transaction_type.to_uppercase().as_str() {
-        "WITHDRAWL" => {
-            if receiver.is_some() {
-                return Err(sqlx::Error::Encode(
-                    String::from("Cannot be done for different account").into(),
-                ));
-            }
+//the transfer that stopped a batch, index is None when the database itself failed
+#[derive(Debug)]
+pub struct BatchFailure {
+    pub index: Option<usize>,
+    pub error: sqlx::Error,
+}
 
-            if sender_details.balance < amount {
-                return Err(sqlx::Error::Encode(
-                    String::from("Insufficient Balance").into(),
-                ));
+//books transfers of one sender in a single database transaction, either all of them or none
+//every balance row is locked up front in user_id order, so a batch cannot deadlock with single transfers
+#[instrument(name = "db.add_transfers_atomically", skip(pool, compliance, transfers), fields(transfers = transfers.len()))]
+pub async fn add_transfers_atomically(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    sender: Uuid,
+    transfers: &[BatchTransfer],
+) -> Result<Vec<Uuid>, BatchFailure> {
+    let failure = |index, error| BatchFailure { index, error };
+    for (i, t) in transfers.iter().enumerate() {
+        validate_amount(t.amount).map_err(|e| failure(Some(i), e))?;
+        t.options.validate().map_err(|e| failure(Some(i), e))?;
+        if t.receiver == sender {
+            return Err(failure(Some(i), TransactionError::SameAccount.into()));
+        }
+    }
+    let mut accounts: Vec<Uuid> = transfers.iter().map(|t| t.receiver).collect();
+    accounts.push(sender);
+    //the revenue accounts of the fees are locked up front as well
+    for t in transfers {
+        let fee = find_fee(pool, sender, "transfer", t.amount).await.map_err(|e| failure(None, e))?;
+        accounts.extend(fee.map(|f| f.revenue_account));
+    }
+    accounts.sort();
+    accounts.dedup();
+
+    let mut tx = pool.begin().await.map_err(|e| failure(None, e))?;
+    //a blocked transfer stops the batch, its alert is written after the rollback
+    let mut blocked = None;
+    let booked: Result<Vec<Uuid>, BatchFailure> = async {
+        for id in &accounts {
+            lock_balance(&mut tx, *id).await.map_err(|e| failure(None, e))?;
+        }
+        let update_at = Utc::now();
+        let mut booked = Vec::with_capacity(transfers.len());
+        for (i, t) in transfers.iter().enumerate() {
+            let transaction_id = Uuid::new_v4();
+            let checked = lock_and_check(&mut tx, compliance, sender, Some(t.receiver), t.amount, "transfer", &t.options)
+                .await
+                .map_err(|e| failure(Some(i), e))?;
+            if checked.verdict.action == Action::Block {
+                blocked = Some((t, checked.verdict, checked.screening));
+                return Err(failure(Some(i), TransactionError::TransactionBlocked.into()));
             }
-            receiver_details = sender_details;
+            write_booking(&mut tx, transaction_id, &checked, t.amount, "transfer", &t.options, update_at)
+                .await
+                .map_err(|e| failure(Some(i), e))?;
+            booked.push(transaction_id);
+        }
+        Ok(booked)
+    }
+    .await;
 
-            flag = false;
+    match booked {
+        Ok(booked) => {
+            tx.commit().await.map_err(|e| failure(None, e))?;
+            for (t, id) in transfers.iter().zip(&booked) {
+                metrics::record_transaction("transfer", t.amount, &Ok(*id));
+            }
+            info!("Batch of transfers completed");
+            Ok(booked)
         }
-        "DEPOSIT" => {
+        Err(e) => {
+            warn!(error = %e.error, index = ?e.index, "Batch of transfers failed, rolled back");
+            let _ = tx.rollback().await;
+            if let Some((t, verdict, screening)) = blocked {
+                record_blocked_screening(pool, screening, sender).await;
+                let candidate = Candidate {
+                    sender,
+                    receiver: Some(t.receiver),
+                    transaction_type: "transfer",
+                    amount: t.amount,
+                };
+                return Err(failure(e.index, record_block(pool, &candidate, &verdict).await));
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn book_transaction(
+    pool: &Pool<Postgres>,
+    compliance: &Compliance,
+    sender: Uuid,
+    receiver: Option<Uuid>,
+    amount: Decimal,
+    transaction_type: &str,
+    options: &TransactionOptions,
+) -> Result<Uuid, sqlx::Error> {
+    validate_amount(amount)?;
+    let (memo, reference) = options.validate()?;
+
+    match transaction_type {
+        "withdrawl" | "deposit" => {
             if receiver.is_some() {
-                return Err(sqlx::Error::Encode(
-                    String::from("Cannot be done for different account").into(),
-                ));
+                return Err(TransactionError::ReceiverNotAllowed.into());
             }
-            receiver_details = sender_details;
-            send_update_balance = recev_update_balance;
-            flag = false;
         }
-        "TRANSFER" => {
-            if receiver.is_none() || sender == receiver.clone().unwrap() {
-                return Err(sqlx::Error::Encode(
-                    String::from("Cannot be done for same user").into(),
-                ));
+        "transfer" => {
+            if receiver.is_none() || Some(sender) == receiver {
+                return Err(TransactionError::SameAccount.into());
             }
-            if sender_details.balance < amount {
-                return Err(sqlx::Error::Encode(
-                    String::from("Insufficient Balance").into(),
-                ));
-            }
-            receiver_details = match get_user_by_id(pool, receiver.unwrap().clone()).await {
-                Ok(v) => match get_balance(pool, v.user_id).await {
-                    Ok(v) => v,
-                    Err(e) => return Err(e),
-                },
-                Err(e) => {
-                    return Err(e);
-                }
-            };
-            recev_update_balance = receiver_details.balance + amount;
         }
         _ => {
             return Err(sqlx::Error::TypeNotFound {
-                type_name: transaction_type,
+                type_name: transaction_type.to_string(),
             })
         }
     }
+
+    let mut tx = pool.begin().await?;
+    let locked = lock_and_check(&mut tx, compliance, sender, receiver, amount, transaction_type, options).await;
+
+    //rejections roll back right away, a dropped transaction keeps its row locks until the connection is reused
+    let checked = match locked {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = tx.rollback().await;
+            return Err(e);
+        }
+    };
+    if checked.verdict.action == Action::Block {
+        let _ = tx.rollback().await;
+        record_blocked_screening(pool, checked.screening, sender).await;
+        let candidate = Candidate {
+            sender,
+            receiver,
+            transaction_type,
+            amount,
+        };
+        return Err(record_block(pool, &candidate, &checked.verdict).await);
+    }
+
     let transaction_id = Uuid::new_v4();
+    tracing::Span::current().record("transaction_id", tracing::field::display(transaction_id));
     let update_at = Utc::now();
-    let qry = "INSERT INTO transactions(transaction_id,sender_id,receiver_id,amount,transaction_type,status,updated_at) Values ($1,$2,$3,$4,$5,$6,$7);";
-    match sqlx::query(qry)
+    let booked = write_booking(&mut tx, transaction_id, &checked, amount, transaction_type, options, update_at).await;
+
+    match booked {
+        Ok(_) => {
+            tx.commit().await?;
+            match checked.verdict.action {
+                Action::Review => info!("Transaction held for review"),
+                _ => info!("Transaction completed"),
+            }
+            Ok(transaction_id)
+        }
+        Err(e) => {
+            warn!(error = %e, "Transaction failed, rolled back");
+            let _ = tx.rollback().await;
+
+            //the attempt is kept as a failed transaction without any balance effect
+            let failed = sqlx::query(INSERT_TRANSACTION)
+                .bind(transaction_id)
+                .bind(checked.sender.user_id)
+                .bind(checked.receiver.map_or(checked.sender.user_id, |r| r.user_id))
+                .bind(amount)
+                .bind(transaction_type)
+                .bind("failed")
+                .bind(update_at)
+                .bind(memo)
+                .bind(reference)
+                .bind(&options.metadata)
+                .bind(&options.idempotency_key)
+                .bind(checked.fee.map_or(Decimal::ZERO, |f| f.amount))
+                .execute(pool)
+                .await;
+            if let Err(e) = failed {
+                error!(error = %e, "Error at recording the failed transaction");
+            }
+            Err(e)
+        }
+    }
+}
+
+const INSERT_TRANSACTION: &str = "INSERT INTO transactions(transaction_id,sender_id,receiver_id,amount,transaction_type,status,updated_at,memo,reference,metadata,idempotency_key,fee) Values ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12);";
+
+//the locked balance rows of a booking that passed every check
+struct Checked {
+    sender: BalanceDetails,
+    receiver: Option<BalanceDetails>,
+    //the sender's balance after the booking, fee included
+    sender_balance: Decimal,
+    fee: Option<Fee>,
+    //what the monitoring rules and the sanctions screening of the receiver say about the booking
+    verdict: Verdict,
+    screening: Option<ReceiverScreening>,
+}
+
+async fn lock_and_check(
+    conn: &mut PgConnection,
+    compliance: &Compliance,
+    sender: Uuid,
+    receiver: Option<Uuid>,
+    amount: Decimal,
+    transaction_type: &str,
+    options: &TransactionOptions,
+) -> Result<Checked, sqlx::Error> {
+    //the fee is paid by the sender, for deposits it is taken from the deposited amount
+    let fee = find_fee(&mut *conn, sender, transaction_type, amount).await?;
+
+    //every row is locked in user_id order so two opposite transfers cannot deadlock
+    let mut ids: Vec<Uuid> = [Some(sender), receiver, fee.map(|f| f.revenue_account)]
+        .into_iter()
+        .flatten()
+        .collect();
+    ids.sort();
+    ids.dedup();
+    let mut locked = Vec::with_capacity(ids.len());
+    for id in ids {
+        locked.push(lock_balance(conn, id).await?);
+    }
+    let find = |id: Uuid| locked.iter().find(|b| b.user_id == id).copied();
+    let sender_details = find(sender).ok_or(sqlx::Error::RowNotFound)?;
+    let receiver_details = receiver.and_then(find);
+
+    //a deposit credits the sender's own account, withdrawls and transfers debit it
+    //the receiver's status is not named, it is someone else's account
+    let allowed = match transaction_type {
+        "deposit" => sender_details.status.can_credit(),
+        _ => sender_details.status.can_debit(),
+    };
+    if !allowed {
+        return Err(status_error(sender_details.status).into());
+    }
+    if receiver_details.is_some_and(|r| !r.status.can_credit()) {
+        return Err(TransactionError::ReceiverUnavailable.into());
+    }
+    //the sender's row is locked, so concurrent bookings cannot pass a limit together
+    enforce_kyc(conn, sender, transaction_type, amount).await?;
+    enforce_limits(conn, sender, transaction_type, amount).await?;
+    //the sender's row is locked, so concurrent transfers cannot both pass the daily limit
+    if let (Some(limits), Some(r)) = (options.limits, receiver_details) {
+        check_limits(conn, sender, r.user_id, amount, limits).await?;
+    }
+
+    let fee_amount = fee.map_or(Decimal::ZERO, |f| f.amount);
+    let send_update_balance = match transaction_type {
+        "deposit" => {
+            if fee_amount >= amount {
+                return Err(TransactionError::FeeExceedsAmount.into());
+            }
+            sender_details.balance + amount - fee_amount
+        }
+        _ => {
+            if sender_details.balance < amount + fee_amount {
+                return Err(TransactionError::InsufficientBalance.into());
+            }
+            sender_details.balance - amount - fee_amount
+        }
+    };
+    //only bookings that would go through are monitored
+    let candidate = Candidate {
+        sender,
+        receiver,
+        transaction_type,
+        amount,
+    };
+    let mut verdict = evaluate(conn, &compliance.rules, &candidate).await?;
+    let screening = match receiver_details {
+        Some(r) if transaction_type == "transfer" => screen_receiver(conn, &compliance.sanctions, r.user_id).await?,
+        _ => None,
+    };
+    if let Some(s) = &screening {
+        s.apply(&mut verdict);
+    }
+    Ok(Checked {
+        sender: sender_details,
+        receiver: receiver_details,
+        sender_balance: send_update_balance,
+        fee,
+        verdict,
+        screening,
+    })
+}
+
+async fn write_booking(
+    conn: &mut PgConnection,
+    transaction_id: Uuid,
+    checked: &Checked,
+    amount: Decimal,
+    transaction_type: &str,
+    options: &TransactionOptions,
+    update_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let (memo, reference) = options.validate()?;
+    sqlx::query(INSERT_TRANSACTION)
         .bind(transaction_id)
-        .bind(sender_details.user_id)
-        .bind(receiver_details.user_id)
+        .bind(checked.sender.user_id)
+        .bind(checked.receiver.map_or(checked.sender.user_id, |r| r.user_id))
         .bind(amount)
-        .bind(transaction_type.to_lowercase())
+        .bind(transaction_type)
         .bind("pending")
         .bind(update_at)
-        .execute(pool)
-        .await
-    {
-        Ok(_) => {
-            //sender balance update
-            match update_balance(pool, sender_details.user_id, send_update_balance).await {
-                Ok(_) => {
-                    if flag {
-                        //receiver balance update
-                        match update_balance(pool, receiver_details.user_id, recev_update_balance)
-                            .await
-                        {
-                            Ok(_) => {
-                                match update_transaction_status(
-                                    pool,
-                                    String::from("completed"),
-                                    transaction_id,
-                                )
-                                .await
-                                {
-                                    Ok(_) => Ok(()),
-                                    Err(e) => return Err(e),
-                                }
-                            }
-                            Err(e) => return Err(e),
-                        }
-                    } else {
-                        match update_transaction_status(pool, String::from("completed"), transaction_id).await
-                        {
-                            Ok(_) => Ok(()),
-                            Err(e) => return Err(e),
-                        }
-                    }
-                }
-                Err(e) => {
-                    match update_transaction_status(pool, String::from("failed"), transaction_id).await {
-                        Ok(_) => (),
-                        Err(e) => println!("Error : {:?}", e),
-                    };
-                    return Err(e);
-                }
+        .bind(memo)
+        .bind(reference)
+        .bind(&options.metadata)
+        .bind(&options.idempotency_key)
+        .bind(checked.fee.map_or(Decimal::ZERO, |f| f.amount))
+        .execute(&mut *conn)
+        .await?;
+    if let Some(s) = &checked.screening {
+        s.record(&mut *conn, checked.sender.user_id, Some(transaction_id)).await?;
+    }
+
+    //a held transaction stays pending, the sender's money (fee included) is set aside until settle_held_transaction
+    if checked.verdict.action == Action::Review {
+        if transaction_type != "deposit" {
+            update_balance(&mut *conn, checked.sender.user_id, checked.sender_balance).await?;
+        }
+        let candidate = Candidate {
+            sender: checked.sender.user_id,
+            receiver: checked.receiver.map(|r| r.user_id),
+            transaction_type,
+            amount,
+        };
+        return record_review(&mut *conn, transaction_id, &candidate, &checked.verdict, checked.fee).await;
+    }
+    update_balance(&mut *conn, checked.sender.user_id, checked.sender_balance).await?;
+    if let Some(r) = checked.receiver {
+        update_balance(&mut *conn, r.user_id, r.balance + amount).await?;
+    }
+    if let Some(fee) = checked.fee {
+        write_fee_leg(&mut *conn, transaction_id, checked.sender.user_id, fee, update_at).await?;
+    }
+    update_transaction_status(&mut *conn, String::from("completed"), transaction_id).await
+}
+
+//the fee is its own completed transaction from the payer to the revenue account, linked to the payment
+//the revenue account is credited relative to its current balance, it may also be the receiver of the payment
+async fn write_fee_leg(
+    conn: &mut PgConnection,
+    parent: Uuid,
+    payer: Uuid,
+    fee: Fee,
+    update_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let qry = "INSERT INTO transactions(transaction_id,sender_id,receiver_id,amount,transaction_type,status,updated_at,parent_transaction_id) Values ($1,$2,$3,$4,'fee','completed',$5,$6);";
+    sqlx::query(qry)
+        .bind(Uuid::new_v4())
+        .bind(payer)
+        .bind(fee.revenue_account)
+        .bind(fee.amount)
+        .bind(update_at)
+        .bind(parent)
+        .execute(&mut *conn)
+        .await?;
+    credit(conn, fee.revenue_account, fee.amount, update_at).await
+}
+
+//the refused booking is rolled back, so its screening is recorded on its own
+async fn record_blocked_screening(pool: &Pool<Postgres>, screening: Option<ReceiverScreening>, sender: Uuid) {
+    if let Some(s) = screening {
+        if let Err(e) = s.record(pool, sender, None).await {
+            error!(error = %e, "Error at recording the screening of a blocked transaction");
+        }
+    }
+}
+
+async fn credit(conn: &mut PgConnection, user_id: Uuid, amount: Decimal, update_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE account_balance SET balance = balance + $1, updated_at = $2 where user_id = $3")
+        .bind(amount)
+        .bind(update_at)
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+//finishes a transaction held for review, called with the alert row locked
+//approving books what write_booking left out, rejecting returns the amount set aside (fee included) to the sender
+pub async fn settle_held_transaction(
+    conn: &mut PgConnection,
+    transaction_id: Uuid,
+    approve: bool,
+    revenue_account: Option<Uuid>,
+) -> Result<(), sqlx::Error> {
+    let qry = "SELECT sender_id, receiver_id, amount, fee, transaction_type, status FROM transactions where transaction_id = $1 FOR UPDATE";
+    let row = sqlx::query(qry).bind(transaction_id).fetch_one(&mut *conn).await?;
+    let status: String = row.get("status");
+    if status != "pending" {
+        return Err(TransactionError::ReviewClosed.into());
+    }
+    let sender: Uuid = row.get("sender_id");
+    let receiver: Uuid = row.get("receiver_id");
+    let amount: Decimal = row.get("amount");
+    let fee_amount: Decimal = row.get("fee");
+    let transaction_type: String = row.get("transaction_type");
+    let fee = revenue_account
+        .filter(|_| fee_amount > Decimal::ZERO)
+        .map(|revenue_account| Fee {
+            amount: fee_amount,
+            revenue_account,
+        });
+
+    let mut ids: Vec<Uuid> = [Some(sender), Some(receiver), fee.map(|f| f.revenue_account)]
+        .into_iter()
+        .flatten()
+        .collect();
+    ids.sort();
+    ids.dedup();
+    let mut locked = Vec::with_capacity(ids.len());
+    for id in ids {
+        locked.push(lock_balance(conn, id).await?);
+    }
+
+    let update_at = Utc::now();
+    if !approve {
+        if transaction_type != "deposit" {
+            credit(conn, sender, amount + fee_amount, update_at).await?;
+        }
+        return update_transaction_status(&mut *conn, String::from("failed"), transaction_id).await;
+    }
+    match transaction_type.as_str() {
+        "transfer" => {
+            if locked.iter().any(|b| b.user_id == receiver && !b.status.can_credit()) {
+                return Err(TransactionError::ReceiverUnavailable.into());
             }
+            credit(conn, receiver, amount, update_at).await?;
         }
-        Err(e) => return Err(e),
+        "deposit" => credit(conn, sender, amount - fee_amount, update_at).await?,
+        _ => {}
+    }
+    if let Some(fee) = fee {
+        write_fee_leg(&mut *conn, transaction_id, sender, fee, update_at).await?;
     }
+    update_transaction_status(&mut *conn, String::from("completed"), transaction_id).await
 }
 
-pub async fn update_transaction_status(
+async fn check_limits(
+    conn: &mut sqlx::PgConnection,
+    sender: Uuid,
+    receiver: Uuid,
+    amount: Decimal,
+    limits: TransferLimits,
+) -> Result<(), sqlx::Error> {
+    if limits.per_transfer.is_some_and(|l| amount > l) {
+        return Err(TransactionError::BeneficiaryLimitExceeded.into());
+    }
+    if let Some(daily) = limits.daily {
+        let qry = "SELECT COALESCE(SUM(amount), 0) FROM transactions
+            where sender_id = $1 and receiver_id = $2 and transaction_type = 'transfer'
+            and status <> 'failed' and created_at >= CURRENT_DATE";
+        let sent: Decimal = sqlx::query_scalar(qry)
+            .bind(sender)
+            .bind(receiver)
+            .fetch_one(&mut *conn)
+            .await?;
+        if sent + amount > daily {
+            return Err(TransactionError::BeneficiaryLimitExceeded.into());
+        }
+    }
+    Ok(())
+}
+
+fn status_error(status: AccountStatus) -> TransactionError {
+    match status {
+        AccountStatus::DebitBlocked => TransactionError::AccountDebitBlocked,
+        AccountStatus::Closed => TransactionError::AccountClosed,
+        _ => TransactionError::AccountFrozen,
+    }
+}
+
+//closes an account for good
+//a remaining balance is moved to sweep_to as a completed transfer in the same database transaction, without one the balance must be zero
+#[instrument(name = "db.close_account", skip(pool, reason), fields(transaction_id))]
+pub async fn close_account(
     pool: &Pool<Postgres>,
+    uid: Uuid,
+    sweep_to: Option<Uuid>,
+    reason: &str,
+    closed_by: Uuid,
+) -> Result<(AccountState, Option<Uuid>), sqlx::Error> {
+    if sweep_to == Some(uid) {
+        return Err(TransactionError::SameAccount.into());
+    }
+
+    let mut tx = pool.begin().await?;
+    let closed = async {
+        //same lock order as transfers
+        let (account, target) = match sweep_to {
+            Some(to) if to < uid => {
+                let t = lock_balance(&mut tx, to).await?;
+                (lock_balance(&mut tx, uid).await?, Some(t))
+            }
+            Some(to) => {
+                let a = lock_balance(&mut tx, uid).await?;
+                (a, Some(lock_balance(&mut tx, to).await?))
+            }
+            None => (lock_balance(&mut tx, uid).await?, None),
+        };
+        if account.status == AccountStatus::Closed {
+            return Err(TransactionError::AccountClosed.into());
+        }
+
+        let sweep = match target {
+            _ if account.balance.is_zero() => None,
+            None => return Err(TransactionError::BalanceNotZero.into()),
+            Some(t) if !t.status.can_credit() => {
+                return Err(TransactionError::ReceiverUnavailable.into())
+            }
+            Some(t) => {
+                let transaction_id = Uuid::new_v4();
+                tracing::Span::current().record("transaction_id", tracing::field::display(transaction_id));
+                let qry = "INSERT INTO transactions(transaction_id,sender_id,receiver_id,amount,transaction_type,status,updated_at) Values ($1,$2,$3,$4,$5,$6,$7);";
+                sqlx::query(qry)
+                    .bind(transaction_id)
+                    .bind(uid)
+                    .bind(t.user_id)
+                    .bind(account.balance)
+                    .bind("transfer")
+                    .bind("completed")
+                    .bind(Utc::now())
+                    .execute(&mut *tx)
+                    .await?;
+                update_balance(&mut *tx, uid, Decimal::ZERO).await?;
+                update_balance(&mut *tx, t.user_id, t.balance + account.balance).await?;
+                Some(transaction_id)
+            }
+        };
+
+        let state = write_account_status(
+            &mut tx,
+            uid,
+            account.status,
+            AccountStatus::Closed,
+            reason,
+            closed_by,
+            sweep,
+        )
+        .await?;
+        Ok((state, sweep, account.balance))
+    }
+    .await;
+
+    match closed {
+        Ok((state, sweep, swept)) => {
+            tx.commit().await?;
+            if let Some(id) = sweep {
+                metrics::record_transaction("transfer", swept, &Ok(id));
+            }
+            info!(user_id = %uid, "Account closed");
+            Ok((state, sweep))
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the transaction booked under the key, if any, a held one included like the unique index does
+#[instrument(name = "db.find_idempotent_transaction", skip(pool))]
+pub async fn find_idempotent_transaction(pool: &Pool<Postgres>, key: &str) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar("SELECT transaction_id FROM transactions where idempotency_key = $1 and status <> 'failed'")
+        .bind(key)
+        .fetch_optional(pool)
+        .await
+}
+
+#[instrument(name = "db.update_transaction_status", skip(executor))]
+pub async fn update_transaction_status<'e, E>(
+    executor: E,
     status: String,
     uuid: Uuid,
-) -> Result<(), sqlx::Error> {
-    println!("Hello from the update transaction status");
-    // let qry = "UPDATE transactions SET status=$1 where sender_id=$2 and receiver_id=$3 and amount=$4 and transaction_type like '$5' and status like 'pending' and updated_at = TO_TIMESTAMP($6, 'YYYY-MM-DD HH24:MI:SS.US');";
-    let qry = "UPDATE transactions SET status=$1 where transaction_id=$2;";
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let qry = "UPDATE transactions SET status=$1, updated_at=$2 where transaction_id=$3;";
 
-    match sqlx::query(&qry)
+    match sqlx::query(qry)
         .bind(status.to_lowercase())
+        .bind(Utc::now())
         .bind(uuid)
-        .execute(pool)
+        .execute(executor)
         .await
     {
-        Ok(_) => {
-            println!("Transaction status updated successfully");
-            Ok(())
-        }
-        Err(e) => return Err(e),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
 
 pub struct TransactionDetails {
     pub transaction_id: Uuid,
     pub sender: Uuid,
     pub receiver: Uuid,
+    #[schema(value_type = String, example = "10.50")]
     pub amount: Decimal,
     pub transaction_type: String,
     pub status: String,
+    #[schema(example = "Dinner on Friday")]
+    pub memo: Option<String>,
+    #[schema(example = "order-1042")]
+    pub reference: Option<String>,
+    #[schema(value_type = Option<Object>, example = json!({"order_id": "1042"}))]
+    pub metadata: Option<Value>,
+    //paid on top of the amount, or taken from it for deposits, booked as a separate fee transaction
+    #[schema(value_type = String, example = "0.25")]
+    pub fee: Decimal,
+    //set on fee transactions, the payment they belong to
+    pub parent_transaction_id: Option<Uuid>,
     pub created_at:NaiveDateTime,
     pub updated_at: NaiveDateTime
 }
+
+fn transaction_details(v: &PgRow) -> TransactionDetails {
+    TransactionDetails {
+        transaction_id: v.get("transaction_id"),
+        sender: v.get("sender_id"),
+        receiver: v.get("receiver_id"),
+        amount: v.get("amount"),
+        status: v.get("status"),
+        transaction_type: v.get("transaction_type"),
+        memo: v.get("memo"),
+        reference: v.get("reference"),
+        metadata: v.get("metadata"),
+        fee: v.get("fee"),
+        parent_transaction_id: v.get("parent_transaction_id"),
+        created_at: v.get("created_at"),
+        updated_at: v.get("updated_at")
+    }
+}
+
+//filters of a transaction list, every given one must match
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+pub struct TransactionFilter {
+    //part of the memo, case insensitive
+    pub memo: Option<String>,
+    //the exact client reference
+    pub reference: Option<String>,
+    //a JSON object the metadata must contain, e.g. {"order_id":"1042"}
+    pub metadata: Option<String>,
+}
+
 pub async fn list_all_transactions(
     pool: &Pool<Postgres>,
     id: Uuid,
 ) -> Result<Vec<TransactionDetails>, sqlx::Error> {
-    println!("Hello from the list_transactions");
+    list_transactions_filtered(pool, id, &TransactionFilter::default()).await
+}
 
-    let qry = "SELECT distinct * FROM transactions where sender_id=$1 or receiver_id=$1;";
-    let mut res = vec![];
-    match sqlx::query(qry).bind(id).fetch_all(pool).await {
-        Ok(v) => {
-            for i in v {
-                res.push(TransactionDetails {
-                    transaction_id: i.get("transaction_id"),
-                    sender: i.get("sender_id"),
-                    receiver: i.get("receiver_id"),
-                    amount: i.get("amount"),
-                    status: i.get("status"),
-                    transaction_type: i.get("transaction_type"),
-                    created_at: i.get("created_at"),
-                    updated_at: i.get("updated_at")
-                });
+#[instrument(name = "db.list_all_transactions", skip(pool))]
+pub async fn list_transactions_filtered(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+    filter: &TransactionFilter,
+) -> Result<Vec<TransactionDetails>, sqlx::Error> {
+    let metadata: Option<Value> = match &filter.metadata {
+        Some(v) => match serde_json::from_str(v) {
+            Ok(v @ Value::Object(_)) => Some(v),
+            _ => {
+                return Err(sqlx::Error::Encode(
+                    "The metadata filter must be a JSON object".into(),
+                ))
             }
-            return Ok(res);
-        }
-        Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    //% and _ typed by the caller are matched literally
+    let memo = filter.memo.as_ref().map(|m| {
+        format!("%{}%", m.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+    });
+    let qry = "SELECT distinct * FROM transactions where (sender_id=$1 or receiver_id=$1)
+        and ($2::text IS NULL or memo ILIKE $2)
+        and ($3::text IS NULL or reference = $3)
+        and ($4::jsonb IS NULL or metadata @> $4);";
+    match sqlx::query(qry)
+        .bind(id)
+        .bind(memo)
+        .bind(filter.reference.as_deref())
+        .bind(metadata)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(v) => Ok(v.iter().map(transaction_details).collect()),
+        Err(e) => Err(e),
     }
 }
 
+#[instrument(name = "db.get_transaction", skip(pool))]
 pub async fn get_transaction(
     pool: &Pool<Postgres>,
     uuid: Uuid,
 ) -> Result<TransactionDetails, sqlx::Error> {
-    println!("Hello from the get_transactions");
-
     let qry = "Select * from transactions where transaction_id = $1;";
 
     match sqlx::query(qry).bind(uuid).fetch_one(pool).await {
-        Ok(v) => Ok(TransactionDetails {
-            transaction_id: v.get("transaction_id"),
-            sender: v.get("sender_id"),
-            receiver: v.get("receiver_id"),
-            amount: v.get("amount"),
-            status: v.get("status"),
-            transaction_type: v.get("transaction_type"),
-            created_at: v.get("created_at"),
-            updated_at: v.get("updated_at")
-        }),
+        Ok(v) => Ok(transaction_details(&v)),
         Err(e) => {
-            return Err(e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //property tests for the ledger
+    //random sequences of operations run against add_transaction while a simple in-memory model tracks what must have happened
+    use std::collections::HashMap;
+
+    use proptest::{
+        prelude::*,
+        test_runner::{Config, TestCaseError, TestRunner},
+    };
+    use rust_decimal::Decimal;
+    use sqlx::{Pool, Postgres, Row};
+    use uuid::Uuid;
+
+    use crate::{
+        models::{
+            kyc::{set_kyc_level, KycLevel},
+            users::register_user,
+        },
+        utilities::test_harness::TestDb,
+    };
+
+    use super::add_transaction;
+
+    #[derive(Debug, Clone)]
+    enum Failure {
+        UnknownType,
+        NonPositiveAmount(Decimal),
+        TooPrecise,
+        SelfTransfer,
+        UnknownReceiver,
+        DepositWithReceiver,
+        Overdraft,
+    }
+
+    //user and transfer indexes are taken modulo whatever exists when the operation runs
+    #[derive(Debug, Clone)]
+    enum Op {
+        Register,
+        Deposit { user: usize, amount: Decimal },
+        Withdrawl { user: usize, amount: Decimal },
+        Transfer { from: usize, to: usize, amount: Decimal },
+        Refund { transfer: usize },
+        Fail { user: usize, failure: Failure },
+    }
+
+    fn amount() -> impl Strategy<Value = Decimal> {
+        (1i64..=100_000).prop_map(|cents| Decimal::new(cents, 2))
+    }
+
+    fn failure() -> impl Strategy<Value = Failure> {
+        prop_oneof![
+            Just(Failure::UnknownType),
+            (-10_000i64..=0).prop_map(|c| Failure::NonPositiveAmount(Decimal::new(c, 2))),
+            Just(Failure::TooPrecise),
+            Just(Failure::SelfTransfer),
+            Just(Failure::UnknownReceiver),
+            Just(Failure::DepositWithReceiver),
+            Just(Failure::Overdraft),
+        ]
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => Just(Op::Register),
+            3 => (any::<usize>(), amount()).prop_map(|(user, amount)| Op::Deposit { user, amount }),
+            2 => (any::<usize>(), amount()).prop_map(|(user, amount)| Op::Withdrawl { user, amount }),
+            3 => (any::<usize>(), any::<usize>(), amount())
+                .prop_map(|(from, to, amount)| Op::Transfer { from, to, amount }),
+            1 => any::<usize>().prop_map(|transfer| Op::Refund { transfer }),
+            2 => (any::<usize>(), failure()).prop_map(|(user, failure)| Op::Fail { user, failure }),
+        ]
+    }
+
+    #[derive(Default)]
+    struct Model {
+        users: Vec<Uuid>,
+        balances: HashMap<Uuid, Decimal>,
+        transfers: Vec<(Uuid, Uuid, Decimal)>,
+        deposits: Decimal,
+        withdrawls: Decimal,
+        completed: i64,
+    }
+
+    //the request to send for an op, and whether the model expects it to succeed
+    struct Attempt {
+        sender: Uuid,
+        receiver: Option<Uuid>,
+        amount: Decimal,
+        transaction_type: &'static str,
+        expect_ok: bool,
+    }
+
+    impl Model {
+        fn user(&self, idx: usize) -> Option<Uuid> {
+            if self.users.is_empty() {
+                None
+            } else {
+                Some(self.users[idx % self.users.len()])
+            }
+        }
+
+        fn attempt(&self, op: &Op) -> Option<Attempt> {
+            match op.clone() {
+                Op::Register => None,
+                Op::Deposit { user, amount } => Some(Attempt {
+                    sender: self.user(user)?,
+                    receiver: None,
+                    amount,
+                    transaction_type: "deposit",
+                    expect_ok: true,
+                }),
+                Op::Withdrawl { user, amount } => {
+                    let sender = self.user(user)?;
+                    Some(Attempt {
+                        sender,
+                        receiver: None,
+                        amount,
+                        transaction_type: "withdrawl",
+                        expect_ok: self.balances[&sender] >= amount,
+                    })
+                }
+                Op::Transfer { from, to, amount } => {
+                    let sender = self.user(from)?;
+                    let receiver = self.user(to)?;
+                    Some(Attempt {
+                        sender,
+                        receiver: Some(receiver),
+                        amount,
+                        transaction_type: "transfer",
+                        expect_ok: sender != receiver && self.balances[&sender] >= amount,
+                    })
+                }
+                //a refund sends a completed transfer back, it fails when the receiver already spent the money
+                Op::Refund { transfer } => {
+                    if self.transfers.is_empty() {
+                        return None;
+                    }
+                    let (sender, receiver, amount) =
+                        self.transfers[transfer % self.transfers.len()];
+                    Some(Attempt {
+                        sender: receiver,
+                        receiver: Some(sender),
+                        amount,
+                        transaction_type: "transfer",
+                        expect_ok: self.balances[&receiver] >= amount,
+                    })
+                }
+                Op::Fail { user, failure } => {
+                    let sender = self.user(user)?;
+                    let (receiver, amount, transaction_type) = match failure {
+                        Failure::UnknownType => (None, Decimal::ONE, "chargeback"),
+                        Failure::NonPositiveAmount(amount) => (None, amount, "deposit"),
+                        Failure::TooPrecise => (None, Decimal::new(1001, 3), "deposit"),
+                        Failure::SelfTransfer => (Some(sender), Decimal::ONE, "transfer"),
+                        Failure::UnknownReceiver => (Some(Uuid::new_v4()), Decimal::ONE, "transfer"),
+                        Failure::DepositWithReceiver => (
+                            Some(self.user(user + 1)?),
+                            Decimal::ONE,
+                            "deposit",
+                        ),
+                        Failure::Overdraft => (
+                            None,
+                            self.balances[&sender] + Decimal::new(1, 2),
+                            "withdrawl",
+                        ),
+                    };
+                    Some(Attempt {
+                        sender,
+                        receiver,
+                        amount,
+                        transaction_type,
+                        expect_ok: false,
+                    })
+                }
+            }
+        }
+
+        fn apply(&mut self, attempt: &Attempt) {
+            self.completed += 1;
+            match attempt.transaction_type {
+                "deposit" => {
+                    *self.balances.get_mut(&attempt.sender).unwrap() += attempt.amount;
+                    self.deposits += attempt.amount;
+                }
+                "withdrawl" => {
+                    *self.balances.get_mut(&attempt.sender).unwrap() -= attempt.amount;
+                    self.withdrawls += attempt.amount;
+                }
+                _ => {
+                    let receiver = attempt.receiver.unwrap();
+                    *self.balances.get_mut(&attempt.sender).unwrap() -= attempt.amount;
+                    *self.balances.get_mut(&receiver).unwrap() += attempt.amount;
+                    self.transfers.push((attempt.sender, receiver, attempt.amount));
+                }
+            }
+        }
+    }
+
+    async fn check_invariants(pool: &Pool<Postgres>, model: &Model) -> Result<(), TestCaseError> {
+        //no negative balances
+        let negative: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM account_balance WHERE balance < 0")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        prop_assert_eq!(negative, 0);
+
+        //total system money equals deposits minus withdrawls
+        let total: Option<Decimal> = sqlx::query_scalar("SELECT sum(balance) FROM account_balance")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        prop_assert_eq!(
+            total.unwrap_or(Decimal::ZERO),
+            model.deposits - model.withdrawls
+        );
+
+        //every completed transaction is reflected exactly once, failed ones not at all
+        let completed: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM transactions WHERE status = 'completed'")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        prop_assert_eq!(completed, model.completed);
+
+        let rows = sqlx::query(
+            "
+            SELECT b.user_id, b.balance,
+                COALESCE((SELECT sum(t.amount) FROM transactions t WHERE t.status = 'completed'
+                    AND ((t.transaction_type = 'deposit' AND t.sender_id = b.user_id)
+                      OR (t.transaction_type = 'transfer' AND t.receiver_id = b.user_id))), 0) AS credits,
+                COALESCE((SELECT sum(t.amount) FROM transactions t WHERE t.status = 'completed'
+                    AND t.transaction_type IN ('withdrawl', 'transfer') AND t.sender_id = b.user_id), 0) AS debits
+            FROM account_balance b
+        ",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        for row in rows {
+            let user_id: Uuid = row.get("user_id");
+            let balance: Decimal = row.get("balance");
+            let credits: Decimal = row.get("credits");
+            let debits: Decimal = row.get("debits");
+            prop_assert_eq!(balance, credits - debits);
+            prop_assert_eq!(balance, model.balances[&user_id]);
+        }
+        Ok(())
+    }
+
+    async fn run_ops(db: &TestDb, ops: Vec<Op>) -> Result<(), TestCaseError> {
+        sqlx::query("TRUNCATE users CASCADE")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let mut model = Model::default();
+        for op in ops {
+            if let Op::Register = op {
+                let n = model.users.len();
+                let uid = register_user(
+                    &db.pool,
+                    &db.compliance.sanctions,
+                    format!("user_{}", n),
+                    format!("user_{}@test.com", n),
+                    String::from("test"),
+                )
+                .await
+                .unwrap();
+                //the model knows no KYC capabilities
+                set_kyc_level(&db.pool, uid, KycLevel::Full).await.unwrap();
+                model.users.push(uid);
+                model.balances.insert(uid, Decimal::ZERO);
+            } else if let Some(attempt) = model.attempt(&op) {
+                let res = add_transaction(
+                    &db.pool,
+                    &db.compliance,
+                    attempt.sender,
+                    attempt.receiver,
+                    attempt.amount,
+                    attempt.transaction_type.to_string(),
+                )
+                .await;
+                prop_assert_eq!(res.is_ok(), attempt.expect_ok, "{:?} -> {:?}", op, res);
+                if res.is_ok() {
+                    model.apply(&attempt);
+                }
+            }
+            check_invariants(&db.pool, &model).await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ledger_invariants() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let db = rt.block_on(TestDb::new());
+
+        let mut runner = TestRunner::new(Config {
+            cases: 48,
+            ..Config::default()
+        });
+        let result = runner.run(&prop::collection::vec(op(), 1..40), |ops| {
+            rt.block_on(run_ops(&db, ops))
+        });
+        if let Err(e) = result {
+            panic!("{}", e);
         }
     }
 }
@@ -0,0 +1,305 @@
+//optional TOTP two-factor authentication
+//enrollment stores a pending secret, the first valid code enables it and hands out the recovery codes
+//once enabled, logins need a code and transfers above auth.step_up_amount need a fresh code or an elevated token
+use chrono::{NaiveDateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{PgConnection, Pool, Postgres, Row};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    models::{notifications::notify, tokens::hash_token},
+    utilities::{errors::TransactionError, rbac::Caller, totp},
+};
+
+pub const RECOVERY_CODES: usize = 10;
+
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub enabled_at: Option<NaiveDateTime>,
+    //unused recovery codes
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Enrollment {
+    //base32, for authenticator apps that cannot scan the QR code
+    #[schema(example = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP")]
+    pub secret: String,
+    #[schema(example = "otpauth://totp/payments_dodo:alice%40example.com?secret=JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP&issuer=payments_dodo")]
+    pub provisioning_uri: String,
+}
+
+//two groups of 5 hex characters, e.g. 3f9a1-c07b2
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+//recovery codes are typed by hand, case and the dash do not matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().replace('-', "").to_lowercase()
+}
+
+fn unix_now() -> u64 {
+    Utc::now().timestamp().max(0) as u64
+}
+
+pub async fn two_factor_enabled(pool: &Pool<Postgres>, uid: Uuid) -> Result<bool, sqlx::Error> {
+    let enabled: Option<bool> = sqlx::query_scalar("SELECT enabled_at IS NOT NULL FROM two_factor where user_id = $1")
+        .bind(uid)
+        .fetch_optional(pool)
+        .await?;
+    Ok(enabled.unwrap_or(false))
+}
+
+#[instrument(name = "db.get_two_factor", skip(pool))]
+pub async fn get_two_factor(pool: &Pool<Postgres>, uid: Uuid) -> Result<TwoFactorStatus, sqlx::Error> {
+    let qry = "SELECT (SELECT enabled_at FROM two_factor where user_id = $1),
+        (SELECT COUNT(*) FROM recovery_codes where user_id = $1 and used_at IS NULL)";
+    let row = sqlx::query(qry).bind(uid).fetch_one(pool).await?;
+    let enabled_at: Option<NaiveDateTime> = row.get(0);
+    Ok(TwoFactorStatus {
+        enabled: enabled_at.is_some(),
+        enabled_at,
+        recovery_codes_left: row.get(1),
+    })
+}
+
+//a new enrollment replaces a pending one, an enabled 2FA has to be disabled first
+#[instrument(name = "db.start_enrollment", skip(pool, account))]
+pub async fn start_enrollment(pool: &Pool<Postgres>, uid: Uuid, account: &str) -> Result<Enrollment, sqlx::Error> {
+    let secret = totp::new_secret();
+    let provisioning_uri = totp::provisioning_uri(&secret, account).map_err(|e| sqlx::Error::Encode(e.into()))?;
+    let qry = "INSERT INTO two_factor(user_id,secret,created_at) Values ($1,$2,$3)
+        ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, created_at = EXCLUDED.created_at, last_step = 0
+        where two_factor.enabled_at IS NULL";
+    let updated = sqlx::query(qry)
+        .bind(uid)
+        .bind(&secret)
+        .bind(Utc::now().naive_utc())
+        .execute(pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(TransactionError::TwoFactorEnabled.into());
+    }
+    info!(user_id = %uid, "Two-factor enrollment started");
+    Ok(Enrollment { secret, provisioning_uri })
+}
+
+//replaces every recovery code of the user, returns the new ones in plain text, only their hashes are kept
+async fn replace_recovery_codes(conn: &mut PgConnection, uid: Uuid) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_codes where user_id = $1")
+        .bind(uid)
+        .execute(&mut *conn)
+        .await?;
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| new_recovery_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_token(&normalize_recovery_code(c))).collect();
+    sqlx::query("INSERT INTO recovery_codes(user_id,code_hash) SELECT $1, * FROM UNNEST($2::text[])")
+        .bind(uid)
+        .bind(&hashes)
+        .execute(&mut *conn)
+        .await?;
+    Ok(codes)
+}
+
+//checks a one-time password, or with allow_recovery an unused recovery code, against the locked two_factor row
+//a TOTP step is accepted once, so a code seen by someone else cannot be replayed
+//pending checks the unconfirmed secret of an enrollment, otherwise 2FA has to be enabled
+async fn check_code(
+    conn: &mut PgConnection,
+    uid: Uuid,
+    otp: &str,
+    allow_recovery: bool,
+    pending: bool,
+) -> Result<(), sqlx::Error> {
+    let row = sqlx::query("SELECT secret, enabled_at IS NOT NULL, last_step FROM two_factor where user_id = $1 FOR UPDATE")
+        .bind(uid)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let (secret, enabled, last_step): (String, bool, i64) = match row {
+        Some(v) => (v.get(0), v.get(1), v.get(2)),
+        None => return Err(TransactionError::InvalidOtp.into()),
+    };
+    if enabled == pending {
+        return Err(TransactionError::InvalidOtp.into());
+    }
+
+    if let Some(step) = totp::matching_step(&secret, otp, unix_now()) {
+        if step as i64 <= last_step {
+            warn!(user_id = %uid, "Replayed one-time password");
+            return Err(TransactionError::InvalidOtp.into());
+        }
+        sqlx::query("UPDATE two_factor SET last_step = $2 where user_id = $1")
+            .bind(uid)
+            .bind(step as i64)
+            .execute(&mut *conn)
+            .await?;
+        return Ok(());
+    }
+
+    if allow_recovery {
+        let qry = "UPDATE recovery_codes SET used_at = $3
+            where id = (SELECT id FROM recovery_codes where user_id = $1 and code_hash = $2 and used_at IS NULL LIMIT 1)";
+        let used = sqlx::query(qry)
+            .bind(uid)
+            .bind(hash_token(&normalize_recovery_code(otp)))
+            .bind(Utc::now().naive_utc())
+            .execute(&mut *conn)
+            .await?;
+        if used.rows_affected() == 1 {
+            info!(user_id = %uid, "Recovery code used");
+            return Ok(());
+        }
+    }
+    Err(TransactionError::InvalidOtp.into())
+}
+
+//a one-time password or recovery code on its own, in a transaction of its own
+async fn verify_code(pool: &Pool<Postgres>, uid: Uuid, otp: &str, allow_recovery: bool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    match check_code(&mut tx, uid, otp, allow_recovery, false).await {
+        Ok(_) => {
+            tx.commit().await?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the first valid code of the pending secret enables 2FA, returns the recovery codes
+#[instrument(name = "db.confirm_enrollment", skip(pool, otp))]
+pub async fn confirm_enrollment(pool: &Pool<Postgres>, uid: Uuid, otp: &str) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let confirmed: Result<Vec<String>, sqlx::Error> = async {
+        check_code(&mut tx, uid, otp, false, true).await?;
+        sqlx::query("UPDATE two_factor SET enabled_at = $2 where user_id = $1")
+            .bind(uid)
+            .bind(Utc::now().naive_utc())
+            .execute(&mut *tx)
+            .await?;
+        notify(&mut *tx, uid, "two_factor_enabled", "Two-factor authentication was enabled for your account", json!({})).await?;
+        replace_recovery_codes(&mut tx, uid).await
+    }
+    .await;
+
+    match confirmed {
+        Ok(codes) => {
+            tx.commit().await?;
+            info!(user_id = %uid, "Two-factor authentication enabled");
+            Ok(codes)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//a recovery code works as well, for users who lost their authenticator
+#[instrument(name = "db.disable_two_factor", skip(pool, otp))]
+pub async fn disable_two_factor(pool: &Pool<Postgres>, uid: Uuid, otp: &str) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let disabled: Result<(), sqlx::Error> = async {
+        check_code(&mut tx, uid, otp, true, false).await?;
+        sqlx::query("DELETE FROM recovery_codes where user_id = $1")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM two_factor where user_id = $1")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+    .await;
+
+    match disabled {
+        Ok(_) => {
+            tx.commit().await?;
+            info!(user_id = %uid, "Two-factor authentication disabled");
+            Ok(())
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.regenerate_recovery_codes", skip(pool, otp))]
+pub async fn regenerate_recovery_codes(pool: &Pool<Postgres>, uid: Uuid, otp: &str) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let regenerated: Result<Vec<String>, sqlx::Error> = async {
+        check_code(&mut tx, uid, otp, false, false).await?;
+        replace_recovery_codes(&mut tx, uid).await
+    }
+    .await;
+
+    match regenerated {
+        Ok(codes) => {
+            tx.commit().await?;
+            info!(user_id = %uid, "Recovery codes regenerated");
+            Ok(codes)
+        }
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+//the second factor of a login with 2FA enabled, a recovery code is accepted as well
+#[instrument(name = "db.check_login", skip(pool, otp))]
+pub async fn check_login(pool: &Pool<Postgres>, uid: Uuid, otp: Option<&str>) -> Result<(), sqlx::Error> {
+    if !two_factor_enabled(pool, uid).await? {
+        return Ok(());
+    }
+    match otp {
+        Some(otp) => verify_code(pool, uid, otp, true).await,
+        None => Err(TransactionError::OtpRequired.into()),
+    }
+}
+
+//a fresh one-time password, needed for elevated tokens and by transfers above the step-up amount
+#[instrument(name = "db.verify_otp", skip(pool, otp))]
+pub async fn verify_otp(pool: &Pool<Postgres>, uid: Uuid, otp: &str) -> Result<(), sqlx::Error> {
+    if !two_factor_enabled(pool, uid).await? {
+        return Err(TransactionError::TwoFactorRequired.into());
+    }
+    verify_code(pool, uid, otp, false).await
+}
+
+//transfers above auth.step_up_amount need an elevated token or a one-time password, a zero amount turns step-up off
+pub fn needs_step_up(caller: &Caller, transaction_type: &str, amount: Decimal, step_up_amount: Decimal) -> bool {
+    transaction_type == "transfer" && !step_up_amount.is_zero() && amount > step_up_amount && !caller.elevated
+}
+
+//recovery codes do not count, they are meant to regain access and not to move money
+pub async fn check_step_up(
+    pool: &Pool<Postgres>,
+    caller: &Caller,
+    transaction_type: &str,
+    amount: Decimal,
+    otp: Option<&str>,
+    step_up_amount: Decimal,
+) -> Result<(), sqlx::Error> {
+    if !needs_step_up(caller, transaction_type, amount, step_up_amount) {
+        return Ok(());
+    }
+    match otp {
+        Some(otp) => verify_otp(pool, caller.user_id, otp).await,
+        None if two_factor_enabled(pool, caller.user_id).await? => Err(TransactionError::StepUpRequired.into()),
+        None => Err(TransactionError::TwoFactorRequired.into()),
+    }
+}
@@ -1,26 +1,41 @@
+use std::fmt;
+
 use chrono::{NaiveDateTime, Utc};
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres, Row};
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+use tracing::{error, instrument};
 use uuid::Uuid;
 
-use crate::models::balance::add_balance_db;
+use crate::{
+    models::{
+        balance::add_balance_db,
+        kyc::KycLevel,
+        screening::{record_registration, record_rename, screen_registration, screen_rename},
+    },
+    utilities::{auth::hash_password, rbac::Role, screening::Sanctions, telemetry::mask_email},
+};
 
+#[instrument(name = "db.register_user", skip_all)]
 pub async fn register_user(
     pool: &Pool<Postgres>,
+    sanctions: &Sanctions,
     username: String,
     email: String,
     passwd: String,
 ) -> Result<Uuid, sqlx::Error> {
-    println!("Hello from the register user");
     let qry = "
         INSERT INTO USERS (user_id,username,email,password,updated_at) VALUES ($1,$2,$3,$4,$5)
     ";
 
+    //the name is screened before anything is written, a blocked name is refused
+    let screening = screen_registration(pool, sanctions, &username).await?;
+    let passwd = hash_password(&passwd).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    let email = email.trim().to_lowercase();
     let uuid = Uuid::new_v4();
     let updated_at = Utc::now();
-    match sqlx::query(&qry)
-    .bind(&uuid)
+    let registered = match sqlx::query(qry)
+    .bind(uuid)
         .bind(&username)
         .bind(&email)
         .bind(&passwd)
@@ -28,93 +43,239 @@ pub async fn register_user(
         .execute(pool)
         .await
     {
-        Ok(v) => match add_balance_db(pool, uuid, dec!(0.0)).await {
+        Ok(_) => match add_balance_db(pool, uuid, dec!(0.0)).await {
             Ok(_) => Ok(uuid),
-            Err(e) => return Err(e),
+            Err(e) => Err(e),
         },
         Err(e) => {
-            println!("Error at Register User : {:?}", e);
-            return Err(e);
+            error!(error = %e, "Error at Register User");
+            Err(e)
         }
+    }?;
+    if let Some(screening) = screening {
+        record_registration(pool, registered, &username, &screening).await?;
     }
+    Ok(registered)
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, PartialEq)]
 pub struct UserInfo {
     pub id: i32,
     pub user_id: Uuid,
     pub username: String,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password: String,
     pub created_at:NaiveDateTime ,
-    pub updated_at: NaiveDateTime
+    pub updated_at: NaiveDateTime,
+    pub role: Role,
+    pub phone: Option<String>,
+    pub handle: Option<String>,
+    pub kyc_level: KycLevel,
+    //none until the user confirmed the address with the e-mailed token
+    pub email_verified_at: Option<NaiveDateTime>,
+}
+
+fn user_info(v: &PgRow) -> UserInfo {
+    UserInfo {
+        id: v.get(0),
+        user_id: v.get("user_id"),
+        email: v.get("email"),
+        username: v.get("username"),
+        password: v.get("password"),
+        created_at: v.get("created_at"),
+        updated_at: v.get("updated_at"),
+        //the column is constrained to the known roles, anything else gets the least privileges
+        role: v.get::<String, _>("role").parse().unwrap_or_default(),
+        phone: v.get("phone"),
+        handle: v.get("handle"),
+        kyc_level: v.get::<String, _>("kyc_level").parse().unwrap_or_default(),
+        email_verified_at: v.get("email_verified_at"),
+    }
+}
+
+//password and email stay out of debug output and therefore out of logs
+impl fmt::Debug for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserInfo")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("email", &mask_email(&self.email))
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .field("role", &self.role)
+            .field("handle", &self.handle)
+            .field("kyc_level", &self.kyc_level)
+            .field("email_verified_at", &self.email_verified_at)
+            .finish_non_exhaustive()
+    }
 }
 
+#[instrument(name = "db.get_user", skip_all, fields(email = %mask_email(&email)))]
 pub async fn get_user(pool: &Pool<Postgres>, email: String) -> Result<UserInfo, sqlx::Error> {
-    println!("Hello from the get_user");
-
-    let qry = format!("SELECT * FROM USERS where email like '{}';", email);
-
-    match sqlx::query(&qry).fetch_one(pool).await {
-        Ok(v) => {
-            println!("Data = {:?}", v);
-            return Ok(UserInfo {
-                id: v.get(0),
-                user_id: v.get("user_id"),
-                email: v.get("email"),
-                username: v.get("username"),
-                password: v.get("password"),
-                created_at: v.get("created_at"),
-                updated_at: v.get("updated_at")
-            });
-        }
+    //an exact match first, for the flagged duplicates of an address that existed before e-mails were unique regardless of case
+    let qry = "SELECT * FROM USERS where lower(email) = lower($1) ORDER BY email = $1 DESC, email_duplicate, id LIMIT 1;";
+
+    match sqlx::query(qry).bind(&email).fetch_one(pool).await {
+        Ok(v) => Ok(user_info(&v)),
         Err(e) => {
-            println!("Error at get user : {:?}", e);
-            return Err(e);
+            error!(error = %e, "Error at get user");
+            Err(e)
         }
     }
 }
 
+#[instrument(name = "db.update_user", skip(pool, sanctions, username))]
 pub async fn update_user(
     pool: &Pool<Postgres>,
+    sanctions: &Sanctions,
     id: Uuid,
     username: String,
 ) -> Result<(), sqlx::Error> {
-    println!("Hello from the update user");
-
     let qry = "UPDATE users SET username=$1 where user_id = $2;";
 
-    match sqlx::query(qry).bind(username).bind(id).execute(pool).await {
-        Ok(v) => {
-            println!("Data = {:?}", v);
-            Ok(())
-        }
+    //the new name is screened like at registration, a blocked name is refused
+    let screening = screen_rename(pool, sanctions, id, &username).await?;
+    match sqlx::query(qry).bind(&username).bind(id).execute(pool).await {
+        Ok(_) => {}
         Err(e) => {
-            println!("Error at update_user : {:?}", e);
+            error!(error = %e, "Error at update_user");
             return Err(e);
         }
     }
+    if let Some(screening) = screening {
+        record_rename(pool, id, &username, &screening).await?;
+    }
+    Ok(())
 }
 
-pub async fn get_user_by_id(pool: &Pool<Postgres>, uuid: Uuid) -> Result<UserInfo, sqlx::Error> {
-    println!("Hello from the get_user_by_id");
+//replaces a password stored in clear text by its hash, unless it changed meanwhile
+#[instrument(name = "db.rehash_password", skip(pool, stored, password))]
+pub async fn rehash_password(pool: &Pool<Postgres>, id: Uuid, stored: &str, password: &str) -> Result<(), sqlx::Error> {
+    let hash = hash_password(password).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+    sqlx::query("UPDATE users SET password = $1 where user_id = $2 and password = $3")
+        .bind(hash)
+        .bind(id)
+        .bind(stored)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+//sets the payee identifiers that are given, Some("") clears one
+//both must already be normalized, see models::payees
+#[instrument(name = "db.update_payee_identifiers", skip(pool, phone, handle))]
+pub async fn update_payee_identifiers(
+    pool: &Pool<Postgres>,
+    id: Uuid,
+    phone: Option<String>,
+    handle: Option<String>,
+) -> Result<(), sqlx::Error> {
+    let qry = "
+        UPDATE users SET
+            phone = CASE WHEN $1 THEN NULLIF($2, '') ELSE phone END,
+            handle = CASE WHEN $3 THEN NULLIF($4, '') ELSE handle END,
+            updated_at = $5
+        where user_id = $6;
+    ";
 
+    match sqlx::query(qry)
+        .bind(phone.is_some())
+        .bind(phone.unwrap_or_default())
+        .bind(handle.is_some())
+        .bind(handle.unwrap_or_default())
+        .bind(Utc::now())
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at update_payee_identifiers");
+            Err(e)
+        }
+    }
+}
+
+#[instrument(name = "db.get_user_by_id", skip(pool))]
+pub async fn get_user_by_id(pool: &Pool<Postgres>, uuid: Uuid) -> Result<UserInfo, sqlx::Error> {
     let qry = "SELECT * from users where user_id = $1";
 
     match sqlx::query(qry).bind(uuid).fetch_one(pool).await {
-        Ok(v) => {
-            return Ok(UserInfo {
-                id: v.get(0),
-                user_id: v.get("user_id"),
-                email: v.get("email"),
-                username: v.get("username"),
-                password: v.get("password"),
-                updated_at: v.get("updated_at"),
-                created_at: v.get("created_at")
-            });
+        Ok(v) => Ok(user_info(&v)),
+        Err(e) => {
+            Err(e)
+        }
+    }
+}
+
+//a JWT is accepted while the password was not reset after it was issued
+//iat has whole seconds, so the reset is compared by the second it happened in
+pub async fn session_active(pool: &Pool<Postgres>, id: Uuid, iat: usize) -> Result<bool, sqlx::Error> {
+    let qry = "SELECT EXISTS(SELECT 1 FROM users where user_id = $1
+        and (password_changed_at IS NULL or EXTRACT(EPOCH FROM date_trunc('second', password_changed_at)) <= $2))";
+    sqlx::query_scalar(qry).bind(id).bind(iat as i64).fetch_one(pool).await
+}
+
+#[instrument(name = "db.get_role", skip(pool))]
+pub async fn get_role(pool: &Pool<Postgres>, uuid: Uuid) -> Result<Role, sqlx::Error> {
+    let qry = "SELECT role from users where user_id = $1";
+
+    let role: String = sqlx::query_scalar(qry).bind(uuid).fetch_one(pool).await?;
+    Ok(role.parse().unwrap_or_default())
+}
+
+#[instrument(name = "db.set_role", skip(pool))]
+pub async fn set_role(pool: &Pool<Postgres>, uuid: Uuid, role: Role) -> Result<(), sqlx::Error> {
+    let qry = "UPDATE users SET role=$1, updated_at=$2 where user_id = $3;";
+
+    match sqlx::query(qry)
+        .bind(role.as_str())
+        .bind(Utc::now())
+        .bind(uuid)
+        .execute(pool)
+        .await
+    {
+        Ok(v) if v.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!(error = %e, "Error at set_role");
+            Err(e)
         }
+    }
+}
+
+//case insensitive substring match on username and email, newest users first
+#[instrument(name = "db.search_users", skip(pool, query))]
+pub async fn search_users(
+    pool: &Pool<Postgres>,
+    query: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<UserInfo>, sqlx::Error> {
+    let qry = "
+        SELECT * FROM users
+        WHERE username ILIKE $1 OR email ILIKE $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT $2 OFFSET $3
+    ";
+
+    //% and _ typed by the caller are matched literally
+    let pattern = format!(
+        "%{}%",
+        query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    );
+    match sqlx::query(qry)
+        .bind(pattern)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(v) => Ok(v.iter().map(user_info).collect()),
         Err(e) => {
-            return Err(e);
+            error!(error = %e, "Error at search_users");
+            Err(e)
         }
     }
 }
@@ -1,36 +1,130 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use chrono::Utc;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-const JWT_SECRET: &[u8] = b"payments_dodo";
+use crate::{config::settings::AuthSettings, models::api_keys::Scope, utilities::rbac::Role};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Claims {
     pub sub: Uuid,
     pub exp: usize,
+    //tokens issued before it was added carry 0, an OAuth2 token must not predate the consent it acts on
+    #[serde(default)]
+    pub iat: usize,
+    //tokens issued before roles existed belong to customers
+    #[serde(default)]
+    pub role: Role,
+    //set on the short-lived tokens of a fresh one-time password, they pass the step-up check of large transfers
+    #[serde(default)]
+    pub elevated: bool,
+    //set on the tokens of POST /v1/oauth/token, JwtMiddleware limits them to their space separated scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    //the grant_type the OAuth2 token was issued for, only client_credentials tokens act on the owner's standing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grant: Option<String>,
 }
-pub fn encode_jwt(uid: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    println!("Helllo from the create_jwt");
 
-    let expiration = Utc::now()
-        .checked_add_signed(chrono::Duration::minutes(15))
+pub fn encode_jwt(uid: Uuid, role: Role, auth: &AuthSettings) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_claims(uid, role, false, auth.token_ttl_minutes, auth)
+}
+
+pub fn encode_elevated_jwt(uid: Uuid, role: Role, auth: &AuthSettings) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_claims(uid, role, true, auth.step_up_ttl_minutes, auth)
+}
+
+//an access token of a third-party client, for the user who consented or for the owner of the client
+pub fn encode_oauth_jwt(
+    uid: Uuid,
+    role: Role,
+    client_id: &str,
+    grant: &str,
+    scopes: &[Scope],
+    auth: &AuthSettings,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let scope = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(" ");
+    let claims = Claims {
+        client_id: Some(client_id.to_string()),
+        scope: Some(scope),
+        grant: Some(grant.to_string()),
+        ..claims(uid, role, false, auth.oauth_token_ttl_minutes)
+    };
+    sign(&claims, auth)
+}
+
+fn claims(uid: Uuid, role: Role, elevated: bool, ttl_minutes: i64) -> Claims {
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(chrono::Duration::minutes(ttl_minutes))
         .expect("Valid Timestamp")
         .timestamp();
 
-    let claims = Claims {
+    Claims {
         sub: uid,
         exp: expiration as usize,
-    };
+        iat: now.timestamp() as usize,
+        role,
+        elevated,
+        client_id: None,
+        scope: None,
+        grant: None,
+    }
+}
 
+fn encode_claims(uid: Uuid, role: Role, elevated: bool, ttl_minutes: i64, auth: &AuthSettings) -> Result<String, jsonwebtoken::errors::Error> {
+    sign(&claims(uid, role, elevated, ttl_minutes), auth)
+}
+
+fn sign(claims: &Claims, auth: &AuthSettings) -> Result<String, jsonwebtoken::errors::Error> {
     let header = Header::new(jsonwebtoken::Algorithm::HS256);
-    encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET))
+    encode(
+        &header,
+        claims,
+        &EncodingKey::from_secret(auth.jwt_secret.as_bytes()),
+    )
 }
 
-pub fn decode_jwt(token: String) -> Result<Claims, jsonwebtoken::errors::Error> {
+pub fn decode_jwt(token: String, auth: &AuthSettings) -> Result<Claims, jsonwebtoken::errors::Error> {
     let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
-    match decode::<Claims>(&token, &DecodingKey::from_secret(JWT_SECRET), &validation) {
+    match decode::<Claims>(
+        &token,
+        &DecodingKey::from_secret(auth.jwt_secret.as_bytes()),
+        &validation,
+    ) {
         Ok(v) => Ok(v.claims),
-        Err(e) => return Err(e),
+        Err(e) => Err(e),
     }
 }
+
+//passwords are stored as argon2id, rows written before that hold the password itself until its next login
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+fn argon2_hash(stored: &str) -> Option<PasswordHash<'_>> {
+    PasswordHash::new(stored)
+        .ok()
+        .filter(|hash| hash.algorithm.as_str().starts_with("argon2"))
+}
+
+//a stored hash never matches itself, so it is no password for the account
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    match argon2_hash(stored) {
+        Some(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        None => stored == password,
+    }
+}
+
+//a password stored in clear text, hashed at its next successful login
+pub fn needs_rehash(stored: &str) -> bool {
+    argon2_hash(stored).is_none()
+}
@@ -0,0 +1,122 @@
+//response envelope of the /v1 api
+//  success: {"status": "Success", "message": ..., "data": ...}
+//  error:   {"status": "Error", "message": ..., "code": ...}
+//the code is stable and meant for programs, the message is meant for people
+use actix_web::{
+    error::{InternalError, JsonPayloadError, PathError, QueryPayloadError},
+    http::StatusCode,
+    HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, warn};
+
+use crate::{models::payouts::InvalidRows, utilities::errors::TransactionError};
+
+pub fn success<T: Serialize>(status: StatusCode, message: &str, data: T) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "status": "Success",
+        "message": message,
+        "data": data
+    }))
+}
+
+pub fn failure(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "status": "Error",
+        "message": message,
+        "code": code
+    }))
+}
+
+//maps model errors to http statuses, unlike the legacy api_error which answers 400 for everything
+pub fn db_failure(error: sqlx::Error) -> HttpResponse {
+    //a rejected upload lists every invalid row next to the code
+    if let Some(rows) = InvalidRows::from_sqlx(&error) {
+        warn!(rows = rows.0.len(), "Upload rejected");
+        return HttpResponse::BadRequest().json(json!({
+            "status": "Error",
+            "message": rows.to_string(),
+            "code": "invalid_rows",
+            "errors": rows.0
+        }));
+    }
+    if let Some(e) = TransactionError::from_sqlx(&error) {
+        warn!(error = %e, "Request rejected");
+        let status = match e {
+            TransactionError::InsufficientBalance => StatusCode::UNPROCESSABLE_ENTITY,
+            TransactionError::AccountFrozen
+            | TransactionError::AccountDebitBlocked
+            | TransactionError::AccountClosed
+            | TransactionError::TransactionBlocked
+            | TransactionError::ScreeningBlocked
+            | TransactionError::SelfReview
+            | TransactionError::KycLevelRequired
+            | TransactionError::TwoFactorRequired => StatusCode::FORBIDDEN,
+            TransactionError::OtpRequired
+            | TransactionError::InvalidOtp
+            | TransactionError::StepUpRequired
+            | TransactionError::InvalidClient => StatusCode::UNAUTHORIZED,
+            TransactionError::ReceiverUnavailable
+            | TransactionError::BeneficiaryLimitExceeded
+            | TransactionError::FeeExceedsAmount
+            | TransactionError::TransactionLimitExceeded
+            | TransactionError::DailyLimitExceeded
+            | TransactionError::MonthlyLimitExceeded
+            | TransactionError::KycLimitExceeded => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            TransactionError::VelocityLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            TransactionError::BalanceNotZero
+            | TransactionError::InvalidScheduleState
+            | TransactionError::ReviewClosed
+            | TransactionError::TwoFactorEnabled => StatusCode::CONFLICT,
+            TransactionError::PayeeNotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        return failure(status, e.reason(), &e.to_string());
+    }
+    match error {
+        sqlx::Error::RowNotFound => {
+            warn!("Not Found Error");
+            failure(StatusCode::NOT_FOUND, "not_found", "Requested item not found")
+        }
+        sqlx::Error::TypeNotFound { type_name } => failure(
+            StatusCode::BAD_REQUEST,
+            "unknown_transaction_type",
+            &format!("Unknown transaction type {}", type_name),
+        ),
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            warn!(error = db_err.message(), "Db-error");
+            failure(StatusCode::CONFLICT, "conflict", "Resource already exists")
+        }
+        sqlx::Error::Encode(e) => {
+            warn!(error = %e, "Request rejected");
+            failure(StatusCode::BAD_REQUEST, "invalid_request", &e.to_string())
+        }
+        e => {
+            error!(error = %e, "Other error");
+            failure(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Internal server error",
+            )
+        }
+    }
+}
+
+//extractor errors answer with the same envelope instead of actix's plain text
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = failure(StatusCode::BAD_REQUEST, "invalid_body", &err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+pub fn path_error(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    let response = failure(StatusCode::NOT_FOUND, "not_found", "Requested item not found");
+    InternalError::from_response(err, response).into()
+}
+
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = failure(StatusCode::BAD_REQUEST, "invalid_query", &err.to_string());
+    InternalError::from_response(err, response).into()
+}
@@ -1,25 +1,176 @@
+use std::fmt;
+
 use actix_web::HttpResponse;
 use serde_json::json;
+use tracing::{error, warn};
+
+//business rule violations of the ledger
+//they travel inside sqlx::Error::Encode so the model signatures stay unchanged, the message is what the client sees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionError {
+    NonPositiveAmount,
+    TooManyDecimals,
+    ReceiverNotAllowed,
+    SameAccount,
+    InsufficientBalance,
+    AccountFrozen,
+    AccountDebitBlocked,
+    AccountClosed,
+    ReceiverUnavailable,
+    BalanceNotZero,
+    InvalidPayee,
+    PayeeNotFound,
+    BeneficiaryLimitExceeded,
+    InvalidScheduleState,
+    FeeExceedsAmount,
+    TransactionLimitExceeded,
+    DailyLimitExceeded,
+    MonthlyLimitExceeded,
+    VelocityLimitExceeded,
+    TransactionBlocked,
+    ReviewClosed,
+    SelfReview,
+    ScreeningBlocked,
+    KycLevelRequired,
+    KycLimitExceeded,
+    InvalidToken,
+    OtpRequired,
+    InvalidOtp,
+    StepUpRequired,
+    TwoFactorRequired,
+    TwoFactorEnabled,
+    InvalidClient,
+    UnauthorizedClient,
+    InvalidGrant,
+    InvalidScope,
+    InvalidRedirectUri,
+}
+
+impl TransactionError {
+    //stable machine readable name, used as metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TransactionError::NonPositiveAmount => "non_positive_amount",
+            TransactionError::TooManyDecimals => "too_many_decimals",
+            TransactionError::ReceiverNotAllowed => "receiver_not_allowed",
+            TransactionError::SameAccount => "same_account",
+            TransactionError::InsufficientBalance => "insufficient_balance",
+            TransactionError::AccountFrozen => "account_frozen",
+            TransactionError::AccountDebitBlocked => "account_debit_blocked",
+            TransactionError::AccountClosed => "account_closed",
+            TransactionError::ReceiverUnavailable => "receiver_unavailable",
+            TransactionError::BalanceNotZero => "balance_not_zero",
+            TransactionError::InvalidPayee => "invalid_payee",
+            TransactionError::PayeeNotFound => "payee_not_found",
+            TransactionError::BeneficiaryLimitExceeded => "beneficiary_limit_exceeded",
+            TransactionError::InvalidScheduleState => "invalid_schedule_state",
+            TransactionError::FeeExceedsAmount => "fee_exceeds_amount",
+            TransactionError::TransactionLimitExceeded => "transaction_limit_exceeded",
+            TransactionError::DailyLimitExceeded => "daily_limit_exceeded",
+            TransactionError::MonthlyLimitExceeded => "monthly_limit_exceeded",
+            TransactionError::VelocityLimitExceeded => "velocity_limit_exceeded",
+            TransactionError::TransactionBlocked => "transaction_blocked",
+            TransactionError::ReviewClosed => "review_closed",
+            //the code every missing permission answers with
+            TransactionError::SelfReview => "forbidden",
+            TransactionError::ScreeningBlocked => "screening_blocked",
+            TransactionError::KycLevelRequired => "kyc_required",
+            TransactionError::KycLimitExceeded => "kyc_limit_exceeded",
+            TransactionError::InvalidToken => "invalid_token",
+            TransactionError::OtpRequired => "otp_required",
+            TransactionError::InvalidOtp => "invalid_otp",
+            TransactionError::StepUpRequired => "step_up_required",
+            TransactionError::TwoFactorRequired => "two_factor_required",
+            TransactionError::TwoFactorEnabled => "two_factor_enabled",
+            //the OAuth2 error codes of RFC 6749
+            TransactionError::InvalidClient => "invalid_client",
+            TransactionError::UnauthorizedClient => "unauthorized_client",
+            TransactionError::InvalidGrant => "invalid_grant",
+            TransactionError::InvalidScope => "invalid_scope",
+            TransactionError::InvalidRedirectUri => "invalid_redirect_uri",
+        }
+    }
+
+    pub fn from_sqlx(error: &sqlx::Error) -> Option<TransactionError> {
+        match error {
+            sqlx::Error::Encode(e) => e.downcast_ref::<TransactionError>().copied(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            TransactionError::NonPositiveAmount => "Amount must be greater than zero",
+            TransactionError::TooManyDecimals => "Amount cannot have more than two decimal places",
+            TransactionError::ReceiverNotAllowed => "Cannot be done for different account",
+            TransactionError::SameAccount => "Cannot be done for same user",
+            TransactionError::InsufficientBalance => "Insufficient Balance",
+            TransactionError::AccountFrozen => "Account is frozen",
+            TransactionError::AccountDebitBlocked => "Account is blocked for outgoing payments",
+            TransactionError::AccountClosed => "Account is closed",
+            TransactionError::ReceiverUnavailable => "Receiver cannot accept funds",
+            TransactionError::BalanceNotZero => "Account balance must be zero or swept to another account",
+            TransactionError::InvalidPayee => {
+                "Payee must be an e-mail, a phone number with country code or an @handle"
+            }
+            TransactionError::PayeeNotFound => "No payee matches these details",
+            TransactionError::BeneficiaryLimitExceeded => "Amount exceeds the limit set for this beneficiary",
+            TransactionError::InvalidScheduleState => "The scheduled payment cannot do this in its current status",
+            TransactionError::FeeExceedsAmount => "The fee is not less than the deposited amount",
+            TransactionError::TransactionLimitExceeded => "Amount exceeds the limit of a single transaction",
+            TransactionError::DailyLimitExceeded => "Amount exceeds what is left of the daily limit",
+            TransactionError::MonthlyLimitExceeded => "Amount exceeds what is left of the monthly limit",
+            TransactionError::VelocityLimitExceeded => "Too many transactions in the last hour",
+            TransactionError::TransactionBlocked => "Transaction was blocked by transaction monitoring",
+            TransactionError::ReviewClosed => "The review has already been decided",
+            TransactionError::SelfReview => "A review of your own account must be decided by someone else",
+            TransactionError::ScreeningBlocked => "The name was blocked by sanctions screening",
+            TransactionError::KycLevelRequired => "Verify your identity to use this transaction type",
+            TransactionError::KycLimitExceeded => "Amount exceeds what your verification level allows",
+            TransactionError::InvalidToken => "The token is invalid, expired or already used",
+            TransactionError::OtpRequired => "A one-time password is required",
+            TransactionError::InvalidOtp => "The one-time password is invalid",
+            TransactionError::StepUpRequired => "Transfers of this amount need a one-time password or an elevated token",
+            TransactionError::TwoFactorRequired => "Enable two-factor authentication to send transfers of this amount",
+            TransactionError::TwoFactorEnabled => "Two-factor authentication is already enabled",
+            TransactionError::InvalidClient => "Unknown client or wrong client secret",
+            TransactionError::UnauthorizedClient => "The client may not use this grant type",
+            TransactionError::InvalidGrant => "The authorization code is invalid, expired or already used",
+            TransactionError::InvalidScope => "The requested scope is unknown or not allowed for this client",
+            TransactionError::InvalidRedirectUri => "The redirect uri is not registered for this client",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl From<TransactionError> for sqlx::Error {
+    fn from(e: TransactionError) -> Self {
+        sqlx::Error::Encode(Box::new(e))
+    }
+}
 
 pub fn api_error(error: sqlx::Error) -> HttpResponse {
-    println!("Api Error");
     let mut message = "Invalid Request".to_string();
 
     match error {
         sqlx::Error::Database(db_err) => {
-            println!("Db-error = {:?}", db_err.message());
+            warn!(error = db_err.message(), "Db-error");
             message = db_err.message().to_string();
         }
         sqlx::Error::Encode(db_err) => {
-            println!("Error = {:?}", db_err);
-            message = "Insufficient Balance".to_string();
+            warn!(error = %db_err, "Request rejected");
+            message = db_err.to_string();
         }
         sqlx::Error::RowNotFound => {
-            println!("Not Found Error");
+            warn!("Not Found Error");
             message = "Requested item not found".to_string();
         }
-        _ => {
-            println!("Other error");
+        e => {
+            error!(error = %e, "Other error");
         }
     };
     HttpResponse::BadRequest().json(json!({
@@ -0,0 +1,148 @@
+//outgoing e-mail, mail.transport picks the implementation
+//  smtp: sent through the server of mail.smtp_url
+//  file: written as .eml files into mail.outbox_dir, to read them during local development
+//  memory: kept in the process, for tests
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{config::settings::MailSettings, utilities::telemetry::mask_email};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    //plain text
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+fn message(from: &Mailbox, email: &Email) -> Result<Message, String> {
+    let to: Mailbox = email.to.parse().map_err(|e| format!("{}: {}", mask_email(&email.to), e))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject)
+        .body(email.body.clone())
+        .map_err(|e| e.to_string())
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(url: &str, from: Mailbox) -> Result<SmtpMailer, String> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+            .map_err(|e| format!("mail.smtp_url: {}", e))?
+            .build();
+        Ok(SmtpMailer { from, transport })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        self.transport
+            .send(message(&self.from, email)?)
+            .await
+            .map_err(|e| e.to_string())?;
+        info!(to = %mask_email(&email.to), subject = %email.subject, "Mail sent");
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: Mailbox) -> FileMailer {
+        FileMailer { from, dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let content = message(&self.from, email)?.formatted();
+        //sortable by the time they were sent
+        let path = self.dir.join(format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S%.3f"), Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, content))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        info!(to = %mask_email(&email.to), subject = %email.subject, path = %path.display(), "Mail written to the outbox");
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    //every e-mail sent so far, oldest first
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().map(|v| v.clone()).unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        self.sent.lock().map_err(|e| e.to_string())?.push(email.clone());
+        Ok(())
+    }
+}
+
+pub fn from_settings(settings: &MailSettings) -> Result<Arc<dyn Mailer>, String> {
+    let from: Mailbox = settings.from.parse().map_err(|e| format!("mail.from: {}", e))?;
+    match settings.transport.as_str() {
+        "smtp" => Ok(Arc::new(SmtpMailer::new(&settings.smtp_url, from)?)),
+        "file" => Ok(Arc::new(FileMailer::new(&settings.outbox_dir, from))),
+        "memory" => Ok(Arc::new(MemoryMailer::default())),
+        v => Err(format!("mail.transport: unknown transport {}", v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Email, FileMailer, Mailer};
+
+    #[actix_web::test]
+    async fn test_file_outbox() {
+        let dir = std::env::temp_dir().join(format!("outbox-{}", uuid::Uuid::new_v4()));
+        let mailer = FileMailer::new(&dir, "payments_dodo <no-reply@localhost>".parse().unwrap());
+        let email = Email {
+            to: String::from("alice@example.com"),
+            subject: String::from("Verify your e-mail address"),
+            body: String::from("Your code: 1234"),
+        };
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|f| f.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(content.contains("To: alice@example.com"));
+        assert!(content.contains("Subject: Verify your e-mail address"));
+        assert!(content.contains("Your code: 1234"));
+
+        let invalid = Email { to: String::from("not an address"), ..email };
+        assert!(mailer.send(&invalid).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
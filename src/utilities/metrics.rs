@@ -0,0 +1,309 @@
+//prometheus metrics of the service, exposed in the text format on /metrics
+//the registry is process wide so models and middlewares can record without access to AppState
+use std::{sync::LazyLock, time::Instant};
+
+use prometheus::{
+    exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use sqlx::{Pool, Postgres};
+use tracing::{span, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use uuid::Uuid;
+
+use crate::utilities::errors::TransactionError;
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+    pub transactions: IntCounterVec,
+    pub transaction_volume: CounterVec,
+    pub transaction_failures: IntCounterVec,
+    pub db_pool_connections: IntGaugeVec,
+    pub db_query_duration: HistogramVec,
+    pub jwt_rejections: IntCounterVec,
+    pub rate_limited: IntCounterVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some(String::from("payments")), None)
+            .expect("Valid metrics prefix");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route"),
+            &["method", "route"],
+        )
+        .unwrap();
+        let transactions = IntCounterVec::new(
+            Opts::new("transactions_total", "Booked transactions by type and status"),
+            &["type", "status"],
+        )
+        .unwrap();
+        let transaction_volume = CounterVec::new(
+            Opts::new("transaction_volume_total", "Sum of transaction amounts by type and status"),
+            &["type", "status"],
+        )
+        .unwrap();
+        let transaction_failures = IntCounterVec::new(
+            Opts::new("transaction_failures_total", "Failed transactions by type and reason"),
+            &["type", "reason"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections by state"),
+            &["state"],
+        )
+        .unwrap();
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Latency of the db.* operations")
+                .buckets(exponential_buckets(0.0005, 2.0, 14).unwrap()),
+            &["query"],
+        )
+        .unwrap();
+        let jwt_rejections = IntCounterVec::new(
+            Opts::new("jwt_rejections_total", "Requests rejected by the JWT middleware"),
+            &["reason"],
+        )
+        .unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests refused by a rate limit or the login lockout"),
+            &["scope"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_duration.clone()),
+            Box::new(transactions.clone()),
+            Box::new(transaction_volume.clone()),
+            Box::new(transaction_failures.clone()),
+            Box::new(db_pool_connections.clone()),
+            Box::new(db_query_duration.clone()),
+            Box::new(jwt_rejections.clone()),
+            Box::new(rate_limited.clone()),
+        ] {
+            registry.register(collector).expect("Unique metric names");
+        }
+
+        Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            transactions,
+            transaction_volume,
+            transaction_failures,
+            db_pool_connections,
+            db_query_duration,
+            jwt_rejections,
+            rate_limited,
+        }
+    }
+
+    //pool gauges are sampled at scrape time instead of tracking every acquire
+    pub fn render(&self, pool: &Pool<Postgres>) -> String {
+        let size = i64::from(pool.size());
+        let idle = pool.num_idle() as i64;
+        let max = i64::from(pool.options().get_max_connections());
+        self.db_pool_connections.with_label_values(&["idle"]).set(idle);
+        self.db_pool_connections.with_label_values(&["in_use"]).set(size - idle);
+        self.db_pool_connections.with_label_values(&["max"]).set(max);
+
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!(error = %e, "Error at metrics encoding");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+//route is the matched pattern, unmatched paths share one label so scanners cannot blow up the cardinality
+pub fn observe_request(method: &str, route: Option<&str>, status: u16, seconds: f64) {
+    let route = route.unwrap_or("unmatched");
+    METRICS
+        .http_requests
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    METRICS
+        .http_duration
+        .with_label_values(&[method, route])
+        .observe(seconds);
+}
+
+pub fn record_transaction(transaction_type: &str, amount: Decimal, result: &Result<Uuid, sqlx::Error>) {
+    let transaction_type = match transaction_type {
+        "deposit" | "withdrawl" | "transfer" => transaction_type,
+        _ => "other",
+    };
+    let status = if result.is_ok() { "completed" } else { "failed" };
+
+    METRICS
+        .transactions
+        .with_label_values(&[transaction_type, status])
+        .inc();
+    if amount > Decimal::ZERO {
+        METRICS
+            .transaction_volume
+            .with_label_values(&[transaction_type, status])
+            .inc_by(amount.to_f64().unwrap_or_default());
+    }
+    if let Err(e) = result {
+        METRICS
+            .transaction_failures
+            .with_label_values(&[transaction_type, failure_reason(e)])
+            .inc();
+    }
+}
+
+fn failure_reason(error: &sqlx::Error) -> &'static str {
+    if let Some(e) = TransactionError::from_sqlx(error) {
+        return e.reason();
+    }
+    match error {
+        sqlx::Error::TypeNotFound { .. } => "unknown_type",
+        sqlx::Error::RowNotFound => "account_not_found",
+        sqlx::Error::Database(_) => "database_error",
+        sqlx::Error::Encode(_) => "invalid_request",
+        _ => "internal_error",
+    }
+}
+
+pub fn record_jwt_rejection(reason: &str) {
+    METRICS.jwt_rejections.with_label_values(&[reason]).inc();
+}
+
+//scope is ip, user, route or login
+pub fn record_rate_limited(scope: &str) {
+    METRICS.rate_limited.with_label_values(&[scope]).inc();
+}
+
+struct SpanStart(Instant);
+
+//times every db.* span, so each model function reports its latency without extra code
+pub struct DbQueryLayer;
+
+impl DbQueryLayer {
+    pub fn is_db_span(metadata: &tracing::Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.name().starts_with("db.")
+    }
+}
+
+impl<S> Layer<S> for DbQueryLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !Self::is_db_span(attrs.metadata()) {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(start) = span.extensions().get::<SpanStart>() {
+                METRICS
+                    .db_query_duration
+                    .with_label_values(&[span.name()])
+                    .observe(start.0.elapsed().as_secs_f64());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+    use rust_decimal_macros::dec;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use crate::{
+        api::metrics::metrics,
+        utilities::{
+            telemetry::RequestTracing,
+            test_harness::TestDb,
+            utils::JwtMiddleware,
+        },
+    };
+
+    use super::{DbQueryLayer, METRICS};
+
+    #[actix_web::test]
+    async fn test_metrics_endpoint() {
+        let _subscriber = tracing::subscriber::set_default(Registry::default().with(DbQueryLayer));
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(10)).create().await;
+        //rejected for insufficient balance
+        let _ = crate::models::transactions::add_transaction(
+            &db.pool,
+            &db.compliance,
+            user.user_id,
+            None,
+            dec!(50),
+            String::from("withdrawl"),
+        )
+        .await;
+
+        let failures_before = METRICS
+            .jwt_rejections
+            .with_label_values(&["missing"])
+            .get();
+
+        let app = init_service(
+            App::new()
+                .wrap(JwtMiddleware)
+                .wrap(RequestTracing)
+                .app_data(db.app_data())
+                .route("/metrics", web::get().to(metrics))
+                .route("/balance/fetch_balance", web::get().to(actix_web::HttpResponse::Ok)),
+        )
+        .await;
+
+        //no JWT needed for the scrape, but the protected route counts a rejection
+        let req = TestRequest::get().uri("/balance/fetch_balance").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            METRICS.jwt_rejections.with_label_values(&["missing"]).get(),
+            failures_before + 1
+        );
+
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get("content-type")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+
+        for expected in [
+            r#"payments_http_requests_total{method="GET",route="/balance/fetch_balance",status="401"}"#,
+            r#"payments_transactions_total{status="completed",type="deposit"}"#,
+            r#"payments_transaction_volume_total{status="completed",type="deposit"}"#,
+            r#"payments_transaction_failures_total{reason="insufficient_balance",type="withdrawl"}"#,
+            r#"payments_db_pool_connections{state="max"} 5"#,
+            r#"payments_db_query_duration_seconds_count{query="db.add_transaction"}"#,
+            r#"payments_jwt_rejections_total{reason="missing"}"#,
+        ] {
+            assert!(body.contains(expected), "{} missing in\n{}", expected, body);
+        }
+    }
+}
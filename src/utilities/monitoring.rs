@@ -0,0 +1,270 @@
+//transaction monitoring rules, read from the toml file named by monitoring.rules_file
+//the rules in use live in the Compliance of the AppState with the sanctions list, a worker reloads them when the file changes
+//a file that does not parse is reported and the rules in use stay as they are
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::{
+    config::settings::MonitoringSettings,
+    models::fees::TRANSACTION_TYPES,
+    utilities::{screening::Sanctions, workers::WorkerHandle},
+};
+
+pub const WORKER_NAME: &str = "monitoring_rules";
+
+//the strongest action of the rules that match wins
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Review,
+    Block,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Review => "review",
+            Action::Block => "block",
+        }
+    }
+}
+
+//what a rule looks for, windows count back from the booking
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    //more than count transactions of the sender in the window, the booking included
+    Velocity { count: i64, window_minutes: i64 },
+    //at least count transactions, the booking included, just below threshold (within margin_percent of it)
+    Structuring {
+        #[schema(value_type = String)]
+        threshold: Decimal,
+        #[schema(value_type = String)]
+        margin_percent: Decimal,
+        count: i64,
+        window_hours: i64,
+    },
+    //a transfer of at least amount to a receiver the sender never paid before
+    NewPayeeLargeAmount {
+        #[schema(value_type = String)]
+        amount: Decimal,
+    },
+    //money leaving within window_minutes of coming in, the outgoing amount being at least percent of what came in
+    RapidInAndOut {
+        window_minutes: i64,
+        #[schema(value_type = String)]
+        percent: Decimal,
+    },
+    //an amount of at least min_amount that is a multiple of multiple
+    RoundAmount {
+        #[schema(value_type = String)]
+        multiple: Decimal,
+        #[schema(value_type = String)]
+        min_amount: Decimal,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, ToSchema)]
+pub struct MonitoringRule {
+    #[schema(example = "structuring_below_10k")]
+    pub name: String,
+    pub action: Action,
+    //the rule looks at these transaction types only, every type when empty
+    #[serde(default)]
+    pub transaction_types: Vec<String>,
+    //and at accounts of these tiers only, every tier when empty
+    #[serde(default)]
+    pub tiers: Vec<String>,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default, ToSchema)]
+pub struct RuleSet {
+    pub rules: Vec<MonitoringRule>,
+    //where and when the rules were read, none for the empty default
+    pub source: Option<String>,
+    pub loaded_at: Option<NaiveDateTime>,
+}
+
+impl MonitoringRule {
+    pub fn applies_to(&self, transaction_type: &str, tier: &str) -> bool {
+        (self.transaction_types.is_empty() || self.transaction_types.iter().any(|t| t == transaction_type))
+            && (self.tiers.is_empty() || self.tiers.iter().any(|t| t == tier))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let problem = |m: &str| Err(format!("rule {}: {}", self.name, m));
+        if self.name.trim().is_empty() {
+            return Err(String::from("every rule needs a name"));
+        }
+        if let Some(t) = self.transaction_types.iter().find(|t| !TRANSACTION_TYPES.contains(&t.as_str())) {
+            return problem(&format!("unknown transaction type {}", t));
+        }
+        let positive = |v: Decimal| v > Decimal::ZERO;
+        let valid = match &self.condition {
+            Condition::Velocity { count, window_minutes } => *count > 0 && *window_minutes > 0,
+            Condition::Structuring {
+                threshold,
+                margin_percent,
+                count,
+                window_hours,
+            } => positive(*threshold) && positive(*margin_percent) && *margin_percent < Decimal::ONE_HUNDRED && *count > 0 && *window_hours > 0,
+            Condition::NewPayeeLargeAmount { amount } => positive(*amount),
+            Condition::RapidInAndOut { window_minutes, percent } => *window_minutes > 0 && positive(*percent),
+            Condition::RoundAmount { multiple, min_amount } => positive(*multiple) && !min_amount.is_sign_negative(),
+        };
+        match valid {
+            true => Ok(()),
+            false => problem("counts, windows and amounts must be greater than zero, margin_percent below 100"),
+        }
+    }
+}
+
+impl RuleSet {
+    pub fn parse(content: &str, source: &str) -> Result<RuleSet, String> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct RulesFile {
+            #[serde(default)]
+            rules: Vec<MonitoringRule>,
+        }
+        let file: RulesFile = toml::from_str(content).map_err(|e| format!("{}: {}", source, e))?;
+        for (i, rule) in file.rules.iter().enumerate() {
+            rule.validate().map_err(|e| format!("{}: {}", source, e))?;
+            if file.rules[..i].iter().any(|r| r.name == rule.name) {
+                return Err(format!("{}: rule {} is defined twice", source, rule.name));
+            }
+        }
+        Ok(RuleSet {
+            rules: file.rules,
+            source: Some(source.to_string()),
+            loaded_at: Some(Utc::now().naive_utc()),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<RuleSet, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        RuleSet::parse(&content, &path.display().to_string())
+    }
+}
+
+#[derive(Default)]
+pub struct Rules(RwLock<Arc<RuleSet>>);
+
+impl Rules {
+    //the rules in use, a booking keeps the set it started with
+    pub fn current(&self) -> Arc<RuleSet> {
+        self.0.read().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    pub fn install(&self, rules: RuleSet) {
+        info!(rules = rules.rules.len(), source = ?rules.source, "Monitoring rules loaded");
+        if let Ok(mut r) = self.0.write() {
+            *r = Arc::new(rules);
+        }
+    }
+
+    //reads the configured file and puts its rules in use, an empty rules_file turns monitoring off
+    pub fn reload(&self, settings: &MonitoringSettings) -> Result<Arc<RuleSet>, String> {
+        let rules = match settings.rules_file.is_empty() {
+            true => RuleSet::default(),
+            false => RuleSet::read(Path::new(&settings.rules_file))?,
+        };
+        self.install(rules);
+        Ok(self.current())
+    }
+}
+
+//what bookings and registrations are checked against, one per AppState so tests can install their own
+#[derive(Default)]
+pub struct Compliance {
+    pub rules: Rules,
+    pub sanctions: Sanctions,
+}
+
+//a check only reads file metadata, the worker is stalled when it did not beat for three intervals
+pub fn max_silence(settings: &MonitoringSettings) -> Duration {
+    Duration::from_secs(settings.reload_interval_secs * 3)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+//polls the modification time of the rules file
+pub async fn run(compliance: Arc<Compliance>, settings: MonitoringSettings, mut handle: WorkerHandle) {
+    let path = PathBuf::from(&settings.rules_file);
+    let interval = Duration::from_secs(settings.reload_interval_secs);
+    let mut seen = modified(&path);
+    loop {
+        handle.heartbeat();
+        let now = modified(&path);
+        if now != seen {
+            seen = now;
+            if let Err(e) = compliance.rules.reload(&settings) {
+                error!(worker = handle.name(), error = %e, "Monitoring rules not reloaded, the previous rules stay in use");
+            }
+        }
+        if handle.sleep(interval).await {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Condition, RuleSet};
+
+    #[test]
+    fn test_rules_file() {
+        let rules = RuleSet::parse(
+            r#"
+            [[rules]]
+            name = "structuring"
+            kind = "structuring"
+            action = "review"
+            threshold = "10000.00"
+            margin_percent = "10"
+            count = 3
+            window_hours = 24
+
+            [[rules]]
+            name = "round"
+            kind = "round_amount"
+            action = "block"
+            transaction_types = ["withdrawl"]
+            multiple = "1000"
+            min_amount = "5000"
+            "#,
+            "rules.toml",
+        )
+        .unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(rules.rules[1].action, Action::Block);
+        assert!(matches!(rules.rules[1].condition, Condition::RoundAmount { .. }));
+        assert!(rules.rules[1].applies_to("withdrawl", "standard"));
+        assert!(!rules.rules[1].applies_to("transfer", "standard"));
+        assert!(Action::Block > Action::Review);
+
+        for bad in [
+            "[[rules]]\nname = \"v\"\nkind = \"velocity\"\naction = \"review\"\ncount = 0\nwindow_minutes = 5",
+            "[[rules]]\nname = \"v\"\nkind = \"teleport\"\naction = \"review\"",
+            "[[rules]]\nname = \"v\"\nkind = \"round_amount\"\naction = \"review\"\nmultiple = \"10\"\nmin_amount = \"0\"\ntransaction_types = [\"refund\"]",
+        ] {
+            assert!(RuleSet::parse(bad, "rules.toml").is_err(), "{}", bad);
+        }
+    }
+}
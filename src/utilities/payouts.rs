@@ -0,0 +1,42 @@
+//background worker that books the uploaded payout batches, one batch at a time
+//several instances may run it at once, a batch is claimed with a lease while its rows are booked
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+use crate::{
+    config::settings::PayoutSettings,
+    models::payouts::process_next_payout_batch,
+    utilities::{monitoring::Compliance, workers::WorkerHandle},
+};
+
+pub const WORKER_NAME: &str = "payouts";
+
+//a single batch may take up to its lease, the worker is stalled when it did not beat for a lease and three intervals
+pub fn max_silence(settings: &PayoutSettings) -> Duration {
+    Duration::from_secs(settings.lease_secs as u64 + settings.poll_interval_secs * 3)
+}
+
+pub async fn run(pool: Pool<Postgres>, compliance: Arc<Compliance>, settings: PayoutSettings, mut handle: WorkerHandle) {
+    let interval = Duration::from_secs(settings.poll_interval_secs);
+    let lease = chrono::Duration::seconds(settings.lease_secs);
+    loop {
+        handle.heartbeat();
+        //the next batch is taken right away, the worker only sleeps when there is nothing left
+        let idle = match process_next_payout_batch(&pool, &compliance, lease).await {
+            Ok(Some(batch)) => {
+                info!(worker = handle.name(), batch_id = %batch.batch_id, status = batch.status.as_str(), "Payout batch processed");
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error!(worker = handle.name(), error = %e, "Error at payout batches");
+                true
+            }
+        };
+        if handle.is_stopping() || (idle && handle.sleep(interval).await) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,586 @@
+//token bucket rate limiting of every request, a bucket holds up to capacity requests and refills continuously
+//  per client address over all routes (rate_limit.per_ip)
+//  per authenticated user over all routes (rate_limit.per_user)
+//  per route for the routes of rate_limit.routes, per user when authenticated and per client address otherwise
+//the per address bucket is taken in front of JwtMiddleware, so requests with bad tokens or unknown API keys count as well,
+//the per user and per route buckets behind it, where the caller is known
+//a request takes a token from each of its buckets of a layer only when every one of them allows it
+//the store keeps the buckets, memory per process or postgres shared between all instances
+//responses carry RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset of the tightest bucket, a refused request Retry-After as well
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix_web::{
+    body::BoxBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    web, Error, HttpMessage, HttpResponse,
+};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use sqlx::{Pool, Postgres, Row};
+use tracing::warn;
+
+use crate::{
+    config::settings::{Limit, RateLimitSettings},
+    utilities::{envelope::failure, metrics::record_rate_limited, rbac::Caller},
+    AppState,
+};
+
+//probes and scrapes are never limited
+pub const EXEMPT_PATHS: &[&str] = &["/metrics", "/healthz", "/readyz"];
+
+//a bucket untouched this long is full again for any sensible limit, the stores drop it
+const IDLE_HOURS: i64 = 1;
+//the stores look for idle buckets every this many requests
+const PRUNE_EVERY: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    //seconds until the bucket is full again
+    pub reset_secs: u64,
+    //seconds until the next request is allowed, 0 when this one was
+    pub retry_after_secs: u64,
+}
+
+impl Bucket {
+    pub fn full(limit: Limit, now: NaiveDateTime) -> Bucket {
+        Bucket {
+            tokens: f64::from(limit.capacity),
+            updated_at: now,
+        }
+    }
+
+    //refills the bucket for the time passed and takes a token when there is one
+    pub fn take(&mut self, limit: Limit, now: NaiveDateTime) -> Decision {
+        take_all(&mut [(self, limit)], now)[0]
+    }
+
+    fn refill(&mut self, limit: Limit, now: NaiveDateTime) {
+        let per_sec = f64::from(limit.refill_per_minute) / 60.0;
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * per_sec).min(f64::from(limit.capacity));
+        self.updated_at = now;
+    }
+
+    fn decision(&self, limit: Limit, allowed: bool) -> Decision {
+        let capacity = f64::from(limit.capacity);
+        let per_sec = f64::from(limit.refill_per_minute) / 60.0;
+        Decision {
+            allowed,
+            limit: limit.capacity,
+            remaining: self.tokens.floor() as u32,
+            reset_secs: ((capacity - self.tokens) / per_sec).ceil() as u64,
+            retry_after_secs: if allowed {
+                0
+            } else {
+                ((1.0 - self.tokens) / per_sec).ceil().max(1.0) as u64
+            },
+        }
+    }
+}
+
+//refills every bucket and takes a token from each of them only when all of them have one
+pub fn take_all(buckets: &mut [(&mut Bucket, Limit)], now: NaiveDateTime) -> Vec<Decision> {
+    for (bucket, limit) in buckets.iter_mut() {
+        bucket.refill(*limit, now);
+    }
+    let allowed = buckets.iter().all(|(b, _)| b.tokens >= 1.0);
+    buckets
+        .iter_mut()
+        .map(|(bucket, limit)| {
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            bucket.decision(*limit, allowed || bucket.tokens >= 1.0)
+        })
+        .collect()
+}
+
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    //one decision per (key, limit), tokens are taken only when every bucket allows the request
+    async fn take(&self, buckets: &[(String, Limit)], now: NaiveDateTime) -> Result<Vec<Decision>, String>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    requests: AtomicU64,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, buckets: &[(String, Limit)], now: NaiveDateTime) -> Result<Vec<Decision>, String> {
+        let mut stored = self.buckets.lock().map_err(|e| e.to_string())?;
+        if self.requests.fetch_add(1, Ordering::Relaxed).is_multiple_of(PRUNE_EVERY) {
+            stored.retain(|_, b| now - b.updated_at < Duration::hours(IDLE_HOURS));
+        }
+        let mut loaded: Vec<Bucket> = buckets
+            .iter()
+            .map(|(key, limit)| *stored.entry(key.clone()).or_insert_with(|| Bucket::full(*limit, now)))
+            .collect();
+        let decisions = take_all(
+            &mut loaded.iter_mut().zip(buckets).map(|(b, (_, limit))| (b, *limit)).collect::<Vec<_>>(),
+            now,
+        );
+        for ((key, _), bucket) in buckets.iter().zip(loaded) {
+            stored.insert(key.clone(), bucket);
+        }
+        Ok(decisions)
+    }
+}
+
+pub struct PostgresStore {
+    pool: Pool<Postgres>,
+    requests: AtomicU64,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool<Postgres>) -> PostgresStore {
+        PostgresStore {
+            pool,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    async fn take_locked(&self, buckets: &[(String, Limit)], now: NaiveDateTime) -> Result<Vec<Decision>, sqlx::Error> {
+        //rows are locked in key order, so requests sharing buckets cannot deadlock
+        let mut order: Vec<usize> = (0..buckets.len()).collect();
+        order.sort_by(|a, b| buckets[*a].0.cmp(&buckets[*b].0));
+        let mut loaded: Vec<Bucket> = buckets.iter().map(|(_, limit)| Bucket::full(*limit, now)).collect();
+
+        let mut tx = self.pool.begin().await?;
+        for &i in &order {
+            //a missing row cannot be locked, so the first request of a key inserts a full bucket before locking it
+            sqlx::query("INSERT INTO rate_limit_buckets(key,tokens,updated_at) Values ($1,$2,$3) ON CONFLICT (key) DO NOTHING")
+                .bind(&buckets[i].0)
+                .bind(loaded[i].tokens)
+                .bind(loaded[i].updated_at)
+                .execute(&mut *tx)
+                .await?;
+            let row = sqlx::query("SELECT tokens, updated_at FROM rate_limit_buckets where key = $1 FOR UPDATE")
+                .bind(&buckets[i].0)
+                .fetch_one(&mut *tx)
+                .await?;
+            loaded[i] = Bucket {
+                tokens: row.get(0),
+                updated_at: row.get(1),
+            };
+        }
+        let decisions = take_all(
+            &mut loaded.iter_mut().zip(buckets).map(|(b, (_, limit))| (b, *limit)).collect::<Vec<_>>(),
+            now,
+        );
+        for &i in &order {
+            sqlx::query("UPDATE rate_limit_buckets SET tokens = $2, updated_at = $3 where key = $1")
+                .bind(&buckets[i].0)
+                .bind(loaded[i].tokens)
+                .bind(loaded[i].updated_at)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        if self.requests.fetch_add(1, Ordering::Relaxed).is_multiple_of(PRUNE_EVERY) {
+            sqlx::query("DELETE FROM rate_limit_buckets where updated_at < $1")
+                .bind(now - Duration::hours(IDLE_HOURS))
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(decisions)
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn take(&self, buckets: &[(String, Limit)], now: NaiveDateTime) -> Result<Vec<Decision>, String> {
+        self.take_locked(buckets, now).await.map_err(|e| e.to_string())
+    }
+}
+
+pub fn store_from_settings(settings: &RateLimitSettings, pool: Pool<Postgres>) -> Arc<dyn RateLimitStore> {
+    match settings.store.as_str() {
+        "postgres" => Arc::new(PostgresStore::new(pool)),
+        _ => Arc::new(MemoryStore::default()),
+    }
+}
+
+fn set_rate_limit_headers(response: &mut HttpResponse, decision: &Decision) {
+    let headers = response.headers_mut();
+    for (name, value) in [
+        ("ratelimit-limit", decision.limit as u64),
+        ("ratelimit-remaining", decision.remaining as u64),
+        ("ratelimit-reset", decision.reset_secs),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+    if !decision.allowed {
+        headers.insert(RETRY_AFTER, HeaderValue::from(decision.retry_after_secs));
+    }
+}
+
+//429 with Retry-After, for the rate limits and the login lockout
+pub fn too_many_requests(retry_after_secs: u64, code: &str, message: &str) -> HttpResponse {
+    let mut response = failure(StatusCode::TOO_MANY_REQUESTS, code, message);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+//the answer to a login of a locked address
+pub fn login_locked(secs: i64) -> HttpResponse {
+    record_rate_limited("login");
+    too_many_requests(
+        secs.max(1) as u64,
+        "login_locked",
+        "Too many failed logins, retry later",
+    )
+}
+
+//the answer to a one-time password of a user whose codes are locked
+pub fn otp_locked(secs: i64) -> HttpResponse {
+    record_rate_limited("otp");
+    too_many_requests(
+        secs.max(1) as u64,
+        "otp_locked",
+        "Too many wrong one-time passwords, retry later",
+    )
+}
+
+//X-Forwarded-For can be set by any client, it only counts behind a proxy that overwrites it
+fn client_addr(req: &ServiceRequest, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        let forwarded = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| !v.is_empty());
+        if let Some(addr) = forwarded {
+            return addr.to_string();
+        }
+    }
+    req.peer_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+//which buckets a RateLimiter takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layer {
+    Address,
+    Caller,
+}
+
+//the per address decision of the outer layer, the inner one reports it in the headers when it is the tightest
+#[derive(Debug, Clone, Copy)]
+struct AddressDecision(Decision);
+
+//the buckets of a request as (scope, key, limit)
+fn buckets(req: &ServiceRequest, settings: &RateLimitSettings, layer: Layer) -> Vec<(&'static str, String, Limit)> {
+    let client = client_addr(req, settings.trust_forwarded_for);
+    if layer == Layer::Address {
+        return vec![("ip", format!("ip:{}", client), settings.per_ip)];
+    }
+    let caller = req.extensions().get::<Caller>().copied();
+    let mut buckets = vec![];
+    let subject = match caller {
+        Some(c) => {
+            buckets.push(("user", format!("user:{}", c.user_id), settings.per_user));
+            format!("user:{}", c.user_id)
+        }
+        None => format!("ip:{}", client),
+    };
+    let route = req.match_pattern();
+    let method = req.method().as_str();
+    for r in &settings.routes {
+        if Some(&r.path) == route.as_ref() && (r.method.is_empty() || r.method.eq_ignore_ascii_case(method)) {
+            buckets.push(("route", format!("route:{} {}:{}", r.method, r.path, subject), r.limit()));
+        }
+    }
+    buckets
+}
+
+//the decision reported in the headers, the refusal with the longest wait or else the bucket with the fewest requests left
+fn tightest(decisions: &[Decision]) -> Option<Decision> {
+    match decisions.iter().filter(|d| !d.allowed).max_by_key(|d| d.retry_after_secs) {
+        Some(refused) => Some(*refused),
+        None => decisions.iter().min_by_key(|d| (d.remaining, u64::MAX - d.reset_secs)).copied(),
+    }
+}
+
+//wrapped twice, per_address in front of JwtMiddleware and per_caller behind it
+pub struct RateLimiter {
+    layer: Layer,
+}
+
+impl RateLimiter {
+    pub fn per_address() -> RateLimiter {
+        RateLimiter { layer: Layer::Address }
+    }
+
+    pub fn per_caller() -> RateLimiter {
+        RateLimiter { layer: Layer::Caller }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimiterService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterService {
+            service: Rc::new(service),
+            layer: self.layer,
+        }))
+    }
+}
+
+pub struct RateLimiterService<S> {
+    service: Rc<S>,
+    layer: Layer,
+}
+
+impl<S> Service<ServiceRequest> for RateLimiterService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let layer = self.layer;
+        let data = req
+            .app_data::<web::Data<AppState>>()
+            .filter(|d| d.settings.rate_limit.enabled)
+            .cloned();
+        let data = match data {
+            Some(d) if !EXEMPT_PATHS.contains(&req.path()) => d,
+            _ => return Box::pin(service.call(req)),
+        };
+        let buckets = buckets(&req, &data.settings.rate_limit, layer);
+
+        Box::pin(async move {
+            let now = Utc::now().naive_utc();
+            let keys: Vec<(String, Limit)> = buckets.iter().map(|(_, key, limit)| (key.clone(), *limit)).collect();
+            let mut decisions = match keys.is_empty() {
+                true => vec![],
+                false => match data.rate_limits.take(&keys, now).await {
+                    Ok(v) => v,
+                    //a broken store lets requests through rather than taking the service down with it
+                    Err(e) => {
+                        warn!(error = %e, "Error at rate limit store, request not limited");
+                        vec![]
+                    }
+                },
+            };
+            for ((scope, _, _), d) in buckets.iter().zip(&decisions) {
+                if !d.allowed {
+                    record_rate_limited(scope);
+                    warn!(scope, path = req.path(), "Rate limit exceeded");
+                }
+            }
+            match layer {
+                Layer::Address => {
+                    if let Some(d) = decisions.first() {
+                        req.extensions_mut().insert(AddressDecision(*d));
+                    }
+                }
+                Layer::Caller => {
+                    if let Some(AddressDecision(d)) = req.extensions().get::<AddressDecision>().copied() {
+                        decisions.push(d);
+                    }
+                }
+            }
+            let decision = tightest(&decisions);
+
+            let mut response = match decision {
+                Some(d) if !d.allowed => {
+                    let response = failure(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "rate_limited",
+                        "Too many requests, retry later",
+                    );
+                    req.into_response(response)
+                }
+                _ => service.call(req).await?,
+            };
+            //the inner layer already reported the tightest bucket of both
+            if let Some(d) = decision.filter(|_| !response.headers().contains_key("ratelimit-limit")) {
+                set_rate_limit_headers(response.response_mut(), &d);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{Duration, NaiveDate};
+    use rust_decimal_macros::dec;
+    use serde_json::{json, Value};
+
+    use super::{take_all, Bucket, RateLimiter};
+    use crate::{
+        config::settings::{Limit, RouteLimit},
+        utilities::{
+            test_harness::{test_settings, TestDb},
+            utils::JwtMiddleware,
+        },
+    };
+
+    #[actix_web::test]
+    async fn test_rate_limits() {
+        let now = NaiveDate::from_ymd_opt(2026, 1, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        //3 requests at once, then one every 20 seconds
+        let limit = Limit {
+            capacity: 3,
+            refill_per_minute: 3,
+        };
+        let mut bucket = Bucket::full(limit, now);
+        for remaining in [2, 1, 0] {
+            let d = bucket.take(limit, now);
+            assert!(d.allowed);
+            assert_eq!(d.remaining, remaining);
+        }
+        let d = bucket.take(limit, now);
+        assert!(!d.allowed);
+        assert_eq!((d.retry_after_secs, d.reset_secs), (20, 60));
+        let d = bucket.take(limit, now + Duration::seconds(10));
+        assert_eq!((d.allowed, d.retry_after_secs), (false, 10));
+        assert!(bucket.take(limit, now + Duration::seconds(20)).allowed);
+        //never more than the capacity
+        assert_eq!(bucket.take(limit, now + Duration::hours(1)).remaining, 2);
+        //a request refused by one bucket takes nothing from the others
+        let mut empty = Bucket { tokens: 0.0, updated_at: now };
+        let mut full = Bucket::full(limit, now);
+        let decisions = take_all(&mut [(&mut empty, limit), (&mut full, limit)], now);
+        assert_eq!((decisions[0].allowed, decisions[1].allowed), (false, true));
+        assert_eq!(full.tokens, 3.0);
+
+        let db = TestDb::new().await;
+        let user = db.user().balance(dec!(10)).create().await;
+        let mut settings = test_settings();
+        settings.rate_limit.enabled = true;
+        settings.rate_limit.store = String::from("postgres");
+        settings.rate_limit.routes = vec![RouteLimit {
+            method: String::from("POST"),
+            path: String::from("/v1/sessions"),
+            capacity: 2,
+            refill_per_minute: 1,
+        }];
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimiter::per_caller())
+                .wrap(JwtMiddleware)
+                .wrap(RateLimiter::per_address())
+                .app_data(db.app_data_with(settings))
+                .configure(crate::routes),
+        )
+        .await;
+
+        let login = json!({"email": user.email, "password": user.password});
+        for remaining in ["1", "0"] {
+            let req = test::TestRequest::post().uri("/v1/sessions").set_json(&login).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "2");
+            assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), remaining);
+        }
+        let req = test::TestRequest::post().uri("/v1/sessions").set_json(&login).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "60");
+        assert_eq!(resp.headers().get("ratelimit-reset").unwrap(), "120");
+        let resp_body: Value = test::read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "rate_limited");
+
+        //another client has buckets of its own
+        let req = test::TestRequest::post()
+            .uri("/v1/sessions")
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .set_json(&login)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        //the per user bucket is the tightest of an authenticated request, probes are not limited
+        let req = test::TestRequest::get().uri("/v1/balance").insert_header(user.bearer()).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "120");
+        assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), "119");
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key("ratelimit-limit"));
+
+        let buckets: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rate_limit_buckets").fetch_one(&db.pool).await.unwrap();
+        assert_eq!(buckets, 5);
+
+        //guessed tokens and API keys count toward the address before JwtMiddleware rejects them
+        let mut settings = test_settings();
+        settings.rate_limit.enabled = true;
+        settings.rate_limit.per_ip = Limit {
+            capacity: 2,
+            refill_per_minute: 1,
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimiter::per_caller())
+                .wrap(JwtMiddleware)
+                .wrap(RateLimiter::per_address())
+                .app_data(db.app_data_with(settings))
+                .configure(crate::routes),
+        )
+        .await;
+        for (token, status, remaining) in [
+            ("sk_unknown_key", StatusCode::UNAUTHORIZED, "1"),
+            ("not-a-jwt", StatusCode::UNAUTHORIZED, "0"),
+            ("sk_unknown_key", StatusCode::TOO_MANY_REQUESTS, "0"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/v1/balance")
+                .peer_addr("198.51.100.1:4000".parse().unwrap())
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+            assert_eq!(resp.headers().get("ratelimit-remaining").unwrap(), remaining);
+        }
+    }
+}
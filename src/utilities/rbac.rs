@@ -0,0 +1,348 @@
+//role based access control
+//the role is stored on the user and copied into the JWT at login, JwtMiddleware turns it into a Caller
+//handlers take Caller for ownership checks, or Authorized<require::X> when the route needs a permission
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+use actix_web::{
+    dev::Payload, http::StatusCode, web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+    ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{models::users::get_role, utilities::envelope::failure, AppState};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Customer,
+    Support,
+    Admin,
+    Auditor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    //book deposits, withdrawls and transfers on the own account
+    Transact,
+    //staff permissions, they reach other users' resources
+    ReadUsers,
+    ReadAccounts,
+    FreezeAccounts,
+    CloseAccounts,
+    ManageRoles,
+    //fee rules and account tiers
+    ManageFees,
+    //transaction limits per tier
+    ManageLimits,
+    //the review queues of transaction monitoring and sanctions screening
+    ReviewTransactions,
+    //KYC submissions
+    ReviewKyc,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Customer => "customer",
+            Role::Support => "support",
+            Role::Admin => "admin",
+            Role::Auditor => "auditor",
+        }
+    }
+
+    //auditors only read, support additionally helps customers with their own money
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Customer => &[Permission::Transact],
+            Role::Support => &[
+                Permission::Transact,
+                Permission::ReadUsers,
+                Permission::ReadAccounts,
+                Permission::ReviewTransactions,
+                Permission::ReviewKyc,
+            ],
+            Role::Auditor => &[Permission::ReadUsers, Permission::ReadAccounts],
+            Role::Admin => &[
+                Permission::Transact,
+                Permission::ReadUsers,
+                Permission::ReadAccounts,
+                Permission::FreezeAccounts,
+                Permission::CloseAccounts,
+                Permission::ManageRoles,
+                Permission::ManageFees,
+                Permission::ManageLimits,
+                Permission::ReviewTransactions,
+                Permission::ReviewKyc,
+            ],
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    pub fn is_staff(&self) -> bool {
+        *self != Role::Customer
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(Role::Customer),
+            "support" => Ok(Role::Support),
+            "admin" => Ok(Role::Admin),
+            "auditor" => Ok(Role::Auditor),
+            _ => Err(format!("Unknown role {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Permission::Transact => "transact",
+            Permission::ReadUsers => "read_users",
+            Permission::ReadAccounts => "read_accounts",
+            Permission::FreezeAccounts => "freeze_accounts",
+            Permission::CloseAccounts => "close_accounts",
+            Permission::ManageRoles => "manage_roles",
+            Permission::ManageFees => "manage_fees",
+            Permission::ManageLimits => "manage_limits",
+            Permission::ReviewTransactions => "review_transactions",
+            Permission::ReviewKyc => "review_kyc",
+        };
+        f.write_str(name)
+    }
+}
+
+//the authenticated user of a request, inserted by JwtMiddleware
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caller {
+    pub user_id: Uuid,
+    pub role: Role,
+    //the token was issued for a fresh one-time password, see POST /v1/sessions/elevate
+    pub elevated: bool,
+}
+
+impl Caller {
+    //owners always reach their own resources, anyone else needs the permission
+    pub fn may_access(&self, owner: Uuid, permission: Permission) -> bool {
+        self.user_id == owner || self.role.can(permission)
+    }
+}
+
+#[derive(Debug)]
+pub enum AccessDenied {
+    Unauthenticated,
+    Forbidden(Permission),
+    Unavailable,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessDenied::Unauthenticated => f.write_str("Invalid or missing JWT token"),
+            AccessDenied::Forbidden(p) => write!(f, "Missing permission {}", p),
+            AccessDenied::Unavailable => f.write_str("Internal server error"),
+        }
+    }
+}
+
+impl ResponseError for AccessDenied {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AccessDenied::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AccessDenied::Forbidden(_) => StatusCode::FORBIDDEN,
+            AccessDenied::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let code = match self {
+            AccessDenied::Unauthenticated => "unauthorized",
+            AccessDenied::Forbidden(_) => "forbidden",
+            AccessDenied::Unavailable => "internal_error",
+        };
+        failure(self.status_code(), code, &self.to_string())
+    }
+}
+
+//a customer token can only ever lose rights, staff tokens are checked against users.role on every request
+//so a demotion applies right away instead of when the token expires
+impl FromRequest for Caller {
+    type Error = AccessDenied;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let caller = req.extensions().get::<Caller>().copied();
+        let data = req.app_data::<web::Data<AppState>>().cloned();
+        Box::pin(async move {
+            let caller = caller.ok_or(AccessDenied::Unauthenticated)?;
+            if !caller.role.is_staff() {
+                return Ok(caller);
+            }
+            let data = data.ok_or(AccessDenied::Unavailable)?;
+            match get_role(&data.db, caller.user_id).await {
+                Ok(role) => Ok(Caller { role, ..caller }),
+                Err(sqlx::Error::RowNotFound) => Err(AccessDenied::Unauthenticated),
+                Err(e) => {
+                    error!(error = %e, "Error at role lookup");
+                    Err(AccessDenied::Unavailable)
+                }
+            }
+        })
+    }
+}
+
+//type level permission of a route, e.g. Authorized<require::FreezeAccounts>
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+pub mod require {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! permissions {
+        ($($name:ident),*) => {
+            $(
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Permission = Permission::$name;
+                }
+            )*
+        };
+    }
+
+    permissions!(
+        Transact,
+        ReadUsers,
+        ReadAccounts,
+        FreezeAccounts,
+        CloseAccounts,
+        ManageRoles,
+        ManageFees,
+        ManageLimits,
+        ReviewTransactions,
+        ReviewKyc
+    );
+}
+
+//extracts the Caller and answers 403 unless its role grants P
+pub struct Authorized<P> {
+    pub caller: Caller,
+    permission: PhantomData<P>,
+}
+
+impl<P: RequiredPermission + 'static> FromRequest for Authorized<P> {
+    type Error = AccessDenied;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let caller = Caller::from_request(req, payload);
+        let path = req.path().to_string();
+        Box::pin(async move {
+            let caller = caller.await?;
+            if !caller.role.can(P::PERMISSION) {
+                warn!(
+                    user_id = %caller.user_id,
+                    role = %caller.role,
+                    permission = %P::PERMISSION,
+                    path,
+                    "Access denied"
+                );
+                return Err(AccessDenied::Forbidden(P::PERMISSION));
+            }
+            Ok(Authorized {
+                caller,
+                permission: PhantomData,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App, HttpResponse,
+    };
+    use serde_json::Value;
+
+    use crate::utilities::{test_harness::TestDb, utils::JwtMiddleware};
+
+    use super::{require, Authorized, Permission, Role};
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Customer.can(Permission::Transact));
+        assert!(!Role::Customer.can(Permission::ReadUsers));
+        assert!(!Role::Auditor.can(Permission::Transact));
+        assert!(Role::Auditor.can(Permission::ReadAccounts));
+        assert!(!Role::Support.can(Permission::FreezeAccounts));
+        assert!(Role::Admin.can(Permission::ManageRoles));
+        for role in [Role::Customer, Role::Support, Role::Admin, Role::Auditor] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_authorized_extractor() {
+        async fn freeze(_: Authorized<require::FreezeAccounts>) -> HttpResponse {
+            HttpResponse::Ok().finish()
+        }
+
+        let db = TestDb::new().await;
+        let customer = db.user().create().await;
+        let admin = db.user().role(Role::Admin).create().await;
+        let app = init_service(
+            App::new()
+                .wrap(JwtMiddleware)
+                .app_data(db.app_data())
+                .route("/freeze", web::post().to(freeze)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/freeze")
+            .insert_header(customer.bearer())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp_body: Value = read_body_json(resp).await;
+        assert_eq!(resp_body["code"], "forbidden");
+
+        let req = TestRequest::post()
+            .uri("/freeze")
+            .insert_header(admin.bearer())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        //the admin token is still valid, but the role was taken away in the meantime
+        crate::models::users::set_role(&db.pool, admin.user_id, Role::Customer)
+            .await
+            .unwrap();
+        let req = TestRequest::post()
+            .uri("/freeze")
+            .insert_header(admin.bearer())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,39 @@
+//background worker that books the due scheduled payments
+//several instances may run it at once, a schedule row is locked while its payment is booked
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+
+use crate::{
+    config::settings::SchedulerSettings,
+    models::schedules::{process_due_schedules, RetryPolicy},
+    utilities::{monitoring::Compliance, workers::WorkerHandle},
+};
+
+pub const WORKER_NAME: &str = "scheduler";
+
+//a poll may run a whole batch, the worker is stalled when it did not beat for three intervals and a minute
+pub fn max_silence(settings: &SchedulerSettings) -> Duration {
+    Duration::from_secs(settings.poll_interval_secs * 3 + 60)
+}
+
+pub async fn run(pool: Pool<Postgres>, compliance: Arc<Compliance>, settings: SchedulerSettings, mut handle: WorkerHandle) {
+    let interval = Duration::from_secs(settings.poll_interval_secs);
+    let policy = RetryPolicy {
+        max_retries: settings.max_retries,
+        delay: chrono::Duration::minutes(settings.retry_delay_minutes),
+    };
+    loop {
+        handle.heartbeat();
+        match process_due_schedules(&pool, &compliance, Utc::now().naive_utc(), &policy, settings.batch_size).await {
+            Ok(0) => {}
+            Ok(n) => info!(worker = handle.name(), processed = n, "Scheduled payments processed"),
+            Err(e) => error!(worker = handle.name(), error = %e, "Error at scheduled payments"),
+        }
+        if handle.sleep(interval).await {
+            break;
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//sanctions screening, names are matched against the list file named by screening.list_file
+//the list is read at startup and on POST /admin/screening/reload, kept in the Compliance of the AppState with the monitoring rules
+//the file uses the OFAC SDN CSV format: no header, ent_num, SDN_Name, SDN_Type, Program, ... with -0- for empty fields
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{config::settings::ScreeningSettings, utilities::monitoring::Action};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ListEntry {
+    //ent_num of the list
+    pub id: String,
+    pub name: String,
+    //individual, vessel, aircraft or empty for entities
+    pub kind: String,
+    pub program: String,
+    //lowercase words in alphabetical order, "SMITH, John" and "john_smith" compare equal
+    normalized: String,
+}
+
+//the list entry closest to a screened name
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Hit {
+    pub entry_id: String,
+    pub name: String,
+    pub program: String,
+    //from 0 to 1
+    pub score: f64,
+}
+
+//allow when nothing reached review_score
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Screening {
+    pub action: Action,
+    pub hit: Option<Hit>,
+    //the source of the list that screened the name
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default, ToSchema)]
+pub struct SanctionsList {
+    #[serde(skip)]
+    entries: Vec<ListEntry>,
+    pub size: usize,
+    //where and when the list was read, none for the empty default
+    pub source: Option<String>,
+    pub loaded_at: Option<NaiveDateTime>,
+    pub review_score: f64,
+    pub block_score: f64,
+}
+
+pub fn normalize(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase();
+    let mut words: Vec<&str> = cleaned.split_whitespace().collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+fn field(v: &str) -> String {
+    match v.trim() {
+        "-0-" => String::new(),
+        v => v.to_string(),
+    }
+}
+
+impl SanctionsList {
+    pub fn parse(content: &[u8], source: &str, settings: &ScreeningSettings) -> Result<SanctionsList, String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(content);
+        let mut entries = vec![];
+        for (i, record) in reader.records().enumerate() {
+            let record = record.map_err(|e| format!("{}: {}", source, e))?;
+            let id = field(record.get(0).unwrap_or_default());
+            //the published file ends with an end-of-file character on its own line
+            if id.is_empty() || id == "\u{1a}" {
+                continue;
+            }
+            let name = field(record.get(1).unwrap_or_default());
+            if id.parse::<u64>().is_err() || name.is_empty() {
+                return Err(format!("{}: line {}: expected ent_num and SDN_Name", source, i + 1));
+            }
+            entries.push(ListEntry {
+                normalized: normalize(&name),
+                id,
+                name,
+                kind: field(record.get(2).unwrap_or_default()),
+                program: field(record.get(3).unwrap_or_default()),
+            });
+        }
+        Ok(SanctionsList {
+            size: entries.len(),
+            entries,
+            source: Some(source.to_string()),
+            loaded_at: Some(Utc::now().naive_utc()),
+            review_score: settings.review_score,
+            block_score: settings.block_score,
+        })
+    }
+
+    pub fn read(path: &Path, settings: &ScreeningSettings) -> Result<SanctionsList, String> {
+        let content = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        SanctionsList::parse(&content, &path.display().to_string(), settings)
+    }
+
+    //the closest entry decides, names are compared with the Jaro-Winkler similarity of their sorted words
+    pub fn screen(&self, name: &str) -> Screening {
+        let screened = normalize(name);
+        let allow = Screening {
+            source: self.source.clone(),
+            ..Screening::default()
+        };
+        if screened.is_empty() {
+            return allow;
+        }
+        let best = self
+            .entries
+            .iter()
+            .map(|e| (e, strsim::jaro_winkler(&screened, &e.normalized)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some((entry, score)) if score >= self.review_score => Screening {
+                action: if score >= self.block_score { Action::Block } else { Action::Review },
+                hit: Some(Hit {
+                    entry_id: entry.id.clone(),
+                    name: entry.name.clone(),
+                    program: entry.program.clone(),
+                    score,
+                }),
+                source: self.source.clone(),
+            },
+            _ => allow,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct Sanctions(RwLock<Arc<SanctionsList>>);
+
+impl Sanctions {
+    //the list in use
+    pub fn current(&self) -> Arc<SanctionsList> {
+        self.0.read().map(|l| l.clone()).unwrap_or_default()
+    }
+
+    pub fn install(&self, list: SanctionsList) {
+        info!(entries = list.size, source = ?list.source, "Sanctions list loaded");
+        if let Ok(mut l) = self.0.write() {
+            *l = Arc::new(list);
+        }
+    }
+
+    //reads the configured file and puts it in use, an empty list_file turns screening off
+    pub fn reload(&self, settings: &ScreeningSettings) -> Result<Arc<SanctionsList>, String> {
+        let list = match settings.list_file.is_empty() {
+            true => SanctionsList::default(),
+            false => SanctionsList::read(Path::new(&settings.list_file), settings)?,
+        };
+        self.install(list);
+        Ok(self.current())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, SanctionsList};
+    use crate::{config::settings::ScreeningSettings, utilities::monitoring::Action};
+
+    #[test]
+    fn test_sdn_list() {
+        let content = b"36,\"AEROCARIBBEAN AIRLINES\",-0- ,\"CUBA\",-0- ,-0- \r\n\
+            2674,\"ABU ZAYD, Ahmad\",\"individual\",\"SDGT\",-0- ,-0- \r\n\
+            \x1a\r\n";
+        let list = SanctionsList::parse(content, "sdn.csv", &ScreeningSettings::default()).unwrap();
+        assert_eq!(list.size, 2);
+        assert_eq!(normalize("ABU ZAYD, Ahmad"), "abu ahmad zayd");
+
+        let hit = list.screen("ahmad_abu_zayd");
+        assert_eq!(hit.action, Action::Block);
+        assert_eq!(hit.hit.unwrap().entry_id, "2674");
+        let close = list.screen("Ahmed Abu Zaid");
+        assert_eq!(close.action, Action::Review);
+        assert_eq!(list.screen("alice_smith").action, Action::Allow);
+        assert!(list.screen("alice_smith").hit.is_none());
+
+        assert!(SanctionsList::parse(b"x,\"NAME\"\n", "sdn.csv", &ScreeningSettings::default()).is_err());
+    }
+}
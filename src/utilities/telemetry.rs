@@ -0,0 +1,426 @@
+use std::{
+    future::{ready, Ready},
+    time::Instant,
+};
+
+use actix_web::{
+    body::BoxBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures_util::future::LocalBoxFuture;
+use tracing::{field::Empty, Instrument};
+use tracing_subscriber::{
+    filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+use uuid::Uuid;
+
+use crate::{config::settings::LoggingSettings, utilities::metrics};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+//keeps the exporter alive for the lifetime of the process and flushes it on drop
+pub struct TelemetryGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Error at otlp shutdown : {:?}", e);
+            }
+        }
+    }
+}
+
+//installs the global subscriber, RUST_LOG wins over logging.level
+pub fn init(settings: &LoggingSettings) -> TelemetryGuard {
+    let fmt_layer = match settings.format.as_str() {
+        "pretty" => tracing_subscriber::fmt::layer().boxed(),
+        _ => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    //filters are per layer, so the query metrics keep their db.* spans whatever the log level is
+    let registry = tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(env_filter(settings)))
+        .with(metrics::DbQueryLayer.with_filter(filter_fn(metrics::DbQueryLayer::is_db_span)));
+
+    #[cfg(feature = "otlp")]
+    {
+        let (otel_layer, provider) = match otlp::layer(settings) {
+            Some((layer, provider)) => (Some(layer.with_filter(env_filter(settings))), Some(provider)),
+            None => (None, None),
+        };
+        registry.with(otel_layer).init();
+        TelemetryGuard { provider }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        TelemetryGuard {}
+    }
+}
+
+fn env_filter(settings: &LoggingSettings) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(settings.level.clone()))
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::{trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use crate::config::settings::LoggingSettings;
+
+    pub fn layer<S>(
+        settings: &LoggingSettings,
+    ) -> Option<(Box<dyn Layer<S> + Send + Sync>, SdkTracerProvider)>
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        if settings.otlp_endpoint.is_empty() {
+            return None;
+        }
+        let exporter = match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!(
+                "{}/v1/traces",
+                settings.otlp_endpoint.trim_end_matches('/')
+            ))
+            .build()
+        {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Error at otlp exporter, traces are not exported : {:?}", e);
+                return None;
+            }
+        };
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(settings.service_name.clone())
+                    .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+                    .build(),
+            )
+            .build();
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = provider.tracer("payments_dodo");
+        Some((
+            tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+            provider,
+        ))
+    }
+}
+
+//W3C trace context, https://www.w3.org/TR/trace-context/#traceparent-header
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub flags: String,
+}
+
+impl TraceContext {
+    pub fn parse(header: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        if parts.len() < 4 {
+            return None;
+        }
+        let (version, trace_id, span_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+        let hex = |s: &str, len: usize| {
+            s.len() == len && s.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        };
+        //version ff is invalid, version 00 must have exactly four fields
+        if !hex(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+        if !hex(trace_id, 32) || !hex(span_id, 16) || !hex(flags, 2) {
+            return None;
+        }
+        if trace_id.chars().all(|c| c == '0') || span_id.chars().all(|c| c == '0') {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            flags: flags.to_string(),
+        })
+    }
+
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: Uuid::new_v4().simple().to_string(),
+            span_id: new_span_id(),
+            flags: String::from("01"),
+        }
+    }
+
+    //the context of this service's span inside the caller's trace
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            flags: self.flags.clone(),
+        }
+    }
+
+    pub fn header(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+fn new_span_id() -> String {
+    Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+//request id of the current request, available to handlers through the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+//a client supplied id is kept when it is short and printable, otherwise a new one is generated
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| !h.is_empty() && h.len() <= 128 && h.chars().all(|c| c.is_ascii_graphic()))
+        .map(|h| h.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+//opens a span per request, tags it with request id and trace context and echoes both on the response
+pub struct RequestTracing;
+
+impl<S> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingService { service }))
+    }
+}
+
+pub struct RequestTracingService<S> {
+    service: S,
+}
+
+impl<S> Service<ServiceRequest> for RequestTracingService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let request_id = request_id(&req);
+        let parent = req
+            .headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(TraceContext::parse);
+        #[allow(unused_mut)]
+        let mut trace = match &parent {
+            Some(p) => p.child(),
+            None => TraceContext::new_root(),
+        };
+
+        let span = tracing::info_span!(
+            "http.request",
+            http.method = %req.method(),
+            http.target = %req.path(),
+            http.route = Empty,
+            http.status_code = Empty,
+            latency_ms = Empty,
+            request_id = %request_id,
+            trace_id = Empty,
+            span_id = Empty,
+        );
+
+        //with the exporter installed the ids come from the exported span so downstream services join the same trace
+        #[cfg(feature = "otlp")]
+        {
+            use opentelemetry::trace::TraceContextExt;
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+            if let Some(p) = &parent {
+                let carrier =
+                    std::collections::HashMap::from([(TRACEPARENT_HEADER.to_string(), p.header())]);
+                let cx = opentelemetry::global::get_text_map_propagator(|prop| prop.extract(&carrier));
+                span.set_parent(cx);
+            }
+            let cx = span.context();
+            let sc = cx.span().span_context().clone();
+            if sc.is_valid() {
+                trace = TraceContext {
+                    trace_id: sc.trace_id().to_string(),
+                    span_id: sc.span_id().to_string(),
+                    flags: format!("{:02x}", sc.trace_flags().to_u8()),
+                };
+            }
+        }
+        span.record("trace_id", trace.trace_id.as_str());
+        span.record("span_id", trace.span_id.as_str());
+
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+        let fut = span.in_scope(|| self.service.call(req));
+
+        Box::pin(
+            async move {
+                let mut res = fut.await?;
+                let span = tracing::Span::current();
+                let route = res.request().match_pattern();
+                if let Some(route) = &route {
+                    span.record("http.route", route.as_str());
+                }
+                span.record("http.status_code", res.status().as_u16());
+                span.record("latency_ms", start.elapsed().as_millis() as u64);
+                metrics::observe_request(
+                    res.request().method().as_str(),
+                    route.as_deref(),
+                    res.status().as_u16(),
+                    start.elapsed().as_secs_f64(),
+                );
+
+                if let Ok(v) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), v);
+                }
+                if let Ok(v) = HeaderValue::from_str(&trace.header()) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(TRACEPARENT_HEADER), v);
+                }
+
+                if res.status().is_server_error() {
+                    tracing::error!("request completed");
+                } else {
+                    tracing::info!("request completed");
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+//e.g. "j***@example.com", enough to correlate log lines without exposing the address
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let first: String = local.chars().take(1).collect();
+            format!("{}***@{}", first, domain)
+        }
+        None => String::from("***"),
+    }
+}
+
+//hides the password of a connection string
+pub fn redact_url(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme), Some(at)) if at > scheme => {
+            let creds = &url[scheme + 3..at];
+            match creds.split_once(':') {
+                Some((user, _)) => format!("{}{}:***{}", &url[..scheme + 3], user, &url[at..]),
+                None => url.to_string(),
+            }
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test::{call_service, init_service, TestRequest}, web, App, HttpResponse};
+
+    use super::{mask_email, redact_url, RequestTracing, TraceContext};
+
+    #[test]
+    fn test_traceparent_parsing() {
+        let parent =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(parent.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.span_id, "00f067aa0ba902b7");
+
+        let child = parent.child();
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_ne!(child.span_id, parent.span_id);
+        assert_eq!(TraceContext::parse(&child.header()), Some(child));
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_redaction() {
+        assert_eq!(mask_email("john@example.com"), "j***@example.com");
+        assert_eq!(
+            redact_url("postgres://postgres:password@db:5432/mydb"),
+            "postgres://postgres:***@db:5432/mydb"
+        );
+        assert_eq!(redact_url("postgres://db/mydb"), "postgres://db/mydb");
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_and_traceparent_propagation() {
+        let app = init_service(
+            App::new()
+                .wrap(RequestTracing)
+                .route("/ping", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/ping")
+            .insert_header(("x-request-id", "abc-123"))
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc-123");
+        let traceparent = resp.headers().get("traceparent").unwrap().to_str().unwrap();
+        let ctx = TraceContext::parse(traceparent).unwrap();
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        //without headers both are generated
+        let req = TestRequest::get().uri("/ping").to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp.headers().get("x-request-id").is_some());
+        assert!(TraceContext::parse(
+            resp.headers().get("traceparent").unwrap().to_str().unwrap()
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,69 @@
+//time-based one-time passwords (RFC 6238) as authenticator apps generate them: SHA-1, 6 digits, 30 second steps
+//a code of the step before or after the current one is accepted as well, for clocks that drift apart
+use rand::{rngs::OsRng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+pub const ISSUER: &str = "payments_dodo";
+pub const STEP_SECS: u64 = 30;
+const DIGITS: usize = 6;
+
+fn totp(secret: &str, account: &str) -> Result<TOTP, String> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| format!("{:?}", e))?;
+    //a colon separates issuer and account in the provisioning uri
+    TOTP::new(Algorithm::SHA1, DIGITS, 1, STEP_SECS, bytes, Some(ISSUER.to_string()), account.replace(':', "_"))
+        .map_err(|e| e.to_string())
+}
+
+//160 random bits, base32 encoded as authenticator apps expect them
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+//otpauth://totp/payments_dodo:<account>?secret=...&issuer=payments_dodo, shown as a QR code by the client
+pub fn provisioning_uri(secret: &str, account: &str) -> Result<String, String> {
+    totp(secret, account).map(|t| t.get_url())
+}
+
+pub fn code_at(secret: &str, time: u64) -> Result<String, String> {
+    totp(secret, "").map(|t| t.generate(time))
+}
+
+//the step of the code at the given unix time, none for a wrong code
+pub fn matching_step(secret: &str, code: &str, time: u64) -> Option<u64> {
+    let totp = totp(secret, "").ok()?;
+    let code = code.trim();
+    let current = time / STEP_SECS;
+    [current.saturating_sub(1), current, current + 1]
+        .into_iter()
+        .find(|step| constant_time_eq(totp.generate(step * STEP_SECS).as_bytes(), code.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code_at, matching_step, new_secret, provisioning_uri};
+
+    #[test]
+    fn test_totp_codes() {
+        //RFC 6238 appendix B, the ascii secret "12345678901234567890" in base32
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(code_at(secret, 59).unwrap(), "287082");
+        assert_eq!(code_at(secret, 1111111109).unwrap(), "081804");
+        assert_eq!(matching_step(secret, "081804", 1111111109), Some(37037036));
+        //one step of drift either way
+        assert_eq!(matching_step(secret, "081804", 1111111109 + 30), Some(37037036));
+        assert_eq!(matching_step(secret, "081804", 1111111109 + 60), None);
+        assert_eq!(matching_step(secret, "000000", 1111111109), None);
+
+        let secret = new_secret();
+        assert_eq!(secret.len(), 32);
+        let uri = provisioning_uri(&secret, "alice@example.com").unwrap();
+        assert!(uri.starts_with("otpauth://totp/payments_dodo:alice%40example.com?secret="));
+        assert!(uri.contains("issuer=payments_dodo"));
+    }
+}
@@ -1,27 +1,110 @@
 use actix_web::{
     body::BoxBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    web::Data,
-    Error, HttpMessage, HttpRequest, HttpResponse,
+    http::StatusCode,
+    middleware::DefaultHeaders,
+    web, Error, HttpMessage, HttpRequest, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::errors::ErrorKind;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{Pool, Postgres};
 use uuid::Uuid;
-use std::future::{ready, Ready};
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
 
 use crate::{
-    models::users::get_user,
-    utilities::{auth::decode_jwt, errors::api_error},
+    models::{
+        api_keys::{authenticate_api_key, Scope, KEY_PREFIX},
+        oauth::token_active,
+        users::{get_user, session_active},
+    },
+    utilities::{
+        auth::{decode_jwt, Claims},
+        envelope::failure,
+        metrics::record_jwt_rejection,
+        rbac::{Caller, Role},
+    },
     AppState,
 };
 
+//reachable without an Authorization header
+pub const PUBLIC_PATHS: &[&str] = &[
+    "/user/register_user",
+    "/user/get_token",
+    "/metrics",
+    "/healthz",
+    "/readyz",
+    "/openapi.json",
+    "/docs",
+    "/v1/users",
+    "/v1/sessions",
+    "/v1/users/verification/confirm",
+    "/v1/password_resets",
+    "/v1/password_resets/confirm",
+    "/v1/oauth/token",
+    "/v1/oauth/introspect",
+];
+//the Swagger UI and its assets
+pub const PUBLIC_PREFIXES: &[&str] = &["/docs/"];
+
+//the routes an API key or OAuth2 access token may call and the scope each needs, every other route takes a login JWT only
+pub const SCOPED_ROUTES: &[(&str, &str, Scope)] = &[
+    ("GET", "/balance/fetch_balance", Scope::BalanceRead),
+    ("GET", "/v1/balance", Scope::BalanceRead),
+    ("GET", "/v1/balance/limits", Scope::BalanceRead),
+    ("GET", "/transaction/fetch_transaction", Scope::TransactionsRead),
+    ("GET", "/transaction/list_trans", Scope::TransactionsRead),
+    ("POST", "/transaction/resolve_payee", Scope::TransactionsRead),
+    ("GET", "/v1/transactions", Scope::TransactionsRead),
+    ("GET", "/v1/transactions/{id}", Scope::TransactionsRead),
+    ("POST", "/v1/transactions/quote", Scope::TransactionsRead),
+    ("POST", "/v1/payees/resolve", Scope::TransactionsRead),
+    ("POST", "/transaction/operations", Scope::TransactionsWrite),
+    ("POST", "/v1/transactions", Scope::TransactionsWrite),
+    ("POST", "/v1/transfers", Scope::TransactionsWrite),
+    ("GET", "/v1/beneficiaries", Scope::BeneficiariesRead),
+    ("GET", "/v1/beneficiaries/{id}", Scope::BeneficiariesRead),
+    ("POST", "/v1/beneficiaries", Scope::BeneficiariesWrite),
+    ("PUT", "/v1/beneficiaries/{id}", Scope::BeneficiariesWrite),
+    ("DELETE", "/v1/beneficiaries/{id}", Scope::BeneficiariesWrite),
+    ("GET", "/v1/payouts", Scope::PayoutsRead),
+    ("GET", "/v1/payouts/{id}", Scope::PayoutsRead),
+    ("GET", "/v1/payouts/{id}/items", Scope::PayoutsRead),
+    ("GET", "/v1/payouts/{id}/results.csv", Scope::PayoutsRead),
+    ("POST", "/v1/payouts", Scope::PayoutsWrite),
+];
+
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path) || PUBLIC_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+pub fn required_scope(method: &str, pattern: &str) -> Option<Scope> {
+    SCOPED_ROUTES
+        .iter()
+        .find(|(m, p, _)| *m == method && *p == pattern)
+        .map(|(_, _, scope)| *scope)
+}
+
+//scopes served before /v1 existed, answered with a Deprecation header (RFC 9745)
+pub const LEGACY_SCOPES: &[&str] = &["/user", "/balance", "/transaction"];
+//2026-10-19, the day /v1 was released
+pub const LEGACY_DEPRECATED_AT: &str = "@1792368000";
+
+pub fn legacy_deprecation() -> DefaultHeaders {
+    DefaultHeaders::new()
+        .add(("Deprecation", LEGACY_DEPRECATED_AT))
+        .add(("Link", "</docs/>; rel=\"deprecation\"; type=\"text/html\""))
+}
+
 pub struct JwtMiddleware;
 
 impl<S> Transform<S, ServiceRequest> for JwtMiddleware
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse<BoxBody>;
@@ -31,17 +114,161 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(JwtMiddleWareService { service }))
+        ready(Ok(JwtMiddleWareService { service: Rc::new(service) }))
     }
 }
 
 pub struct JwtMiddleWareService<S> {
-    service: S,
+    service: Rc<S>,
 }
 
-impl<S> Service<ServiceRequest> for JwtMiddleWareService<S>
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized().json(json!(
+        {
+            "status": "Error",
+    "message": "Invalid or missing JWT token",
+    "detailed_Message" : "Connection Timeout. JWT is invalid",
+    "code": "unauthorized"
+        }
+    ))
+}
+
+fn internal_error() -> HttpResponse {
+    failure(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error")
+}
+
+//the message of the 403 when the scopes of an API key or OAuth2 token do not cover the route
+fn scope_denied(req: &ServiceRequest, scopes: &[Scope]) -> Option<String> {
+    let scope = req
+        .match_pattern()
+        .and_then(|pattern| required_scope(req.method().as_str(), &pattern));
+    match scope {
+        Some(scope) if scopes.contains(&scope) => None,
+        Some(scope) => Some(format!("Missing the scope {}", scope)),
+        None => Some("Route is not available to API keys and OAuth2 tokens".to_string()),
+    }
+}
+
+//API keys and OAuth2 tokens reach the routes of their scopes only, and never pass the step-up check
+async fn call_scoped<S>(
+    service: Rc<S>,
+    req: ServiceRequest,
+    user_id: Uuid,
+    role: Role,
+    scopes: &[Scope],
+) -> Result<ServiceResponse<BoxBody>, Error>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
+{
+    if let Some(message) = scope_denied(&req, scopes) {
+        record_jwt_rejection("insufficient_scope");
+        tracing::warn!(path = req.path(), "Rejected request without the scope of the route");
+        let response = failure(StatusCode::FORBIDDEN, "insufficient_scope", &message);
+        return Ok(req.into_response(response));
+    }
+    req.extensions_mut().insert(user_id);
+    req.extensions_mut().insert(Caller {
+        user_id,
+        role,
+        elevated: false,
+    });
+    service.call(req).await
+}
+
+impl<S> JwtMiddleWareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    //Bearer sk_..., the key is looked up in the database
+    fn call_with_api_key(&self, req: ServiceRequest, key: String) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let owner = match req.app_data::<web::Data<AppState>>().cloned() {
+                Some(data) => authenticate_api_key(&data.db, &key).await,
+                None => Ok(None),
+            };
+            match owner {
+                Ok(Some(owner)) => call_scoped(service, req, owner.user_id, owner.role, &owner.scopes).await,
+                Ok(None) => {
+                    record_jwt_rejection("invalid_api_key");
+                    tracing::warn!(path = req.path(), "Rejected request with an unknown, revoked or expired API key");
+                    Ok(req.into_response(unauthorized()))
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Error at API key lookup");
+                    Ok(req.into_response(internal_error()))
+                }
+            }
+        })
+    }
+
+    //a JWT of a login, it ends early when the password is reset
+    fn call_with_jwt(&self, req: ServiceRequest, claims: Claims) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let active = match req.app_data::<web::Data<AppState>>().cloned() {
+                Some(data) => session_active(&data.db, claims.sub, claims.iat).await,
+                None => Ok(false),
+            };
+            match active {
+                Ok(true) => {
+                    req.extensions_mut().insert(claims.sub);
+                    req.extensions_mut().insert(Caller {
+                        user_id: claims.sub,
+                        role: claims.role,
+                        elevated: claims.elevated,
+                    });
+                    service.call(req).await
+                }
+                Ok(false) => {
+                    record_jwt_rejection("revoked");
+                    tracing::warn!(path = req.path(), "Rejected JWT issued before a password reset");
+                    Ok(req.into_response(unauthorized()))
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Error at session lookup");
+                    Ok(req.into_response(internal_error()))
+                }
+            }
+        })
+    }
+
+    //a JWT of POST /v1/oauth/token, it ends early when its client or the consent of the user is revoked or narrowed
+    fn call_with_oauth_token(&self, req: ServiceRequest, claims: Claims) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let active = match req.app_data::<web::Data<AppState>>().cloned() {
+                Some(data) => token_active(&data.db, &claims).await,
+                None => Ok(false),
+            };
+            match active {
+                Ok(true) => {
+                    let scopes: Vec<Scope> = claims
+                        .scope
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .filter_map(|s| s.parse().ok())
+                        .collect();
+                    call_scoped(service, req, claims.sub, claims.role, &scopes).await
+                }
+                Ok(false) => {
+                    record_jwt_rejection("revoked");
+                    tracing::warn!(path = req.path(), client_id = claims.client_id, "Rejected OAuth2 token of a revoked client or consent");
+                    Ok(req.into_response(unauthorized()))
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Error at OAuth2 token lookup");
+                    Ok(req.into_response(internal_error()))
+                }
+            }
+        })
+    }
+}
+
+impl<S> Service<ServiceRequest> for JwtMiddleWareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse<BoxBody>;
@@ -51,37 +278,48 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!(
-            "Hello from the JwtMiddleWareService. You requested: {}",
-            req.path()
-        );
         let auth_header = req
             .headers()
             .get("Authorization")
             .and_then(|h| h.to_str().ok());
 
-        if let Some(auth_value) = auth_header {
+        let reason = if let Some(auth_value) = auth_header {
             if auth_value.starts_with("Bearer ") {
                 let token = auth_value.trim_start_matches("Bearer ");
-                if let Ok(tok) = decode_jwt(token.to_string()) {
-                    req.extensions_mut().insert(tok.sub as Uuid);
-                    let fut = self.service.call(req);
-                    return Box::pin(async move { fut.await });
+                if token.starts_with(KEY_PREFIX) {
+                    let key = token.to_string();
+                    return self.call_with_api_key(req, key);
                 }
+                let decoded = req
+                    .app_data::<web::Data<AppState>>()
+                    .map(|data| decode_jwt(token.to_string(), &data.settings.auth));
+                match decoded {
+                    Some(Ok(tok)) if tok.client_id.is_some() => {
+                        return self.call_with_oauth_token(req, tok);
+                    }
+                    Some(Ok(tok)) => {
+                        return self.call_with_jwt(req, tok);
+                    }
+                    Some(Err(e)) if *e.kind() == ErrorKind::ExpiredSignature => "expired",
+                    _ => "invalid",
+                }
+            } else if is_public(req.path()) {
+                //e.g. the Basic authentication of OAuth2 clients at the token endpoint
+                let fut = self.service.call(req);
+                return Box::pin(fut);
+            } else {
+                "malformed"
             }
-        } else if req.path() == "/user/register_user" || req.path() == "/user/get_token" {
+        } else if is_public(req.path()) {
             let fut = self.service.call(req);
-            return Box::pin(async move { fut.await });
-        }
-        let response = HttpResponse::Unauthorized().json(json!(
-            {
-                "status": "Error",
-        "message": "Invalid or missing JWT token",
-        "detailed_Message" : "Connection Timeout. JWT is invalid"
-            }
-        ));
+            return Box::pin(fut);
+        } else {
+            "missing"
+        };
+        record_jwt_rejection(reason);
+        tracing::warn!(path = req.path(), reason, "Rejected request without a valid JWT");
         Box::pin(async move {
-            let response = req.into_response(response);
+            let response = req.into_response(unauthorized());
             Ok(response)
         })
     }
@@ -104,20 +342,16 @@ pub async fn validate_user(
     req: HttpRequest,
     email: String,
 ) -> Result<ValidateUserDetails, ValidateUserError> {
-    println!("Hello from the vslidate user");
     let uid = if let Some(id) = req.extensions().get::<i32>() {
-        id.clone()
+        *id
     } else {
         return Err(ValidateUserError {
             message: String::from("user id not found"),
             status: String::from("Error"),
         });
     };
-    println!("uid = {:?}", uid);
     match get_user(pool, email).await {
         Ok(v) => {
-            println!("{:?}", v);
-            println!("{}", uid);
             if v.id != uid {
                 return Err(ValidateUserError {
                     message: String::from("Not Authorized"),
@@ -132,7 +366,7 @@ pub async fn validate_user(
         Err(e) => {
             let mut message = "Invalid user details";
             if let sqlx::Error::Database(db_err) = &e {
-                println!("Db-error = {:?}", db_err.message());
+                tracing::warn!(error = db_err.message(), "Db-error");
                 message = db_err.message();
             }
             Err(ValidateUserError {
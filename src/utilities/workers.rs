@@ -0,0 +1,204 @@
+//registry of the background workers
+//every worker reports heartbeats, /readyz fails when one of them goes quiet or exits on its own
+//on shutdown the workers are signalled through a watch channel and main waits for them to flush
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub struct Workers {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    heartbeats: Mutex<HashMap<String, Heartbeat>>,
+    handles: Mutex<Vec<(String, JoinHandle<()>)>>,
+    shutdown: watch::Sender<bool>,
+}
+
+struct Heartbeat {
+    last: Instant,
+    max_silence: Duration,
+    exited: bool,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Workers::new()
+    }
+}
+
+impl Workers {
+    pub fn new() -> Workers {
+        let (shutdown, _) = watch::channel(false);
+        Workers {
+            inner: Arc::new(Inner {
+                heartbeats: Mutex::new(HashMap::new()),
+                handles: Mutex::new(vec![]),
+                shutdown,
+            }),
+        }
+    }
+
+    //starts a worker on the runtime, it is considered stalled when it does not beat within max_silence
+    pub fn spawn<F, Fut>(&self, name: &str, max_silence: Duration, worker: F)
+    where
+        F: FnOnce(WorkerHandle) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.heartbeats.lock().unwrap().insert(
+            name.to_string(),
+            Heartbeat {
+                last: Instant::now(),
+                max_silence,
+                exited: false,
+            },
+        );
+        let handle = WorkerHandle {
+            name: name.to_string(),
+            workers: self.clone(),
+            shutdown: self.inner.shutdown.subscribe(),
+        };
+        let fut = worker(handle);
+        let workers = self.clone();
+        let worker_name = name.to_string();
+        let join = tokio::spawn(async move {
+            fut.await;
+            if let Some(h) = workers.inner.heartbeats.lock().unwrap().get_mut(&worker_name) {
+                h.exited = true;
+            }
+        });
+        self.inner
+            .handles
+            .lock()
+            .unwrap()
+            .push((name.to_string(), join));
+        info!(worker = name, "Worker started");
+    }
+
+    //names of the workers that stopped beating, or exited while the service is not shutting down
+    pub fn stalled(&self) -> Vec<String> {
+        let stopping = self.is_shutting_down();
+        let mut stalled: Vec<String> = self
+            .inner
+            .heartbeats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, h)| {
+                if h.exited {
+                    !stopping
+                } else {
+                    h.last.elapsed() > h.max_silence
+                }
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        stalled.sort();
+        stalled
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.shutdown.borrow()
+    }
+
+    //signals every worker to stop and waits up to timeout for them to finish their current batch
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.inner.shutdown.send_replace(true);
+        let handles: Vec<(String, JoinHandle<()>)> =
+            self.inner.handles.lock().unwrap().drain(..).collect();
+        let deadline = tokio::time::Instant::now() + timeout;
+        for (name, handle) in handles {
+            let abort = handle.abort_handle();
+            match tokio::time::timeout_at(deadline, handle).await {
+                Ok(_) => info!(worker = name, "Worker stopped"),
+                Err(_) => {
+                    warn!(worker = name, "Worker did not stop in time, aborting");
+                    abort.abort();
+                }
+            }
+        }
+    }
+}
+
+//given to each worker to report progress and watch for shutdown
+pub struct WorkerHandle {
+    name: String,
+    workers: Workers,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl WorkerHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn heartbeat(&self) {
+        if let Some(h) = self.workers.inner.heartbeats.lock().unwrap().get_mut(&self.name) {
+            h.last = Instant::now();
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    //waits for the given time or until shutdown is requested, returns true on shutdown
+    pub async fn sleep(&mut self, duration: Duration) -> bool {
+        if self.is_stopping() {
+            return true;
+        }
+        let stopped = tokio::select! {
+            _ = tokio::time::sleep(duration) => false,
+            _ = self.shutdown.wait_for(|stop| *stop) => true,
+        };
+        stopped || self.is_stopping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Workers;
+
+    #[actix_web::test]
+    async fn test_heartbeats_and_shutdown() {
+        let workers = Workers::new();
+        let flushed = Arc::new(AtomicBool::new(false));
+
+        let done = flushed.clone();
+        workers.spawn("beating", Duration::from_secs(5), |mut handle| async move {
+            loop {
+                handle.heartbeat();
+                if handle.sleep(Duration::from_millis(10)).await {
+                    break;
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        workers.spawn("silent", Duration::from_millis(20), |mut handle| async move {
+            handle.sleep(Duration::from_secs(60)).await;
+        });
+        workers.spawn("crashed", Duration::from_secs(5), |_| async {});
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(workers.stalled(), vec!["crashed", "silent"]);
+
+        workers.shutdown(Duration::from_secs(5)).await;
+        assert!(flushed.load(Ordering::SeqCst));
+        assert!(workers.is_shutting_down());
+    }
+}